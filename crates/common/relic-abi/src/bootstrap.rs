@@ -1,5 +1,24 @@
 use crate::prelude::CAddr;
 
+/// Start of the stack of sigma. The kernel maps [`SIGMA_STACK_PAGES`] base pages from here.
+pub const SIGMA_STACK_START: u64 = 0x6FF_F000_0000;
+pub const SIGMA_STACK_PAGES: usize = 10;
+
+/// Number of unmapped pages reserved below the user stacks that the kernel creates.
+pub const USER_STACK_GUARD_PAGES: usize = 16;
+
+/// Location of the task buffer of sigma, which is also its TCB.
+pub const SIGMA_BUFFER_START: u64 = 0x600_0000_0000;
+
+/// Location of the frame buffer of sigma, see [`FrameBufferInfo`].
+pub const SIGMA_VGA_START: u64 = 0x5000_0000_0000;
+
+/// Location of the initrd of sigma, see [`InitrdInfo`].
+pub const SIGMA_INITRD_START: u64 = 0x5800_0000_0000;
+
+/// Maximum number of device memory regions given to sigma.
+pub const MAX_DEVICE_MEMORY_REGIONS: usize = 32;
+
 /// Info from the kernel to the sigma space. This provides the initial
 /// data needed for the sigma process.
 #[repr(C)]
@@ -9,13 +28,58 @@ pub struct BootstrapInfo {
     /// [StartCaddr, EndCAddr]
     pub free_mem_regions: (CAddr, CAddr),
 
-    /// Top level page table for this task.
+    /// Top level page table for this task. It is a PML5 if
+    /// [`Self::virtual_address_bits`] is 57.
     pub top_level_pml4: CAddr,
 
     /// Information about TLS.
     pub tls_info: TlsInfo,
 
     pub fb_info: FrameBufferInfo,
+
+    /// Device untyped capabilities for the memory mapped IO regions found by the
+    /// kernel. Only the first [`Self::device_memory_count`] entries are valid.
+    pub device_memory: [DeviceMemoryInfo; MAX_DEVICE_MEMORY_REGIONS],
+    pub device_memory_count: usize,
+
+    /// I/O port range capability for all I/O ports.
+    pub io_ports: CAddr,
+
+    /// Width of virtual addresses: 48 with four-level paging and 57 with
+    /// five-level paging. User addresses are below `1 << (bits - 1)`.
+    pub virtual_address_bits: u32,
+
+    /// The initrd, mapped read-only. Empty if the task has no initrd.
+    pub initrd_info: InitrdInfo,
+}
+
+const_assert!(core::mem::size_of::<BootstrapInfo>() <= 2048);
+
+impl BootstrapInfo {
+    /// The device memory regions given to sigma.
+    pub fn device_memory(&self) -> &[DeviceMemoryInfo] {
+        &self.device_memory[..self.device_memory_count]
+    }
+
+    /// The device memory region that contains the physical address.
+    pub fn find_device_memory(&self, paddr: u64) -> Option<&DeviceMemoryInfo> {
+        self.device_memory()
+            .iter()
+            .find(|region| region.paddr <= paddr && paddr < region.paddr + region.length)
+    }
+}
+
+/// A memory mapped IO region. Pages retyped from the untyped capability are
+/// not cleared and can be mapped with `MAP_PERMISSION_CACHE_DISABLE`.
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct DeviceMemoryInfo {
+    /// Device untyped capability of the region.
+    pub caddr: CAddr,
+    /// Start of the region. Page aligned.
+    pub paddr: u64,
+    /// Length of the region in bytes. A multiple of the page size.
+    pub length: u64,
 }
 
 #[derive(Debug, Default)]
@@ -29,6 +93,14 @@ pub struct FrameBufferInfo {
     pub frame_buffer_mode: ColorMode,
 }
 
+/// Location of the initrd archive in the address space of the task.
+#[derive(Debug, Default)]
+pub struct InitrdInfo {
+    pub initrd_vaddr: u64,
+    /// Size of the archive in bytes. The mapping is rounded up to pages.
+    pub initrd_size: u64,
+}
+
 #[derive(Debug, Default)]
 pub struct TlsInfo {
     pub tls_loaded: bool,
@@ -35,6 +35,23 @@ pub enum CapabilityErrors {
 
     /// Task buffer doesn't exist.
     TaskBufferNotFound,
+    /// The payload in the task buffer is not valid for the syscall.
+    TaskBufferPayloadInvalid,
+    /// The operation is not allowed on the task in its current state.
+    TaskInvalidState,
+
+    /// The budget or period of a scheduling context is invalid.
+    SchedContextParametersInvalid,
+
+    /// Nothing is mapped at the address or it is mapped by another page.
+    MemoryNotMapped,
+    /// The operation is only allowed on the original capability, not on a copy.
+    CapabilityIsDerived,
+    /// The capability is borrowed by another operation.
+    CapabilityAlreadyInUse,
+
+    /// The ports are not part of the I/O port range.
+    IoPortRangeInvalid,
 
     /// Unknown cap error.
     Unknown,
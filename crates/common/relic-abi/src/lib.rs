@@ -5,6 +5,7 @@ pub mod bootstrap;
 pub mod caddr;
 pub mod cap;
 pub mod syscall;
+pub mod task;
 
 #[macro_use]
 extern crate static_assertions;
@@ -2,6 +2,9 @@ use core::mem::MaybeUninit;
 
 use crate::{prelude::CAddr, SetDefault};
 
+/// Length of the payload data in a [`TaskBuffer`].
+pub const TASK_BUFFER_PAYLOAD_LENGTH: usize = 2048;
+
 /// Represents a task buffer used for system calls.
 #[derive(Debug)]
 #[repr(C)]
@@ -13,7 +16,7 @@ pub struct TaskBuffer {
     pub payload_length: usize,
     /// Payload data in the task buffer. Only data upto
     /// [`Self::payload_length`] is valid when used.
-    pub payload_data: [u8; 2048],
+    pub payload_data: [u8; TASK_BUFFER_PAYLOAD_LENGTH],
 
     /// Capability information when system call requires it.
     pub caps: [Option<CAddr>; 32],
@@ -88,6 +91,8 @@ pub enum SystemCall {
 
     /**
     Copy the provided capability into the provided cpool.
+    Copies of raw pages can be mapped into other address spaces
+    to share memory.
     Returns the new CAddr.
     */
     CopyCapability {
@@ -154,8 +159,197 @@ pub enum SystemCall {
         */
         vaddr: u64,
     },
+
+    /**
+    Exit the current task with the provided exit code. The task is
+    removed from scheduling and never resumed.
+    */
+    TaskExit(u64),
+    /**
+    Wait for the task at the given caddr to exit. The caller is resumed
+    once the task exits. Returns the exit code of the task. Joining the
+    caller, a task that was never started, or a task that waits for the
+    caller fails.
+    */
+    TaskJoin(CAddr),
+
+    /**
+    Get information about the task at the given caddr.
+    Returns a [`crate::task::TaskInfo`] in the task buffer.
+    */
+    TaskInfo(CAddr),
+    /**
+    Read the registers of the task at the given caddr.
+    Returns a [`crate::task::TaskRegisters`] in the task buffer.
+    */
+    TaskReadRegisters(CAddr),
+    /**
+    Write the registers of the task at the given caddr. The registers
+    are read as a [`crate::task::TaskRegisters`] from the task buffer.
+    */
+    TaskWriteRegisters(CAddr),
+
+    /**
+    Create a new scheduling context using the provided untyped memory
+    and store the capability in the current cpool. The task linked to
+    the context may run for `budget` timer ticks in every `period`.
+    Returns the new CAddr.
+    */
+    SchedContextRetype {
+        untyped_memory: CAddr,
+        budget: u64,
+        period: u64,
+    },
+    /**
+    Limit the task at the given caddr with the provided scheduling
+    context. Tasks without a scheduling context are not limited.
+    */
+    TaskSetSchedContext { task: CAddr, sched_context: CAddr },
+
+    /**
+    Map a given page into the provided address with the given permissions.
+    The permissions are passed in the lower 12 bits of `vaddr`, which must
+    otherwise be page aligned. See [`MAP_PERMISSION_WRITE`],
+    [`MAP_PERMISSION_EXECUTE`] and the memory types [`MAP_PERMISSION_CACHE_DISABLE`],
+    [`MAP_PERMISSION_WRITE_THROUGH`] and [`MAP_PERMISSION_WRITE_COMBINING`].
+    Without permissions, the page is read only and cached.
+    */
+    RawPageMapWithPermissions {
+        untyped_memory: CAddr,
+        top_level_table: CAddr,
+        vaddr: u64,
+        raw_page: CAddr,
+    },
+    /**
+    Unmap the page mapped at the provided address. The raw page must be
+    the capability that was used to map the page.
+    */
+    RawPageUnmap {
+        top_level_table: CAddr,
+        vaddr: u64,
+        raw_page: CAddr,
+    },
+    /**
+    Unmap and delete every copy of the raw page made with
+    [`SystemCall::CopyCapability`]. Must be used on the original page.
+    */
+    RawPageRevoke(CAddr),
+
+    /**
+    Clone the address space of the top level table into a new one stored
+    in the provided cpool. Page tables are created from the untyped memory.
+    Pages are shared and copied on the first write. The capabilities of the
+    page tables and pages are stored in new cpools created from the untyped
+    memory, which are stored in the provided cpool too. Nothing is changed if
+    the clone does not fit. Returns the index in the provided cpool.
+    */
+    L4Clone {
+        untyped_memory: CAddr,
+        top_level_table: CAddr,
+        cpool_to_store_in: CAddr,
+    },
+    /**
+    Nominate the untyped memory that copies of copy-on-write pages are
+    allocated from when the task at the given caddr writes to them.
+    */
+    TaskSetFaultUntyped { task: CAddr, untyped_memory: CAddr },
+
+    /**
+    Get the physical address of the raw page at the given caddr. Devices
+    use it to access the page directly. Returns the physical address and
+    the length of the page.
+    */
+    PageGetPhysical(CAddr),
+
+    /**
+    Create an I/O port range capability for `count` ports from `first_port`
+    and store it in the current cpool. The ports must be in the provided
+    I/O port range. Returns the new CAddr.
+    */
+    IoPortRangeDerive {
+        io_port_range: CAddr,
+        first_port: u64,
+        count: u64,
+    },
+    /**
+    Allow the task at the given caddr to use the ports of the I/O port
+    range from user mode.
+    */
+    TaskGrantIoPorts { task: CAddr, io_port_range: CAddr },
+
+    /**
+    Create a new untyped memory capability for `length` bytes of the provided
+    untyped memory and store it in the provided cpool. The length is rounded up
+    to base pages. Returns the index in the provided cpool.
+    */
+    UntypedRetype {
+        untyped_memory: CAddr,
+        length: u64,
+        cpool_to_store_in: CAddr,
+    },
+    /**
+    Create the top level table of a new address space using the provided
+    untyped memory and store it in the provided cpool. Returns the index in
+    the provided cpool.
+    */
+    TopLevelTableRetype {
+        untyped_memory: CAddr,
+        cpool_to_store_in: CAddr,
+    },
+    /**
+    Create a new raw page capability like [`SystemCall::RawPageRetype`], but
+    store it in the provided cpool. Returns the index in the provided cpool.
+    */
+    RawPageRetypeInto {
+        untyped_memory: CAddr,
+        size: u64,
+        cpool_to_store_in: CAddr,
+    },
+    /**
+    Create a new task using the provided untyped memory and link the cpool,
+    top level table and task buffer page to it. The task has the priority of
+    the caller and does not run until it is started with [`SystemCall::TaskStart`].
+    Returns the new CAddr.
+    */
+    TaskRetype {
+        untyped_memory: CAddr,
+        cpool: CAddr,
+        top_level_table: CAddr,
+        task_buffer: CAddr,
+    },
+    /**
+    Schedule the task at the given caddr, which was never started. Its
+    registers are set with [`SystemCall::TaskWriteRegisters`] before.
+    */
+    TaskStart(CAddr),
+    /**
+    Report page faults of the task at the given caddr in `start..end` as stack
+    overflows. The range is left unmapped below the stack of the task and must
+    be in the user half.
+    */
+    TaskSetStackGuard { task: CAddr, start: u64, end: u64 },
 }
 
+/// Permission to write to a page mapped with [`SystemCall::RawPageMapWithPermissions`].
+pub const MAP_PERMISSION_WRITE: u64 = 0b0010;
+/// Permission to execute a page mapped with [`SystemCall::RawPageMapWithPermissions`].
+pub const MAP_PERMISSION_EXECUTE: u64 = 0b0100;
+/// Disable caching for a page mapped with [`SystemCall::RawPageMapWithPermissions`].
+pub const MAP_PERMISSION_CACHE_DISABLE: u64 = 0b1000;
+/// Write through the cache for a page mapped with [`SystemCall::RawPageMapWithPermissions`].
+/// Ignored with [`MAP_PERMISSION_CACHE_DISABLE`].
+pub const MAP_PERMISSION_WRITE_THROUGH: u64 = 0b1_0000;
+/// Combine writes for a page mapped with [`SystemCall::RawPageMapWithPermissions`], which
+/// suits frame buffers. Ignored with the other memory types.
+pub const MAP_PERMISSION_WRITE_COMBINING: u64 = 0b10_0000;
+
+/// Exit code used by a task that exited because of a panic.
+pub const TASK_EXIT_CODE_PANIC: u64 = 101;
+
+/// Exit code of a task that the kernel exited because of a fault that the task
+/// cannot recover from.
+pub const TASK_EXIT_CODE_FAULT: u64 = 139;
+
 impl Default for SystemCall {
     fn default() -> Self {
         Self::None
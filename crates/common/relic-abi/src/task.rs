@@ -0,0 +1,66 @@
+/// General purpose register state of a task as seen by user space.
+/// Used to inspect and modify tasks, for example from a debugger.
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct TaskRegisters {
+    pub rax: u64,
+    pub rbx: u64,
+    pub rcx: u64,
+    pub rdx: u64,
+    pub rsi: u64,
+    pub rdi: u64,
+    pub rbp: u64,
+    pub rsp: u64,
+    pub r8: u64,
+    pub r9: u64,
+    pub r10: u64,
+    pub r11: u64,
+    pub r12: u64,
+    pub r13: u64,
+    pub r14: u64,
+    pub r15: u64,
+    pub rip: u64,
+    pub rflags: u64,
+    /// TCB location.
+    pub fs: u64,
+}
+
+/// Scheduling state of a task.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+#[repr(u64)]
+pub enum TaskState {
+    /// Unknown task state.
+    Unknown = 0,
+    /// The task has never been started before.
+    Inactive,
+    /// The task is currently running.
+    Running,
+    /// The task is waiting in the scheduler to be run.
+    Ready,
+    /// The task is blocked on a syscall or another task.
+    Blocked,
+    /// The task has exited. See [`TaskInfo::exit_code`].
+    Exited,
+}
+
+impl Default for TaskState {
+    fn default() -> Self {
+        Self::Unknown
+    }
+}
+
+/// Information about a task.
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct TaskInfo {
+    /// Unique ID of the task.
+    pub task_id: u64,
+    /// Current state of the task.
+    pub state: TaskState,
+    /// Exit code of the task. Only valid when the state is [`TaskState::Exited`].
+    pub exit_code: u64,
+    /// Scheduling priority of the task.
+    pub priority: u8,
+    /// CPU time consumed by the task in timestamp counter cycles.
+    pub cpu_time_cycles: u64,
+}
@@ -0,0 +1,196 @@
+//! Memory mapped IO of devices.
+
+use alloc::vec::Vec;
+use relic_abi::{
+    bootstrap::DeviceMemoryInfo,
+    cap::CapabilityErrors,
+    prelude::CAddr,
+    syscall::{MAP_PERMISSION_CACHE_DISABLE, MAP_PERMISSION_WRITE},
+};
+use relic_utils::align;
+use spin::Mutex;
+
+use crate::{
+    syscall_wrapper,
+    untyped::{PageSize, BASE_PAGE_SIZE},
+    vspace,
+};
+
+/// Pages retyped from a device memory region, in order from its start.
+#[derive(Debug)]
+struct RegionPages {
+    region: CAddr,
+    pages: Vec<DevicePage>,
+}
+
+#[derive(Debug)]
+struct DevicePage {
+    page: CAddr,
+    mapped: bool,
+}
+
+/// Pages of the device memory regions that were retyped so far. Retyped pages
+/// cannot be given back to their untyped memory, so they are kept for later
+/// mappings of the same registers.
+static RETYPED: Mutex<Vec<RegionPages>> = Mutex::new(Vec::new());
+
+/**
+Retype the pages of `region` up to page `end`. Device memory is handed out in order,
+so the pages from the start of the region are retyped as well. Pages that were
+retyped before a failure are kept.
+*/
+fn retype_up_to(
+    region: &DeviceMemoryInfo,
+    pages: &mut Vec<DevicePage>,
+    end: usize,
+) -> Result<(), CapabilityErrors> {
+    if pages.len() >= end {
+        return Ok(());
+    }
+
+    let missing = (end - pages.len()) as u64;
+    let (_, free_space) = syscall_wrapper::get_free_space(region.caddr)?;
+    if (free_space as u64) < missing * BASE_PAGE_SIZE {
+        return Err(CapabilityErrors::MemoryNotSufficient);
+    }
+
+    while pages.len() < end {
+        let page = syscall_wrapper::retype_raw_page(region.caddr, PageSize::Base.size_type())?;
+        let expected = region.paddr + pages.len() as u64 * BASE_PAGE_SIZE;
+        if syscall_wrapper::page_get_physical(page)?.0 != expected {
+            // Someone else retyped from the region, so the pages are out of order.
+            return Err(CapabilityErrors::MemoryNotSufficient);
+        }
+        pages.push(DevicePage {
+            page,
+            mapped: false,
+        });
+    }
+    Ok(())
+}
+
+/// Registers of a device that are mapped uncached into the address space. The
+/// registers are unmapped when dropped.
+#[derive(Debug)]
+pub struct DeviceMemory {
+    region: CAddr,
+    vaddr: u64,
+    paddr: u64,
+    length: u64,
+    first_page: usize,
+    pages: Vec<CAddr>,
+}
+
+impl DeviceMemory {
+    /**
+    Map `length` bytes at `offset` into the device memory `region`. The pages are
+    retyped the first time they are mapped and reused after the registers are
+    dropped. Fails with [`CapabilityErrors::MemoryAlreadyMapped`] if any of the
+    pages is mapped by another [`DeviceMemory`]. The pages are not cleared.
+    */
+    pub fn map(
+        region: &DeviceMemoryInfo,
+        offset: u64,
+        length: u64,
+    ) -> Result<Self, CapabilityErrors> {
+        let end = offset
+            .checked_add(length)
+            .filter(|&end| length > 0 && end <= region.length)
+            .ok_or(CapabilityErrors::InvalidMemoryAddress)?;
+        let first_page = (offset / BASE_PAGE_SIZE) as usize;
+        let end_page = (align::align_up(end, BASE_PAGE_SIZE) / BASE_PAGE_SIZE) as usize;
+
+        let mut retyped = RETYPED.lock();
+        let index = match retyped
+            .iter()
+            .position(|pages| pages.region == region.caddr)
+        {
+            Some(index) => index,
+            None => {
+                retyped.push(RegionPages {
+                    region: region.caddr,
+                    pages: Vec::new(),
+                });
+                retyped.len() - 1
+            }
+        };
+        let region_pages = &mut retyped[index].pages;
+        retype_up_to(region, region_pages, end_page)?;
+
+        let mapped_pages = &mut region_pages[first_page..end_page];
+        if mapped_pages.iter().any(|page| page.mapped) {
+            return Err(CapabilityErrors::MemoryAlreadyMapped);
+        }
+        let pages: Vec<CAddr> = mapped_pages.iter().map(|page| page.page).collect();
+
+        let permissions = MAP_PERMISSION_WRITE | MAP_PERMISSION_CACHE_DISABLE;
+        let vaddr = vspace::map_pages(&pages, PageSize::Base, permissions)?;
+        for page in mapped_pages {
+            page.mapped = true;
+        }
+
+        Ok(Self {
+            region: region.caddr,
+            vaddr: vaddr + offset % BASE_PAGE_SIZE,
+            paddr: region.paddr + offset,
+            length,
+            first_page,
+            pages,
+        })
+    }
+
+    /// Address of the registers in the address space of the task.
+    pub fn vaddr(&self) -> u64 {
+        self.vaddr
+    }
+
+    /// Physical address of the registers.
+    pub fn paddr(&self) -> u64 {
+        self.paddr
+    }
+
+    pub fn length(&self) -> u64 {
+        self.length
+    }
+
+    /// Page capabilities of the registers, in order.
+    pub fn pages(&self) -> &[CAddr] {
+        &self.pages
+    }
+
+    /// Read the register at `offset`.
+    ///
+    /// # Safety
+    /// The device must have a register of type `T` at `offset`.
+    pub unsafe fn read<T: Copy>(&self, offset: u64) -> T {
+        assert!(offset + core::mem::size_of::<T>() as u64 <= self.length);
+        core::ptr::read_volatile((self.vaddr + offset) as *const T)
+    }
+
+    /// Write the register at `offset`.
+    ///
+    /// # Safety
+    /// The device must have a register of type `T` at `offset`.
+    pub unsafe fn write<T: Copy>(&mut self, offset: u64, value: T) {
+        assert!(offset + core::mem::size_of::<T>() as u64 <= self.length);
+        core::ptr::write_volatile((self.vaddr + offset) as *mut T, value)
+    }
+}
+
+impl Drop for DeviceMemory {
+    fn drop(&mut self) {
+        // Pages that are still mapped cannot be mapped again.
+        let mapping = align::align_down(self.vaddr, BASE_PAGE_SIZE);
+        if vspace::unmap(mapping).is_err() {
+            return;
+        }
+
+        let mut retyped = RETYPED.lock();
+        if let Some(region_pages) = retyped.iter_mut().find(|pages| pages.region == self.region) {
+            let end_page = self.first_page + self.pages.len();
+            for page in &mut region_pages.pages[self.first_page..end_page] {
+                page.mapped = false;
+            }
+        }
+    }
+}
@@ -0,0 +1,313 @@
+//! Buffers that devices access directly with DMA.
+
+use alloc::vec::Vec;
+use relic_abi::{
+    cap::CapabilityErrors,
+    prelude::CAddr,
+    syscall::{MAP_PERMISSION_CACHE_DISABLE, MAP_PERMISSION_WRITE, MAP_PERMISSION_WRITE_COMBINING},
+};
+use relic_utils::align;
+use spin::Mutex;
+
+use crate::{
+    syscall_wrapper,
+    untyped::{PageSize, BASE_PAGE_SIZE, LARGE_PAGE_SIZE},
+    vspace,
+};
+
+/// How the CPU caches a DMA buffer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DmaCaching {
+    /// Cached like normal memory. Enough for devices that snoop the caches.
+    Cached,
+    /// Not cached, for devices that do not snoop the caches.
+    Uncached,
+    /// Not cached, but writes are combined. Suits buffers the CPU only writes to.
+    WriteCombining,
+}
+
+/// Physically contiguous pages of the same size.
+#[derive(Debug)]
+struct PageRun {
+    pages: Vec<CAddr>,
+    size: PageSize,
+    bus_address: u64,
+}
+
+impl PageRun {
+    /// Split off the pages from `count` on, if there are any.
+    fn split_off(&mut self, count: usize) -> Option<PageRun> {
+        if self.pages.len() <= count {
+            return None;
+        }
+        Some(PageRun {
+            pages: self.pages.split_off(count),
+            size: self.size,
+            bus_address: self.bus_address + count as u64 * self.size.length(),
+        })
+    }
+}
+
+/// Pages of dropped buffers and failed allocations. Retyped pages cannot be given
+/// back to their untyped memory, so they are reused by new buffers instead.
+#[cfg_attr(test, thread_local)]
+static FREE_RUNS: Mutex<Vec<PageRun>> = Mutex::new(Vec::new());
+
+/// Take `count` pages of `size` from the free runs, with the bus address aligned
+/// to `alignment`.
+fn take_free_run(size: PageSize, count: usize, alignment: u64) -> Option<PageRun> {
+    let mut free_runs = FREE_RUNS.lock();
+    let index = free_runs.iter().position(|run| {
+        run.size == size && run.pages.len() >= count && run.bus_address % alignment == 0
+    })?;
+    let mut run = free_runs.swap_remove(index);
+    if let Some(rest) = run.split_off(count) {
+        free_runs.push(rest);
+    }
+    Some(run)
+}
+
+fn free_run(run: PageRun) {
+    if !run.pages.is_empty() {
+        FREE_RUNS.lock().push(run);
+    }
+}
+
+/// Retype `count` physically contiguous pages of `size` from `untyped`. Pages that
+/// were retyped before a failure are kept for other buffers.
+fn retype_run(untyped: CAddr, size: PageSize, count: usize) -> Result<PageRun, CapabilityErrors> {
+    // Retyped pages cannot be given back, so check the space up front.
+    let (_, free_space) = syscall_wrapper::get_free_space(untyped)?;
+    if (free_space as u64) < (count as u64 + 1) * size.length() {
+        return Err(CapabilityErrors::MemoryNotSufficient);
+    }
+
+    collect_run(size, count, || {
+        let page = syscall_wrapper::retype_raw_page(untyped, size.size_type())?;
+        Ok((page, syscall_wrapper::page_get_physical(page)?.0))
+    })
+}
+
+/// Collect `count` pages of `size` from `retype`, which returns a new page and its
+/// physical address. Fails if a page does not follow the previous one. The pages
+/// collected before a failure are kept for other buffers.
+fn collect_run<F>(size: PageSize, count: usize, mut retype: F) -> Result<PageRun, CapabilityErrors>
+where
+    F: FnMut() -> Result<(CAddr, u64), CapabilityErrors>,
+{
+    let mut run = PageRun {
+        pages: Vec::with_capacity(count),
+        size,
+        bus_address: 0,
+    };
+    for index in 0..count as u64 {
+        let (page, paddr) = match retype() {
+            Ok(retyped) => retyped,
+            Err(error) => {
+                free_run(run);
+                return Err(error);
+            }
+        };
+        if index == 0 {
+            run.bus_address = paddr;
+        } else if paddr != run.bus_address + index * size.length() {
+            free_run(run);
+            free_run(PageRun {
+                pages: alloc::vec![page],
+                size,
+                bus_address: paddr,
+            });
+            return Err(CapabilityErrors::MemoryNotSufficient);
+        }
+        run.pages.push(page);
+    }
+    Ok(run)
+}
+
+/// Physically contiguous memory that is mapped into the address space. The
+/// buffer is unmapped when dropped and its pages are reused by later buffers.
+#[derive(Debug)]
+pub struct DmaBuffer {
+    vaddr: u64,
+    length: u64,
+    run: PageRun,
+}
+
+impl DmaBuffer {
+    /**
+    Take physically contiguous pages for `length` bytes and map them. The pages
+    of dropped buffers are reused, new ones are retyped from `untyped`. The bus
+    address of the buffer is aligned to `alignment`, which must be a power of two
+    of at most 2 MiB. The memory is zeroed. Empty buffers fail with
+    [`CapabilityErrors::InvalidMemoryAddress`].
+    */
+    pub fn new(
+        untyped: CAddr,
+        length: u64,
+        alignment: u64,
+        caching: DmaCaching,
+    ) -> Result<Self, CapabilityErrors> {
+        if length == 0 {
+            return Err(CapabilityErrors::InvalidMemoryAddress);
+        }
+        if !alignment.is_power_of_two() || alignment > LARGE_PAGE_SIZE {
+            return Err(CapabilityErrors::MemoryAlignmentFailure);
+        }
+
+        // Pages are aligned to their size.
+        let size = if alignment > BASE_PAGE_SIZE || length >= LARGE_PAGE_SIZE {
+            PageSize::Large
+        } else {
+            PageSize::Base
+        };
+        let count = (align::align_up(length, size.length()) / size.length()) as usize;
+
+        // Retyped pages are zeroed already.
+        let (run, reused) = match take_free_run(size, count, alignment) {
+            Some(run) => (run, true),
+            None => (retype_run(untyped, size, count)?, false),
+        };
+
+        let permissions = match caching {
+            DmaCaching::Cached => MAP_PERMISSION_WRITE,
+            DmaCaching::Uncached => MAP_PERMISSION_WRITE | MAP_PERMISSION_CACHE_DISABLE,
+            DmaCaching::WriteCombining => MAP_PERMISSION_WRITE | MAP_PERMISSION_WRITE_COMBINING,
+        };
+        let vaddr = match vspace::map_pages(&run.pages, size, permissions) {
+            Ok(vaddr) => vaddr,
+            Err(error) => {
+                free_run(run);
+                return Err(error);
+            }
+        };
+        if reused {
+            unsafe { core::ptr::write_bytes(vaddr as *mut u8, 0, count * size.length() as usize) };
+        }
+
+        Ok(Self { vaddr, length, run })
+    }
+
+    /// Address of the buffer in the address space of the task.
+    pub fn vaddr(&self) -> u64 {
+        self.vaddr
+    }
+
+    /// Address that devices use to access the buffer.
+    pub fn bus_address(&self) -> u64 {
+        self.run.bus_address
+    }
+
+    pub fn length(&self) -> u64 {
+        self.length
+    }
+
+    /// Page capabilities of the buffer, in order.
+    pub fn pages(&self) -> &[CAddr] {
+        &self.run.pages
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        unsafe { core::slice::from_raw_parts(self.vaddr as *const u8, self.length as usize) }
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        unsafe { core::slice::from_raw_parts_mut(self.vaddr as *mut u8, self.length as usize) }
+    }
+}
+
+impl Drop for DmaBuffer {
+    fn drop(&mut self) {
+        // Pages that are still mapped cannot be given to another buffer.
+        if vspace::unmap(self.vaddr).is_ok() {
+            free_run(PageRun {
+                pages: core::mem::take(&mut self.run.pages),
+                size: self.run.size,
+                bus_address: self.run.bus_address,
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn page(index: u8) -> CAddr {
+        CAddr::from(index)
+    }
+
+    #[test]
+    fn test_free_runs() {
+        free_run(PageRun {
+            pages: Vec::new(),
+            size: PageSize::Base,
+            bus_address: 0x1000,
+        });
+        free_run(PageRun {
+            pages: (1..=4).map(page).collect(),
+            size: PageSize::Base,
+            bus_address: 0x10_1000,
+        });
+        assert_eq!(FREE_RUNS.lock().len(), 1);
+
+        // The size, length and alignment of the run must fit.
+        assert!(take_free_run(PageSize::Large, 1, BASE_PAGE_SIZE).is_none());
+        assert!(take_free_run(PageSize::Base, 5, BASE_PAGE_SIZE).is_none());
+        assert!(take_free_run(PageSize::Base, 1, 0x2000).is_none());
+
+        // The rest of the run is kept for later buffers.
+        let run = take_free_run(PageSize::Base, 3, BASE_PAGE_SIZE).unwrap();
+        assert_eq!(run.pages, [page(1), page(2), page(3)]);
+        assert_eq!(run.bus_address, 0x10_1000);
+        let run = take_free_run(PageSize::Base, 1, 0x4000).unwrap();
+        assert_eq!(run.pages, [page(4)]);
+        assert_eq!(run.bus_address, 0x10_4000);
+        assert!(FREE_RUNS.lock().is_empty());
+    }
+
+    #[test]
+    fn test_contiguity() {
+        let mut paddrs = [0x20_0000, 0x20_1000, 0x20_2000].iter();
+        let mut index = 0;
+        let run = collect_run(PageSize::Base, 3, || {
+            index += 1;
+            Ok((page(index), *paddrs.next().unwrap()))
+        })
+        .unwrap();
+        assert_eq!(run.pages, [page(1), page(2), page(3)]);
+        assert_eq!(run.bus_address, 0x20_0000);
+        assert!(FREE_RUNS.lock().is_empty());
+
+        // The pages before a gap are kept apart from the page after it.
+        let mut paddrs = [0x40_0000, 0x40_1000, 0x40_3000].iter();
+        let result = collect_run(PageSize::Base, 3, || {
+            index += 1;
+            Ok((page(index), *paddrs.next().unwrap()))
+        });
+        assert_eq!(result.unwrap_err(), CapabilityErrors::MemoryNotSufficient);
+        let run = take_free_run(PageSize::Base, 2, BASE_PAGE_SIZE).unwrap();
+        assert_eq!(
+            (run.pages.as_slice(), run.bus_address),
+            (&[page(4), page(5)][..], 0x40_0000)
+        );
+        let run = take_free_run(PageSize::Base, 1, BASE_PAGE_SIZE).unwrap();
+        assert_eq!(
+            (run.pages.as_slice(), run.bus_address),
+            (&[page(6)][..], 0x40_3000)
+        );
+
+        // Pages retyped before an error are kept too.
+        let mut retyped = false;
+        let result = collect_run(PageSize::Large, 2, || {
+            if retyped {
+                return Err(CapabilityErrors::MemoryNotSufficient);
+            }
+            retyped = true;
+            Ok((page(7), 0x60_0000))
+        });
+        assert_eq!(result.unwrap_err(), CapabilityErrors::MemoryNotSufficient);
+        let run = take_free_run(PageSize::Large, 1, LARGE_PAGE_SIZE).unwrap();
+        assert_eq!(run.pages, [page(7)]);
+        assert!(FREE_RUNS.lock().is_empty());
+    }
+}
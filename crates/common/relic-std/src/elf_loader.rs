@@ -0,0 +1,427 @@
+//! Loads ELF binaries into new tasks. Each task gets its own cpool, address space
+//! and untyped memory, and starts with the layout that the kernel gives sigma: the
+//! image at the start of the address space, the stack at [`SIGMA_STACK_START`] above
+//! [`USER_STACK_GUARD_PAGES`] unmapped guard pages and the task buffer at
+//! [`SIGMA_BUFFER_START`]. The `relic-std` of the task sets up its heap and TLS from
+//! there.
+
+use core::ops::Range;
+
+use alloc::vec::Vec;
+use elfloader::{ElfBinary, ElfLoader, Flags, LoadableHeaders, Rela, P64};
+use relic_abi::{
+    bootstrap::{
+        BootstrapInfo, TlsInfo, SIGMA_BUFFER_START, SIGMA_STACK_PAGES, SIGMA_STACK_START,
+        USER_STACK_GUARD_PAGES,
+    },
+    cap::CapabilityErrors,
+    prelude::CAddr,
+    syscall::{TaskBuffer, MAP_PERMISSION_EXECUTE, MAP_PERMISSION_WRITE},
+};
+use relic_utils::{
+    align,
+    elf::{self, RelocationError},
+};
+
+use crate::{
+    syscall_wrapper,
+    untyped::{PageSize, BASE_PAGE_SIZE},
+    vspace::{self, IMAGE_RANGE},
+};
+
+/// The image is loaded at the start of the address space, like sigma.
+const IMAGE_BASE: u64 = IMAGE_RANGE.start;
+
+/// Alignment of the initial stack pointer.
+const STACK_ALIGN: u64 = 128;
+
+/// Reasons why a binary cannot be spawned.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LoadError {
+    /// The binary is malformed. Holds the message of the parser.
+    InvalidBinary(&'static str),
+    /// A segment is outside of the part of the address space for the image.
+    SegmentOutOfBounds,
+    /// Data or a relocation is outside of the mapped segments.
+    WriteOutOfBounds,
+    /// The relocation type with the given number is not supported.
+    UnsupportedRelocation(u32),
+    /// A relocation refers to the undefined symbol with the given index.
+    UndefinedSymbol(u32),
+    /// A TLS relocation is used without a TLS segment.
+    MissingTlsSegment,
+    /// The binary has more than one TLS segment.
+    MultipleTlsSegments,
+    /// Creating or mapping the capabilities of the task failed.
+    Capability(CapabilityErrors),
+}
+
+impl LoadError {
+    /// Message for the `elfloader` crate, which only passes strings through.
+    fn message(self) -> &'static str {
+        match self {
+            LoadError::InvalidBinary(message) => message,
+            LoadError::SegmentOutOfBounds => "Segment is outside of the image",
+            LoadError::WriteOutOfBounds => "Write is outside of the mapped segments",
+            LoadError::UnsupportedRelocation(_) => "Unsupported relocation",
+            LoadError::UndefinedSymbol(_) => "Relocation against an undefined symbol",
+            LoadError::MissingTlsSegment => "TLS relocation without a TLS segment",
+            LoadError::MultipleTlsSegments => "Multiple TLS segments",
+            LoadError::Capability(_) => "Failed to map the binary",
+        }
+    }
+}
+
+impl From<CapabilityErrors> for LoadError {
+    fn from(error: CapabilityErrors) -> Self {
+        LoadError::Capability(error)
+    }
+}
+
+impl From<RelocationError> for LoadError {
+    fn from(error: RelocationError) -> Self {
+        match error {
+            RelocationError::Unsupported(kind) => LoadError::UnsupportedRelocation(kind),
+            RelocationError::InvalidSymbol(_) => {
+                LoadError::InvalidBinary("Symbol index is out of bounds")
+            }
+            RelocationError::UndefinedSymbol(index) => LoadError::UndefinedSymbol(index),
+            RelocationError::MissingTls => LoadError::MissingTlsSegment,
+        }
+    }
+}
+
+/// A task started by [`spawn`]. All capabilities are in the cpool of the caller.
+#[derive(Debug)]
+pub struct Program {
+    /// The task, which can be joined with [`syscall_wrapper::task_join`].
+    pub task: CAddr,
+    /// Cpool of the task. It holds the untyped memory and the top level table of
+    /// the task.
+    pub cpool: CAddr,
+    /// Cpools that hold the pages of the image, the stack and the task buffer.
+    pub page_pools: Vec<CAddr>,
+}
+
+/// Pages of the task next to each other in its address space. They are mapped
+/// into the current address space while they are written.
+struct Segment {
+    range: Range<u64>,
+    /// Each page with the permissions it is mapped with in the task.
+    pages: Vec<(CAddr, u64)>,
+    /// Start of the pages in the current address space.
+    window: Option<u64>,
+}
+
+struct Loader {
+    /// Untyped memory of the caller for the cpools that hold the pages.
+    untyped: CAddr,
+    /// Untyped memory of the task for its pages.
+    task_untyped: CAddr,
+    page_pools: Vec<CAddr>,
+    /// Sorted by their start and never adjacent.
+    segments: Vec<Segment>,
+    tls_info: TlsInfo,
+    /// Dynamic symbol table of the binary.
+    dynamic_symbols: Vec<u8>,
+    /// The first error of the load, which is reported by [`spawn`].
+    error: Option<LoadError>,
+}
+
+impl Loader {
+    /// Remember `error` for [`spawn`] and return its message for the `elfloader`
+    /// crate.
+    fn fail(&mut self, error: LoadError) -> &'static str {
+        self.error.get_or_insert(error);
+        error.message()
+    }
+
+    /// Retype a zeroed page from the memory of the task. A new page pool is
+    /// created when the current one is full.
+    fn new_page(&mut self) -> Result<CAddr, CapabilityErrors> {
+        let size_type = PageSize::Base.size_type();
+        if let Some(&pool) = self.page_pools.last() {
+            match syscall_wrapper::retype_raw_page_into(self.task_untyped, size_type, pool) {
+                Err(CapabilityErrors::CapabilitySlotsFull) => {}
+                result => return result.map(|index| CAddr::from([pool.0[0], index])),
+            }
+        }
+
+        let pool = syscall_wrapper::retype_cpool(self.untyped)?;
+        self.page_pools.push(pool);
+        let index = syscall_wrapper::retype_raw_page_into(self.task_untyped, size_type, pool)?;
+        Ok(CAddr::from([pool.0[0], index]))
+    }
+
+    /// Create the pages of `range` in the task, each with its permissions. The
+    /// range is page aligned and follows the last segment.
+    fn add_segment(&mut self, range: Range<u64>, permissions: &[u64]) -> Result<(), LoadError> {
+        let mut pages = Vec::with_capacity(permissions.len());
+        for &page_permissions in permissions {
+            pages.push((self.new_page()?, page_permissions));
+        }
+
+        let caps: Vec<CAddr> = pages.iter().map(|&(page, _)| page).collect();
+        let window = vspace::map_pages(&caps, PageSize::Base, MAP_PERMISSION_WRITE)?;
+        self.segments.push(Segment {
+            range,
+            pages,
+            window: Some(window),
+        });
+        Ok(())
+    }
+
+    /// The bytes at `range` of the task, through the mapping in the current
+    /// address space.
+    fn bytes_mut(&mut self, range: Range<u64>) -> Result<&mut [u8], LoadError> {
+        let segment = self
+            .segments
+            .iter()
+            .find(|segment| segment.range.start <= range.start && range.end <= segment.range.end)
+            .ok_or(LoadError::WriteOutOfBounds)?;
+        let window = segment.window.ok_or(LoadError::WriteOutOfBounds)?;
+
+        let start = window + (range.start - segment.range.start);
+        Ok(unsafe {
+            core::slice::from_raw_parts_mut(start as *mut u8, (range.end - range.start) as usize)
+        })
+    }
+
+    fn write(&mut self, vaddr: u64, data: &[u8]) -> Result<(), LoadError> {
+        let end = vaddr
+            .checked_add(data.len() as u64)
+            .ok_or(LoadError::WriteOutOfBounds)?;
+        self.bytes_mut(vaddr..end)?.copy_from_slice(data);
+        Ok(())
+    }
+
+    /// Value that `entry` writes. The TLS block ends at the thread pointer, like in
+    /// the layout of [`crate::tls`].
+    fn relocation_value(&self, entry: &Rela<P64>) -> Result<u64, LoadError> {
+        let tls_block_size = if self.tls_info.tls_loaded {
+            Some(align::align_up(
+                self.tls_info.total_size,
+                core::cmp::max(self.tls_info.tls_align, 1),
+            ))
+        } else {
+            None
+        };
+
+        Ok(elf::relocation_value(
+            entry.get_type(),
+            entry.get_symbol_table_index(),
+            entry.get_addend(),
+            &self.dynamic_symbols,
+            IMAGE_BASE,
+            tls_block_size,
+        )?)
+    }
+
+    /// Unmap the pages from the current address space and map them into the
+    /// address space of the task.
+    fn map_into_task(&mut self, top_level_table: CAddr) -> Result<(), CapabilityErrors> {
+        for segment in &mut self.segments {
+            if let Some(window) = segment.window.take() {
+                vspace::unmap(window)?;
+            }
+
+            for (index, &(page, permissions)) in segment.pages.iter().enumerate() {
+                syscall_wrapper::map_raw_page_with_permissions(
+                    self.task_untyped,
+                    top_level_table,
+                    segment.range.start + index as u64 * BASE_PAGE_SIZE,
+                    page,
+                    permissions,
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Loader {
+    fn drop(&mut self) {
+        for segment in &mut self.segments {
+            if let Some(window) = segment.window.take() {
+                let _ = vspace::unmap(window);
+            }
+        }
+    }
+}
+
+impl ElfLoader for Loader {
+    fn allocate(&mut self, load_headers: LoadableHeaders) -> Result<(), &'static str> {
+        let mut segments = Vec::new();
+        for header in load_headers {
+            let end = IMAGE_BASE
+                .checked_add(header.virtual_addr())
+                .and_then(|start| start.checked_add(header.mem_size()))
+                .filter(|&end| end <= IMAGE_RANGE.end);
+            let end = match end {
+                Some(end) => end,
+                None => return Err(self.fail(LoadError::SegmentOutOfBounds)),
+            };
+
+            let mut permissions = 0;
+            if header.flags().is_write() {
+                permissions |= MAP_PERMISSION_WRITE;
+            }
+            if header.flags().is_execute() {
+                permissions |= MAP_PERMISSION_EXECUTE;
+            }
+
+            let start = align::align_down(IMAGE_BASE + header.virtual_addr(), BASE_PAGE_SIZE);
+            segments.push((start..align::align_up(end, BASE_PAGE_SIZE), permissions));
+        }
+
+        // Segments may share pages, so every page gets the permissions of all
+        // segments that contain it. Pages next to each other are mapped together.
+        let mut pages: Vec<(u64, u64)> = Vec::new();
+        for (range, _) in &segments {
+            for page in range.clone().step_by(BASE_PAGE_SIZE as usize) {
+                let permissions = segments
+                    .iter()
+                    .filter(|(segment, _)| segment.contains(&page))
+                    .fold(0, |permissions, (_, segment_permissions)| {
+                        permissions | segment_permissions
+                    });
+                pages.push((page, permissions));
+            }
+        }
+        pages.sort_unstable();
+        pages.dedup();
+
+        let mut start = 0;
+        while start < pages.len() {
+            let mut end = start + 1;
+            while end < pages.len() && pages[end].0 == pages[end - 1].0 + BASE_PAGE_SIZE {
+                end += 1;
+            }
+
+            let range = pages[start].0..pages[end - 1].0 + BASE_PAGE_SIZE;
+            let permissions: Vec<u64> = pages[start..end].iter().map(|&(_, p)| p).collect();
+            if let Err(error) = self.add_segment(range, &permissions) {
+                return Err(self.fail(error));
+            }
+            start = end;
+        }
+
+        Ok(())
+    }
+
+    fn load(&mut self, _flags: Flags, base: u64, region: &[u8]) -> Result<(), &'static str> {
+        self.write(IMAGE_BASE + base, region)
+            .map_err(|error| self.fail(error))
+    }
+
+    fn relocate(&mut self, entry: &Rela<P64>) -> Result<(), &'static str> {
+        let result = self
+            .relocation_value(entry)
+            .and_then(|value| self.write(IMAGE_BASE + entry.get_offset(), &value.to_le_bytes()));
+        result.map_err(|error| self.fail(error))
+    }
+
+    fn tls(
+        &mut self,
+        tdata_start: u64,
+        tdata_length: u64,
+        total_size: u64,
+        align: u64,
+    ) -> Result<(), &'static str> {
+        if self.tls_info.tls_loaded {
+            return Err(self.fail(LoadError::MultipleTlsSegments));
+        }
+
+        self.tls_info = TlsInfo {
+            tls_loaded: true,
+            tdata_start: IMAGE_BASE + tdata_start,
+            tdata_length,
+            total_size,
+            tls_align: align,
+        };
+        Ok(())
+    }
+}
+
+/**
+Load the ELF binary in `data` into a new task and start it. The task gets `memory`
+bytes of `untyped_memory` as its only untyped memory, for its pages, page tables,
+heap and TLS. Its cpool, page pools and task are created from `untyped_memory` as
+well. The task has the priority of the caller and gets no devices or I/O ports.
+
+The capabilities that were created before a failure are not freed.
+*/
+pub fn spawn(data: &[u8], untyped_memory: CAddr, memory: u64) -> Result<Program, LoadError> {
+    let binary = ElfBinary::new("", data).map_err(LoadError::InvalidBinary)?;
+    let dynamic_symbols = elf::dynamic_symbol_table(data)
+        .map_err(LoadError::InvalidBinary)?
+        .to_vec();
+
+    let cpool = syscall_wrapper::retype_cpool(untyped_memory)?;
+    let untyped_index = syscall_wrapper::retype_untyped(untyped_memory, memory, cpool)?;
+    let task_untyped = CAddr::from([cpool.0[0], untyped_index]);
+    let table_index = syscall_wrapper::retype_top_level_table(task_untyped, cpool)?;
+    let top_level_table = CAddr::from([cpool.0[0], table_index]);
+
+    let mut loader = Loader {
+        untyped: untyped_memory,
+        task_untyped,
+        page_pools: Vec::new(),
+        segments: Vec::new(),
+        tls_info: TlsInfo::default(),
+        dynamic_symbols,
+        error: None,
+    };
+    binary.load(&mut loader).map_err(|message| {
+        loader
+            .error
+            .take()
+            .unwrap_or(LoadError::InvalidBinary(message))
+    })?;
+
+    let stack_end = SIGMA_STACK_START + SIGMA_STACK_PAGES as u64 * BASE_PAGE_SIZE;
+    let buffer_end = SIGMA_BUFFER_START + BASE_PAGE_SIZE;
+    loader.add_segment(
+        SIGMA_STACK_START..stack_end,
+        &[MAP_PERMISSION_WRITE; SIGMA_STACK_PAGES],
+    )?;
+    loader.add_segment(SIGMA_BUFFER_START..buffer_end, &[MAP_PERMISSION_WRITE])?;
+
+    // The task reaches all of its capabilities through its own cpool.
+    let bootstrap_info = BootstrapInfo {
+        free_mem_regions: (untyped_index.into(), untyped_index.into()),
+        top_level_pml4: table_index.into(),
+        tls_info: core::mem::take(&mut loader.tls_info),
+        virtual_address_bits: vspace::virtual_address_bits(),
+        ..Default::default()
+    };
+    let buffer = loader.bytes_mut(SIGMA_BUFFER_START..buffer_end)?;
+    let buffer = unsafe { &mut *(buffer.as_mut_ptr() as *mut TaskBuffer) };
+    buffer.self_address = SIGMA_BUFFER_START;
+    buffer
+        .write_to_task_buffer(&bootstrap_info)
+        .map_err(|_| CapabilityErrors::TaskBufferPayloadInvalid)?;
+
+    let task_buffer = loader.segments.last().unwrap().pages[0].0;
+    loader.map_into_task(top_level_table)?;
+    let task = syscall_wrapper::task_retype(untyped_memory, cpool, top_level_table, task_buffer)?;
+    // Nothing is mapped below the stack, the image ends far below it.
+    let stack_guard = USER_STACK_GUARD_PAGES as u64 * BASE_PAGE_SIZE;
+    syscall_wrapper::task_set_stack_guard(
+        task,
+        SIGMA_STACK_START - stack_guard..SIGMA_STACK_START,
+    )?;
+
+    let mut registers = syscall_wrapper::task_read_registers(task)?;
+    registers.rip = IMAGE_BASE + binary.entry_point();
+    registers.rsp = align::align_down(stack_end - 1, STACK_ALIGN);
+    registers.fs = SIGMA_BUFFER_START;
+    syscall_wrapper::task_write_registers(task, &registers)?;
+    syscall_wrapper::task_start(task)?;
+
+    Ok(Program {
+        task,
+        cpool,
+        page_pools: core::mem::take(&mut loader.page_pools),
+    })
+}
@@ -1,15 +1,39 @@
-use core::alloc::Layout;
+use core::{alloc::Layout, ops::Range};
 
-use buddy_system_allocator::LockedHeapWithRescue;
-use relic_abi::bootstrap::BootstrapInfo;
+use buddy_system_allocator::{Heap, LockedHeapWithRescue};
+use relic_abi::{
+    bootstrap::BootstrapInfo, cap::CapabilityErrors, prelude::CAddr, syscall::MAP_PERMISSION_WRITE,
+};
+use relic_utils::align;
 
-use crate::syscall_wrapper;
+use crate::untyped::{PageSize, UntypedRegions, LARGE_PAGE_SIZE};
+
+/// Blocks of the heap are smaller than `1 << HEAP_ORDER` bytes.
+const HEAP_ORDER: usize = 32;
 
 #[cfg_attr(target_os = "none", global_allocator)]
-pub static HEAP: LockedHeapWithRescue<25> = LockedHeapWithRescue::new(expand_heap);
+pub static HEAP: LockedHeapWithRescue<HEAP_ORDER> = LockedHeapWithRescue::new(expand_heap);
+
+/// Called by [`HEAP`] when it cannot satisfy an allocation. Maps more pages
+/// after the end of the heap so that the allocation can be retried.
+fn expand_heap(heap: &mut Heap<HEAP_ORDER>, layout: &Layout) {
+    // Only accessed with the heap locked once the heap is initialized.
+    let state = match unsafe { HEAP_STATE.as_mut() } {
+        Some(state) => state,
+        None => return,
+    };
 
-fn expand_heap(_heap: &mut buddy_system_allocator::Heap<25>, _layout: &Layout) {
-    todo!("Heap expansion needs to be implemetned.")
+    // The allocator needs a free block of the next power of two, aligned to its size.
+    let block_size = core::cmp::max(layout.size(), layout.align()).next_power_of_two() as u64;
+    let target_end = align::align_up(state.end, block_size) + block_size;
+
+    let start = state.end;
+    // Whatever could be mapped is still added to the heap, the allocation fails
+    // later if it is not enough.
+    let _ = state.grow(align::align_up(target_end, LARGE_PAGE_SIZE));
+    if state.end > start {
+        unsafe { heap.add_to_heap(start as _, state.end as _) };
+    }
 }
 
 #[cfg_attr(target_os = "none", alloc_error_handler)]
@@ -19,39 +43,119 @@ fn _alloc_error_handler(layout: alloc::alloc::Layout) -> ! {
 
 const HEAP_LOCATION: u64 = 0x1_0000_0000;
 
-crate fn init_heap(bootstrap_info: &BootstrapInfo) {
-    // Allocate a 2MiB heap
-    let start_addr: u8 = bootstrap_info.free_mem_regions.0 .0[0];
-    let end_addr: u8 = bootstrap_info.free_mem_regions.1 .0[0];
-
-    const NUM_PAGES: usize = 5;
-
-    let mut target_addr = None;
-    for addr in start_addr..=end_addr {
-        let free_sapce = syscall_wrapper::get_free_space(addr.into()).unwrap();
-        if free_sapce.1 > NUM_PAGES * 0x20_0000 {
-            target_addr = Some(addr);
-            break;
+/// The heap never grows beyond this size.
+const HEAP_MAX_SIZE: u64 = 0x10_0000_0000;
+
+/// Addresses the heap may grow into.
+crate const HEAP_RANGE: Range<u64> = HEAP_LOCATION..HEAP_LOCATION + HEAP_MAX_SIZE;
+
+const DEFAULT_INITIAL_HEAP_SIZE: u64 = LARGE_PAGE_SIZE;
+
+/// Initial size of the heap in bytes. Programs built with `RELIC_HEAP_SIZE` set
+/// start with a heap of that size instead of the default. The size is parsed while
+/// building, so a value that is not a size in bytes fails the build.
+const INITIAL_HEAP_SIZE: u64 = parse_heap_size(option_env!("RELIC_HEAP_SIZE"));
+
+/// Parse a heap size in bytes. An unset or empty size gives the default.
+const fn parse_heap_size(size: Option<&str>) -> u64 {
+    let bytes = match size {
+        Some(size) if !size.is_empty() => size.as_bytes(),
+        _ => return DEFAULT_INITIAL_HEAP_SIZE,
+    };
+
+    let mut result: u64 = 0;
+    let mut index = 0;
+    while index < bytes.len() {
+        let digit = bytes[index];
+        if !digit.is_ascii_digit() {
+            panic!("RELIC_HEAP_SIZE must be a size in bytes");
+        }
+        result = match result.checked_mul(10) {
+            Some(result) => match result.checked_add((digit - b'0') as u64) {
+                Some(result) => result,
+                None => panic!("RELIC_HEAP_SIZE is too large"),
+            },
+            None => panic!("RELIC_HEAP_SIZE is too large"),
+        };
+        index += 1;
+    }
+    result
+}
+
+/// What the heap needs to map more pages.
+struct HeapState {
+    untyped: UntypedRegions,
+    top_level_table: CAddr,
+    /// End of the mapped heap.
+    end: u64,
+}
+
+/// Set by `init_heap` before anything is allocated.
+static mut HEAP_STATE: Option<HeapState> = None;
+
+impl HeapState {
+    /// Map pages until the heap ends at `target_end`. Large pages are used where
+    /// the heap is aligned for them and there is space for them.
+    fn grow(&mut self, target_end: u64) -> Result<(), CapabilityErrors> {
+        if target_end > HEAP_RANGE.end {
+            return Err(CapabilityErrors::MemoryNotSufficient);
+        }
+
+        while self.end < target_end {
+            let large_page_fits =
+                self.end % LARGE_PAGE_SIZE == 0 && target_end - self.end >= LARGE_PAGE_SIZE;
+            if !(large_page_fits && self.map_page(PageSize::Large).is_ok()) {
+                self.map_page(PageSize::Base)?;
+            }
         }
+
+        Ok(())
+    }
+
+    /// Map a new page at the end of the heap.
+    fn map_page(&mut self, size: PageSize) -> Result<(), CapabilityErrors> {
+        self.untyped
+            .map_new_page(self.top_level_table, self.end, size, MAP_PERMISSION_WRITE)?;
+        self.end += size.length();
+        Ok(())
     }
+}
 
-    if target_addr.is_none() {
+crate fn init_heap(bootstrap_info: &BootstrapInfo) {
+    let mut state = HeapState {
+        untyped: UntypedRegions::new(bootstrap_info),
+        top_level_table: bootstrap_info.top_level_pml4,
+        end: HEAP_LOCATION,
+    };
+    let size = align::align_up(INITIAL_HEAP_SIZE, LARGE_PAGE_SIZE);
+    if state.grow(HEAP_LOCATION + size).is_err() {
         panic!("No memory space enough to hold heap");
     }
 
-    //TODO: This can fail if available space is exactly enough and system needs more space to create
-    // more paging structures.
-    // Map 10MiB of heap.
-    for i in 0..NUM_PAGES {
-        let raw_page = syscall_wrapper::retype_raw_page(target_addr.unwrap().into(), 1).unwrap();
-        syscall_wrapper::map_raw_page(
-            target_addr.unwrap().into(),
-            bootstrap_info.top_level_pml4,
-            HEAP_LOCATION + (i as u64 * 0x20_0000),
-            raw_page,
-        )
-        .unwrap();
+    unsafe {
+        HEAP.lock()
+            .init(HEAP_LOCATION as _, (state.end - HEAP_LOCATION) as _);
+        HEAP_STATE = Some(state);
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_heap_size() {
+        assert_eq!(parse_heap_size(None), DEFAULT_INITIAL_HEAP_SIZE);
+        assert_eq!(parse_heap_size(Some("")), DEFAULT_INITIAL_HEAP_SIZE);
+        assert_eq!(parse_heap_size(Some("8388608")), 0x80_0000);
 
-    unsafe { HEAP.lock().init(HEAP_LOCATION as _, 0x20_0000 * NUM_PAGES) };
+        const OVERRIDE: u64 = parse_heap_size(Some("4096"));
+        assert_eq!(OVERRIDE, 4096);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_parse_bad_heap_size() {
+        parse_heap_size(Some("2M"));
+    }
 }
@@ -0,0 +1,21 @@
+//! The initrd, which the kernel maps read-only into sigma. It is a USTAR archive
+//! that holds sigma and the other userspace programs.
+
+use relic_abi::bootstrap::BootstrapInfo;
+
+pub use relic_utils::ustar::{Entry, EntryType, UStarArchive, UStarError};
+
+/// The archive in the initrd, or `None` if the task was not given one.
+pub fn archive(bootstrap_info: &BootstrapInfo) -> Option<UStarArchive<'static>> {
+    let initrd_info = &bootstrap_info.initrd_info;
+    if initrd_info.initrd_size == 0 {
+        return None;
+    }
+
+    Some(UStarArchive::new(unsafe {
+        core::slice::from_raw_parts(
+            initrd_info.initrd_vaddr as *const u8,
+            initrd_info.initrd_size as usize,
+        )
+    }))
+}
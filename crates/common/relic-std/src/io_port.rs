@@ -0,0 +1,56 @@
+//! Access to I/O ports. The ports must be granted to the task with
+//! [`crate::syscall_wrapper::task_grant_io_ports`], other ports fault.
+
+/// Read a byte from the port.
+///
+/// # Safety
+/// Reading a port can change the state of the device.
+pub unsafe fn read_u8(port: u16) -> u8 {
+    let value: u8;
+    asm!("in al, dx", out("al") value, in("dx") port, options(nomem, nostack, preserves_flags));
+    value
+}
+
+/// Read a word from the port.
+///
+/// # Safety
+/// Reading a port can change the state of the device.
+pub unsafe fn read_u16(port: u16) -> u16 {
+    let value: u16;
+    asm!("in ax, dx", out("ax") value, in("dx") port, options(nomem, nostack, preserves_flags));
+    value
+}
+
+/// Read a double word from the port.
+///
+/// # Safety
+/// Reading a port can change the state of the device.
+pub unsafe fn read_u32(port: u16) -> u32 {
+    let value: u32;
+    asm!("in eax, dx", out("eax") value, in("dx") port, options(nomem, nostack, preserves_flags));
+    value
+}
+
+/// Write a byte to the port.
+///
+/// # Safety
+/// Writing a port can change the state of the device.
+pub unsafe fn write_u8(port: u16, value: u8) {
+    asm!("out dx, al", in("dx") port, in("al") value, options(nomem, nostack, preserves_flags));
+}
+
+/// Write a word to the port.
+///
+/// # Safety
+/// Writing a port can change the state of the device.
+pub unsafe fn write_u16(port: u16, value: u16) {
+    asm!("out dx, ax", in("dx") port, in("ax") value, options(nomem, nostack, preserves_flags));
+}
+
+/// Write a double word to the port.
+///
+/// # Safety
+/// Writing a port can change the state of the device.
+pub unsafe fn write_u32(port: u16, value: u32) {
+    asm!("out dx, eax", in("dx") port, in("eax") value, options(nomem, nostack, preserves_flags));
+}
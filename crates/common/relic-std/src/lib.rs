@@ -23,22 +23,29 @@ pub use core::*;
 use crate::prelude::*;
 
 pub mod debug;
+pub mod device;
+pub mod dma;
+pub mod elf_loader;
 pub mod heap;
+pub mod initrd;
+pub mod io_port;
 pub mod raw_syscall;
 pub mod syscall_wrapper;
 pub mod tls;
+pub mod untyped;
+pub mod vspace;
 
 use core::panic::PanicInfo;
 
 use relic_abi::bootstrap::BootstrapInfo;
-use relic_abi::syscall::TaskBuffer;
+use relic_abi::syscall::{TaskBuffer, TASK_EXIT_CODE_PANIC};
 
-use crate::{heap::init_heap, tls::load_tls};
+use crate::{heap::init_heap, tls::load_tls, vspace::init_vspace};
 
 /// This function is called on panic.
 #[cfg_attr(target_os = "none", panic_handler)]
 fn _panic_handler(_info: &PanicInfo) -> ! {
-    loop {}
+    syscall_wrapper::task_exit(TASK_EXIT_CODE_PANIC)
 }
 
 #[cfg_attr(target_os = "none", no_mangle)]
@@ -56,8 +63,9 @@ pub fn _start() -> ! {
     }
 
     init_heap(&bootstrap_info);
+    init_vspace(&bootstrap_info);
     load_tls(&bootstrap_info, tcb_ptr);
 
     unsafe { asm!("call user_main", in("rdi") &bootstrap_info) };
-    loop {}
+    syscall_wrapper::task_exit(0)
 }
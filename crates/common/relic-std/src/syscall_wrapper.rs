@@ -1,11 +1,21 @@
+use core::ops::Range;
+
 use relic_abi::{
     cap::CapabilityErrors,
     prelude::CAddr,
     syscall::{SystemCall, TaskBuffer},
+    task::{TaskInfo, TaskRegisters},
 };
 
 use crate::raw_syscall;
 
+/// Give up the rest of the timeslice. The task is resumed after the other
+/// tasks of the same priority have run.
+pub fn yield_now() {
+    let syscall = SystemCall::Yield;
+    let _ = raw_syscall::make_syscall(&syscall);
+}
+
 /// Get total size and free size for an untyped capability.
 pub fn get_free_space(cap: CAddr) -> Result<(usize, usize), CapabilityErrors> {
     let syscall = SystemCall::UntypedTotalFree(cap);
@@ -44,7 +54,283 @@ pub fn map_raw_page(
     raw_syscall::make_syscall(&syscall).map(|(_, _)| ())
 }
 
-#[allow(dead_code)]
+/// Map a given page into the provided address with the given permissions.
+/// Permissions are a combination of [`relic_abi::syscall::MAP_PERMISSION_WRITE`],
+/// [`relic_abi::syscall::MAP_PERMISSION_EXECUTE`] and one of the memory types
+/// [`relic_abi::syscall::MAP_PERMISSION_CACHE_DISABLE`],
+/// [`relic_abi::syscall::MAP_PERMISSION_WRITE_THROUGH`] and
+/// [`relic_abi::syscall::MAP_PERMISSION_WRITE_COMBINING`]. The page is read only
+/// without permissions.
+pub fn map_raw_page_with_permissions(
+    untyped_memory: CAddr,
+    top_level_table: CAddr,
+    vaddr: u64,
+    raw_page: CAddr,
+    permissions: u64,
+) -> Result<(), CapabilityErrors> {
+    let syscall = SystemCall::RawPageMapWithPermissions {
+        raw_page,
+        vaddr: vaddr | permissions,
+        untyped_memory,
+        top_level_table,
+    };
+    raw_syscall::make_syscall(&syscall).map(|(_, _)| ())
+}
+
+/// Unmap the page mapped at the provided address.
+pub fn unmap_raw_page(
+    top_level_table: CAddr,
+    vaddr: u64,
+    raw_page: CAddr,
+) -> Result<(), CapabilityErrors> {
+    let syscall = SystemCall::RawPageUnmap {
+        top_level_table,
+        vaddr,
+        raw_page,
+    };
+    raw_syscall::make_syscall(&syscall).map(|(_, _)| ())
+}
+
+/// Unmap and delete every copy of the given raw page.
+pub fn revoke_raw_page(raw_page: CAddr) -> Result<(), CapabilityErrors> {
+    let syscall = SystemCall::RawPageRevoke(raw_page);
+    raw_syscall::make_syscall(&syscall).map(|(_, _)| ())
+}
+
+/// Get the physical address and the length of the given raw page.
+pub fn page_get_physical(raw_page: CAddr) -> Result<(u64, usize), CapabilityErrors> {
+    let syscall = SystemCall::PageGetPhysical(raw_page);
+    raw_syscall::make_syscall(&syscall).map(|(a, b)| (a, b as usize))
+}
+
+/// Copy the capability into the given cpool and returns the CAddr of the copy.
+/// A copy of a raw page can be mapped into another address space to share it.
+pub fn copy_capability(
+    address: CAddr,
+    cpool_to_store_in: CAddr,
+) -> Result<CAddr, CapabilityErrors> {
+    let syscall = SystemCall::CopyCapability {
+        address,
+        cpool_to_store_in,
+    };
+    raw_syscall::make_syscall(&syscall).map(|(a, _)| (a as u8).into())
+}
+
+/// Exit the current task with the provided exit code. This never returns.
+pub fn task_exit(exit_code: u64) -> ! {
+    let syscall = SystemCall::TaskExit(exit_code);
+    let _ = raw_syscall::make_syscall(&syscall);
+
+    // The kernel never resumes an exited task.
+    loop {}
+}
+
+/// Wait for the task at the given caddr to exit and return its exit code. Fails with
+/// [`CapabilityErrors::TaskInvalidState`] for the calling task, a task that was never
+/// started, or a task that waits for the caller to exit.
+pub fn task_join(task: CAddr) -> Result<u64, CapabilityErrors> {
+    let syscall = SystemCall::TaskJoin(task);
+    raw_syscall::make_syscall(&syscall).map(|(a, _)| a)
+}
+
+/// Get information about the task at the given caddr.
+pub fn task_info(task: CAddr) -> Result<TaskInfo, CapabilityErrors> {
+    let syscall = SystemCall::TaskInfo(task);
+    raw_syscall::make_syscall(&syscall)?;
+    unsafe {
+        (*get_task_buffer())
+            .read_from_task_buffer()
+            .map_err(|_| CapabilityErrors::TaskBufferPayloadInvalid)
+    }
+}
+
+/// Read the registers of the task at the given caddr.
+pub fn task_read_registers(task: CAddr) -> Result<TaskRegisters, CapabilityErrors> {
+    let syscall = SystemCall::TaskReadRegisters(task);
+    raw_syscall::make_syscall(&syscall)?;
+    unsafe {
+        (*get_task_buffer())
+            .read_from_task_buffer()
+            .map_err(|_| CapabilityErrors::TaskBufferPayloadInvalid)
+    }
+}
+
+/// Write the registers of the task at the given caddr. The task must not be
+/// running or exited.
+pub fn task_write_registers(
+    task: CAddr,
+    registers: &TaskRegisters,
+) -> Result<(), CapabilityErrors> {
+    unsafe {
+        (*get_task_buffer())
+            .write_to_task_buffer(registers)
+            .map_err(|_| CapabilityErrors::TaskBufferPayloadInvalid)?;
+    }
+    let syscall = SystemCall::TaskWriteRegisters(task);
+    raw_syscall::make_syscall(&syscall).map(|(_, _)| ())
+}
+
+/// Retype untyped memory into a scheduling context and returns its CAddr.
+/// A task linked to the context may run for `budget` timer ticks in every
+/// `period`.
+pub fn retype_sched_context(
+    untyped_memory: CAddr,
+    budget: u64,
+    period: u64,
+) -> Result<CAddr, CapabilityErrors> {
+    let syscall = SystemCall::SchedContextRetype {
+        untyped_memory,
+        budget,
+        period,
+    };
+    raw_syscall::make_syscall(&syscall).map(|(a, _)| (a as u8).into())
+}
+
+/// Limit the processor time of the task at the given caddr with the
+/// scheduling context.
+pub fn task_set_sched_context(task: CAddr, sched_context: CAddr) -> Result<(), CapabilityErrors> {
+    let syscall = SystemCall::TaskSetSchedContext {
+        task,
+        sched_context,
+    };
+    raw_syscall::make_syscall(&syscall).map(|(_, _)| ())
+}
+
+/// Clone the address space of the top level table into the given cpool and
+/// returns the index of the clone in that cpool. Pages are shared until either
+/// address space writes to them.
+pub fn clone_top_level_table(
+    untyped_memory: CAddr,
+    top_level_table: CAddr,
+    cpool_to_store_in: CAddr,
+) -> Result<u8, CapabilityErrors> {
+    let syscall = SystemCall::L4Clone {
+        untyped_memory,
+        top_level_table,
+        cpool_to_store_in,
+    };
+    raw_syscall::make_syscall(&syscall).map(|(a, _)| a as u8)
+}
+
+/// Use the untyped memory for the copies of copy-on-write pages that the
+/// task at the given caddr writes to.
+pub fn task_set_fault_untyped(task: CAddr, untyped_memory: CAddr) -> Result<(), CapabilityErrors> {
+    let syscall = SystemCall::TaskSetFaultUntyped {
+        task,
+        untyped_memory,
+    };
+    raw_syscall::make_syscall(&syscall).map(|(_, _)| ())
+}
+
+/// Create a capability for `count` ports from `first_port` out of the I/O port
+/// range and returns its CAddr.
+pub fn derive_io_port_range(
+    io_port_range: CAddr,
+    first_port: u16,
+    count: u64,
+) -> Result<CAddr, CapabilityErrors> {
+    let syscall = SystemCall::IoPortRangeDerive {
+        io_port_range,
+        first_port: first_port as u64,
+        count,
+    };
+    raw_syscall::make_syscall(&syscall).map(|(a, _)| (a as u8).into())
+}
+
+/// Allow the task at the given caddr to use the ports of the I/O port range.
+/// See [`crate::io_port`] to access the ports.
+pub fn task_grant_io_ports(task: CAddr, io_port_range: CAddr) -> Result<(), CapabilityErrors> {
+    let syscall = SystemCall::TaskGrantIoPorts {
+        task,
+        io_port_range,
+    };
+    raw_syscall::make_syscall(&syscall).map(|(_, _)| ())
+}
+
+/// Retype untyped memory into a cpool and returns its CAddr.
+pub fn retype_cpool(untyped_memory: CAddr) -> Result<CAddr, CapabilityErrors> {
+    let syscall = SystemCall::CpoolRetype { untyped_memory };
+    raw_syscall::make_syscall(&syscall).map(|(a, _)| (a as u8).into())
+}
+
+/// Split `length` bytes off the untyped memory into a new untyped capability
+/// and store it in the given cpool. Returns its index in that cpool.
+pub fn retype_untyped(
+    untyped_memory: CAddr,
+    length: u64,
+    cpool_to_store_in: CAddr,
+) -> Result<u8, CapabilityErrors> {
+    let syscall = SystemCall::UntypedRetype {
+        untyped_memory,
+        length,
+        cpool_to_store_in,
+    };
+    raw_syscall::make_syscall(&syscall).map(|(a, _)| a as u8)
+}
+
+/// Retype untyped memory into the top level table of a new address space and
+/// store it in the given cpool. Returns its index in that cpool.
+pub fn retype_top_level_table(
+    untyped_memory: CAddr,
+    cpool_to_store_in: CAddr,
+) -> Result<u8, CapabilityErrors> {
+    let syscall = SystemCall::TopLevelTableRetype {
+        untyped_memory,
+        cpool_to_store_in,
+    };
+    raw_syscall::make_syscall(&syscall).map(|(a, _)| a as u8)
+}
+
+/// Like [`retype_raw_page`], but stores the page in the given cpool. Returns
+/// its index in that cpool.
+pub fn retype_raw_page_into(
+    untyped_memory: CAddr,
+    size_type: u64,
+    cpool_to_store_in: CAddr,
+) -> Result<u8, CapabilityErrors> {
+    let syscall = SystemCall::RawPageRetypeInto {
+        untyped_memory,
+        size: size_type,
+        cpool_to_store_in,
+    };
+    raw_syscall::make_syscall(&syscall).map(|(a, _)| a as u8)
+}
+
+/// Create a task that runs in the given cpool and address space and returns
+/// its CAddr. The task buffer must be a base page. The task does not run until
+/// it is started with [`task_start`].
+pub fn task_retype(
+    untyped_memory: CAddr,
+    cpool: CAddr,
+    top_level_table: CAddr,
+    task_buffer: CAddr,
+) -> Result<CAddr, CapabilityErrors> {
+    let syscall = SystemCall::TaskRetype {
+        untyped_memory,
+        cpool,
+        top_level_table,
+        task_buffer,
+    };
+    raw_syscall::make_syscall(&syscall).map(|(a, _)| (a as u8).into())
+}
+
+/// Schedule the task at the given caddr for the first time. Its registers are
+/// set with [`task_write_registers`] before.
+pub fn task_start(task: CAddr) -> Result<(), CapabilityErrors> {
+    let syscall = SystemCall::TaskStart(task);
+    raw_syscall::make_syscall(&syscall).map(|(_, _)| ())
+}
+
+/// Report page faults of the task at the given caddr in `guard` as stack overflows.
+pub fn task_set_stack_guard(task: CAddr, guard: Range<u64>) -> Result<(), CapabilityErrors> {
+    let syscall = SystemCall::TaskSetStackGuard {
+        task,
+        start: guard.start,
+        end: guard.end,
+    };
+    raw_syscall::make_syscall(&syscall).map(|(_, _)| ())
+}
+
 unsafe fn get_task_buffer() -> *mut TaskBuffer {
     let tls: *mut TaskBuffer;
     asm!(
@@ -1,6 +1,6 @@
 use relic_abi::bootstrap::BootstrapInfo;
 
-use crate::syscall_wrapper;
+use crate::{syscall_wrapper, vspace};
 
 crate fn load_tls(bootstrap_info: &BootstrapInfo, tcb_ptr: u64) {
     let tls_info = &bootstrap_info.tls_info;
@@ -42,6 +42,9 @@ crate fn load_tls(bootstrap_info: &BootstrapInfo, tcb_ptr: u64) {
         .unwrap();
     }
 
+    let tls_start = tcb_ptr - num_pages as u64 * 4096;
+    vspace::reserve(tls_start..tcb_ptr, "TLS").unwrap();
+
     // Now all the addresses are mapped into the current address space. Now, we copy the tdata image.
     let tdata_size_with_align =
         relic_utils::align::align_up(tls_info.total_size, tls_info.tls_align);
@@ -0,0 +1,99 @@
+//! Untyped memory of the task. New pages and the page tables needed to map
+//! them are retyped from it.
+
+use relic_abi::{bootstrap::BootstrapInfo, cap::CapabilityErrors, prelude::CAddr};
+
+use crate::syscall_wrapper;
+
+/// Size of a base page.
+pub const BASE_PAGE_SIZE: u64 = 0x1000;
+
+/// Size of a large page.
+pub const LARGE_PAGE_SIZE: u64 = 0x20_0000;
+
+/// Space kept free in an untyped region for the page tables needed by a mapping.
+const TABLE_RESERVE: u64 = 3 * BASE_PAGE_SIZE;
+
+/// Size of a page capability.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PageSize {
+    /// 4 KiB page.
+    Base,
+    /// 2 MiB page.
+    Large,
+}
+
+impl PageSize {
+    pub fn length(self) -> u64 {
+        match self {
+            PageSize::Base => BASE_PAGE_SIZE,
+            PageSize::Large => LARGE_PAGE_SIZE,
+        }
+    }
+
+    /// The size type expected by [`syscall_wrapper::retype_raw_page`].
+    pub fn size_type(self) -> u64 {
+        match self {
+            PageSize::Base => 0,
+            PageSize::Large => 1,
+        }
+    }
+}
+
+/// The untyped capabilities given to the task at startup.
+#[derive(Debug)]
+pub struct UntypedRegions {
+    start: u8,
+    end: u8,
+    /// Untyped capability that memory was last taken from.
+    current: u8,
+}
+
+impl UntypedRegions {
+    pub fn new(bootstrap_info: &BootstrapInfo) -> Self {
+        let start = bootstrap_info.free_mem_regions.0 .0[0];
+        let end = bootstrap_info.free_mem_regions.1 .0[0];
+        Self {
+            start,
+            end,
+            current: start,
+        }
+    }
+
+    /// Find an untyped capability that can hold a page of the given size and
+    /// the tables to map it. Starts with the current one and moves on to the
+    /// others once it is exhausted.
+    pub fn find(&mut self, size: PageSize) -> Result<CAddr, CapabilityErrors> {
+        let bytes = size.length() + TABLE_RESERVE;
+        for addr in (self.current..=self.end).chain(self.start..self.current) {
+            let (_, free_space) = syscall_wrapper::get_free_space(addr.into())?;
+            if free_space as u64 >= bytes {
+                self.current = addr;
+                return Ok(addr.into());
+            }
+        }
+
+        Err(CapabilityErrors::MemoryNotSufficient)
+    }
+
+    /// Retype a new page and map it at `vaddr` with the given permissions.
+    /// Returns the capability of the page.
+    pub fn map_new_page(
+        &mut self,
+        top_level_table: CAddr,
+        vaddr: u64,
+        size: PageSize,
+        permissions: u64,
+    ) -> Result<CAddr, CapabilityErrors> {
+        let untyped = self.find(size)?;
+        let raw_page = syscall_wrapper::retype_raw_page(untyped, size.size_type())?;
+        syscall_wrapper::map_raw_page_with_permissions(
+            untyped,
+            top_level_table,
+            vaddr,
+            raw_page,
+            permissions,
+        )?;
+        Ok(raw_page)
+    }
+}
@@ -0,0 +1,393 @@
+//! Virtual address space of the task. Keeps track of the regions that are
+//! reserved or mapped and hands out free ranges for new mappings, so that
+//! libraries can map memory without colliding with each other.
+
+use core::ops::Range;
+
+use alloc::vec::Vec;
+use relic_abi::{
+    bootstrap::{
+        BootstrapInfo, SIGMA_BUFFER_START, SIGMA_STACK_PAGES, SIGMA_STACK_START,
+        USER_STACK_GUARD_PAGES,
+    },
+    cap::CapabilityErrors,
+    prelude::CAddr,
+    syscall::MAP_PERMISSION_WRITE,
+};
+use relic_utils::align;
+use spin::Mutex;
+
+use crate::{
+    heap::HEAP_RANGE,
+    syscall_wrapper,
+    untyped::{PageSize, UntypedRegions, BASE_PAGE_SIZE},
+};
+
+/// Free ranges are handed out from here up to the end of the user half.
+const MAPPING_AREA_START: u64 = 0x100_0000_0000;
+
+/// Space left free below the end of the user half.
+const MAPPING_AREA_END_GAP: u64 = 0x1_0000;
+
+/// The program image is loaded at the start of the address space, below the heap.
+crate const IMAGE_RANGE: Range<u64> = 0..HEAP_RANGE.start;
+
+#[derive(Debug)]
+enum RegionKind {
+    /// Used by something that maps its own pages, like the heap or the stack.
+    Reserved(&'static str),
+    /// Base pages mapped by [`map_anonymous`]. They belong to the address space.
+    Anonymous(Vec<CAddr>),
+    /// Pages of the caller mapped by [`map_pages`].
+    Pages(Vec<CAddr>, PageSize),
+}
+
+impl RegionKind {
+    fn name(&self) -> &'static str {
+        match self {
+            RegionKind::Reserved(name) => name,
+            RegionKind::Anonymous(_) => "anonymous",
+            RegionKind::Pages(..) => "pages",
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Region {
+    range: Range<u64>,
+    kind: RegionKind,
+    /// Permissions the pages are mapped with. Unused for reserved regions.
+    permissions: u64,
+}
+
+struct VSpace {
+    top_level_table: CAddr,
+    untyped: UntypedRegions,
+    /// Sorted by their start and never overlapping.
+    regions: Vec<Region>,
+    /// Anonymous pages that were unmapped and can be mapped again.
+    free_pages: Vec<CAddr>,
+    /// Width of virtual addresses, see [`BootstrapInfo::virtual_address_bits`].
+    virtual_address_bits: u32,
+}
+
+static VSPACE: Mutex<Option<VSpace>> = Mutex::new(None);
+
+impl VSpace {
+    fn overlaps(&self, range: &Range<u64>) -> bool {
+        self.regions
+            .iter()
+            .any(|region| region.range.start < range.end && range.start < region.range.end)
+    }
+
+    fn insert(&mut self, region: Region) {
+        let index = self
+            .regions
+            .partition_point(|other| other.range.start <= region.range.start);
+        self.regions.insert(index, region);
+    }
+
+    /// Part of the address space that free ranges are handed out from. The user half
+    /// is larger with five-level paging.
+    fn mapping_area(&self) -> Range<u64> {
+        let user_end = 1u64 << (self.virtual_address_bits - 1);
+        MAPPING_AREA_START..user_end - MAPPING_AREA_END_GAP
+    }
+
+    /// Find the lowest free range of `length` bytes in the mapping area that is
+    /// aligned to `alignment`.
+    fn find_free(&self, length: u64, alignment: u64) -> Result<u64, CapabilityErrors> {
+        let mapping_area = self.mapping_area();
+        let mut start = align::align_up(mapping_area.start, alignment);
+        for region in &self.regions {
+            if region.range.end <= start {
+                continue;
+            }
+            if region.range.start >= start + length {
+                break;
+            }
+            start = align::align_up(region.range.end, alignment);
+        }
+
+        if start + length > mapping_area.end {
+            return Err(CapabilityErrors::MemoryNotSufficient);
+        }
+        Ok(start)
+    }
+
+    /// Map the pages next to each other from `start`. Stops at the first page that
+    /// fails and returns the number of pages that were mapped with the error.
+    fn map_pages_at(
+        &mut self,
+        start: u64,
+        pages: &[CAddr],
+        size: PageSize,
+        permissions: u64,
+    ) -> Result<(), (usize, CapabilityErrors)> {
+        let top_level_table = self.top_level_table;
+        for (index, &page) in pages.iter().enumerate() {
+            let vaddr = start + index as u64 * size.length();
+            self.untyped
+                .find(PageSize::Base)
+                .and_then(|untyped| {
+                    syscall_wrapper::map_raw_page_with_permissions(
+                        untyped,
+                        top_level_table,
+                        vaddr,
+                        page,
+                        permissions,
+                    )
+                })
+                .map_err(|error| (index, error))?;
+        }
+        Ok(())
+    }
+
+    /// Unmap the pages mapped from `start`. Stops at the first page that fails and
+    /// returns the number of pages that were unmapped with the error.
+    fn unmap_pages(
+        &self,
+        start: u64,
+        pages: &[CAddr],
+        size: PageSize,
+    ) -> Result<(), (usize, CapabilityErrors)> {
+        for (index, &page) in pages.iter().enumerate() {
+            let vaddr = start + index as u64 * size.length();
+            syscall_wrapper::unmap_raw_page(self.top_level_table, vaddr, page)
+                .map_err(|error| (index, error))?;
+        }
+        Ok(())
+    }
+
+    /// Map a page that was unmapped before and clear it. The page must be mapped writable.
+    fn map_reused_page(
+        &mut self,
+        vaddr: u64,
+        page: CAddr,
+        permissions: u64,
+    ) -> Result<CAddr, CapabilityErrors> {
+        let top_level_table = self.top_level_table;
+        let mapped = self.untyped.find(PageSize::Base).and_then(|untyped| {
+            syscall_wrapper::map_raw_page_with_permissions(
+                untyped,
+                top_level_table,
+                vaddr,
+                page,
+                permissions,
+            )
+        });
+        if let Err(error) = mapped {
+            self.free_pages.push(page);
+            return Err(error);
+        }
+
+        unsafe { core::ptr::write_bytes(vaddr as *mut u8, 0, BASE_PAGE_SIZE as _) };
+        Ok(page)
+    }
+
+    fn map_anonymous(&mut self, length: u64, permissions: u64) -> Result<u64, CapabilityErrors> {
+        let length = align::align_up(length, BASE_PAGE_SIZE);
+        let start = self.find_free(length, BASE_PAGE_SIZE)?;
+
+        let mut pages = Vec::new();
+        let mut result = Ok(());
+        for vaddr in (start..start + length).step_by(BASE_PAGE_SIZE as usize) {
+            // Pages that were used before can only be cleared through a writable mapping.
+            let reused = if permissions & MAP_PERMISSION_WRITE != 0 {
+                self.free_pages.pop()
+            } else {
+                None
+            };
+            let page = match reused {
+                Some(page) => self.map_reused_page(vaddr, page, permissions),
+                None => self.untyped.map_new_page(
+                    self.top_level_table,
+                    vaddr,
+                    PageSize::Base,
+                    permissions,
+                ),
+            };
+
+            match page {
+                Ok(page) => pages.push(page),
+                Err(error) => {
+                    result = Err(error);
+                    break;
+                }
+            }
+        }
+
+        if let Err(error) = result {
+            self.unmap_pages(start, &pages, PageSize::Base)
+                .map_err(|(_, error)| error)?;
+            self.free_pages.extend(pages);
+            return Err(error);
+        }
+
+        self.insert(Region {
+            range: start..start + length,
+            kind: RegionKind::Anonymous(pages),
+            permissions,
+        });
+        Ok(start)
+    }
+
+    fn map_pages(
+        &mut self,
+        pages: &[CAddr],
+        size: PageSize,
+        permissions: u64,
+    ) -> Result<u64, CapabilityErrors> {
+        let length = pages.len() as u64 * size.length();
+        let start = self.find_free(length, size.length())?;
+
+        if let Err((mapped, error)) = self.map_pages_at(start, pages, size, permissions) {
+            self.unmap_pages(start, &pages[..mapped], size)
+                .map_err(|(_, error)| error)?;
+            return Err(error);
+        }
+
+        self.insert(Region {
+            range: start..start + length,
+            kind: RegionKind::Pages(pages.to_vec(), size),
+            permissions,
+        });
+        Ok(start)
+    }
+
+    fn unmap(&mut self, vaddr: u64) -> Result<(), CapabilityErrors> {
+        let index = self
+            .regions
+            .iter()
+            .position(|region| region.range.start == vaddr)
+            .ok_or(CapabilityErrors::MemoryNotMapped)?;
+        // Reserved regions are used by something that maps its own pages.
+        if let RegionKind::Reserved(_) = self.regions[index].kind {
+            return Err(CapabilityErrors::MemoryNotMapped);
+        }
+
+        let region = self.regions.remove(index);
+        let (pages, size) = match &region.kind {
+            RegionKind::Anonymous(pages) => (pages, PageSize::Base),
+            RegionKind::Pages(pages, size) => (pages, *size),
+            RegionKind::Reserved(_) => unreachable!(),
+        };
+        if let Err((unmapped, error)) = self.unmap_pages(vaddr, pages, size) {
+            // The region stays tracked, so its pages are mapped again. Their page
+            // tables are still there.
+            let _ = self.map_pages_at(vaddr, &pages[..unmapped], size, region.permissions);
+            self.regions.insert(index, region);
+            return Err(error);
+        }
+
+        if let RegionKind::Anonymous(pages) = region.kind {
+            self.free_pages.extend(pages);
+        }
+        Ok(())
+    }
+}
+
+fn with_vspace<T>(f: impl FnOnce(&mut VSpace) -> T) -> T {
+    let mut vspace = VSPACE.lock();
+    f(vspace
+        .as_mut()
+        .expect("The address space is not initialized"))
+}
+
+/// Reserve a range of the address space so that it is never handed out.
+/// Fails with [`CapabilityErrors::MemoryAlreadyMapped`] if any of it is in use.
+pub fn reserve(range: Range<u64>, name: &'static str) -> Result<(), CapabilityErrors> {
+    with_vspace(|vspace| {
+        if vspace.overlaps(&range) {
+            return Err(CapabilityErrors::MemoryAlreadyMapped);
+        }
+        vspace.insert(Region {
+            range,
+            kind: RegionKind::Reserved(name),
+            permissions: 0,
+        });
+        Ok(())
+    })
+}
+
+/// Map `length` bytes of new zeroed memory and return its address. See
+/// [`syscall_wrapper::map_raw_page_with_permissions`] for the permissions.
+pub fn map_anonymous(length: u64, permissions: u64) -> Result<u64, CapabilityErrors> {
+    with_vspace(|vspace| vspace.map_anonymous(length, permissions))
+}
+
+/// Map the pages next to each other and return the address of the first one.
+/// The pages stay owned by the caller.
+pub fn map_pages(
+    pages: &[CAddr],
+    size: PageSize,
+    permissions: u64,
+) -> Result<u64, CapabilityErrors> {
+    with_vspace(|vspace| vspace.map_pages(pages, size, permissions))
+}
+
+/// Unmap the region that starts at `vaddr`. Reserved regions cannot be unmapped and
+/// fail with [`CapabilityErrors::MemoryNotMapped`]. If a page cannot be unmapped, the
+/// region stays mapped as a whole.
+pub fn unmap(vaddr: u64) -> Result<(), CapabilityErrors> {
+    with_vspace(|vspace| vspace.unmap(vaddr))
+}
+
+/// The region that contains `vaddr` and its name.
+pub fn region_at(vaddr: u64) -> Option<(Range<u64>, &'static str)> {
+    with_vspace(|vspace| {
+        vspace
+            .regions
+            .iter()
+            .find(|region| region.range.contains(&vaddr))
+            .map(|region| (region.range.clone(), region.kind.name()))
+    })
+}
+
+/// Width of virtual addresses, see [`BootstrapInfo::virtual_address_bits`].
+pub fn virtual_address_bits() -> u32 {
+    with_vspace(|vspace| vspace.virtual_address_bits)
+}
+
+/// Track the address space of the task and reserve the regions that are in use
+/// at startup. Needs the heap.
+crate fn init_vspace(bootstrap_info: &BootstrapInfo) {
+    *VSPACE.lock() = Some(VSpace {
+        top_level_table: bootstrap_info.top_level_pml4,
+        untyped: UntypedRegions::new(bootstrap_info),
+        regions: Vec::new(),
+        free_pages: Vec::new(),
+        virtual_address_bits: bootstrap_info.virtual_address_bits,
+    });
+
+    let stack_guard = USER_STACK_GUARD_PAGES as u64 * BASE_PAGE_SIZE;
+    let stack_end = SIGMA_STACK_START + SIGMA_STACK_PAGES as u64 * BASE_PAGE_SIZE;
+    reserve(IMAGE_RANGE, "program image").unwrap();
+    reserve(HEAP_RANGE, "heap").unwrap();
+    reserve(SIGMA_STACK_START - stack_guard..stack_end, "stack").unwrap();
+    reserve(
+        SIGMA_BUFFER_START..SIGMA_BUFFER_START + BASE_PAGE_SIZE,
+        "task buffer",
+    )
+    .unwrap();
+
+    let fb_info = &bootstrap_info.fb_info;
+    if fb_info.frame_buffer_size > 0 {
+        let fb_end = fb_info.frame_buffer_vaddr + fb_info.frame_buffer_size as u64;
+        reserve(
+            fb_info.frame_buffer_vaddr..align::align_up(fb_end, BASE_PAGE_SIZE),
+            "frame buffer",
+        )
+        .unwrap();
+    }
+
+    let initrd_info = &bootstrap_info.initrd_info;
+    if initrd_info.initrd_size > 0 {
+        let initrd_end = initrd_info.initrd_vaddr + initrd_info.initrd_size;
+        reserve(
+            initrd_info.initrd_vaddr..align::align_up(initrd_end, BASE_PAGE_SIZE),
+            "initrd",
+        )
+        .unwrap();
+    }
+}
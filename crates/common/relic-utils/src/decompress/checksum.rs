@@ -0,0 +1,184 @@
+//! Checksums of the compressed formats: CRC-32 for gzip, XXH32 for lz4 and XXH64
+//! for zstd.
+
+/// Lookup table of the reflected CRC-32 polynomial `0xEDB88320`.
+const CRC32_TABLE: [u32; 256] = {
+    let mut table = [0; 256];
+    let mut index = 0;
+    while index < 256 {
+        let mut crc = index as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 {
+                0xEDB8_8320 ^ (crc >> 1)
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[index] = crc;
+        index += 1;
+    }
+    table
+};
+
+/// CRC-32 as used by gzip.
+pub fn crc32(data: &[u8]) -> u32 {
+    !data.iter().fold(!0, |crc, &byte| {
+        CRC32_TABLE[((crc ^ byte as u32) & 0xFF) as usize] ^ (crc >> 8)
+    })
+}
+
+fn read_u32(data: &[u8]) -> u32 {
+    u32::from_le_bytes([data[0], data[1], data[2], data[3]])
+}
+
+fn read_u64(data: &[u8]) -> u64 {
+    read_u32(data) as u64 | (read_u32(&data[4..]) as u64) << 32
+}
+
+const XXH32_PRIME_1: u32 = 0x9E37_79B1;
+const XXH32_PRIME_2: u32 = 0x85EB_CA77;
+const XXH32_PRIME_3: u32 = 0xC2B2_AE3D;
+const XXH32_PRIME_4: u32 = 0x27D4_EB2F;
+const XXH32_PRIME_5: u32 = 0x1656_67B1;
+
+fn xxh32_round(accumulator: u32, lane: u32) -> u32 {
+    accumulator
+        .wrapping_add(lane.wrapping_mul(XXH32_PRIME_2))
+        .rotate_left(13)
+        .wrapping_mul(XXH32_PRIME_1)
+}
+
+/// XXH32 with a seed of 0, as used by lz4 frames.
+pub fn xxh32(data: &[u8]) -> u32 {
+    let mut chunks = data.chunks_exact(16);
+    let mut hash = if data.len() >= 16 {
+        let mut lanes = [
+            XXH32_PRIME_1.wrapping_add(XXH32_PRIME_2),
+            XXH32_PRIME_2,
+            0,
+            0u32.wrapping_sub(XXH32_PRIME_1),
+        ];
+        for chunk in &mut chunks {
+            for (index, lane) in lanes.iter_mut().enumerate() {
+                *lane = xxh32_round(*lane, read_u32(&chunk[index * 4..]));
+            }
+        }
+        lanes[0]
+            .rotate_left(1)
+            .wrapping_add(lanes[1].rotate_left(7))
+            .wrapping_add(lanes[2].rotate_left(12))
+            .wrapping_add(lanes[3].rotate_left(18))
+    } else {
+        XXH32_PRIME_5
+    };
+    hash = hash.wrapping_add(data.len() as u32);
+
+    let mut rest = chunks.remainder().chunks_exact(4);
+    for word in &mut rest {
+        hash = hash
+            .wrapping_add(read_u32(word).wrapping_mul(XXH32_PRIME_3))
+            .rotate_left(17)
+            .wrapping_mul(XXH32_PRIME_4);
+    }
+    for &byte in rest.remainder() {
+        hash = hash
+            .wrapping_add((byte as u32).wrapping_mul(XXH32_PRIME_5))
+            .rotate_left(11)
+            .wrapping_mul(XXH32_PRIME_1);
+    }
+
+    hash ^= hash >> 15;
+    hash = hash.wrapping_mul(XXH32_PRIME_2);
+    hash ^= hash >> 13;
+    hash = hash.wrapping_mul(XXH32_PRIME_3);
+    hash ^ hash >> 16
+}
+
+const XXH64_PRIME_1: u64 = 0x9E37_79B1_85EB_CA87;
+const XXH64_PRIME_2: u64 = 0xC2B2_AE3D_27D4_EB4F;
+const XXH64_PRIME_3: u64 = 0x1656_67B1_9E37_79F9;
+const XXH64_PRIME_4: u64 = 0x85EB_CA77_C2B2_AE63;
+const XXH64_PRIME_5: u64 = 0x27D4_EB2F_1656_67C5;
+
+fn xxh64_round(accumulator: u64, lane: u64) -> u64 {
+    accumulator
+        .wrapping_add(lane.wrapping_mul(XXH64_PRIME_2))
+        .rotate_left(31)
+        .wrapping_mul(XXH64_PRIME_1)
+}
+
+fn xxh64_merge(hash: u64, lane: u64) -> u64 {
+    (hash ^ xxh64_round(0, lane))
+        .wrapping_mul(XXH64_PRIME_1)
+        .wrapping_add(XXH64_PRIME_4)
+}
+
+/// XXH64 with a seed of 0, as used by zstd frames.
+pub fn xxh64(data: &[u8]) -> u64 {
+    let mut chunks = data.chunks_exact(32);
+    let mut hash = if data.len() >= 32 {
+        let mut lanes = [
+            XXH64_PRIME_1.wrapping_add(XXH64_PRIME_2),
+            XXH64_PRIME_2,
+            0,
+            0u64.wrapping_sub(XXH64_PRIME_1),
+        ];
+        for chunk in &mut chunks {
+            for (index, lane) in lanes.iter_mut().enumerate() {
+                *lane = xxh64_round(*lane, read_u64(&chunk[index * 8..]));
+            }
+        }
+        let hash = lanes[0]
+            .rotate_left(1)
+            .wrapping_add(lanes[1].rotate_left(7))
+            .wrapping_add(lanes[2].rotate_left(12))
+            .wrapping_add(lanes[3].rotate_left(18));
+        lanes
+            .iter()
+            .fold(hash, |hash, &lane| xxh64_merge(hash, lane))
+    } else {
+        XXH64_PRIME_5
+    };
+    hash = hash.wrapping_add(data.len() as u64);
+
+    let mut rest = chunks.remainder().chunks_exact(8);
+    for word in &mut rest {
+        hash = (hash ^ xxh64_round(0, read_u64(word)))
+            .rotate_left(27)
+            .wrapping_mul(XXH64_PRIME_1)
+            .wrapping_add(XXH64_PRIME_4);
+    }
+    let mut rest = rest.remainder().chunks_exact(4);
+    for word in &mut rest {
+        hash = (hash ^ (read_u32(word) as u64).wrapping_mul(XXH64_PRIME_1))
+            .rotate_left(23)
+            .wrapping_mul(XXH64_PRIME_2)
+            .wrapping_add(XXH64_PRIME_3);
+    }
+    for &byte in rest.remainder() {
+        hash = (hash ^ (byte as u64).wrapping_mul(XXH64_PRIME_5))
+            .rotate_left(11)
+            .wrapping_mul(XXH64_PRIME_1);
+    }
+
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(XXH64_PRIME_2);
+    hash ^= hash >> 29;
+    hash = hash.wrapping_mul(XXH64_PRIME_3);
+    hash ^ hash >> 32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_checksums() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+        assert_eq!(xxh32(b""), 0x02CC_5D05);
+        assert_eq!(xxh64(b""), 0xEF46_DB37_51D8_E999);
+    }
+}
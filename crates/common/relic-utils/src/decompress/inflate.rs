@@ -0,0 +1,297 @@
+//! gzip members (RFC 1952) holding deflate data (RFC 1951). Huffman codes are
+//! decoded canonically one bit at a time, like zlib's `puff`, which needs no
+//! lookup tables.
+
+use super::{checksum, BitReader, ByteReader, DecompressError, Result, Sink};
+
+pub const GZIP_MAGIC: [u8; 2] = [0x1F, 0x8B];
+
+/// The only compression method of gzip.
+const METHOD_DEFLATE: u8 = 8;
+
+const FLAG_HEADER_CRC: u8 = 1 << 1;
+const FLAG_EXTRA: u8 = 1 << 2;
+const FLAG_NAME: u8 = 1 << 3;
+const FLAG_COMMENT: u8 = 1 << 4;
+const FLAG_RESERVED: u8 = 0xE0;
+
+/// Longest Huffman code of deflate.
+const MAX_BITS: usize = 15;
+/// Literal/length symbols, including the two invalid ones of the fixed code.
+const MAX_LENGTH_CODES: usize = 288;
+/// Distance symbols, including the two invalid ones of the fixed code.
+const MAX_DISTANCE_CODES: usize = 32;
+
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+const DISTANCE_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DISTANCE_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+
+/// Order in which the lengths of the code length code are stored.
+const CODE_LENGTH_ORDER: [usize; 19] = [
+    16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15,
+];
+
+/// Decode all gzip members in `data`.
+pub fn decode_gzip<S: Sink>(data: &[u8], sink: &mut S) -> Result<()> {
+    let mut reader = ByteReader::new(data);
+    loop {
+        skip_header(&mut reader)?;
+
+        let start = sink.position();
+        let mut bits = BitReader::new(reader.remaining());
+        inflate(&mut bits, sink)?;
+        reader = ByteReader::new(bits.remaining());
+
+        let crc = reader.u32()?;
+        let size = reader.u32()?;
+        if size != (sink.position() - start) as u32 {
+            return Err(DecompressError::Invalid(
+                "Size of the member does not match",
+            ));
+        }
+        if let Some(output) = sink.output_since(start) {
+            if checksum::crc32(output) != crc {
+                return Err(DecompressError::ChecksumMismatch);
+            }
+        }
+
+        if reader.is_empty() {
+            return Ok(());
+        }
+    }
+}
+
+fn skip_header(reader: &mut ByteReader<'_>) -> Result<()> {
+    if reader.bytes(2)? != GZIP_MAGIC {
+        return Err(DecompressError::Invalid("Not a gzip member"));
+    }
+    if reader.u8()? != METHOD_DEFLATE {
+        return Err(DecompressError::Unsupported(
+            "Compression method is not deflate",
+        ));
+    }
+    let flags = reader.u8()?;
+    if flags & FLAG_RESERVED != 0 {
+        return Err(DecompressError::Invalid("Reserved flags are set"));
+    }
+    // Modification time, extra flags and operating system.
+    reader.bytes(6)?;
+
+    if flags & FLAG_EXTRA != 0 {
+        let length = reader.u16()?;
+        reader.bytes(length as usize)?;
+    }
+    for &flag in [FLAG_NAME, FLAG_COMMENT].iter() {
+        if flags & flag != 0 {
+            while reader.u8()? != 0 {}
+        }
+    }
+    if flags & FLAG_HEADER_CRC != 0 {
+        reader.u16()?;
+    }
+    Ok(())
+}
+
+/// A canonical Huffman code, stored as the number of codes of each length and the
+/// symbols ordered by their code.
+struct Huffman<const N: usize> {
+    counts: [u16; MAX_BITS + 1],
+    symbols: [u16; N],
+}
+
+impl<const N: usize> Huffman<N> {
+    /// Build the code from the code length of each symbol. Incomplete codes are
+    /// allowed, their unused codes fail to decode.
+    fn new(lengths: &[u8]) -> Result<Self> {
+        let mut code = Self {
+            counts: [0; MAX_BITS + 1],
+            symbols: [0; N],
+        };
+        for &length in lengths {
+            code.counts[length as usize] += 1;
+        }
+
+        let mut left = 1i32;
+        for length in 1..=MAX_BITS {
+            left = (left << 1) - code.counts[length] as i32;
+            if left < 0 {
+                return Err(DecompressError::Invalid("Huffman code is over-subscribed"));
+            }
+        }
+
+        // Symbols without a code are not stored.
+        let mut offsets = [0u16; MAX_BITS + 1];
+        for length in 1..MAX_BITS {
+            offsets[length + 1] = offsets[length] + code.counts[length];
+        }
+
+        for (symbol, &length) in lengths.iter().enumerate() {
+            if length != 0 {
+                code.symbols[offsets[length as usize] as usize] = symbol as u16;
+                offsets[length as usize] += 1;
+            }
+        }
+        Ok(code)
+    }
+
+    fn decode(&self, bits: &mut BitReader<'_>) -> Result<u16> {
+        let mut code = 0i32;
+        let mut first = 0i32;
+        let mut index = 0i32;
+        for length in 1..=MAX_BITS {
+            code |= bits.bits(1)? as i32;
+            let count = self.counts[length] as i32;
+            if code - first < count {
+                return Ok(self.symbols[(index + code - first) as usize]);
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        Err(DecompressError::Invalid("Huffman code is not assigned"))
+    }
+}
+
+type LengthCode = Huffman<MAX_LENGTH_CODES>;
+type DistanceCode = Huffman<MAX_DISTANCE_CODES>;
+
+/// Decode the deflate blocks until the final one.
+fn inflate<S: Sink>(bits: &mut BitReader<'_>, sink: &mut S) -> Result<()> {
+    loop {
+        let last = bits.bits(1)? == 1;
+        match bits.bits(2)? {
+            0 => stored(bits, sink)?,
+            1 => {
+                let (lengths, distances) = fixed_codes()?;
+                codes(bits, sink, &lengths, &distances)?
+            }
+            2 => {
+                let (lengths, distances) = dynamic_codes(bits)?;
+                codes(bits, sink, &lengths, &distances)?
+            }
+            _ => return Err(DecompressError::Invalid("Reserved block type")),
+        }
+        if last {
+            bits.align();
+            return Ok(());
+        }
+    }
+}
+
+fn stored<S: Sink>(bits: &mut BitReader<'_>, sink: &mut S) -> Result<()> {
+    bits.align();
+    let header = bits.bytes(4)?;
+    let length = u16::from_le_bytes([header[0], header[1]]);
+    if length != !u16::from_le_bytes([header[2], header[3]]) {
+        return Err(DecompressError::Invalid("Stored block length is corrupted"));
+    }
+    sink.extend(bits.bytes(length as usize)?)
+}
+
+fn fixed_codes() -> Result<(LengthCode, DistanceCode)> {
+    let mut lengths = [0; MAX_LENGTH_CODES];
+    lengths[..144].iter_mut().for_each(|length| *length = 8);
+    lengths[144..256].iter_mut().for_each(|length| *length = 9);
+    lengths[256..280].iter_mut().for_each(|length| *length = 7);
+    lengths[280..].iter_mut().for_each(|length| *length = 8);
+    Ok((
+        Huffman::new(&lengths)?,
+        Huffman::new(&[5; MAX_DISTANCE_CODES])?,
+    ))
+}
+
+fn dynamic_codes(bits: &mut BitReader<'_>) -> Result<(LengthCode, DistanceCode)> {
+    let length_count = bits.bits(5)? as usize + 257;
+    let distance_count = bits.bits(5)? as usize + 1;
+    let code_length_count = bits.bits(4)? as usize + 4;
+    if length_count > 286 || distance_count > 30 {
+        return Err(DecompressError::Invalid(
+            "Too many length or distance codes",
+        ));
+    }
+
+    let mut code_lengths = [0; 19];
+    for &symbol in &CODE_LENGTH_ORDER[..code_length_count] {
+        code_lengths[symbol] = bits.bits(3)? as u8;
+    }
+    let code_length_code = Huffman::<19>::new(&code_lengths)?;
+
+    // The lengths of both codes are one sequence, repeats may cross from one to
+    // the other.
+    let mut lengths = [0u8; MAX_LENGTH_CODES + MAX_DISTANCE_CODES];
+    let total = length_count + distance_count;
+    let mut index = 0;
+    while index < total {
+        let symbol = code_length_code.decode(bits)?;
+        let (length, repeat) = match symbol {
+            0..=15 => (symbol as u8, 1),
+            16 => {
+                if index == 0 {
+                    return Err(DecompressError::Invalid("Repeat without a length"));
+                }
+                (lengths[index - 1], 3 + bits.bits(2)? as usize)
+            }
+            17 => (0, 3 + bits.bits(3)? as usize),
+            _ => (0, 11 + bits.bits(7)? as usize),
+        };
+        if index + repeat > total {
+            return Err(DecompressError::Invalid("Code lengths repeat past the end"));
+        }
+        lengths[index..index + repeat]
+            .iter_mut()
+            .for_each(|slot| *slot = length);
+        index += repeat;
+    }
+    if lengths[256] == 0 {
+        return Err(DecompressError::Invalid("No code for the end of the block"));
+    }
+
+    Ok((
+        Huffman::new(&lengths[..length_count])?,
+        Huffman::new(&lengths[length_count..total])?,
+    ))
+}
+
+/// Decode the symbols of a compressed block until the end of the block.
+fn codes<S: Sink>(
+    bits: &mut BitReader<'_>,
+    sink: &mut S,
+    lengths: &LengthCode,
+    distances: &DistanceCode,
+) -> Result<()> {
+    loop {
+        let symbol = lengths.decode(bits)? as usize;
+        match symbol {
+            0..=255 => sink.push(symbol as u8)?,
+            256 => return Ok(()),
+            _ => {
+                let symbol = symbol - 257;
+                if symbol >= LENGTH_BASE.len() {
+                    return Err(DecompressError::Invalid("Invalid length symbol"));
+                }
+                let length =
+                    LENGTH_BASE[symbol] as usize + bits.bits(LENGTH_EXTRA[symbol] as u32)? as usize;
+
+                let symbol = distances.decode(bits)? as usize;
+                if symbol >= DISTANCE_BASE.len() {
+                    return Err(DecompressError::Invalid("Invalid distance symbol"));
+                }
+                let distance = DISTANCE_BASE[symbol] as usize
+                    + bits.bits(DISTANCE_EXTRA[symbol] as u32)? as usize;
+                sink.repeat(distance, length)?;
+            }
+        }
+    }
+}
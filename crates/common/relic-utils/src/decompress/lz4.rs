@@ -0,0 +1,123 @@
+//! LZ4 frames, see <https://github.com/lz4/lz4/blob/dev/doc/lz4_Frame_format.md>
+//! and the block format next to it.
+
+use super::{checksum, is_skippable_frame, ByteReader, DecompressError, Result, Sink};
+
+pub const MAGIC: u32 = 0x184D_2204;
+
+const VERSION_MASK: u8 = 0xC0;
+const VERSION: u8 = 0x40;
+const FLAG_BLOCK_CHECKSUM: u8 = 1 << 4;
+const FLAG_CONTENT_SIZE: u8 = 1 << 3;
+const FLAG_CONTENT_CHECKSUM: u8 = 1 << 2;
+const FLAG_DICTIONARY: u8 = 1 << 0;
+
+/// The block size field has this bit set when the block is stored uncompressed.
+const UNCOMPRESSED_BLOCK: u32 = 1 << 31;
+
+/// Matches are at least this long.
+const MIN_MATCH: usize = 4;
+
+/// Decode all frames in `data`.
+pub fn decode<S: Sink>(data: &[u8], sink: &mut S) -> Result<()> {
+    let mut reader = ByteReader::new(data);
+    loop {
+        let magic = reader.u32()?;
+        if is_skippable_frame(magic) {
+            let length = reader.u32()?;
+            reader.bytes(length as usize)?;
+        } else if magic == MAGIC {
+            decode_frame(&mut reader, sink)?;
+        } else {
+            return Err(DecompressError::Invalid("Not an LZ4 frame"));
+        }
+        if reader.is_empty() {
+            return Ok(());
+        }
+    }
+}
+
+fn decode_frame<S: Sink>(reader: &mut ByteReader<'_>, sink: &mut S) -> Result<()> {
+    let descriptor = reader.remaining();
+    let flags = reader.u8()?;
+    if flags & VERSION_MASK != VERSION {
+        return Err(DecompressError::Unsupported("LZ4 frame version"));
+    }
+    if flags & FLAG_DICTIONARY != 0 {
+        return Err(DecompressError::Unsupported("LZ4 dictionaries"));
+    }
+    // Maximum block size, which the output slice does not depend on.
+    reader.u8()?;
+    let content_size = if flags & FLAG_CONTENT_SIZE != 0 {
+        Some(reader.u64()?)
+    } else {
+        None
+    };
+    let descriptor = &descriptor[..descriptor.len() - reader.remaining().len()];
+    if reader.u8()? != (checksum::xxh32(descriptor) >> 8) as u8 {
+        return Err(DecompressError::ChecksumMismatch);
+    }
+
+    let start = sink.position();
+    loop {
+        let size = reader.u32()?;
+        if size == 0 {
+            break;
+        }
+        let block = reader.bytes((size & !UNCOMPRESSED_BLOCK) as usize)?;
+        if flags & FLAG_BLOCK_CHECKSUM != 0 && reader.u32()? != checksum::xxh32(block) {
+            return Err(DecompressError::ChecksumMismatch);
+        }
+        if size & UNCOMPRESSED_BLOCK != 0 {
+            sink.extend(block)?;
+        } else {
+            decode_block(block, sink)?;
+        }
+    }
+
+    if let Some(content_size) = content_size {
+        if content_size != (sink.position() - start) as u64 {
+            return Err(DecompressError::Invalid("Content size does not match"));
+        }
+    }
+    if flags & FLAG_CONTENT_CHECKSUM != 0 {
+        let expected = reader.u32()?;
+        if let Some(output) = sink.output_since(start) {
+            if checksum::xxh32(output) != expected {
+                return Err(DecompressError::ChecksumMismatch);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Read the rest of a length that does not fit into the 4 bits of the token.
+fn extended_length(reader: &mut ByteReader<'_>, mut length: usize) -> Result<usize> {
+    if length == 0xF {
+        loop {
+            let byte = reader.u8()?;
+            length += byte as usize;
+            if byte != 0xFF {
+                break;
+            }
+        }
+    }
+    Ok(length)
+}
+
+/// Decode the sequences of a block. The last sequence only has literals.
+fn decode_block<S: Sink>(block: &[u8], sink: &mut S) -> Result<()> {
+    let mut reader = ByteReader::new(block);
+    loop {
+        let token = reader.u8()?;
+        let literals = extended_length(&mut reader, (token >> 4) as usize)?;
+        sink.extend(reader.bytes(literals)?)?;
+        if reader.is_empty() {
+            return Ok(());
+        }
+
+        let offset = reader.u16()?;
+        let length = extended_length(&mut reader, (token & 0xF) as usize)? + MIN_MATCH;
+        sink.repeat(offset as usize, length)?;
+    }
+}
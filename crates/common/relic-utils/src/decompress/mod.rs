@@ -0,0 +1,426 @@
+//! Decompressors for gzip, zstd and lz4 frames, so that the initrd can be stored
+//! compressed.
+//!
+//! Nothing is allocated: the output goes into a slice given by the caller. To find
+//! how large that slice has to be, [`decompressed_size`] decodes the input once
+//! without storing the output.
+
+use std::fmt;
+
+mod checksum;
+mod inflate;
+mod lz4;
+mod zstd;
+
+/// Compression formats recognized by their magic number.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// gzip members with deflate data.
+    Gzip,
+    /// Zstandard frames.
+    Zstd,
+    /// LZ4 frames. The legacy format is not supported.
+    Lz4,
+}
+
+impl Format {
+    /// The format of `data`, or `None` if it is not compressed by a known format.
+    pub fn detect(data: &[u8]) -> Option<Format> {
+        if data.starts_with(&inflate::GZIP_MAGIC) {
+            Some(Format::Gzip)
+        } else if data.starts_with(&zstd::MAGIC.to_le_bytes()) {
+            Some(Format::Zstd)
+        } else if data.starts_with(&lz4::MAGIC.to_le_bytes()) {
+            Some(Format::Lz4)
+        } else {
+            None
+        }
+    }
+}
+
+/// Reasons why the input cannot be decompressed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecompressError {
+    /// The input ends in the middle of the stream.
+    Truncated,
+    /// The stream is malformed. Holds what is wrong with it.
+    Invalid(&'static str),
+    /// The stream uses a feature that is not implemented.
+    Unsupported(&'static str),
+    /// A match refers to data before the start of the output.
+    InvalidDistance,
+    /// The output does not fit into the given slice.
+    OutputTooSmall,
+    /// A checksum in the stream does not match the data.
+    ChecksumMismatch,
+}
+
+impl fmt::Display for DecompressError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecompressError::Truncated => write!(f, "Input is truncated"),
+            DecompressError::Invalid(message) => write!(f, "Invalid stream: {}", message),
+            DecompressError::Unsupported(message) => write!(f, "Unsupported: {}", message),
+            DecompressError::InvalidDistance => write!(f, "Match is out of bounds"),
+            DecompressError::OutputTooSmall => write!(f, "Output is too small"),
+            DecompressError::ChecksumMismatch => write!(f, "Checksum mismatch"),
+        }
+    }
+}
+
+/// Result type of the decompressors.
+pub type Result<T> = core::result::Result<T, DecompressError>;
+
+/// Number of bytes that `data` decompresses to.
+pub fn decompressed_size(format: Format, data: &[u8]) -> Result<usize> {
+    let mut counter = Counter { position: 0 };
+    decode(format, data, &mut counter)?;
+    Ok(counter.position)
+}
+
+/// Decompress `data` into `output` and return the number of bytes written.
+pub fn decompress(format: Format, data: &[u8], output: &mut [u8]) -> Result<usize> {
+    let mut writer = Writer {
+        output,
+        position: 0,
+    };
+    decode(format, data, &mut writer)?;
+    Ok(writer.position)
+}
+
+fn decode<S: Sink>(format: Format, data: &[u8], sink: &mut S) -> Result<()> {
+    match format {
+        Format::Gzip => inflate::decode_gzip(data, sink),
+        Format::Zstd => zstd::decode(data, sink),
+        Format::Lz4 => lz4::decode(data, sink),
+    }
+}
+
+/**
+Destination of the decoders. The same decoder runs once with a [`Counter`] to find
+the size of the output and once with a [`Writer`].
+*/
+trait Sink {
+    /// Number of bytes produced so far.
+    fn position(&self) -> usize;
+
+    /// Append `bytes`.
+    fn extend(&mut self, bytes: &[u8]) -> Result<()>;
+
+    /// Append `length` copies of `byte`.
+    fn fill(&mut self, byte: u8, length: usize) -> Result<()>;
+
+    /// Append `length` bytes copied from `distance` bytes before the end. The
+    /// source may overlap the appended bytes.
+    fn repeat(&mut self, distance: usize, length: usize) -> Result<()>;
+
+    /// The bytes produced since `start`, if they are kept. Checksums are only
+    /// verified when they are.
+    fn output_since(&self, start: usize) -> Option<&[u8]>;
+
+    /// Append a single byte.
+    fn push(&mut self, byte: u8) -> Result<()> {
+        self.extend(&[byte])
+    }
+}
+
+/// Counts the output without storing it.
+struct Counter {
+    position: usize,
+}
+
+impl Sink for Counter {
+    fn position(&self) -> usize {
+        self.position
+    }
+
+    fn extend(&mut self, bytes: &[u8]) -> Result<()> {
+        self.fill(0, bytes.len())
+    }
+
+    fn fill(&mut self, _byte: u8, length: usize) -> Result<()> {
+        self.position = self
+            .position
+            .checked_add(length)
+            .ok_or(DecompressError::OutputTooSmall)?;
+        Ok(())
+    }
+
+    fn repeat(&mut self, distance: usize, length: usize) -> Result<()> {
+        if distance == 0 || distance > self.position {
+            return Err(DecompressError::InvalidDistance);
+        }
+        self.fill(0, length)
+    }
+
+    fn output_since(&self, _start: usize) -> Option<&[u8]> {
+        None
+    }
+}
+
+/// Writes the output into a slice.
+struct Writer<'a> {
+    output: &'a mut [u8],
+    position: usize,
+}
+
+impl Writer<'_> {
+    /// Reserve `length` bytes and return the range they are written to.
+    fn reserve(&mut self, length: usize) -> Result<core::ops::Range<usize>> {
+        let start = self.position;
+        let end = start
+            .checked_add(length)
+            .filter(|&end| end <= self.output.len())
+            .ok_or(DecompressError::OutputTooSmall)?;
+        self.position = end;
+        Ok(start..end)
+    }
+}
+
+impl Sink for Writer<'_> {
+    fn position(&self) -> usize {
+        self.position
+    }
+
+    fn extend(&mut self, bytes: &[u8]) -> Result<()> {
+        let range = self.reserve(bytes.len())?;
+        self.output[range].copy_from_slice(bytes);
+        Ok(())
+    }
+
+    fn fill(&mut self, byte: u8, length: usize) -> Result<()> {
+        let range = self.reserve(length)?;
+        for out in &mut self.output[range] {
+            *out = byte;
+        }
+        Ok(())
+    }
+
+    fn push(&mut self, byte: u8) -> Result<()> {
+        let range = self.reserve(1)?;
+        self.output[range.start] = byte;
+        Ok(())
+    }
+
+    fn repeat(&mut self, distance: usize, length: usize) -> Result<()> {
+        if distance == 0 || distance > self.position {
+            return Err(DecompressError::InvalidDistance);
+        }
+        let range = self.reserve(length)?;
+        if distance >= length {
+            self.output
+                .copy_within(range.start - distance..range.end - distance, range.start);
+        } else {
+            // The source overlaps the destination, so the copied bytes repeat.
+            for index in range {
+                self.output[index] = self.output[index - distance];
+            }
+        }
+        Ok(())
+    }
+
+    fn output_since(&self, start: usize) -> Option<&[u8]> {
+        self.output.get(start..self.position)
+    }
+}
+
+/// Reads little endian fields from the front of the input.
+#[derive(Debug, Clone)]
+struct ByteReader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> ByteReader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    fn remaining(&self) -> &'a [u8] {
+        self.data
+    }
+
+    fn bytes(&mut self, length: usize) -> Result<&'a [u8]> {
+        if length > self.data.len() {
+            return Err(DecompressError::Truncated);
+        }
+        let (bytes, rest) = self.data.split_at(length);
+        self.data = rest;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    /// Read an unsigned little endian integer of `length` bytes, at most 8.
+    fn uint(&mut self, length: usize) -> Result<u64> {
+        Ok(self
+            .bytes(length)?
+            .iter()
+            .rev()
+            .fold(0, |value, &byte| value << 8 | byte as u64))
+    }
+
+    fn u16(&mut self) -> Result<u16> {
+        Ok(self.uint(2)? as u16)
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(self.uint(4)? as u32)
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        self.uint(8)
+    }
+}
+
+/// Reads bits from the front of the input, least significant bit first.
+struct BitReader<'a> {
+    data: &'a [u8],
+    position: usize,
+    buffer: u64,
+    count: u32,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self {
+            data,
+            position: 0,
+            buffer: 0,
+            count: 0,
+        }
+    }
+
+    fn bits(&mut self, count: u32) -> Result<u32> {
+        while self.count < count {
+            let byte = *self
+                .data
+                .get(self.position)
+                .ok_or(DecompressError::Truncated)?;
+            self.position += 1;
+            self.buffer |= (byte as u64) << self.count;
+            self.count += 8;
+        }
+        let value = self.buffer & ((1 << count) - 1);
+        self.buffer >>= count;
+        self.count -= count;
+        Ok(value as u32)
+    }
+
+    /// Drop the bits up to the next byte boundary.
+    fn align(&mut self) {
+        self.buffer = 0;
+        self.count = 0;
+    }
+
+    /// The input after the current byte. Only valid after [`BitReader::align`].
+    fn remaining(&self) -> &'a [u8] {
+        &self.data[self.position..]
+    }
+
+    fn bytes(&mut self, length: usize) -> Result<&'a [u8]> {
+        let mut reader = ByteReader::new(self.remaining());
+        let bytes = reader.bytes(length)?;
+        self.position += length;
+        Ok(bytes)
+    }
+}
+
+/// Skippable frames, which zstd and lz4 share, have magic numbers from
+/// `0x184D2A50` to `0x184D2A5F`.
+fn is_skippable_frame(magic: u32) -> bool {
+    magic & 0xFFFF_FFF0 == 0x184D_2A50
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Text with enough repetition for matches and enough variation for entropy
+    /// coding. The fixtures in `testdata` are this text compressed by the command
+    /// line tools.
+    pub(super) fn sample_text() -> Vec<u8> {
+        let mut text = Vec::new();
+        for line in 0..400u64 {
+            let word = [
+                "relic",
+                "sigma",
+                "kernel",
+                "capability",
+                "untyped",
+                "initrd",
+            ][(line * 7 % 6) as usize];
+            text.extend_from_slice(
+                format!(
+                    "{:04} {} {:x} {}\n",
+                    line,
+                    word,
+                    line * line * 2_654_435_761 % 9973,
+                    word
+                )
+                .as_bytes(),
+            );
+        }
+        text
+    }
+
+    fn roundtrip(format: Format, data: &[u8]) {
+        assert_eq!(Format::detect(data), Some(format));
+        let expected = sample_text();
+        assert_eq!(decompressed_size(format, data), Ok(expected.len()));
+
+        let mut output = vec![0; expected.len()];
+        assert_eq!(decompress(format, data, &mut output), Ok(expected.len()));
+        assert_eq!(output, expected);
+
+        let mut output = vec![0; expected.len() - 1];
+        assert_eq!(
+            decompress(format, data, &mut output),
+            Err(DecompressError::OutputTooSmall)
+        );
+        for length in [0, 10, data.len() / 2, data.len() - 1].iter() {
+            assert!(decompress(format, &data[..*length], &mut vec![0; expected.len()]).is_err());
+        }
+    }
+
+    #[test]
+    fn test_gzip() {
+        roundtrip(Format::Gzip, include_bytes!("testdata/sample.gz"));
+        roundtrip(Format::Gzip, include_bytes!("testdata/sample-fast.gz"));
+    }
+
+    #[test]
+    fn test_zstd() {
+        roundtrip(Format::Zstd, include_bytes!("testdata/sample.zst"));
+        roundtrip(Format::Zstd, include_bytes!("testdata/sample-19.zst"));
+    }
+
+    #[test]
+    fn test_lz4() {
+        roundtrip(Format::Lz4, include_bytes!("testdata/sample.lz4"));
+        roundtrip(Format::Lz4, include_bytes!("testdata/sample-checksums.lz4"));
+    }
+
+    #[test]
+    fn test_checksum_mismatch() {
+        let mut data = include_bytes!("testdata/sample.gz").to_vec();
+        let crc = data.len() - 8;
+        data[crc] ^= 1;
+        let mut output = vec![0; sample_text().len()];
+        assert_eq!(
+            decompress(Format::Gzip, &data, &mut output),
+            Err(DecompressError::ChecksumMismatch)
+        );
+    }
+
+    #[test]
+    fn test_detect() {
+        assert_eq!(Format::detect(b"./userspace/relic-sigma"), None);
+        assert_eq!(Format::detect(&[]), None);
+    }
+}
@@ -0,0 +1,876 @@
+//! Zstandard frames (RFC 8878) without dictionaries.
+//!
+//! Literals are decoded while the sequences consume them instead of into a
+//! buffer. The Huffman streams hold the literals in order, so a block needs no
+//! memory besides its tables.
+
+use super::{checksum, is_skippable_frame, BitReader, ByteReader, DecompressError, Result, Sink};
+use crate::align;
+
+pub const MAGIC: u32 = 0xFD2F_B528;
+
+/// Blocks never hold more than 128 KiB.
+const MAX_BLOCK_SIZE: usize = 128 * 1024;
+
+const BLOCK_RAW: u32 = 0;
+const BLOCK_RLE: u32 = 1;
+const BLOCK_COMPRESSED: u32 = 2;
+
+const LITERALS_RAW: u8 = 0;
+const LITERALS_RLE: u8 = 1;
+const LITERALS_COMPRESSED: u8 = 2;
+
+const MODE_PREDEFINED: u8 = 0;
+const MODE_RLE: u8 = 1;
+const MODE_COMPRESSED: u8 = 2;
+
+/// Longest Huffman code for literals.
+const MAX_HUFFMAN_BITS: u32 = 11;
+/// Largest accuracy log of the FSE table of the Huffman weights.
+const MAX_WEIGHT_LOG: u32 = 6;
+
+const MAX_LITERAL_LENGTH_LOG: u32 = 9;
+const MAX_MATCH_LENGTH_LOG: u32 = 9;
+const MAX_OFFSET_LOG: u32 = 8;
+
+const MAX_LITERAL_LENGTH_CODE: usize = 35;
+const MAX_MATCH_LENGTH_CODE: usize = 52;
+const MAX_OFFSET_CODE: usize = 31;
+
+const LITERAL_LENGTH_DEFAULT: [i16; 36] = [
+    4, 3, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 1, 1, 1, 2, 2, 2, 2, 2, 2, 2, 2, 2, 3, 2, 1, 1, 1, 1, 1,
+    -1, -1, -1, -1,
+];
+const MATCH_LENGTH_DEFAULT: [i16; 53] = [
+    1, 4, 3, 2, 2, 2, 2, 2, 2, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1,
+    1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, -1, -1, -1, -1, -1, -1, -1,
+];
+const OFFSET_DEFAULT: [i16; 29] = [
+    1, 1, 1, 1, 1, 1, 2, 2, 2, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, -1, -1, -1, -1, -1,
+];
+
+/// Baseline and number of extra bits of the literal length codes.
+const LITERAL_LENGTHS: [(u32, u8); 36] = [
+    (0, 0),
+    (1, 0),
+    (2, 0),
+    (3, 0),
+    (4, 0),
+    (5, 0),
+    (6, 0),
+    (7, 0),
+    (8, 0),
+    (9, 0),
+    (10, 0),
+    (11, 0),
+    (12, 0),
+    (13, 0),
+    (14, 0),
+    (15, 0),
+    (16, 1),
+    (18, 1),
+    (20, 1),
+    (22, 1),
+    (24, 2),
+    (28, 2),
+    (32, 3),
+    (40, 3),
+    (48, 4),
+    (64, 6),
+    (128, 7),
+    (256, 8),
+    (512, 9),
+    (1024, 10),
+    (2048, 11),
+    (4096, 12),
+    (8192, 13),
+    (16384, 14),
+    (32768, 15),
+    (65536, 16),
+];
+
+/// Baseline and number of extra bits of the match length codes. Codes 0 to 31
+/// stand for lengths 3 to 34.
+const MATCH_LENGTHS: [(u32, u8); 21] = [
+    (35, 1),
+    (37, 1),
+    (39, 1),
+    (41, 1),
+    (43, 2),
+    (47, 2),
+    (51, 3),
+    (59, 3),
+    (67, 4),
+    (83, 4),
+    (99, 5),
+    (131, 7),
+    (259, 8),
+    (515, 9),
+    (1027, 10),
+    (2051, 11),
+    (4099, 12),
+    (8195, 13),
+    (16387, 14),
+    (32771, 15),
+    (65539, 16),
+];
+
+/// Decode all frames in `data`.
+pub fn decode<S: Sink>(data: &[u8], sink: &mut S) -> Result<()> {
+    let mut reader = ByteReader::new(data);
+    loop {
+        let magic = reader.u32()?;
+        if is_skippable_frame(magic) {
+            let length = reader.u32()?;
+            reader.bytes(length as usize)?;
+        } else if magic == MAGIC {
+            decode_frame(&mut reader, sink)?;
+        } else {
+            return Err(DecompressError::Invalid("Not a zstd frame"));
+        }
+        if reader.is_empty() {
+            return Ok(());
+        }
+    }
+}
+
+fn decode_frame<S: Sink>(reader: &mut ByteReader<'_>, sink: &mut S) -> Result<()> {
+    let descriptor = reader.u8()?;
+    let single_segment = descriptor & 0x20 != 0;
+    if descriptor & 0x08 != 0 {
+        return Err(DecompressError::Invalid(
+            "Reserved bit of the frame header is set",
+        ));
+    }
+    if !single_segment {
+        // The window size does not matter when all of the output is kept.
+        reader.u8()?;
+    }
+    let dictionary_id = reader.uint([0, 1, 2, 4][(descriptor & 0x3) as usize])?;
+    if dictionary_id != 0 {
+        return Err(DecompressError::Unsupported("zstd dictionaries"));
+    }
+    let content_size = match descriptor >> 6 {
+        0 if single_segment => Some(reader.uint(1)?),
+        0 => None,
+        1 => Some(reader.uint(2)? + 256),
+        2 => Some(reader.uint(4)?),
+        _ => Some(reader.uint(8)?),
+    };
+
+    let start = sink.position();
+    let mut decoder = Decoder::new();
+    loop {
+        let header = reader.uint(3)? as u32;
+        let size = (header >> 3) as usize;
+        if size > MAX_BLOCK_SIZE {
+            return Err(DecompressError::Invalid("Block is too large"));
+        }
+        match (header >> 1) & 0x3 {
+            BLOCK_RAW => sink.extend(reader.bytes(size)?)?,
+            BLOCK_RLE => sink.fill(reader.u8()?, size)?,
+            BLOCK_COMPRESSED => decoder.decode_block(reader.bytes(size)?, sink)?,
+            _ => return Err(DecompressError::Invalid("Reserved block type")),
+        }
+        if header & 1 != 0 {
+            break;
+        }
+    }
+
+    if let Some(content_size) = content_size {
+        if content_size != (sink.position() - start) as u64 {
+            return Err(DecompressError::Invalid("Content size does not match"));
+        }
+    }
+    if descriptor & 0x04 != 0 {
+        let expected = reader.u32()?;
+        if let Some(output) = sink.output_since(start) {
+            if checksum::xxh64(output) as u32 != expected {
+                return Err(DecompressError::ChecksumMismatch);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Reads bits from the end of the input towards the start, as zstd stores its
+/// entropy coded streams. The highest set bit of the last byte marks the start.
+struct ReverseBitReader<'a> {
+    data: &'a [u8],
+    /// Number of bits left. Reading past the start reads zeros and makes this
+    /// negative.
+    position: isize,
+}
+
+impl<'a> ReverseBitReader<'a> {
+    fn new(data: &'a [u8]) -> Result<Self> {
+        match data.last() {
+            Some(&last) if last != 0 => Ok(Self {
+                data,
+                position: (data.len() * 8 - 1) as isize - last.leading_zeros() as isize,
+            }),
+            _ => Err(DecompressError::Invalid("Bitstream has no start marker")),
+        }
+    }
+
+    /// The next `count` bits, at most 32, without consuming them.
+    fn peek(&self, count: u32) -> u64 {
+        let end = self.position;
+        let start = end - count as isize;
+        let low = start.max(0);
+        if end <= low {
+            return 0;
+        }
+        let first = (low / 8) as usize;
+        let word = self.data[first..=((end - 1) / 8) as usize]
+            .iter()
+            .rev()
+            .fold(0u64, |word, &byte| word << 8 | byte as u64);
+        let value = (word >> (low - first as isize * 8)) & ((1 << (end - low)) - 1);
+        value << (low - start)
+    }
+
+    fn consume(&mut self, count: u32) {
+        self.position -= count as isize;
+    }
+
+    fn bits(&mut self, count: u32) -> u64 {
+        let value = self.peek(count);
+        self.consume(count);
+        value
+    }
+
+    /// Whether all bits were read, without reading past the start.
+    fn is_finished(&self) -> bool {
+        self.position == 0
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct FseEntry {
+    symbol: u8,
+    bits: u8,
+    base: u16,
+}
+
+/// Decoding table of finite state entropy. Each state decodes to a symbol and
+/// says how many bits to read for the next state.
+struct FseTable<const N: usize> {
+    entries: [FseEntry; N],
+    accuracy_log: u32,
+    /// Tables are only valid after they were set by a block, the repeat mode
+    /// reuses them.
+    valid: bool,
+}
+
+impl<const N: usize> FseTable<N> {
+    fn new() -> Self {
+        Self {
+            entries: [FseEntry::default(); N],
+            accuracy_log: 0,
+            valid: false,
+        }
+    }
+
+    /// A table that always decodes to `symbol` and reads no bits.
+    fn set_rle(&mut self, symbol: u8) {
+        self.entries[0] = FseEntry {
+            symbol,
+            bits: 0,
+            base: 0,
+        };
+        self.accuracy_log = 0;
+        self.valid = true;
+    }
+
+    /// Build the table from the normalized count of each symbol. A count of -1
+    /// stands for a probability below 1.
+    fn set_counts(&mut self, counts: &[i16], accuracy_log: u32) -> Result<()> {
+        const INVALID: DecompressError =
+            DecompressError::Invalid("FSE counts do not fill the table");
+        let size = 1usize << accuracy_log;
+        if size > N {
+            return Err(DecompressError::Invalid("FSE accuracy log is too large"));
+        }
+
+        // Symbols with a probability below 1 take the last states.
+        let mut next_states = [0u16; 256];
+        let mut high = size;
+        for (symbol, &count) in counts.iter().enumerate() {
+            if count == -1 {
+                high = high.checked_sub(1).ok_or(INVALID)?;
+                self.entries[high].symbol = symbol as u8;
+                next_states[symbol] = 1;
+            } else {
+                next_states[symbol] = count as u16;
+            }
+        }
+
+        let step = (size >> 1) + (size >> 3) + 3;
+        let mask = size - 1;
+        let mut position = 0;
+        for (symbol, &count) in counts.iter().enumerate() {
+            for _ in 0..count.max(0) {
+                self.entries[position].symbol = symbol as u8;
+                position = (position + step) & mask;
+                while position >= high {
+                    position = (position + step) & mask;
+                }
+            }
+        }
+        if position != 0 {
+            return Err(INVALID);
+        }
+
+        for entry in &mut self.entries[..size] {
+            let next_state = next_states[entry.symbol as usize];
+            next_states[entry.symbol as usize] += 1;
+            let bits = accuracy_log - (15 - next_state.leading_zeros());
+            entry.bits = bits as u8;
+            entry.base = ((next_state << bits) as usize - size) as u16;
+        }
+        self.accuracy_log = accuracy_log;
+        self.valid = true;
+        Ok(())
+    }
+
+    /// Read the normalized counts at the start of `data` and build the table.
+    /// Returns the number of bytes read.
+    fn read(&mut self, data: &[u8], max_log: u32, max_symbol: usize) -> Result<usize> {
+        let mut bits = BitReader::new(data);
+        let accuracy_log = bits.bits(4)? + 5;
+        if accuracy_log > max_log {
+            return Err(DecompressError::Invalid("FSE accuracy log is too large"));
+        }
+
+        let mut counts = [0i16; 256];
+        let mut remaining = 1i32 << accuracy_log;
+        let mut symbol = 0;
+        while remaining > 0 {
+            if symbol > max_symbol {
+                return Err(DecompressError::Invalid("FSE counts have too many symbols"));
+            }
+            // Values from 0 to `remaining + 1`, the small ones use one bit less.
+            let count_bits = 32 - ((remaining + 1) as u32).leading_zeros();
+            let lower_mask = (1 << (count_bits - 1)) - 1;
+            let threshold = (1 << count_bits) - 1 - (remaining as u32 + 1);
+            let mut value = bits.bits(count_bits - 1)?;
+            if value >= threshold {
+                value |= bits.bits(1)? << (count_bits - 1);
+                if value > lower_mask {
+                    value -= threshold;
+                }
+            }
+
+            let count = value as i16 - 1;
+            remaining -= count.abs() as i32;
+            counts[symbol] = count;
+            symbol += 1;
+            if count == 0 {
+                loop {
+                    let repeat = bits.bits(2)?;
+                    symbol += repeat as usize;
+                    if repeat != 3 {
+                        break;
+                    }
+                }
+            }
+        }
+        if remaining != 0 || symbol > max_symbol + 1 {
+            return Err(DecompressError::Invalid("FSE counts do not fill the table"));
+        }
+
+        bits.align();
+        let read = data.len() - bits.remaining().len();
+        self.set_counts(&counts[..symbol], accuracy_log)?;
+        Ok(read)
+    }
+
+    /// Set the table for a compression mode and return the number of bytes read.
+    fn update(
+        &mut self,
+        mode: u8,
+        data: &[u8],
+        default: (&[i16], u32),
+        max_log: u32,
+        max_symbol: usize,
+    ) -> Result<usize> {
+        match mode {
+            MODE_PREDEFINED => self.set_counts(default.0, default.1).map(|_| 0),
+            MODE_RLE => {
+                let symbol = *data.first().ok_or(DecompressError::Truncated)?;
+                if symbol as usize > max_symbol {
+                    return Err(DecompressError::Invalid("RLE symbol is too large"));
+                }
+                self.set_rle(symbol);
+                Ok(1)
+            }
+            MODE_COMPRESSED => self.read(data, max_log, max_symbol),
+            _ if self.valid => Ok(0),
+            _ => Err(DecompressError::Invalid("Repeated FSE table is not set")),
+        }
+    }
+
+    fn init_state(&self, bits: &mut ReverseBitReader<'_>) -> usize {
+        bits.bits(self.accuracy_log) as usize
+    }
+
+    fn symbol(&self, state: usize) -> u8 {
+        self.entries[state].symbol
+    }
+
+    fn next_state(&self, state: usize, bits: &mut ReverseBitReader<'_>) -> usize {
+        let entry = &self.entries[state];
+        entry.base as usize + bits.bits(entry.bits as u32) as usize
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct HuffmanEntry {
+    symbol: u8,
+    bits: u8,
+}
+
+/// Decoding table of the Huffman code of the literals, indexed by the next
+/// `max_bits` bits.
+struct HuffmanTable {
+    entries: [HuffmanEntry; 1 << MAX_HUFFMAN_BITS],
+    max_bits: u32,
+}
+
+impl HuffmanTable {
+    /// Read the weights of the code at the start of `data` and build the table.
+    /// Returns the number of bytes read.
+    fn read(data: &[u8]) -> Result<(Self, usize)> {
+        let mut weights = [0u8; 256];
+        let header = *data.first().ok_or(DecompressError::Truncated)? as usize;
+        let (count, read) = if header < 128 {
+            let data = data.get(1..1 + header).ok_or(DecompressError::Truncated)?;
+            (Self::read_fse_weights(data, &mut weights)?, 1 + header)
+        } else {
+            let count = header - 127;
+            let length = align::align_up(count, 2) / 2;
+            let data = data.get(1..1 + length).ok_or(DecompressError::Truncated)?;
+            for (index, weight) in weights[..count].iter_mut().enumerate() {
+                let byte = data[index / 2];
+                *weight = if index % 2 == 0 {
+                    byte >> 4
+                } else {
+                    byte & 0xF
+                };
+            }
+            (count, 1 + length)
+        };
+        Ok((Self::from_weights(&mut weights, count)?, read))
+    }
+
+    /// Weights compressed with FSE, decoded by two states taking turns.
+    fn read_fse_weights(data: &[u8], weights: &mut [u8; 256]) -> Result<usize> {
+        let mut table = FseTable::<{ 1 << MAX_WEIGHT_LOG }>::new();
+        let read = table.read(data, MAX_WEIGHT_LOG, MAX_HUFFMAN_BITS as usize)?;
+        let mut bits = ReverseBitReader::new(&data[read..])?;
+        let mut states = [table.init_state(&mut bits), table.init_state(&mut bits)];
+
+        let mut count = 0;
+        loop {
+            for turn in 0..2 {
+                if count >= 254 {
+                    return Err(DecompressError::Invalid("Too many Huffman weights"));
+                }
+                weights[count] = table.symbol(states[turn]);
+                count += 1;
+                states[turn] = table.next_state(states[turn], &mut bits);
+                if bits.position < 0 {
+                    weights[count] = table.symbol(states[1 - turn]);
+                    return Ok(count + 1);
+                }
+            }
+        }
+    }
+
+    /// Build the table from the weights of all symbols but the last, whose
+    /// weight makes the code complete.
+    fn from_weights(weights: &mut [u8; 256], count: usize) -> Result<Self> {
+        if count >= weights.len() {
+            return Err(DecompressError::Invalid("Too many Huffman weights"));
+        }
+        let mut total = 0u32;
+        for &weight in &weights[..count] {
+            if weight as u32 > MAX_HUFFMAN_BITS {
+                return Err(DecompressError::Invalid("Huffman weight is too large"));
+            }
+            if weight > 0 {
+                total += 1 << (weight - 1);
+            }
+        }
+        if total == 0 {
+            return Err(DecompressError::Invalid("Huffman weights are empty"));
+        }
+        let max_bits = 32 - total.leading_zeros();
+        let left = (1 << max_bits) - total;
+        if max_bits > MAX_HUFFMAN_BITS || !left.is_power_of_two() {
+            return Err(DecompressError::Invalid(
+                "Huffman weights do not form a code",
+            ));
+        }
+        weights[count] = left.trailing_zeros() as u8 + 1;
+
+        // Codes are ordered by increasing weight, then by symbol.
+        let mut table = Self {
+            entries: [HuffmanEntry::default(); 1 << MAX_HUFFMAN_BITS],
+            max_bits,
+        };
+        let mut position = 0;
+        for weight in 1..=max_bits as u8 {
+            for (symbol, _) in weights[..=count]
+                .iter()
+                .enumerate()
+                .filter(|(_, &symbol_weight)| symbol_weight == weight)
+            {
+                let length = 1 << (weight - 1);
+                let entry = HuffmanEntry {
+                    symbol: symbol as u8,
+                    bits: (max_bits + 1) as u8 - weight,
+                };
+                table.entries[position..position + length]
+                    .iter_mut()
+                    .for_each(|slot| *slot = entry);
+                position += length;
+            }
+        }
+        Ok(table)
+    }
+
+    fn decode(&self, bits: &mut ReverseBitReader<'_>) -> u8 {
+        let entry = self.entries[bits.peek(self.max_bits) as usize];
+        bits.consume(entry.bits as u32);
+        entry.symbol
+    }
+}
+
+/// Where the literals of a block come from.
+enum LiteralSection<'a> {
+    Raw(&'a [u8]),
+    Rle(u8),
+    /// One or four Huffman coded streams and the number of literals in each.
+    Huffman {
+        streams: [&'a [u8]; 4],
+        sizes: [usize; 4],
+    },
+}
+
+/// Hands out the literals of a block in order, decoding Huffman streams as they
+/// are needed.
+struct Literals<'a> {
+    section: LiteralSection<'a>,
+    table: Option<&'a HuffmanTable>,
+    /// Literals left in the block.
+    left: usize,
+    stream: usize,
+    stream_left: usize,
+    bits: Option<ReverseBitReader<'a>>,
+}
+
+impl<'a> Literals<'a> {
+    fn new(section: LiteralSection<'a>, count: usize, table: Option<&'a HuffmanTable>) -> Self {
+        Self {
+            section,
+            table,
+            left: count,
+            stream: 0,
+            stream_left: 0,
+            bits: None,
+        }
+    }
+
+    /// Append the next `count` literals to the output.
+    fn copy<S: Sink>(&mut self, count: usize, sink: &mut S) -> Result<()> {
+        if count > self.left {
+            return Err(DecompressError::Invalid(
+                "Sequences use more literals than the block has",
+            ));
+        }
+        self.left -= count;
+        match &mut self.section {
+            LiteralSection::Raw(data) => {
+                let (literals, rest) = data.split_at(count);
+                *data = rest;
+                sink.extend(literals)
+            }
+            LiteralSection::Rle(byte) => sink.fill(*byte, count),
+            LiteralSection::Huffman { .. } => {
+                for _ in 0..count {
+                    let literal = self.next_huffman()?;
+                    sink.push(literal)?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    fn next_huffman(&mut self) -> Result<u8> {
+        let (streams, sizes) = match self.section {
+            LiteralSection::Huffman { streams, sizes } => (streams, sizes),
+            _ => unreachable!(),
+        };
+        while self.stream_left == 0 {
+            if self.bits.is_some() {
+                self.finish_stream()?;
+                self.stream += 1;
+            }
+            self.stream_left = sizes[self.stream];
+            self.bits = Some(ReverseBitReader::new(streams[self.stream])?);
+        }
+
+        self.stream_left -= 1;
+        let table = self
+            .table
+            .ok_or(DecompressError::Invalid("Huffman table is not set"))?;
+        Ok(table.decode(self.bits.as_mut().unwrap()))
+    }
+
+    /// Check that the current stream was read exactly.
+    fn finish_stream(&self) -> Result<()> {
+        match &self.bits {
+            Some(bits) if !bits.is_finished() => Err(DecompressError::Invalid(
+                "Huffman stream does not match its size",
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// State that blocks of a frame share.
+struct Decoder {
+    huffman: Option<HuffmanTable>,
+    literal_lengths: FseTable<{ 1 << MAX_LITERAL_LENGTH_LOG }>,
+    offsets: FseTable<{ 1 << MAX_OFFSET_LOG }>,
+    match_lengths: FseTable<{ 1 << MAX_MATCH_LENGTH_LOG }>,
+    repeat_offsets: [usize; 3],
+}
+
+impl Decoder {
+    fn new() -> Self {
+        Self {
+            huffman: None,
+            literal_lengths: FseTable::new(),
+            offsets: FseTable::new(),
+            match_lengths: FseTable::new(),
+            repeat_offsets: [1, 4, 8],
+        }
+    }
+
+    fn decode_block<S: Sink>(&mut self, block: &[u8], sink: &mut S) -> Result<()> {
+        let (section, literal_count, read) = self.read_literals(block)?;
+        let mut reader = ByteReader::new(&block[read..]);
+
+        let sequence_count = match reader.u8()? as usize {
+            byte if byte < 128 => byte,
+            byte if byte < 255 => ((byte - 128) << 8) + reader.u8()? as usize,
+            _ => reader.u16()? as usize + 0x7F00,
+        };
+        if sequence_count > 0 {
+            self.read_tables(&mut reader)?;
+        }
+
+        let mut literals = Literals::new(section, literal_count, self.huffman.as_ref());
+        if sequence_count > 0 {
+            let mut bits = ReverseBitReader::new(reader.remaining())?;
+            let mut states = [
+                self.literal_lengths.init_state(&mut bits),
+                self.offsets.init_state(&mut bits),
+                self.match_lengths.init_state(&mut bits),
+            ];
+
+            for index in 0..sequence_count {
+                let offset_code = self.offsets.symbol(states[1]) as u32;
+                let offset_value = (1u64 << offset_code) + bits.bits(offset_code);
+
+                let match_code = self.match_lengths.symbol(states[2]) as usize;
+                let match_length = match match_code {
+                    0..=31 => match_code + 3,
+                    _ => {
+                        let (base, extra) = MATCH_LENGTHS[match_code - 32];
+                        base as usize + bits.bits(extra as u32) as usize
+                    }
+                };
+
+                let (base, extra) =
+                    LITERAL_LENGTHS[self.literal_lengths.symbol(states[0]) as usize];
+                let literal_length = base as usize + bits.bits(extra as u32) as usize;
+
+                if index + 1 < sequence_count {
+                    states[0] = self.literal_lengths.next_state(states[0], &mut bits);
+                    states[2] = self.match_lengths.next_state(states[2], &mut bits);
+                    states[1] = self.offsets.next_state(states[1], &mut bits);
+                }
+
+                let offset =
+                    resolve_offset(&mut self.repeat_offsets, offset_value, literal_length)?;
+                literals.copy(literal_length, sink)?;
+                sink.repeat(offset, match_length)?;
+            }
+            if !bits.is_finished() {
+                return Err(DecompressError::Invalid(
+                    "Sequence stream does not match its count",
+                ));
+            }
+        }
+
+        let left = literals.left;
+        literals.copy(left, sink)?;
+        literals.finish_stream()
+    }
+
+    /// Read the literals section header and, for compressed literals, the
+    /// Huffman table. Returns the literals, their count and the bytes read.
+    fn read_literals<'a>(&mut self, block: &'a [u8]) -> Result<(LiteralSection<'a>, usize, usize)> {
+        let mut reader = ByteReader::new(block);
+        let first = reader.u8()?;
+        let kind = first & 0x3;
+        let size_format = (first >> 2) & 0x3;
+
+        if kind == LITERALS_RAW || kind == LITERALS_RLE {
+            let count = match size_format {
+                0 | 2 => (first >> 3) as usize,
+                1 => (first >> 4) as usize + ((reader.u8()? as usize) << 4),
+                _ => (first >> 4) as usize + ((reader.uint(2)? as usize) << 4),
+            };
+            let header = block.len() - reader.remaining().len();
+            return if kind == LITERALS_RAW {
+                Ok((
+                    LiteralSection::Raw(reader.bytes(count)?),
+                    count,
+                    header + count,
+                ))
+            } else {
+                Ok((LiteralSection::Rle(reader.u8()?), count, header + 1))
+            };
+        }
+
+        let (stream_count, header, size_bits) = match size_format {
+            0 => (1, 3, 10),
+            1 => (4, 3, 10),
+            2 => (4, 4, 14),
+            _ => (4, 5, 18),
+        };
+        let fields = ByteReader::new(block).uint(header)?;
+        let mask = (1 << size_bits) - 1;
+        let count = ((fields >> 4) & mask) as usize;
+        let size = ((fields >> (4 + size_bits)) & mask) as usize;
+        let mut data = block
+            .get(header..header + size)
+            .ok_or(DecompressError::Truncated)?;
+
+        if kind == LITERALS_COMPRESSED {
+            let (table, read) = HuffmanTable::read(data)?;
+            self.huffman = Some(table);
+            data = &data[read..];
+        } else if self.huffman.is_none() {
+            return Err(DecompressError::Invalid(
+                "Repeated Huffman table is not set",
+            ));
+        }
+
+        let section = if stream_count == 1 {
+            LiteralSection::Huffman {
+                streams: [data, &[], &[], &[]],
+                sizes: [count, 0, 0, 0],
+            }
+        } else {
+            // The jump table holds the sizes of the first three streams.
+            let mut reader = ByteReader::new(data);
+            let sizes = [reader.u16()?, reader.u16()?, reader.u16()?];
+            let mut streams = [&[][..]; 4];
+            for (stream, &size) in streams.iter_mut().zip(sizes.iter()) {
+                *stream = reader.bytes(size as usize)?;
+            }
+            streams[3] = reader.remaining();
+
+            let segment = align::align_up(count, 4) / 4;
+            let last = count
+                .checked_sub(3 * segment)
+                .ok_or(DecompressError::Invalid(
+                    "Too few literals for four streams",
+                ))?;
+            LiteralSection::Huffman {
+                streams,
+                sizes: [segment, segment, segment, last],
+            }
+        };
+        Ok((section, count, header + size))
+    }
+
+    /// Read the compression modes and the FSE tables of the sequences.
+    fn read_tables(&mut self, reader: &mut ByteReader<'_>) -> Result<()> {
+        let modes = reader.u8()?;
+        if modes & 0x3 != 0 {
+            return Err(DecompressError::Invalid(
+                "Reserved bits of the modes are set",
+            ));
+        }
+
+        let read = self.literal_lengths.update(
+            modes >> 6,
+            reader.remaining(),
+            (&LITERAL_LENGTH_DEFAULT, 6),
+            MAX_LITERAL_LENGTH_LOG,
+            MAX_LITERAL_LENGTH_CODE,
+        )?;
+        reader.bytes(read)?;
+        let read = self.offsets.update(
+            (modes >> 4) & 0x3,
+            reader.remaining(),
+            (&OFFSET_DEFAULT, 5),
+            MAX_OFFSET_LOG,
+            MAX_OFFSET_CODE,
+        )?;
+        reader.bytes(read)?;
+        let read = self.match_lengths.update(
+            (modes >> 2) & 0x3,
+            reader.remaining(),
+            (&MATCH_LENGTH_DEFAULT, 6),
+            MAX_MATCH_LENGTH_LOG,
+            MAX_MATCH_LENGTH_CODE,
+        )?;
+        reader.bytes(read)?;
+        Ok(())
+    }
+}
+
+/// Turn the offset value of a sequence into a distance. Values up to 3 refer to
+/// the last three offsets, which are kept up to date.
+fn resolve_offset(
+    repeat_offsets: &mut [usize; 3],
+    offset_value: u64,
+    literal_length: usize,
+) -> Result<usize> {
+    let [first, second, third] = *repeat_offsets;
+    if offset_value > 3 {
+        let offset = (offset_value - 3) as usize;
+        *repeat_offsets = [offset, first, second];
+        return Ok(offset);
+    }
+
+    // Without literals, the first repeat offset is skipped.
+    let index = if literal_length == 0 {
+        offset_value
+    } else {
+        offset_value - 1
+    };
+    let offset = match index {
+        0 => first,
+        1 => second,
+        2 => third,
+        _ => first - 1,
+    };
+    if offset == 0 {
+        return Err(DecompressError::InvalidDistance);
+    }
+    *repeat_offsets = match index {
+        0 => [first, second, third],
+        1 => [second, first, third],
+        _ => [offset, first, second],
+    };
+    Ok(offset)
+}
@@ -0,0 +1,275 @@
+//! Parts of ELF64 binaries that the `elfloader` crate leaves to the loader. It only
+//! passes the relocation entries on, so the symbols they refer to are read from the
+//! dynamic symbol table and the values are computed here.
+
+use std::convert::TryInto;
+
+/// `S + A`: Address of the symbol plus the addend.
+pub const R_X86_64_64: u32 = 1;
+/// `S`: Address of the symbol, stored in the global offset table.
+pub const R_X86_64_GLOB_DAT: u32 = 6;
+/// `S`: Address of the function, stored in the procedure linkage table.
+pub const R_X86_64_JUMP_SLOT: u32 = 7;
+/// `B + A`: Base address of the binary plus the addend.
+pub const R_X86_64_RELATIVE: u32 = 8;
+/// ID of the module that holds the TLS symbol.
+pub const R_X86_64_DTPMOD64: u32 = 16;
+/// Offset of the TLS symbol in the TLS block of its module.
+pub const R_X86_64_DTPOFF64: u32 = 17;
+/// Offset of the TLS symbol from the thread pointer.
+pub const R_X86_64_TPOFF64: u32 = 18;
+
+/// Size of an entry of the dynamic symbol table.
+const SYMBOL_SIZE: usize = 24;
+
+/// A symbol of the dynamic symbol table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DynamicSymbol {
+    pub value: u64,
+    pub defined: bool,
+    pub weak: bool,
+    /// The value of TLS symbols is an offset into the TLS segment.
+    pub tls: bool,
+}
+
+/// Reasons why a relocation cannot be applied.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RelocationError {
+    /// The relocation type with the given number is not supported.
+    Unsupported(u32),
+    /// The symbol with the given index is not in the dynamic symbol table.
+    InvalidSymbol(u32),
+    /// The symbol with the given index is undefined and not weak.
+    UndefinedSymbol(u32),
+    /// A TLS relocation is used without a TLS segment.
+    MissingTls,
+}
+
+/// Read `N` bytes at `offset` of `data`.
+fn read_field<const N: usize>(data: &[u8], offset: usize) -> Option<[u8; N]> {
+    offset
+        .checked_add(N)
+        .and_then(|end| data.get(offset..end))
+        .map(|bytes| bytes.try_into().unwrap())
+}
+
+/**
+Find the dynamic symbol table of the ELF64 binary in `data` through its section
+headers. The table is empty if the binary has none.
+*/
+pub fn dynamic_symbol_table(data: &[u8]) -> Result<&[u8], &'static str> {
+    const SHT_DYNSYM: u32 = 11;
+    const OUT_OF_BOUNDS: &str = "Section header is out of bounds";
+
+    let header_offset = u64::from_le_bytes(read_field(data, 0x28).ok_or(OUT_OF_BOUNDS)?);
+    let header_size = u16::from_le_bytes(read_field(data, 0x3A).ok_or(OUT_OF_BOUNDS)?);
+    let header_count = u16::from_le_bytes(read_field(data, 0x3C).ok_or(OUT_OF_BOUNDS)?);
+
+    for index in 0..header_count as usize {
+        let header = (header_offset as usize)
+            .checked_add(index * header_size as usize)
+            .and_then(|start| data.get(start..))
+            .ok_or(OUT_OF_BOUNDS)?;
+        if u32::from_le_bytes(read_field(header, 0x4).ok_or(OUT_OF_BOUNDS)?) != SHT_DYNSYM {
+            continue;
+        }
+
+        let offset = u64::from_le_bytes(read_field(header, 0x18).ok_or(OUT_OF_BOUNDS)?) as usize;
+        let size = u64::from_le_bytes(read_field(header, 0x20).ok_or(OUT_OF_BOUNDS)?) as usize;
+        return offset
+            .checked_add(size)
+            .and_then(|end| data.get(offset..end))
+            .ok_or("Dynamic symbol table is out of bounds");
+    }
+
+    Ok(&[])
+}
+
+/// The symbol with the given index in the dynamic symbol table `table`.
+pub fn dynamic_symbol(table: &[u8], index: u32) -> Option<DynamicSymbol> {
+    const STB_WEAK: u8 = 2;
+    const STT_TLS: u8 = 6;
+
+    let symbol = table.chunks_exact(SYMBOL_SIZE).nth(index as usize)?;
+    let info = symbol[4];
+    Some(DynamicSymbol {
+        value: u64::from_le_bytes(read_field(symbol, 8)?),
+        defined: u16::from_le_bytes(read_field(symbol, 6)?) != 0,
+        weak: info >> 4 == STB_WEAK,
+        tls: info & 0xF == STT_TLS,
+    })
+}
+
+/**
+Value that a relocation writes, following the x86_64 System V ABI. `symbols` is
+the dynamic symbol table and `base` the offset that the binary is loaded at.
+
+The binary must be the only module with thread local storage. `tls_block_size`
+is the size of its TLS block, which ends at the thread pointer, aligned to the
+TLS segment.
+*/
+pub fn relocation_value(
+    kind: u32,
+    symbol_index: u32,
+    addend: u64,
+    symbols: &[u8],
+    base: u64,
+    tls_block_size: Option<u64>,
+) -> Result<u64, RelocationError> {
+    let symbol = || -> Result<u64, RelocationError> {
+        // The symbol 0 is the undefined symbol, which relocations use without a symbol.
+        if symbol_index == 0 {
+            return Ok(0);
+        }
+
+        let symbol = dynamic_symbol(symbols, symbol_index)
+            .ok_or(RelocationError::InvalidSymbol(symbol_index))?;
+        match (symbol.defined, symbol.weak) {
+            // Undefined weak symbols are null.
+            (false, true) => Ok(0),
+            (false, false) => Err(RelocationError::UndefinedSymbol(symbol_index)),
+            _ if symbol.tls => Ok(symbol.value),
+            _ => Ok(base.wrapping_add(symbol.value)),
+        }
+    };
+
+    match kind {
+        R_X86_64_RELATIVE => Ok(base.wrapping_add(addend)),
+        R_X86_64_64 => Ok(symbol()?.wrapping_add(addend)),
+        R_X86_64_GLOB_DAT | R_X86_64_JUMP_SLOT => symbol(),
+        R_X86_64_DTPMOD64 => Ok(1),
+        R_X86_64_DTPOFF64 => Ok(symbol()?.wrapping_add(addend)),
+        R_X86_64_TPOFF64 => {
+            let block = tls_block_size.ok_or(RelocationError::MissingTls)?;
+            Ok(symbol()?.wrapping_add(addend).wrapping_sub(block))
+        }
+        _ => Err(RelocationError::Unsupported(kind)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn symbol(info: u8, section: u16, value: u64) -> [u8; SYMBOL_SIZE] {
+        let mut symbol = [0; SYMBOL_SIZE];
+        symbol[4] = info;
+        symbol[6..8].copy_from_slice(&section.to_le_bytes());
+        symbol[8..16].copy_from_slice(&value.to_le_bytes());
+        symbol
+    }
+
+    #[test]
+    fn test_relocation_value() {
+        let mut symbols = [0; SYMBOL_SIZE * 4];
+        // A defined global, an undefined weak and a defined TLS symbol.
+        symbols[SYMBOL_SIZE..SYMBOL_SIZE * 2].copy_from_slice(&symbol(0x12, 1, 0x1000));
+        symbols[SYMBOL_SIZE * 2..SYMBOL_SIZE * 3].copy_from_slice(&symbol(0x22, 0, 0));
+        symbols[SYMBOL_SIZE * 3..].copy_from_slice(&symbol(0x16, 2, 0x10));
+        let base = 0x40_0000;
+
+        let value =
+            |kind, index, addend, tls| relocation_value(kind, index, addend, &symbols, base, tls);
+        assert_eq!(value(R_X86_64_RELATIVE, 0, 0x20, None), Ok(0x40_0020));
+        assert_eq!(value(R_X86_64_64, 1, 0x8, None), Ok(0x40_1008));
+        assert_eq!(value(R_X86_64_GLOB_DAT, 1, 0x8, None), Ok(0x40_1000));
+        assert_eq!(value(R_X86_64_JUMP_SLOT, 2, 0, None), Ok(0));
+        assert_eq!(value(R_X86_64_DTPOFF64, 3, 0x4, None), Ok(0x14));
+        assert_eq!(
+            value(R_X86_64_TPOFF64, 3, 0, Some(0x40)),
+            Ok(-0x30i64 as u64)
+        );
+        assert_eq!(
+            value(R_X86_64_TPOFF64, 3, 0, None),
+            Err(RelocationError::MissingTls)
+        );
+        assert_eq!(
+            value(R_X86_64_64, 4, 0, None),
+            Err(RelocationError::InvalidSymbol(4))
+        );
+        // R_X86_64_PC32
+        assert_eq!(value(2, 1, 0, None), Err(RelocationError::Unsupported(2)));
+    }
+
+    #[test]
+    fn test_malformed_symbols() {
+        let mut symbols = [0; SYMBOL_SIZE * 2 + 8];
+        // An undefined global symbol and a symbol cut off at the end of the table.
+        symbols[SYMBOL_SIZE..SYMBOL_SIZE * 2].copy_from_slice(&symbol(0x12, 0, 0));
+        let value = |kind, index| relocation_value(kind, index, 0, &symbols, 0x40_0000, None);
+        assert_eq!(
+            value(R_X86_64_GLOB_DAT, 1),
+            Err(RelocationError::UndefinedSymbol(1))
+        );
+        assert_eq!(
+            value(R_X86_64_64, 2),
+            Err(RelocationError::InvalidSymbol(2))
+        );
+        assert_eq!(
+            value(R_X86_64_JUMP_SLOT, u32::MAX),
+            Err(RelocationError::InvalidSymbol(u32::MAX))
+        );
+        // Relocations without a symbol never read the table.
+        assert_eq!(value(R_X86_64_RELATIVE, u32::MAX), Ok(0x40_0000));
+        assert_eq!(
+            relocation_value(R_X86_64_RELATIVE, 0, 1, &[], u64::MAX, None),
+            Ok(0)
+        );
+    }
+
+    /// ELF header with `count` section headers of `size` bytes at `offset`.
+    fn header(offset: u64, size: u16, count: u16) -> Vec<u8> {
+        let mut data = vec![0; 0x40];
+        data[0x28..0x30].copy_from_slice(&offset.to_le_bytes());
+        data[0x3A..0x3C].copy_from_slice(&size.to_le_bytes());
+        data[0x3C..0x3E].copy_from_slice(&count.to_le_bytes());
+        data
+    }
+
+    /// Section header of the given type for `size` bytes at `offset`.
+    fn section(kind: u32, offset: u64, size: u64) -> [u8; 0x40] {
+        let mut section = [0; 0x40];
+        section[0x4..0x8].copy_from_slice(&kind.to_le_bytes());
+        section[0x18..0x20].copy_from_slice(&offset.to_le_bytes());
+        section[0x20..0x28].copy_from_slice(&size.to_le_bytes());
+        section
+    }
+
+    #[test]
+    fn test_dynamic_symbol_table() {
+        const SHT_DYNSYM: u32 = 11;
+
+        assert_eq!(
+            dynamic_symbol_table(&[0; 0x20]),
+            Err("Section header is out of bounds")
+        );
+        assert_eq!(dynamic_symbol_table(&header(0x40, 0x40, 0)), Ok(&[][..]));
+
+        // A table of the symbol after the section headers.
+        let mut data = header(0x40, 0x40, 2);
+        data.extend_from_slice(&section(1, 0, 0));
+        data.extend_from_slice(&section(SHT_DYNSYM, 0xC0, SYMBOL_SIZE as u64));
+        data.extend_from_slice(&symbol(0x12, 1, 0x1000));
+        assert_eq!(dynamic_symbol_table(&data), Ok(&data[0xC0..]));
+
+        // Section headers and tables past the end of the binary.
+        let mut truncated = data.clone();
+        truncated.truncate(0x9C);
+        assert_eq!(
+            dynamic_symbol_table(&truncated),
+            Err("Section header is out of bounds")
+        );
+        let mut wrapping = data.clone();
+        wrapping[0x28..0x30].copy_from_slice(&u64::MAX.to_le_bytes());
+        assert_eq!(
+            dynamic_symbol_table(&wrapping),
+            Err("Section header is out of bounds")
+        );
+        let mut too_long = data.clone();
+        too_long[0x80 + 0x20..0x80 + 0x28].copy_from_slice(&u64::MAX.to_le_bytes());
+        assert_eq!(
+            dynamic_symbol_table(&too_long),
+            Err("Dynamic symbol table is out of bounds")
+        );
+    }
+}
@@ -5,5 +5,14 @@ extern crate core as std;
 /// Align support functions.
 pub mod align;
 
+/// ELF symbols and relocations.
+pub mod elf;
+
 /// Utility macros
 pub mod macros;
+
+/// gzip, zstd and lz4 decompression for the initrd.
+pub mod decompress;
+
+/// USTAR archives, like the initrd.
+pub mod ustar;
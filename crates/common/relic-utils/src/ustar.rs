@@ -0,0 +1,887 @@
+//! USTAR / TAR file format support, including pax extended headers and GNU long
+//! names. Adapted from <https://wiki.osdev.org/USTAR> and the POSIX `pax` format.
+//!
+//! The reader works on a byte slice without allocating. Paths are compared by
+//! their components, so `./a//b` and `a/b` name the same entry.
+
+use std::{fmt, fmt::Display, str};
+
+use crate::align;
+
+/// Size of a header and the unit that data is padded to.
+const BLOCK_SIZE: usize = 512;
+
+/// Paths with more components cannot be looked up.
+pub const MAX_PATH_COMPONENTS: usize = 64;
+
+/// Resolving a path follows at most this many links, which stops link loops.
+pub const MAX_LINK_HOPS: usize = 16;
+
+/// Representation of the header of an entry in an archive
+#[repr(C)]
+#[allow(missing_docs)]
+#[allow(dead_code)]
+struct UstarHeader {
+    /// File name without a slash.
+    pub name: [u8; 100],
+    /// File mode
+    pub mode: [u8; 8],
+    /// User ID
+    pub uid: [u8; 8],
+    /// Group ID
+    pub gid: [u8; 8],
+    /// Size in bytes (octal)
+    pub size: [u8; 12],
+    /// Latest modification time
+    pub mtime: [u8; 12],
+    /// File and header checksum
+    pub cksum: [u8; 8],
+    /// File type (Link indicator)
+    pub typeflag: u8,
+    /// Linked path name or file name
+    pub linkname: [u8; 100],
+
+    // UStar format
+    /// Forat representation for tar
+    pub magic: [u8; 6],
+    /// Version representation for tar
+    pub version: [u8; 2],
+    /// User name
+    pub uname: [u8; 32],
+    /// Group name
+    pub gname: [u8; 32],
+    /// Major device representation
+    pub dev_major: [u8; 8],
+    /// Minor device representation
+    pub dev_minor: [u8; 8],
+    /// Path name without trailing slashes
+    pub prefix: [u8; 155],
+
+    /// Padding to make the header 512 bytes.
+    pub pad: [u8; 12],
+}
+
+/// Offset of the checksum field in a header.
+const CHECKSUM_RANGE: std::ops::Range<usize> = 148..156;
+
+/// Magic of POSIX archives, which have a prefix field.
+const POSIX_MAGIC: &[u8; 6] = b"ustar\0";
+
+/// Type flags of headers that describe the entry after them.
+const PAX_LOCAL: u8 = b'x';
+const PAX_GLOBAL: u8 = b'g';
+const GNU_LONG_NAME: u8 = b'L';
+const GNU_LONG_LINK_NAME: u8 = b'K';
+
+// See https://en.wikipedia.org/wiki/Tar_%28computing%29#UStar_format
+/// Indicate for the type of file described by a header.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum EntryType {
+    /// Regular file
+    Regular,
+    /// Hard link
+    Link,
+    /// Symbolic link
+    Symlink,
+    /// Character device
+    Char,
+    /// Block device
+    Block,
+    /// Directory
+    Directory,
+    /// Named pipe (fifo)
+    Fifo,
+    /// Contiguous file
+    Contiguous,
+    /// Any other type flag.
+    Unknown(u8),
+}
+
+impl From<u8> for EntryType {
+    fn from(typeflag: u8) -> Self {
+        match typeflag {
+            // Old archives mark regular files with a null byte.
+            b'0' | b'\0' => EntryType::Regular,
+            b'1' => EntryType::Link,
+            b'2' => EntryType::Symlink,
+            b'3' => EntryType::Char,
+            b'4' => EntryType::Block,
+            b'5' => EntryType::Directory,
+            b'6' => EntryType::Fifo,
+            b'7' => EntryType::Contiguous,
+            other => EntryType::Unknown(other),
+        }
+    }
+}
+
+/// Reasons why an archive cannot be read.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum UStarError {
+    /// The header at the given offset has a wrong checksum.
+    InvalidChecksum(usize),
+    /// The header at the given offset, or its extended header, is malformed.
+    InvalidHeader(usize),
+    /// The archive ends inside the entry at the given offset.
+    Truncated(usize),
+    /// The path has more than [`MAX_PATH_COMPONENTS`] components.
+    PathTooLong,
+    /// Resolving the path followed more than [`MAX_LINK_HOPS`] links.
+    TooManyLinks,
+    /// No entry has the path.
+    NotFound,
+}
+
+/// Path of an entry. Long paths are split into a prefix and a name.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct EntryPath<'a> {
+    prefix: &'a str,
+    name: &'a str,
+}
+
+impl<'a> EntryPath<'a> {
+    /// The non-empty components of the path. `.` components are skipped.
+    pub fn components(&self) -> impl Iterator<Item = &'a str> {
+        self.prefix
+            .split('/')
+            .chain(self.name.split('/'))
+            .filter(|component| !component.is_empty() && *component != ".")
+    }
+
+    /// The last component of the path.
+    pub fn file_name(&self) -> Option<&'a str> {
+        self.components().last()
+    }
+
+    fn normalized(&self) -> Result<Components<'a>, UStarError> {
+        let mut components = Components::new();
+        components.join(self.prefix)?;
+        components.join(self.name)?;
+        Ok(components)
+    }
+}
+
+impl Display for EntryPath<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.prefix.is_empty() {
+            write!(f, "{}", self.name)
+        } else {
+            write!(f, "{}/{}", self.prefix, self.name)
+        }
+    }
+}
+
+/// An entry of the archive.
+#[derive(Clone, Copy, Debug)]
+pub struct Entry<'a> {
+    path: EntryPath<'a>,
+    entry_type: EntryType,
+    link_name: &'a str,
+    mode: u32,
+    data: &'a [u8],
+}
+
+impl<'a> Entry<'a> {
+    pub fn path(&self) -> EntryPath<'a> {
+        self.path
+    }
+
+    pub fn entry_type(&self) -> EntryType {
+        self.entry_type
+    }
+
+    /// Target of a link. Symbolic links are relative to their directory and hard
+    /// links name another entry of the archive.
+    pub fn link_name(&self) -> &'a str {
+        self.link_name
+    }
+
+    /// Permission bits of the entry.
+    pub fn mode(&self) -> u32 {
+        self.mode
+    }
+
+    /// Contents of the entry. Empty for everything but files.
+    pub fn data(&self) -> &'a [u8] {
+        self.data
+    }
+}
+
+/// A path as a list of components. Paths added with [`Components::join`] are
+/// normalized: `.` and empty components are dropped and `..` removes the
+/// component before it.
+#[derive(Clone, Copy)]
+struct Components<'p> {
+    parts: [&'p str; MAX_PATH_COMPONENTS],
+    len: usize,
+}
+
+impl<'p> Components<'p> {
+    fn new() -> Self {
+        Self {
+            parts: [""; MAX_PATH_COMPONENTS],
+            len: 0,
+        }
+    }
+
+    fn as_slice(&self) -> &[&'p str] {
+        &self.parts[..self.len]
+    }
+
+    fn push(&mut self, component: &'p str) -> Result<(), UStarError> {
+        if self.len == MAX_PATH_COMPONENTS {
+            return Err(UStarError::PathTooLong);
+        }
+        self.parts[self.len] = component;
+        self.len += 1;
+        Ok(())
+    }
+
+    fn pop(&mut self) -> Option<&'p str> {
+        self.len = self.len.checked_sub(1)?;
+        Some(self.parts[self.len])
+    }
+
+    /// Append the components of `path`. The path is relative even if it starts
+    /// with `/`.
+    fn join(&mut self, path: &'p str) -> Result<(), UStarError> {
+        for component in path.split('/') {
+            match component {
+                "" | "." => {}
+                ".." => {
+                    self.pop();
+                }
+                _ => self.push(component)?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Push the components of `path` in reverse, so that they are popped in order.
+    fn push_reversed(&mut self, path: &'p str) -> Result<(), UStarError> {
+        for component in path.rsplit('/') {
+            self.push(component)?;
+        }
+        Ok(())
+    }
+}
+
+/// Values of pax extended headers that replace the fields of the header.
+#[derive(Clone, Copy, Default)]
+struct Overrides<'a> {
+    path: Option<&'a str>,
+    link_path: Option<&'a str>,
+    size: Option<u64>,
+}
+
+impl<'a> Overrides<'a> {
+    fn is_empty(&self) -> bool {
+        self.path.is_none() && self.link_path.is_none() && self.size.is_none()
+    }
+
+    /// Apply the records of a pax extended header, which look like `"%d %s=%s\n"`
+    /// where the number is the length of the whole record.
+    fn parse_pax(&mut self, mut data: &'a [u8], offset: usize) -> Result<(), UStarError> {
+        let invalid = UStarError::InvalidHeader(offset);
+        while !data.is_empty() {
+            let space = data.iter().position(|&b| b == b' ').ok_or(invalid)?;
+            let length: usize = str::from_utf8(&data[..space])
+                .ok()
+                .and_then(|length| length.parse().ok())
+                .ok_or(invalid)?;
+            let record = data
+                .get(space + 1..length)
+                .and_then(|record| record.strip_suffix(b"\n"))
+                .ok_or(invalid)?;
+            let equals = record.iter().position(|&b| b == b'=').ok_or(invalid)?;
+            let value = str::from_utf8(&record[equals + 1..]).map_err(|_| invalid)?;
+            // An empty value removes the value of a global header.
+            let value = Some(value).filter(|value| !value.is_empty());
+
+            match &record[..equals] {
+                b"path" => self.path = value,
+                b"linkpath" => self.link_path = value,
+                b"size" => {
+                    self.size = match value {
+                        Some(size) => Some(size.parse().map_err(|_| invalid)?),
+                        None => None,
+                    }
+                }
+                _ => {}
+            }
+            data = &data[length..];
+        }
+        Ok(())
+    }
+
+    /// Values of this header, then of `global`.
+    fn or(self, global: Overrides<'a>) -> Overrides<'a> {
+        Overrides {
+            path: self.path.or(global.path),
+            link_path: self.link_path.or(global.link_path),
+            size: self.size.or(global.size),
+        }
+    }
+}
+
+/// Parse a numeric field. Fields are octal, padded with spaces or null bytes, or
+/// base-256 with the high bit of the first byte set for large values.
+fn parse_number(field: &[u8]) -> Option<u64> {
+    match field.first() {
+        Some(0x80) => {
+            return field[1..]
+                .iter()
+                .try_fold(0u64, |n, &b| n.checked_mul(256)?.checked_add(b as u64))
+        }
+        // Negative base-256 numbers.
+        Some(b) if b & 0x80 != 0 => return None,
+        _ => {}
+    }
+
+    let mut n = 0u64;
+    let mut has_digits = false;
+    for &b in field {
+        match b {
+            b'0'..=b'7' => {
+                n = n.checked_mul(8)?.checked_add((b - b'0') as u64)?;
+                has_digits = true;
+            }
+            b' ' if !has_digits => {}
+            b' ' | b'\0' => break,
+            _ => return None,
+        }
+    }
+    Some(n)
+}
+
+/// Parse a string field, which ends at the first null byte.
+fn parse_str(field: &[u8]) -> Option<&str> {
+    let end = field.iter().position(|&b| b == 0).unwrap_or(field.len());
+    str::from_utf8(&field[..end]).ok()
+}
+
+/// Check the header checksum, which is the sum of the header bytes with the
+/// checksum field taken as spaces. Some archivers sum signed bytes.
+fn verify_checksum(block: &[u8], offset: usize) -> Result<(), UStarError> {
+    let stored = parse_number(&block[CHECKSUM_RANGE]).ok_or(UStarError::InvalidHeader(offset))?;
+
+    let mut unsigned = 0u64;
+    let mut signed = 0i64;
+    for (index, &b) in block.iter().enumerate() {
+        let b = if CHECKSUM_RANGE.contains(&index) {
+            b' '
+        } else {
+            b
+        };
+        unsigned += b as u64;
+        signed += b as i8 as i64;
+    }
+
+    if stored == unsigned || stored as i64 == signed {
+        Ok(())
+    } else {
+        Err(UStarError::InvalidChecksum(offset))
+    }
+}
+
+/// Iterator over the entries of an archive. Extended headers are applied to the
+/// entries they describe. Stops after the first error.
+pub struct Entries<'a> {
+    data: &'a [u8],
+    offset: usize,
+    global: Overrides<'a>,
+    done: bool,
+}
+
+impl<'a> Entries<'a> {
+    fn next_entry(&mut self) -> Result<Option<Entry<'a>>, UStarError> {
+        let mut local = Overrides::default();
+        loop {
+            let offset = self.offset;
+            let block = match self.data.get(offset..offset + BLOCK_SIZE) {
+                Some(block) if block.iter().any(|&b| b != 0) => block,
+                // The archive ends with zero blocks. Padding after them is ignored.
+                _ if local.is_empty()
+                    && self
+                        .data
+                        .get(offset..)
+                        .unwrap_or(&[])
+                        .iter()
+                        .all(|&b| b == 0) =>
+                {
+                    return Ok(None)
+                }
+                _ => return Err(UStarError::Truncated(offset)),
+            };
+            verify_checksum(block, offset)?;
+            let header = unsafe { &*(block.as_ptr() as *const UstarHeader) };
+
+            let invalid = UStarError::InvalidHeader(offset);
+            let overrides = local.or(self.global);
+            let size = match header.typeflag {
+                PAX_LOCAL | PAX_GLOBAL | GNU_LONG_NAME | GNU_LONG_LINK_NAME => None,
+                _ => overrides.size,
+            };
+            let size = match size {
+                Some(size) => size,
+                None => parse_number(&header.size).ok_or(invalid)?,
+            };
+
+            let data_start = offset + BLOCK_SIZE;
+            let data = (size as usize)
+                .checked_add(data_start)
+                .and_then(|data_end| self.data.get(data_start..data_end))
+                .ok_or(UStarError::Truncated(offset))?;
+            self.offset = data_start + align::align_up(data.len(), BLOCK_SIZE);
+
+            match header.typeflag {
+                PAX_LOCAL => local.parse_pax(data, offset)?,
+                PAX_GLOBAL => self.global.parse_pax(data, offset)?,
+                GNU_LONG_NAME => local.path = Some(parse_str(data).ok_or(invalid)?),
+                GNU_LONG_LINK_NAME => local.link_path = Some(parse_str(data).ok_or(invalid)?),
+                typeflag => {
+                    let path = match overrides.path {
+                        Some(path) => EntryPath {
+                            prefix: "",
+                            name: path,
+                        },
+                        None => EntryPath {
+                            // Only POSIX archives have a prefix, GNU uses the field otherwise.
+                            prefix: if &header.magic == POSIX_MAGIC {
+                                parse_str(&header.prefix).ok_or(invalid)?
+                            } else {
+                                ""
+                            },
+                            name: parse_str(&header.name).ok_or(invalid)?,
+                        },
+                    };
+                    let link_name = match overrides.link_path {
+                        Some(link_path) => link_path,
+                        None => parse_str(&header.linkname).ok_or(invalid)?,
+                    };
+
+                    return Ok(Some(Entry {
+                        path,
+                        entry_type: typeflag.into(),
+                        link_name,
+                        mode: parse_number(&header.mode).ok_or(invalid)? as u32,
+                        data,
+                    }));
+                }
+            }
+        }
+    }
+}
+
+impl<'a> Iterator for Entries<'a> {
+    type Item = Result<Entry<'a>, UStarError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+
+        let result = self.next_entry();
+        if !matches!(result, Ok(Some(_))) {
+            self.done = true;
+        }
+        result.transpose()
+    }
+}
+
+/// Iterator over the entries in a directory, see [`UStarArchive::read_dir`] and
+/// [`UStarArchive::walk`].
+pub struct Walk<'a, 'p> {
+    entries: Entries<'a>,
+    directory: Components<'p>,
+    recursive: bool,
+}
+
+impl<'a, 'p> Iterator for Walk<'a, 'p> {
+    type Item = Result<Entry<'a>, UStarError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        for entry in &mut self.entries {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(error) => return Some(Err(error)),
+            };
+            let path = match entry.path.normalized() {
+                Ok(path) => path,
+                Err(error) => return Some(Err(error)),
+            };
+
+            let path = path.as_slice();
+            let directory = self.directory.as_slice();
+            let depth = path.len().saturating_sub(directory.len());
+            if path.starts_with(directory) && (depth == 1 || (self.recursive && depth > 1)) {
+                return Some(Ok(entry));
+            }
+        }
+        None
+    }
+}
+
+pub struct UStarArchive<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> UStarArchive<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        UStarArchive { data }
+    }
+
+    /// All entries of the archive in order.
+    pub fn entries(&self) -> Entries<'a> {
+        Entries {
+            data: self.data,
+            offset: 0,
+            global: Overrides::default(),
+            done: false,
+        }
+    }
+
+    /// The entry at `path`. Links are followed, also for the last component.
+    pub fn entry(&self, path: &str) -> Result<Entry<'a>, UStarError> {
+        let path = self.resolve(path, true)?;
+        self.find(path.as_slice())?.ok_or(UStarError::NotFound)
+    }
+
+    /// The entry at `path` like [`Self::entry`], but a link in the last component
+    /// is returned itself.
+    pub fn symlink_entry(&self, path: &str) -> Result<Entry<'a>, UStarError> {
+        let path = self.resolve(path, false)?;
+        self.find(path.as_slice())?.ok_or(UStarError::NotFound)
+    }
+
+    /// Contents of the file at `path`, following links. `None` if there is no
+    /// such file or the archive cannot be read.
+    pub fn lookup(&self, path: &str) -> Option<&'a [u8]> {
+        match self.entry(path) {
+            Ok(entry) if matches!(entry.entry_type, EntryType::Regular | EntryType::Contiguous) => {
+                Some(entry.data)
+            }
+            _ => None,
+        }
+    }
+
+    /// The entries directly in the directory at `path`, following links. The root
+    /// of the archive is `""` or `"."`. Directories are only listed if the archive
+    /// has an entry for them.
+    pub fn read_dir<'p>(&self, path: &'p str) -> Result<Walk<'a, 'p>, UStarError>
+    where
+        'a: 'p,
+    {
+        self.walk_directory(path, false)
+    }
+
+    /// All entries below the directory at `path`, like [`Self::read_dir`].
+    pub fn walk<'p>(&self, path: &'p str) -> Result<Walk<'a, 'p>, UStarError>
+    where
+        'a: 'p,
+    {
+        self.walk_directory(path, true)
+    }
+
+    fn walk_directory<'p>(&self, path: &'p str, recursive: bool) -> Result<Walk<'a, 'p>, UStarError>
+    where
+        'a: 'p,
+    {
+        Ok(Walk {
+            entries: self.entries(),
+            directory: self.resolve(path, true)?,
+            recursive,
+        })
+    }
+
+    /// The last entry with the path. Later entries replace earlier ones.
+    fn find(&self, path: &[&str]) -> Result<Option<Entry<'a>>, UStarError> {
+        let mut found = None;
+        for entry in self.entries() {
+            let entry = entry?;
+            if entry.path.normalized()?.as_slice() == path {
+                found = Some(entry);
+            }
+        }
+        Ok(found)
+    }
+
+    /// Resolve the links in `path` component by component. Symbolic links are
+    /// relative to their directory, or to the root if they start with `/`.
+    fn resolve<'p>(&self, path: &'p str, follow_last: bool) -> Result<Components<'p>, UStarError>
+    where
+        'a: 'p,
+    {
+        let mut resolved = Components::new();
+        let mut pending = Components::new();
+        pending.push_reversed(path)?;
+
+        let mut hops = 0;
+        while let Some(component) = pending.pop() {
+            match component {
+                "" | "." => continue,
+                ".." => {
+                    resolved.pop();
+                    continue;
+                }
+                _ => resolved.push(component)?,
+            }
+
+            let is_last = pending
+                .as_slice()
+                .iter()
+                .all(|component| component.is_empty() || *component == ".");
+            if is_last && !follow_last {
+                break;
+            }
+
+            let entry = match self.find(resolved.as_slice())? {
+                Some(entry) => entry,
+                None => continue,
+            };
+            match entry.entry_type {
+                EntryType::Symlink => {
+                    resolved.pop();
+                    if entry.link_name.starts_with('/') {
+                        resolved = Components::new();
+                    }
+                }
+                // Hard links name the other entry from the root of the archive.
+                EntryType::Link => resolved = Components::new(),
+                _ => continue,
+            }
+
+            hops += 1;
+            if hops > MAX_LINK_HOPS {
+                return Err(UStarError::TooManyLinks);
+            }
+            pending.push_reversed(entry.link_name)?;
+        }
+
+        Ok(resolved)
+    }
+}
+
+impl Display for UStarArchive<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut s = f.debug_map();
+        for entry in self.entries() {
+            match entry {
+                Ok(entry) => s.entry(
+                    &format_args!("{}", entry.path),
+                    &format_args!("{:?} - {} KiB", entry.entry_type, entry.data.len() / 1024),
+                ),
+                Err(error) => s.entry(&"error", &error),
+            };
+        }
+
+        s.finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(name: &str, typeflag: u8, size: usize, link_name: &str) -> [u8; BLOCK_SIZE] {
+        let mut block = [0; BLOCK_SIZE];
+        block[..name.len()].copy_from_slice(name.as_bytes());
+        block[100..107].copy_from_slice(b"0000644");
+        block[124..135].copy_from_slice(format!("{:011o}", size).as_bytes());
+        block[156] = typeflag;
+        block[157..157 + link_name.len()].copy_from_slice(link_name.as_bytes());
+        block[257..263].copy_from_slice(POSIX_MAGIC);
+        block[263..265].copy_from_slice(b"00");
+        set_checksum(&mut block);
+        block
+    }
+
+    fn set_checksum(block: &mut [u8; BLOCK_SIZE]) {
+        block[CHECKSUM_RANGE].copy_from_slice(b"        ");
+        let sum: u32 = block.iter().map(|&b| b as u32).sum();
+        block[148..155].copy_from_slice(format!("{:06o}\0", sum).as_bytes());
+    }
+
+    /// Append an entry with its data, padded to blocks.
+    fn push(archive: &mut Vec<u8>, header: [u8; BLOCK_SIZE], data: &[u8]) {
+        archive.extend_from_slice(&header);
+        archive.extend_from_slice(data);
+        archive.resize(align::align_up(archive.len(), BLOCK_SIZE), 0);
+    }
+
+    fn file(archive: &mut Vec<u8>, name: &str, data: &[u8]) {
+        push(archive, header(name, b'0', data.len(), ""), data);
+    }
+
+    fn pax(archive: &mut Vec<u8>, typeflag: u8, records: &[(&str, &str)]) {
+        let mut data = Vec::new();
+        for (key, value) in records {
+            // The length counts its own digits.
+            let base = key.len() + value.len() + 3;
+            let mut length = base + 1;
+            while length != base + format!("{}", length).len() {
+                length = base + format!("{}", length).len();
+            }
+            data.extend_from_slice(format!("{} {}={}\n", length, key, value).as_bytes());
+        }
+        push(archive, header("pax", typeflag, data.len(), ""), &data);
+    }
+
+    fn finish(mut archive: Vec<u8>) -> Vec<u8> {
+        archive.resize(archive.len() + 2 * BLOCK_SIZE, 0);
+        archive
+    }
+
+    #[test]
+    fn test_entries_and_prefix() {
+        let mut archive = Vec::new();
+        push(&mut archive, header("./userspace/", b'5', 0, ""), &[]);
+        file(&mut archive, "./userspace/relic-sigma", b"sigma");
+        let long_name = "b".repeat(90);
+        let mut long = header(&long_name, b'0', 4, "");
+        long[345..345 + 16].copy_from_slice(b"userspace/nested");
+        set_checksum(&mut long);
+        push(&mut archive, long, b"long");
+        let archive = finish(archive);
+        let archive = UStarArchive::new(&archive);
+
+        let entries: Vec<_> = archive.entries().map(Result::unwrap).collect();
+        assert_eq!(entries.len(), 3);
+        assert_eq!(entries[0].entry_type(), EntryType::Directory);
+        assert_eq!(entries[1].mode(), 0o644);
+        assert_eq!(
+            format!("{}", entries[2].path()),
+            format!("userspace/nested/{}", long_name)
+        );
+
+        assert_eq!(
+            archive.lookup("./userspace/relic-sigma"),
+            Some(&b"sigma"[..])
+        );
+        assert_eq!(
+            archive.lookup("/userspace//relic-sigma"),
+            Some(&b"sigma"[..])
+        );
+        assert_eq!(
+            archive.lookup(&format!("userspace/nested/{}", long_name)),
+            Some(&b"long"[..])
+        );
+        assert_eq!(archive.lookup(&long_name), None);
+        assert_eq!(
+            archive.entry("userspace/missing").unwrap_err(),
+            UStarError::NotFound
+        );
+    }
+
+    #[test]
+    fn test_checksum_and_truncation() {
+        let mut archive = Vec::new();
+        file(&mut archive, "a", b"data");
+        let mut archive = finish(archive);
+
+        archive[0] = b'b';
+        assert_eq!(
+            UStarArchive::new(&archive)
+                .entries()
+                .next()
+                .unwrap()
+                .unwrap_err(),
+            UStarError::InvalidChecksum(0)
+        );
+
+        let mut archive = Vec::new();
+        push(&mut archive, header("a", b'0', 4096, ""), b"data");
+        assert_eq!(
+            UStarArchive::new(&archive)
+                .entries()
+                .next()
+                .unwrap()
+                .unwrap_err(),
+            UStarError::Truncated(0)
+        );
+    }
+
+    #[test]
+    fn test_links() {
+        let mut archive = Vec::new();
+        push(&mut archive, header("usr/bin/", b'5', 0, ""), &[]);
+        file(&mut archive, "usr/bin/init", b"init");
+        push(&mut archive, header("bin", b'2', 0, "usr/bin"), &[]);
+        push(&mut archive, header("usr/bin/sh", b'2', 0, "./init"), &[]);
+        push(
+            &mut archive,
+            header("usr/bin/up", b'2', 0, "../bin/init"),
+            &[],
+        );
+        push(
+            &mut archive,
+            header("usr/bin/abs", b'2', 0, "/bin/init"),
+            &[],
+        );
+        push(&mut archive, header("init", b'1', 0, "usr/bin/init"), &[]);
+        push(&mut archive, header("loop", b'2', 0, "loop"), &[]);
+        let archive = finish(archive);
+        let archive = UStarArchive::new(&archive);
+
+        for path in &["bin/init", "bin/sh", "usr/bin/up", "bin/abs", "init"] {
+            assert_eq!(archive.lookup(path), Some(&b"init"[..]), "{}", path);
+        }
+        let link = archive.symlink_entry("bin/sh").unwrap();
+        assert_eq!(link.entry_type(), EntryType::Symlink);
+        assert_eq!(link.link_name(), "./init");
+        assert_eq!(archive.entry("loop").unwrap_err(), UStarError::TooManyLinks);
+    }
+
+    #[test]
+    fn test_pax_and_long_names() {
+        let long_name = format!("{}/file", "d".repeat(200));
+        let mut archive = Vec::new();
+        pax(&mut archive, b'x', &[("path", &long_name), ("size", "3")]);
+        // The size in the header is replaced by the size of the pax header.
+        push(&mut archive, header("short", b'0', 0, ""), b"pax");
+        pax(&mut archive, b'g', &[("linkpath", "target")]);
+        push(&mut archive, header("link", b'2', 0, ""), &[]);
+        push(
+            &mut archive,
+            header("././@LongLink", b'L', 6, ""),
+            b"gnu/a\0",
+        );
+        file(&mut archive, "gnu-short", b"gnu");
+        let archive = finish(archive);
+        let archive = UStarArchive::new(&archive);
+
+        assert_eq!(archive.lookup(&long_name), Some(&b"pax"[..]));
+        assert_eq!(archive.symlink_entry("link").unwrap().link_name(), "target");
+        assert_eq!(archive.lookup("gnu/a"), Some(&b"gnu"[..]));
+        assert_eq!(archive.lookup("short"), None);
+    }
+
+    #[test]
+    fn test_read_dir() {
+        let mut archive = Vec::new();
+        push(&mut archive, header("etc/", b'5', 0, ""), &[]);
+        file(&mut archive, "etc/hosts", b"");
+        push(&mut archive, header("etc/init/", b'5', 0, ""), &[]);
+        file(&mut archive, "etc/init/sigma", b"");
+        push(&mut archive, header("config", b'2', 0, "etc"), &[]);
+        file(&mut archive, "readme", b"");
+        let archive = finish(archive);
+        let archive = UStarArchive::new(&archive);
+
+        let names = |walk: Walk| -> Vec<String> {
+            walk.map(|entry| format!("{}", entry.unwrap().path()))
+                .collect()
+        };
+        assert_eq!(
+            names(archive.read_dir("").unwrap()),
+            ["etc/", "config", "readme"]
+        );
+        assert_eq!(
+            names(archive.read_dir("config").unwrap()),
+            ["etc/hosts", "etc/init/"]
+        );
+        assert_eq!(
+            names(archive.walk("./etc").unwrap()),
+            ["etc/hosts", "etc/init/", "etc/init/sigma"]
+        );
+    }
+}
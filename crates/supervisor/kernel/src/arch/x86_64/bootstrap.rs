@@ -1,6 +1,7 @@
 use crate::{
     addr::*,
-    arch::paging::{table::*, utils},
+    arch::paging::{la57, pat, pcid, table::*, utils},
+    bootboot::MMapEntType,
     util::memory_region::MemoryRegion,
 };
 use heapless::Vec;
@@ -8,7 +9,10 @@ use log::LevelFilter;
 use relic_utils::align;
 use x86_64::registers::{control::Cr4Flags, model_specific::EferFlags};
 
-use crate::arch::globals;
+use crate::arch::{globals, stack};
+
+/// Index of the bootstrap core. Its stacks are the first ones.
+const BSP_CORE: usize = 0;
 
 static BSP_STACK: [u8; globals::BSP_TEMP_STACK_SIZE_BYTES] =
     [0; globals::BSP_TEMP_STACK_SIZE_BYTES];
@@ -62,10 +66,24 @@ fn initialize_bootstrap_core2() -> ! {
 
     {
         let mut cr4 = x86_64::registers::control::Cr4::read();
-        cr4 |= Cr4Flags::PAGE_GLOBAL;
-        cr4 |= Cr4Flags::PCID;
+        if cfg!(feature = "kpti") {
+            // Global kernel pages would stay in the TLB while user mode runs.
+            cr4 -= Cr4Flags::PAGE_GLOBAL;
+        } else {
+            cr4 |= Cr4Flags::PAGE_GLOBAL;
+        }
+        if pcid::detect() {
+            cr4 |= Cr4Flags::PCID;
+        }
+        cr4 |= super::user_memory::detect_protection();
+        unsafe {
+            x86_64::registers::control::Cr4::write(cr4);
+        }
     }
 
+    // Write-combining is selected with the PAT bit of page table entries.
+    pat::init();
+
     let current_page_table: &mut PML4;
     {
         info!(target: "bootstrap", "Create offset mapping");
@@ -128,6 +146,9 @@ fn initialize_bootstrap_core2() -> ! {
     {
         let mem_map_entries = unsafe { crate::bootboot::bootboot.get_mmap_entries() };
         for entry in mem_map_entries {
+            if entry.get_type() == MMapEntType::Mmio {
+                super::device_memory::add_region(entry.ptr() as u64, entry.size() as u64);
+            }
             if !entry.is_free() {
                 continue;
             }
@@ -163,12 +184,9 @@ fn initialize_bootstrap_core2() -> ! {
         assert!(!is_present);
 
         // Allocate new kernel stacks.
-        let allocate_stack = |free_regions: &mut Vec<MemoryRegion, 32>| {
+        let allocate = |free_regions: &mut Vec<MemoryRegion, 32>, size: usize, align: usize| {
             for region in free_regions {
-                let allocated = region.try_allocate(
-                    globals::KERNEL_STACK_NUM_PAGES * 1024 * 1024 * 2, // 2MiB * num pagse
-                    2 * 1024 * 1024,                                   // 2MiB
-                );
+                let allocated = region.try_allocate(size, align);
 
                 if let Some(addr) = allocated {
                     return addr;
@@ -177,14 +195,44 @@ fn initialize_bootstrap_core2() -> ! {
 
             panic!("Not enough memory");
         };
-        // TODO: we only map upto 1GiB
-        let num_cores = unsafe { crate::bootboot::bootboot.numcores };
-        for i in 0..(num_cores as usize) {
-            // const SIZE_OF_STACK: usize = (globals::KERNEL_STACK_NUM_PAGES + 1) * 2 * 1024 * 1024;
-            // let stack_start = globals::KERNEL_STACK_START + SIZE_OF_STACK * i;
-            let allocate_addr = allocate_stack(&mut free_regions);
+        // The stacks of all cores are mapped by a single page directory.
+        let num_cores = unsafe { crate::bootboot::bootboot.numcores } as usize;
+        assert!(
+            num_cores * stack::SLOT_PAGES <= 512,
+            "Kernel stacks of {} cores do not fit into 1GiB",
+            num_cores
+        );
+        for i in 0..num_cores {
+            // The entry stacks are mapped in 4 KiB pages. Every page that is not
+            // mapped is a guard page.
+            let entry_table_paddr = allocate(
+                &mut free_regions,
+                globals::BASE_PAGE_LENGTH,
+                globals::BASE_PAGE_LENGTH,
+            );
+            let entry_table: &mut PT = unsafe { addr_mapping(entry_table_paddr).as_mut_ptr() };
+            *entry_table = [PTEntry::empty(); 512];
+            for entry_stack in &[stack::double_fault_stack(i), stack::privilege_0_stack(i)] {
+                let size = (entry_stack.end - entry_stack.start) as usize;
+                let stack_paddr = allocate(&mut free_regions, size, globals::BASE_PAGE_LENGTH);
+                for offset in (0..size).step_by(globals::BASE_PAGE_LENGTH) {
+                    let pt_flags = PTEntry::PRESENT | PTEntry::GLOBAL | PTEntry::READ_WRITE;
+                    let vaddr = VAddr::new(entry_stack.start + offset as u64);
+                    entry_table[pt_index(vaddr)] = PTEntry::new(stack_paddr + offset, pt_flags);
+                }
+            }
+            let pd_flags = PDEntry::PRESENT | PDEntry::READ_WRITE;
+            let pd_entry = PDEntry::new(entry_table_paddr, pd_flags);
+            unsafe { KERNEL_STACK_PD_ENTRIES.0[stack::SLOT_PAGES * i] = pd_entry };
+
+            // The rest of the first 2MiB guards the kernel stack.
+            let allocate_addr = allocate(
+                &mut free_regions,
+                globals::KERNEL_STACK_NUM_PAGES * 1024 * 1024 * 2, // 2MiB * num pages
+                2 * 1024 * 1024,                                   // 2MiB
+            );
             for page in 0..globals::KERNEL_STACK_NUM_PAGES {
-                let pd_index = (globals::KERNEL_STACK_NUM_PAGES + 1) * i + page;
+                let pd_index = stack::SLOT_PAGES * i + 1 + page;
                 let pd_flags =
                     PDEntry::PRESENT | PDEntry::GLOBAL | PDEntry::LARGE_PAGE | PDEntry::READ_WRITE;
                 let pd_entry = PDEntry::new(allocate_addr + (page * 2 * 1024 * 1024), pd_flags);
@@ -203,6 +251,44 @@ fn initialize_bootstrap_core2() -> ! {
         info!(target: "bootstrap", "Create kernel stacks complete");
     }
 
+    // The kernel GDT is loaded afterwards, so the segments of the trampoline are replaced.
+    if globals::FIVE_LEVEL_PAGING && la57::is_supported() {
+        if cfg!(feature = "kpti") {
+            info!(target: "bootstrap", "Five-level paging is not supported with page table isolation");
+        } else {
+            info!(target: "bootstrap", "Switch to five-level paging");
+
+            // The trampoline runs with paging disabled, so it needs memory below 4GiB.
+            let mut allocate_low_page = || {
+                for region in free_regions.iter_mut() {
+                    let start: u64 = region.start_paddr().into();
+                    if start + 2 * globals::BASE_PAGE_LENGTH as u64 > la57::TRAMPOLINE_LIMIT {
+                        continue;
+                    }
+                    let allocated =
+                        region.try_allocate(globals::BASE_PAGE_LENGTH, globals::BASE_PAGE_LENGTH);
+                    if let Some(addr) = allocated {
+                        return addr;
+                    }
+                }
+
+                panic!("Not enough memory below 4GiB");
+            };
+            let pml5 = allocate_low_page();
+            let kernel_pml4 = allocate_low_page();
+            let trampoline = allocate_low_page();
+            unsafe { la57::enable(pml5, kernel_pml4, trampoline) };
+
+            info!(target: "bootstrap", "Switch to five-level paging complete");
+        }
+    }
+
+    {
+        info!(target: "bootstrap", "Initialize kernel heap");
+        crate::heap::initialize(&mut free_regions);
+        info!(target: "bootstrap", "Initialize kernel heap complete");
+    }
+
     {
         info!(target: "bootstrap", "Initialize TLS");
         super::tls::initialize_tls(&mut free_regions);
@@ -211,7 +297,7 @@ fn initialize_bootstrap_core2() -> ! {
 
     {
         info!(target: "bootstrap", "Initialize GDT");
-        super::gdt::initialize_gdt();
+        super::gdt::initialize_gdt(BSP_CORE);
         info!(target: "bootstrap", "GDT ready");
     }
 
@@ -221,6 +307,31 @@ fn initialize_bootstrap_core2() -> ! {
         info!(target: "bootstrap", "IDT ready");
     }
 
+    #[cfg(feature = "kpti")]
+    {
+        info!(target: "bootstrap", "Map kernel entry points into user page tables");
+        super::paging::kpti::initialize(BSP_CORE);
+        info!(target: "bootstrap", "Kernel page-table isolation ready");
+    }
+
+    // Interrupt setup excludes the APICs afterwards.
+    {
+        info!(target: "bootstrap", "Find device memory");
+        super::device_memory::scan_pci();
+
+        // Memory and the frame buffer are never given out as device memory.
+        let mem_map_entries = unsafe { crate::bootboot::bootboot.get_mmap_entries() };
+        for entry in mem_map_entries {
+            if let MMapEntType::Free | MMapEntType::Acpi = entry.get_type() {
+                super::device_memory::exclude_region(entry.ptr() as u64, entry.size() as u64);
+            }
+        }
+        let fb_ptr = unsafe { crate::bootboot::bootboot.fb_ptr };
+        let fb_size = unsafe { crate::bootboot::bootboot.fb_size } as u64;
+        super::device_memory::exclude_region(fb_ptr, fb_size);
+        info!(target: "bootstrap", "Find device memory complete");
+    }
+
     {
         info!(target: "bootstrap", "load interrupts");
         super::interrupts::load_interrupts_bsp().unwrap();
@@ -230,13 +341,8 @@ fn initialize_bootstrap_core2() -> ! {
     {
         info!(target: "bootstrap", "Kernel stack switching");
 
-        let start = globals::KERNEL_STACK_START;
-        let proc_count = 0;
-
-        let stack_start =
-            start + ((globals::KERNEL_STACK_NUM_PAGES + 1) * 2 * 1024 * 1024 * proc_count);
-        let stack_end = stack_start + (globals::KERNEL_STACK_NUM_PAGES * 2 * 1024 * 1024);
-        let aligned_stack_end = align::align_down(stack_end, globals::STACK_ALIGN);
+        let stack_end = stack::kernel_stack(BSP_CORE).end;
+        let aligned_stack_end = align::align_down(stack_end, globals::STACK_ALIGN as u64);
 
         info!(target: "bootstrap", "Kernel stack switching to {:x}", aligned_stack_end);
         unsafe { FREE_REGIONS = Some(free_regions) }
@@ -0,0 +1,146 @@
+/*!
+Roots of address spaces.
+
+The root of an address space is an [`L5`] with five-level paging and an [`L4`]
+otherwise. The functions here take the root of the active paging mode and fail
+with [`CapabilityErrors::CapabilityMismatch`] for any other capability. So an L4
+that is mapped by an L5 is never used as an address space.
+*/
+
+use relic_abi::cap::CapabilityErrors;
+
+use super::*;
+use crate::{
+    addr::{PAddr, VAddr},
+    arch::paging::la57,
+};
+
+impl StoredCap {
+    /// Create the root of a new address space from untyped memory and store it in
+    /// the provided cpool. Returns the created capability and its index in the cpool.
+    pub fn address_space_retype_from(
+        untyped: &mut CapAccessorMut<'_, UntypedMemory>,
+        cpool_to_store_in: &mut Cpool,
+    ) -> Result<(StoredCap, usize), CapabilityErrors> {
+        if la57::is_active() {
+            StoredCap::pml5_retype_from(untyped, cpool_to_store_in)
+        } else {
+            StoredCap::pml4_retype_from(untyped, cpool_to_store_in)
+        }
+    }
+
+    /// Clone the address space of `source`. See [`StoredCap::l4_clone`].
+    pub fn address_space_clone(
+        source: &StoredCap,
+        untyped: &mut CapAccessorMut<'_, UntypedMemory>,
+        cpool: &mut CapAccessorMut<'_, Cpool>,
+    ) -> Result<(StoredCap, usize), CapabilityErrors> {
+        if la57::is_active() {
+            StoredCap::l5_clone(source, untyped, cpool)
+        } else {
+            StoredCap::l4_clone(source, untyped, cpool)
+        }
+    }
+
+    /// Physical address of the top-level table of the address space.
+    pub fn address_space_paddr(&self) -> Result<PAddr, CapabilityErrors> {
+        let paddr = if la57::is_active() {
+            self.as_l5()?.start_paddr()
+        } else {
+            self.as_l4()?.start_paddr()
+        };
+        Ok(paddr.to_paddr())
+    }
+
+    /// Map the raw page at `vaddr`. See [`CapAccessorMut::l4_map`].
+    pub fn address_space_map(
+        &self,
+        vaddr: VAddr,
+        raw_page: &StoredCap,
+        untyped: &mut CapAccessorMut<'_, UntypedMemory>,
+        store_cpool: &mut CapAccessorMut<'_, Cpool>,
+        search_cpool: Option<&mut CapAccessorMut<'_, Cpool>>,
+        perms: MapPermissions,
+    ) -> Result<(), CapabilityErrors> {
+        if la57::is_active() {
+            self.as_l5_mut()?
+                .l5_map(vaddr, raw_page, untyped, store_cpool, search_cpool, perms)
+        } else {
+            self.as_l4_mut()?
+                .l4_map(vaddr, raw_page, untyped, store_cpool, search_cpool, perms)
+        }
+    }
+
+    /// Unmap the raw page mapped at `vaddr`. See [`CapAccessorMut::l4_unmap`].
+    pub fn address_space_unmap(
+        &self,
+        vaddr: VAddr,
+        raw_page: &StoredCap,
+    ) -> Result<(), CapabilityErrors> {
+        if la57::is_active() {
+            self.as_l5_mut()?.l5_unmap(vaddr, raw_page)
+        } else {
+            self.as_l4_mut()?.l4_unmap(vaddr, raw_page)
+        }
+    }
+
+    /// Resolve a write to the copy-on-write page mapped at `vaddr`. See
+    /// [`CapAccessorMut::l4_copy_on_write`].
+    pub fn address_space_copy_on_write(
+        &self,
+        vaddr: VAddr,
+        untyped: &mut UntypedMemory,
+        cpool: &mut Cpool,
+    ) -> Result<(), CapabilityErrors> {
+        if la57::is_active() {
+            self.as_l5_mut()?.l5_copy_on_write(vaddr, untyped, cpool)
+        } else {
+            self.as_l4_mut()?.l4_copy_on_write(vaddr, untyped, cpool)
+        }
+    }
+
+    /// Make the page mapped at `vaddr` private to the address space. See
+    /// [`CapAccessorMut::l4_make_private`].
+    pub fn address_space_make_private(
+        &self,
+        vaddr: VAddr,
+        untyped: &mut UntypedMemory,
+        cpool: &mut Cpool,
+    ) -> Result<(), CapabilityErrors> {
+        if la57::is_active() {
+            self.as_l5_mut()?.l5_make_private(vaddr, untyped, cpool)
+        } else {
+            self.as_l4_mut()?.l4_make_private(vaddr, untyped, cpool)
+        }
+    }
+
+    /// Switch to the address space.
+    pub fn address_space_switch_to(&self) -> Result<(), CapabilityErrors> {
+        if la57::is_active() {
+            self.as_l5_mut()?.switch_to();
+        } else {
+            self.as_l4_mut()?.switch_to();
+        }
+        Ok(())
+    }
+
+    /// Link the address space to the task that runs in it. An address space is
+    /// used by a single task.
+    pub fn address_space_link_task(&self, task: &StoredCap) -> Result<(), CapabilityErrors> {
+        let mut l5;
+        let mut l4;
+        let linked_task = if la57::is_active() {
+            l5 = self.as_l5_mut()?;
+            &mut l5.linked_task
+        } else {
+            l4 = self.as_l4_mut()?;
+            &mut l4.linked_task
+        };
+        if linked_task.is_some() {
+            return Err(CapabilityErrors::CapabilityAlreadyOccupied);
+        }
+
+        *linked_task = Some(task.clone());
+        Ok(())
+    }
+}
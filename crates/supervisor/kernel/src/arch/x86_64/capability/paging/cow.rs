@@ -0,0 +1,410 @@
+/*!
+Copy-on-write cloning of address spaces.
+
+A clone duplicates the page tables of an address space, while the pages are
+shared through copies of their capabilities. Writable pages are mapped read
+only in both address spaces and marked copy-on-write. The first write to such a
+page faults and the page is copied into memory from the untyped memory of the
+faulting task.
+
+The capabilities of the cloned page tables and pages are stored in new cpools,
+the clone pools, so a clone is not limited by the free slots of a single cpool.
+The memory and the slots that a clone needs are checked before anything is
+created, so a clone that fails leaves the source untouched.
+*/
+
+use relic_abi::cap::CapabilityErrors;
+
+use super::*;
+use crate::{
+    addr::{PAddr, VAddr},
+    arch::paging::pcid,
+};
+
+/// How a mapped page is copied.
+#[derive(Debug, Clone, Copy)]
+pub(super) enum PageCopy {
+    /// Resolve a write to a copy-on-write page.
+    Write,
+    /// Copy the page if it is mapped elsewhere too, whatever its permissions.
+    Private,
+}
+
+/// Physical address of a capability in the paging tree.
+fn paging_item_paddr(cap: &Capability) -> Option<PAddr> {
+    let paddr = match &cap.capability_data {
+        CapabilityEnum::L3(l3) => l3.start_paddr(),
+        CapabilityEnum::L2(l2) => l2.start_paddr(),
+        CapabilityEnum::L1(l1) => l1.start_paddr(),
+        CapabilityEnum::BasePage(page) => page.start_paddr(),
+        CapabilityEnum::LargePage(page) => page.start_paddr(),
+        CapabilityEnum::HugePage(page) => page.start_paddr(),
+        _ => return None,
+    };
+    Some(paddr.to_paddr())
+}
+
+/// Find the child of a paging capability that starts at `paddr`.
+fn find_child(first_child: Option<StoredCap>, paddr: PAddr) -> Result<StoredCap, CapabilityErrors> {
+    let mut current = first_child;
+    while let Some(child) = current {
+        if paging_item_paddr(&child.borrow()) == Some(paddr) {
+            return Ok(child);
+        }
+        current = child.borrow_mut().get_next_paging_item_mut().clone();
+    }
+    Err(CapabilityErrors::MemoryNotMapped)
+}
+
+/// Capabilities that a clone creates for the paging items of the source.
+#[derive(Debug, Default, Clone, Copy)]
+pub(super) struct CloneCount {
+    /// L4s, which are only mapped by an L5.
+    pub l4_tables: usize,
+    /// L3, L2 and L1 tables.
+    pub tables: usize,
+    /// Copies of page capabilities.
+    pub pages: usize,
+}
+
+impl CloneCount {
+    /// Count the paging items from `first_child` on and their children.
+    pub fn add_children(&mut self, first_child: Option<StoredCap>) -> Result<(), CapabilityErrors> {
+        let mut current = first_child;
+        while let Some(child) = current {
+            let grandchild = match &child.borrow().capability_data {
+                CapabilityEnum::L3(l3) => Some(l3.child_paging_item.clone()),
+                CapabilityEnum::L2(l2) => Some(l2.child_paging_item.clone()),
+                CapabilityEnum::L1(l1) => Some(l1.child_paging_item.clone()),
+                CapabilityEnum::BasePage(_)
+                | CapabilityEnum::LargePage(_)
+                | CapabilityEnum::HugePage(_) => None,
+                _ => return Err(CapabilityErrors::CapabilityMismatch),
+            };
+            match grandchild {
+                Some(first_grandchild) => {
+                    self.tables += 1;
+                    self.add_children(first_grandchild)?;
+                }
+                None => self.pages += 1,
+            }
+            current = child.borrow_mut().get_next_paging_item_mut().clone();
+        }
+        Ok(())
+    }
+
+    /// Number of clone pools that hold the capabilities.
+    fn pool_count(&self) -> usize {
+        let slots = CLONE_POOL_SLOTS;
+        (self.l4_tables + self.tables + self.pages + slots - 1) / slots
+    }
+}
+
+/// Slots of a clone pool.
+const CLONE_POOL_SLOTS: usize = 256;
+
+/**
+Cpools that a clone stores the capabilities of its page tables and pages in. They
+are filled one after another.
+*/
+pub(super) struct ClonePools {
+    pools: heapless::Vec<StoredCap, CLONE_POOL_SLOTS>,
+    current: usize,
+}
+
+impl ClonePools {
+    /**
+    Check that a clone with the root region `(length, alignment)` and the paging items
+    in `count` fits into `untyped` and into the free slots of `cpool`. The root is
+    allocated first, then the clone pools and then the page tables, as in
+    [`Self::new`] and the clone functions.
+    */
+    pub fn check(
+        untyped: &UntypedMemory,
+        cpool: &Cpool,
+        root: (usize, usize),
+        count: CloneCount,
+    ) -> Result<(), CapabilityErrors> {
+        if untyped.is_device_memory() {
+            return Err(CapabilityErrors::DeviceMemoryConflict);
+        }
+
+        let l4_length = core::mem::size_of::<[PML4Table; PML4_TABLE_COUNT]>();
+        // Five-level paging is not used with page table isolation, so the L4s and the
+        // lower tables have the same length and alignment and their order does not matter.
+        let regions = [
+            (root.0, root.1, 1),
+            (
+                core::mem::size_of::<CpoolInner>(),
+                core::mem::align_of::<CpoolInner>(),
+                count.pool_count(),
+            ),
+            (l4_length, l4_length, count.l4_tables),
+            (BASE_PAGE_LENGTH, BASE_PAGE_LENGTH, count.tables),
+        ];
+        if !untyped.can_allocate_all(&regions) {
+            return Err(CapabilityErrors::MemoryNotSufficient);
+        }
+        if cpool.free_slot_count() < 1 + count.pool_count() {
+            return Err(CapabilityErrors::CapabilitySlotsFull);
+        }
+        Ok(())
+    }
+
+    /// Create the clone pools for the paging items in `count` from `untyped` and
+    /// store them in `cpool`.
+    pub fn new(
+        untyped: &mut UntypedMemory,
+        cpool: &mut Cpool,
+        count: CloneCount,
+    ) -> Result<Self, CapabilityErrors> {
+        let mut pools = heapless::Vec::new();
+        for _ in 0..count.pool_count() {
+            let (pool, _) = StoredCap::cpool_retype_from(untyped, cpool)?;
+            pools
+                .push(pool)
+                .map_err(|_| CapabilityErrors::CapabilitySlotsFull)?;
+        }
+        Ok(Self { pools, current: 0 })
+    }
+
+    /// Store a capability with `f` in the first clone pool that has a free slot.
+    pub fn store<T>(
+        &mut self,
+        f: impl FnOnce(&mut Cpool) -> Result<T, CapabilityErrors>,
+    ) -> Result<T, CapabilityErrors> {
+        while let Some(pool) = self.pools.get(self.current) {
+            let mut pool = pool.as_cpool_mut()?;
+            if pool.get_free_index().is_ok() {
+                return f(&mut pool);
+            }
+            self.current += 1;
+        }
+        Err(CapabilityErrors::CapabilitySlotsFull)
+    }
+}
+
+/// Clone the user entries of `source` into `clone`. The kernel entries of `clone`
+/// are kept.
+pub(super) fn clone_l4_table(
+    source: &mut CapAccessorMut<'_, L4>,
+    clone: &mut CapAccessorMut<'_, L4>,
+    untyped: &mut CapAccessorMut<'_, UntypedMemory>,
+    pools: &mut ClonePools,
+) -> Result<(), CapabilityErrors> {
+    for index in 0..source.page_data.len() {
+        let entry = source.page_data[index];
+        // The kernel entries are already present in every address space.
+        if !entry.is_present() || clone.page_data[index].is_present() {
+            continue;
+        }
+
+        let source_l3 = find_child(source.child_paging_item.clone(), entry.get_address())?;
+        let (clone_l3, _) = pools.store(|pool| StoredCap::pdpt_retype_from(untyped, pool))?;
+        let mut clone_l3 = clone_l3.as_l3_mut()?;
+        let flags = PML4Entry::from_bits_truncate(entry.bits());
+        clone.l4_map_l3(index, &mut clone_l3, Some(flags))?;
+        clone_l3_table(&mut source_l3.as_l3_mut()?, &mut clone_l3, untyped, pools)?;
+    }
+    Ok(())
+}
+
+fn clone_l3_table(
+    source: &mut CapAccessorMut<'_, L3>,
+    clone: &mut CapAccessorMut<'_, L3>,
+    untyped: &mut CapAccessorMut<'_, UntypedMemory>,
+    pools: &mut ClonePools,
+) -> Result<(), CapabilityErrors> {
+    for index in 0..source.page_data.len() {
+        let entry = source.page_data[index];
+        if !entry.is_present() {
+            continue;
+        }
+
+        let child = find_child(source.child_paging_item.clone(), entry.get_address())?;
+        if entry.contains(PDPTEntry::HUGE_PAGE) {
+            pools.store(|pool| child.huge_page_clone_mapping(source, clone, index, pool))?;
+            continue;
+        }
+
+        let (clone_l2, _) = pools.store(|pool| StoredCap::pd_retype_from(untyped, pool))?;
+        let mut clone_l2 = clone_l2.as_l2_mut()?;
+        let flags = PDPTEntry::from_bits_truncate(entry.bits());
+        clone.l3_map_l2(index, &mut clone_l2, Some(flags))?;
+        clone_l2_table(&mut child.as_l2_mut()?, &mut clone_l2, untyped, pools)?;
+    }
+    Ok(())
+}
+
+fn clone_l2_table(
+    source: &mut CapAccessorMut<'_, L2>,
+    clone: &mut CapAccessorMut<'_, L2>,
+    untyped: &mut CapAccessorMut<'_, UntypedMemory>,
+    pools: &mut ClonePools,
+) -> Result<(), CapabilityErrors> {
+    for index in 0..source.page_data.len() {
+        let entry = source.page_data[index];
+        if !entry.is_present() {
+            continue;
+        }
+
+        let child = find_child(source.child_paging_item.clone(), entry.get_address())?;
+        if entry.contains(PDEntry::LARGE_PAGE) {
+            pools.store(|pool| child.large_page_clone_mapping(source, clone, index, pool))?;
+            continue;
+        }
+
+        let (clone_l1, _) = pools.store(|pool| StoredCap::pt_retype_from(untyped, pool))?;
+        let mut clone_l1 = clone_l1.as_l1_mut()?;
+        let flags = PDEntry::from_bits_truncate(entry.bits());
+        clone.l2_map_l1(index, &mut clone_l1, Some(flags))?;
+        clone_l1_table(&mut child.as_l1_mut()?, &mut clone_l1, pools)?;
+    }
+    Ok(())
+}
+
+fn clone_l1_table(
+    source: &mut CapAccessorMut<'_, L1>,
+    clone: &mut CapAccessorMut<'_, L1>,
+    pools: &mut ClonePools,
+) -> Result<(), CapabilityErrors> {
+    for index in 0..source.page_data.len() {
+        let entry = source.page_data[index];
+        if !entry.is_present() {
+            continue;
+        }
+
+        let page = find_child(source.child_paging_item.clone(), entry.get_address())?;
+        pools.store(|pool| page.base_page_clone_mapping(source, clone, index, pool))?;
+    }
+    Ok(())
+}
+
+impl StoredCap {
+    /**
+    Clone the address space of `source` into a new L4 stored in the provided cpool.
+    The page tables are created from `untyped` while every page is mapped through
+    a new copy of its capability. Their capabilities are stored in clone pools that
+    are created from `untyped` and stored in the provided cpool too. Writable pages
+    are copied on the first write, see [`CapAccessorMut::l4_copy_on_write`].
+
+    Fails with [`CapabilityErrors::MemoryNotSufficient`] or
+    [`CapabilityErrors::CapabilitySlotsFull`] before anything is changed if the clone
+    does not fit. Returns the new L4 and its index in the cpool.
+    */
+    pub fn l4_clone(
+        source: &StoredCap,
+        untyped: &mut CapAccessorMut<'_, UntypedMemory>,
+        cpool: &mut CapAccessorMut<'_, Cpool>,
+    ) -> Result<(StoredCap, usize), CapabilityErrors> {
+        let mut source_l4 = source.as_l4_mut()?;
+        let mut count = CloneCount::default();
+        count.add_children(source_l4.child_paging_item.clone())?;
+        let root_length = core::mem::size_of::<[PML4Table; PML4_TABLE_COUNT]>();
+        ClonePools::check(untyped, cpool, (root_length, root_length), count)?;
+
+        let (clone, clone_index) = StoredCap::pml4_retype_from(untyped, cpool)?;
+        let mut pools = ClonePools::new(untyped, cpool, count)?;
+        let mut clone_l4 = clone.as_l4_mut()?;
+        clone_l4_table(&mut source_l4, &mut clone_l4, untyped, &mut pools)?;
+
+        // Writable pages of the source are read only now.
+        pcid::invalidate_all();
+        core::mem::drop(clone_l4);
+        Ok((clone, clone_index))
+    }
+}
+
+impl CapAccessorMut<'_, L4> {
+    /**
+    Resolve a write to the copy-on-write page mapped at `vaddr`. The page is copied
+    into memory from `untyped` unless no other address space maps it anymore. Fails
+    with [`CapabilityErrors::MemoryNotMapped`] if no copy-on-write page is mapped at
+    the address.
+    */
+    pub fn l4_copy_on_write(
+        &mut self,
+        vaddr: VAddr,
+        untyped: &mut UntypedMemory,
+        cpool: &mut Cpool,
+    ) -> Result<(), CapabilityErrors> {
+        self.copy_page(vaddr, PageCopy::Write, untyped, cpool)?;
+        self.invalidate(vaddr);
+        Ok(())
+    }
+
+    /**
+    Make the page mapped at `vaddr` private to this address space, so that it can be
+    written through its physical memory, like for breakpoints of a debugger. A shared
+    page is copied into memory from `untyped`, see [`StoredCap::base_page_make_private`].
+    */
+    pub fn l4_make_private(
+        &mut self,
+        vaddr: VAddr,
+        untyped: &mut UntypedMemory,
+        cpool: &mut Cpool,
+    ) -> Result<(), CapabilityErrors> {
+        self.copy_page(vaddr, PageCopy::Private, untyped, cpool)?;
+        self.invalidate(vaddr);
+        Ok(())
+    }
+
+    /// Resolve a write like [`Self::l4_copy_on_write`] or make the page private like
+    /// [`Self::l4_make_private`], but leave the TLB to the caller.
+    pub(super) fn copy_page(
+        &mut self,
+        vaddr: VAddr,
+        copy: PageCopy,
+        untyped: &mut UntypedMemory,
+        cpool: &mut Cpool,
+    ) -> Result<(), CapabilityErrors> {
+        let entry = self.page_data[pml4_index(vaddr)];
+        if !entry.is_present() {
+            return Err(CapabilityErrors::MemoryNotMapped);
+        }
+        let l3 = find_child(self.child_paging_item.clone(), entry.get_address())?;
+        let mut l3 = l3.as_l3_mut()?;
+
+        // L3
+        let index = pdpt_index(vaddr);
+        let entry = l3.page_data[index];
+        if !entry.is_present() {
+            return Err(CapabilityErrors::MemoryNotMapped);
+        }
+        let child = find_child(l3.child_paging_item.clone(), entry.get_address())?;
+        if entry.contains(PDPTEntry::HUGE_PAGE) {
+            return match copy {
+                PageCopy::Write => child.huge_page_copy_on_write(&mut l3, index, untyped, cpool),
+                PageCopy::Private => child.huge_page_make_private(&mut l3, index, untyped, cpool),
+            };
+        }
+        let mut l2 = child.as_l2_mut()?;
+
+        // L2
+        let index = pd_index(vaddr);
+        let entry = l2.page_data[index];
+        if !entry.is_present() {
+            return Err(CapabilityErrors::MemoryNotMapped);
+        }
+        let child = find_child(l2.child_paging_item.clone(), entry.get_address())?;
+        if entry.contains(PDEntry::LARGE_PAGE) {
+            return match copy {
+                PageCopy::Write => child.large_page_copy_on_write(&mut l2, index, untyped, cpool),
+                PageCopy::Private => child.large_page_make_private(&mut l2, index, untyped, cpool),
+            };
+        }
+        let mut l1 = child.as_l1_mut()?;
+
+        // L1
+        let index = pt_index(vaddr);
+        let entry = l1.page_data[index];
+        if !entry.is_present() {
+            return Err(CapabilityErrors::MemoryNotMapped);
+        }
+        let page = find_child(l1.child_paging_item.clone(), entry.get_address())?;
+        match copy {
+            PageCopy::Write => page.base_page_copy_on_write(&mut l1, index, untyped, cpool),
+            PageCopy::Private => page.base_page_make_private(&mut l1, index, untyped, cpool),
+        }
+    }
+}
@@ -1,9 +1,13 @@
+mod address_space;
+mod cow;
 mod pml4;
+mod pml5;
 mod raw_page;
 
 use relic_abi::cap::CapabilityErrors;
 
 pub use pml4::*;
+pub use pml5::*;
 pub use raw_page::*;
 
 use crate::{
@@ -23,6 +27,8 @@ macro_rules! paging_cap_impl {
                 pub child_paging_item: Option<StoredCap>,
                 pub next_paging_item: Option<StoredCap>,
                 pub prev_paging_item: Option<StoredCap>,
+                /// Index of the entry that maps this table in its parent table.
+                pub mapped_index: Option<usize>,
             }
 
             impl $paging {
@@ -35,6 +41,7 @@ macro_rules! paging_cap_impl {
                         child_paging_item: None,
                         next_paging_item: None,
                         prev_paging_item: None,
+                        mapped_index: None,
                     }
                 }
             }
@@ -43,6 +50,10 @@ macro_rules! paging_cap_impl {
         }
     };
     ($paging: ty, $inner: ty, $child: ty) => {
+        paging_cap_impl!($paging, $inner, $child, tables = 1);
+    };
+    // Every capability owns `$tables` consecutive page tables.
+    ($paging: ty, $inner: ty, $child: ty, tables = $tables: expr) => {
         paste! {
             #[derive(Debug)]
             #[repr(C, align(4096))]
@@ -75,11 +86,11 @@ macro_rules! paging_cap_impl {
                     let mut result_index = 0;
 
                     let result = untyped.derive(
-                        Some(core::mem::size_of::<[< $inner Table >]>()),
+                        Some(core::mem::size_of::<[[< $inner Table >]; $tables]>()),
                         false,
-                        |memory: *mut [< $inner Table >]| {
+                        |memory: *mut [[< $inner Table >]; $tables]| {
                             unsafe {
-                                core::ptr::write_bytes(memory as *mut u8, 0, 4096);
+                                core::ptr::write_bytes(memory, 0, 1);
                             }
                             let boxed = unsafe { Boxed::new((memory as u64).into()) };
 
@@ -130,7 +141,7 @@ macro_rules! paging_cap_impl {
                         }
                         let soon_to_be_second = self.child_paging_item.clone();
 
-                        if child.next_paging_item.is_some() {
+                        if child.prev_paging_item.is_some() {
                             return Err(CapabilityErrors::MemoryAlreadyMapped);
                         }
 
@@ -150,6 +161,7 @@ macro_rules! paging_cap_impl {
 
                         child.next_paging_item = soon_to_be_second.clone();
                         child.prev_paging_item = Some(self.cap().clone());
+                        child.mapped_index = Some(index);
 
                         if let Some(soon_to_be_sec_val) = soon_to_be_second {
                             *soon_to_be_sec_val.borrow_mut().get_prev_paging_item_mut() =
@@ -167,7 +179,7 @@ macro_rules! paging_cap_impl {
 paging_cap_impl!(L1, PT, BasePage, include_child_structs);
 paging_cap_impl!(L2, PD, L1, include_child_structs);
 paging_cap_impl!(L3, PDPT, L2, include_child_structs);
-paging_cap_impl!(L4, PML4, L3);
+paging_cap_impl!(L4, PML4, L3, tables = PML4_TABLE_COUNT);
 
 paging_cap_impl!(L2, PD, LargePage, map_fn, LARGE_PAGE);
 paging_cap_impl!(L3, PDPT, HugePage, map_fn, HUGE_PAGE);
@@ -176,7 +188,7 @@ paging_cap_impl!(L3, PDPT, HugePage, map_fn, HUGE_PAGE);
 mod tests {
     use std::{cell::RefCell, mem::MaybeUninit};
 
-    use crate::util::unsafe_ref::UnsafeRef;
+    use crate::{capability::test_fixture::Fixture, util::unsafe_ref::UnsafeRef};
 
     use super::*;
 
@@ -283,4 +295,456 @@ mod tests {
             CapabilityEnum::EmptyCap
         ));
     }
+
+    #[test]
+    fn test_shared_mapping() {
+        let fixture = Fixture::new();
+        let mut untyped = fixture.untyped();
+        let mut cpool = fixture.cpool();
+
+        let l4_a = StoredCap::pml4_retype_from(&mut untyped, &mut cpool).unwrap();
+        let l4_b = StoredCap::pml4_retype_from(&mut untyped, &mut cpool).unwrap();
+        let raw_page =
+            StoredCap::base_page_retype_from::<[u8; 10]>(&mut untyped, &mut cpool, true).unwrap();
+        let copy = StoredCap::base_page_copy(&raw_page.0, &mut cpool).unwrap();
+
+        l4_a.0
+            .as_l4_mut()
+            .unwrap()
+            .l4_map(
+                0x1000u64.into(),
+                &raw_page.0,
+                &mut untyped,
+                &mut cpool,
+                None,
+                MapPermissions::WRITE,
+            )
+            .unwrap();
+
+        // A page capability can only be mapped once.
+        let _fail_map = l4_b
+            .0
+            .as_l4_mut()
+            .unwrap()
+            .l4_map(
+                0x1000u64.into(),
+                &raw_page.0,
+                &mut untyped,
+                &mut cpool,
+                None,
+                MapPermissions::WRITE,
+            )
+            .unwrap_err();
+        assert_matches!(CapabilityErrors::MemoryAlreadyMapped, _fail_map);
+
+        let mut l4_b_0 = l4_b.0.as_l4_mut().unwrap();
+        l4_b_0
+            .l4_map(
+                0x2000u64.into(),
+                &copy.0,
+                &mut untyped,
+                &mut cpool,
+                None,
+                MapPermissions::empty(),
+            )
+            .unwrap();
+        let paddr = raw_page.0.as_base_page().unwrap().start_paddr().to_paddr();
+        let l1_b = copy.0.base_page_parent().unwrap();
+        let is_mapped_in_l1_b = || {
+            l1_b.as_l1()
+                .unwrap()
+                .page_data
+                .iter()
+                .any(|entry| entry.is_present() && entry.get_address() == paddr)
+        };
+        assert!(is_mapped_in_l1_b());
+
+        let _fail_unmap = l4_b_0.l4_unmap(0x1000u64.into(), &copy.0).unwrap_err();
+        assert_matches!(CapabilityErrors::MemoryNotMapped, _fail_unmap);
+        core::mem::drop(l4_b_0);
+
+        // Only the original page can be revoked.
+        assert_matches!(
+            copy.0.base_page_revoke(),
+            Err(CapabilityErrors::CapabilityIsDerived)
+        );
+        raw_page.0.base_page_revoke().unwrap();
+        assert_matches!(
+            fixture.cpool_inner.unsafe_data[copy.1]
+                .borrow()
+                .capability_data,
+            CapabilityEnum::EmptyCap
+        );
+        assert!(!is_mapped_in_l1_b());
+
+        let mut l4_a_0 = l4_a.0.as_l4_mut().unwrap();
+        l4_a_0.l4_unmap(0x1000u64.into(), &raw_page.0).unwrap();
+        assert!(raw_page.0.base_page_parent().is_none());
+    }
+
+    #[test]
+    fn test_revoke_copy_in_same_table() {
+        let fixture = Fixture::new();
+        let mut untyped = fixture.untyped();
+        let mut cpool = fixture.cpool();
+
+        let l4 = StoredCap::pml4_retype_from(&mut untyped, &mut cpool).unwrap();
+        let raw_page =
+            StoredCap::base_page_retype_from::<[u8; 10]>(&mut untyped, &mut cpool, true).unwrap();
+        let copy = StoredCap::base_page_copy(&raw_page.0, &mut cpool).unwrap();
+
+        // The copy is mapped after the original in the same table.
+        let mut l4_0 = l4.0.as_l4_mut().unwrap();
+        for (vaddr, page) in [(0x1000u64, &raw_page.0), (0x2000u64, &copy.0)] {
+            l4_0.l4_map(
+                vaddr.into(),
+                page,
+                &mut untyped,
+                &mut cpool,
+                None,
+                MapPermissions::empty(),
+            )
+            .unwrap();
+        }
+
+        // A capability only unmaps its own entry.
+        let _fail_unmap = l4_0.l4_unmap(0x1000u64.into(), &copy.0).unwrap_err();
+        assert_matches!(CapabilityErrors::MemoryNotMapped, _fail_unmap);
+        core::mem::drop(l4_0);
+
+        let paddr = raw_page.0.as_base_page().unwrap().start_paddr().to_paddr();
+        let l1 = raw_page.0.base_page_parent().unwrap();
+        raw_page.0.base_page_revoke().unwrap();
+        let l1 = l1.as_l1().unwrap();
+        assert!(l1.page_data[1].is_present());
+        assert_eq!(l1.page_data[1].get_address(), paddr);
+        assert!(!l1.page_data[2].is_present());
+    }
+
+    #[test]
+    fn test_revoke_borrowed_copy() {
+        let fixture = Fixture::new();
+        let mut untyped = fixture.untyped();
+        let mut cpool = fixture.cpool();
+
+        let l4 = StoredCap::pml4_retype_from(&mut untyped, &mut cpool).unwrap();
+        let raw_page =
+            StoredCap::base_page_retype_from::<[u8; 10]>(&mut untyped, &mut cpool, true).unwrap();
+        let borrowed_copy = StoredCap::base_page_copy(&raw_page.0, &mut cpool).unwrap();
+        // Copies are inserted right after the page, so this one is walked first.
+        let mapped_copy = StoredCap::base_page_copy(&raw_page.0, &mut cpool).unwrap();
+
+        let mut l4_0 = l4.0.as_l4_mut().unwrap();
+        l4_0.l4_map(
+            0x1000u64.into(),
+            &mapped_copy.0,
+            &mut untyped,
+            &mut cpool,
+            None,
+            MapPermissions::empty(),
+        )
+        .unwrap();
+        core::mem::drop(l4_0);
+
+        let borrow = borrowed_copy.0.borrow();
+        assert_matches!(
+            raw_page.0.base_page_revoke(),
+            Err(CapabilityErrors::CapabilityAlreadyInUse)
+        );
+        core::mem::drop(borrow);
+        assert!(mapped_copy.0.base_page_parent().is_some());
+        assert_matches!(
+            fixture.cpool_inner.unsafe_data[borrowed_copy.1]
+                .borrow()
+                .capability_data,
+            CapabilityEnum::BasePage(..)
+        );
+
+        raw_page.0.base_page_revoke().unwrap();
+        assert_matches!(
+            fixture.cpool_inner.unsafe_data[mapped_copy.1]
+                .borrow()
+                .capability_data,
+            CapabilityEnum::EmptyCap
+        );
+    }
+
+    #[test]
+    fn test_copy_on_write_clone() {
+        let fixture = Fixture::new();
+        let mut untyped = fixture.untyped();
+        let mut cpool = fixture.cpool();
+
+        let l4 = StoredCap::pml4_retype_from(&mut untyped, &mut cpool).unwrap();
+        let raw_page =
+            StoredCap::base_page_retype_from::<[u8; 10]>(&mut untyped, &mut cpool, true).unwrap();
+        raw_page.0.as_base_page_mut().unwrap().page_data_mut_raw()[0] = 42;
+        l4.0.as_l4_mut()
+            .unwrap()
+            .l4_map(
+                0x1000u64.into(),
+                &raw_page.0,
+                &mut untyped,
+                &mut cpool,
+                None,
+                MapPermissions::WRITE,
+            )
+            .unwrap();
+        let paddr = raw_page.0.as_base_page().unwrap().start_paddr().to_paddr();
+
+        let clone = StoredCap::l4_clone(&l4.0, &mut untyped, &mut cpool).unwrap();
+        let copy = raw_page.0.borrow().next_mem_item.clone().unwrap();
+        let l1 = raw_page.0.base_page_parent().unwrap();
+        let clone_l1 = copy.base_page_parent().unwrap();
+        assert_ne!(l1.as_ptr(), clone_l1.as_ptr());
+
+        // Both address spaces map the page read only.
+        for table in [&l1, &clone_l1] {
+            let entry = table.as_l1().unwrap().page_data[1];
+            assert_eq!(entry.get_address(), paddr);
+            assert!(!entry.is_writeable());
+            assert!(entry.contains(PTEntry::COPY_ON_WRITE));
+        }
+
+        // The first write copies the page.
+        clone
+            .0
+            .as_l4_mut()
+            .unwrap()
+            .l4_copy_on_write(0x1000u64.into(), &mut untyped, &mut cpool)
+            .unwrap();
+        assert!(copy.base_page_parent().is_none());
+        let entry = clone_l1.as_l1().unwrap().page_data[1];
+        assert_ne!(entry.get_address(), paddr);
+        assert!(entry.is_writeable());
+        assert!(!entry.contains(PTEntry::COPY_ON_WRITE));
+        let new_page = clone_l1.as_l1().unwrap().child_paging_item.clone().unwrap();
+        assert_eq!(new_page.as_base_page().unwrap().page_data_raw()[0], 42);
+
+        // The source is the only mapping left, so it is made writable in place.
+        let mut l4_0 = l4.0.as_l4_mut().unwrap();
+        l4_0.l4_copy_on_write(0x1000u64.into(), &mut untyped, &mut cpool)
+            .unwrap();
+        let entry = l1.as_l1().unwrap().page_data[1];
+        assert_eq!(entry.get_address(), paddr);
+        assert!(entry.is_writeable());
+
+        let _fail_write = l4_0
+            .l4_copy_on_write(0x1000u64.into(), &mut untyped, &mut cpool)
+            .unwrap_err();
+        assert_matches!(CapabilityErrors::MemoryNotMapped, _fail_write);
+    }
+
+    #[test]
+    fn test_clone_into_pools() {
+        let fixture = Fixture::new();
+        let mut untyped = fixture.untyped();
+        let mut cpool = fixture.cpool();
+
+        let l4 = StoredCap::pml4_retype_from(&mut untyped, &mut cpool).unwrap();
+        let mut l4_0 = l4.0.as_l4_mut().unwrap();
+        for vaddr in [0x1000u64, 0x2000u64] {
+            let raw_page =
+                StoredCap::base_page_retype_from::<[u8; 10]>(&mut untyped, &mut cpool, true)
+                    .unwrap();
+            l4_0.l4_map(
+                vaddr.into(),
+                &raw_page.0,
+                &mut untyped,
+                &mut cpool,
+                None,
+                MapPermissions::WRITE,
+            )
+            .unwrap();
+        }
+        core::mem::drop(l4_0);
+
+        // The L4 and one clone pool for the L3, L2, L1 and both page copies.
+        let free_slots = cpool.free_slot_count();
+        let clone = StoredCap::l4_clone(&l4.0, &mut untyped, &mut cpool).unwrap();
+        assert_eq!(cpool.free_slot_count(), free_slots - 2);
+        assert_matches!(
+            fixture.cpool_inner.unsafe_data[clone.1 + 1]
+                .borrow()
+                .capability_data,
+            CapabilityEnum::Cpool(..)
+        );
+        let pool = fixture.cpool_inner.unsafe_data[clone.1 + 1].borrow();
+        let pool = match &pool.capability_data {
+            CapabilityEnum::Cpool(pool) => pool,
+            _ => unreachable!(),
+        };
+        assert_eq!(pool.free_slot_count(), 256 - 5);
+    }
+
+    #[test]
+    fn test_clone_fails_before_changes() {
+        let fixture = Fixture::new();
+        let mut untyped = fixture.untyped();
+        let mut cpool = fixture.cpool();
+
+        let l4 = StoredCap::pml4_retype_from(&mut untyped, &mut cpool).unwrap();
+        let raw_page =
+            StoredCap::base_page_retype_from::<[u8; 10]>(&mut untyped, &mut cpool, true).unwrap();
+        l4.0.as_l4_mut()
+            .unwrap()
+            .l4_map(
+                0x1000u64.into(),
+                &raw_page.0,
+                &mut untyped,
+                &mut cpool,
+                None,
+                MapPermissions::WRITE,
+            )
+            .unwrap();
+        let l1 = raw_page.0.base_page_parent().unwrap();
+        let is_writable = || l1.as_l1().unwrap().page_data[1].is_writeable();
+
+        // Without free slots for the L4 and its clone pool.
+        let mut copies = Vec::new();
+        while cpool.free_slot_count() > 1 {
+            copies.push(StoredCap::base_page_copy(&raw_page.0, &mut cpool).unwrap());
+        }
+        let _fail_clone = StoredCap::l4_clone(&l4.0, &mut untyped, &mut cpool).unwrap_err();
+        assert_matches!(CapabilityErrors::CapabilitySlotsFull, _fail_clone);
+        assert!(is_writable());
+        assert_eq!(cpool.free_slot_count(), 1);
+
+        // Without memory for the page tables.
+        let free_space = untyped.get_free_space() as usize;
+        untyped.allocate(free_space - 0x1000, 1).unwrap();
+        raw_page.0.base_page_revoke().unwrap();
+        let _fail_clone = StoredCap::l4_clone(&l4.0, &mut untyped, &mut cpool).unwrap_err();
+        assert_matches!(CapabilityErrors::MemoryNotSufficient, _fail_clone);
+        assert!(is_writable());
+        let next = raw_page.0.borrow().next_mem_item.clone().unwrap();
+        assert_eq!(next.as_ptr(), l4.0.as_ptr());
+    }
+
+    #[test]
+    fn test_clone_shares_device_and_uncached_pages() {
+        let fixture = Fixture::new();
+        let mut untyped = fixture.untyped();
+        let mut cpool = fixture.cpool();
+
+        // The device memory is taken from the memory of the fixture.
+        let (device_paddr, _) = untyped.allocate(0x1000, 0x1000).unwrap();
+        let device_untyped_ref =
+            RefCell::new(unsafe { UntypedMemory::bootstrap(device_paddr, 0x1000, true) });
+        let device_untyped = unsafe { UnsafeRef::from_raw(&device_untyped_ref) };
+        let device_page = StoredCap::base_page_retype_from::<[u8; 10]>(
+            &mut device_untyped.as_untyped_memory_mut().unwrap(),
+            &mut cpool,
+            false,
+        )
+        .unwrap();
+        let uncached_page =
+            StoredCap::base_page_retype_from::<[u8; 10]>(&mut untyped, &mut cpool, true).unwrap();
+
+        let l4 = StoredCap::pml4_retype_from(&mut untyped, &mut cpool).unwrap();
+        let mut l4_0 = l4.0.as_l4_mut().unwrap();
+        for (vaddr, page, perms) in [
+            (0x1000u64, &device_page.0, MapPermissions::WRITE),
+            (
+                0x2000u64,
+                &uncached_page.0,
+                MapPermissions::WRITE | MapPermissions::CACHE_DISABLE,
+            ),
+        ] {
+            l4_0.l4_map(vaddr.into(), page, &mut untyped, &mut cpool, None, perms)
+                .unwrap();
+        }
+        core::mem::drop(l4_0);
+
+        StoredCap::l4_clone(&l4.0, &mut untyped, &mut cpool).unwrap();
+        let l1 = device_page.0.base_page_parent().unwrap();
+        let copy = device_page.0.borrow().next_mem_item.clone().unwrap();
+        let clone_l1 = copy.base_page_parent().unwrap();
+        assert_ne!(l1.as_ptr(), clone_l1.as_ptr());
+
+        for table in [&l1, &clone_l1] {
+            for index in [1, 2] {
+                let entry = table.as_l1().unwrap().page_data[index];
+                assert!(entry.is_writeable());
+                assert!(!entry.contains(PTEntry::COPY_ON_WRITE));
+            }
+        }
+    }
+
+    #[test]
+    fn test_memory_types() {
+        let fixture = Fixture::new();
+        let mut untyped = fixture.untyped();
+        let mut cpool = fixture.cpool();
+
+        let l4 = StoredCap::pml4_retype_from(&mut untyped, &mut cpool).unwrap();
+        let large_page =
+            StoredCap::large_page_retype_from::<[u8; 10]>(&mut untyped, &mut cpool, true).unwrap();
+        let mut base_pages = Vec::new();
+        for _ in 0..4 {
+            base_pages.push(
+                StoredCap::base_page_retype_from::<[u8; 10]>(&mut untyped, &mut cpool, true)
+                    .unwrap(),
+            );
+        }
+
+        let mut l4_0 = l4.0.as_l4_mut().unwrap();
+        let base_perms = [
+            MapPermissions::WRITE,
+            MapPermissions::WRITE_THROUGH,
+            MapPermissions::CACHE_DISABLE,
+            MapPermissions::WRITE_COMBINING,
+        ];
+        for (index, (page, perms)) in base_pages.iter().zip(base_perms.iter()).enumerate() {
+            let vaddr = (index as u64 + 1) * 0x1000;
+            l4_0.l4_map(
+                vaddr.into(),
+                &page.0,
+                &mut untyped,
+                &mut cpool,
+                None,
+                *perms,
+            )
+            .unwrap();
+        }
+        l4_0.l4_map(
+            0x20_0000u64.into(),
+            &large_page.0,
+            &mut untyped,
+            &mut cpool,
+            None,
+            MapPermissions::WRITE_COMBINING,
+        )
+        .unwrap();
+        core::mem::drop(l4_0);
+
+        // Write-combining selects PAT entry 4 with only the PAT bit.
+        let l1 = base_pages[0].0.base_page_parent().unwrap();
+        let l1 = l1.as_l1().unwrap();
+        let memory_type = |entry: PTEntry| {
+            (
+                entry.contains(PTEntry::PAT),
+                entry.contains(PTEntry::CACHE_DISABLE),
+                entry.contains(PTEntry::WRITE_THROUGH),
+            )
+        };
+        assert_eq!(memory_type(l1.page_data[1]), (false, false, false));
+        assert_eq!(memory_type(l1.page_data[2]), (false, false, true));
+        assert_eq!(memory_type(l1.page_data[3]), (false, true, false));
+        assert_eq!(memory_type(l1.page_data[4]), (true, false, false));
+
+        // The PAT bit of large pages is not part of their address.
+        let paddr = large_page
+            .0
+            .as_large_page()
+            .unwrap()
+            .start_paddr()
+            .to_paddr();
+        let l2 = large_page.0.large_page_parent().unwrap();
+        let entry = l2.as_l2().unwrap().page_data[1];
+        assert!(entry.is_page());
+        assert!(entry.is_pat());
+        assert!(!entry.contains(PDEntry::WRITE_THROUGH) && !entry.contains(PDEntry::CACHE_DISABLE));
+        assert_eq!(entry.get_address(), paddr);
+    }
 }
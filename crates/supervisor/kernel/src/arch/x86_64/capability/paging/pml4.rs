@@ -1,7 +1,17 @@
 use relic_abi::cap::CapabilityErrors;
 
 use super::*;
-use crate::{addr::VAddr, arch::paging::utils, util::boxed::Boxed};
+#[cfg(feature = "kpti")]
+use crate::arch::paging::kpti;
+use crate::{
+    addr::{PAddr, VAddr},
+    arch::paging::{la57, pcid, utils},
+    util::boxed::Boxed,
+};
+
+/// Number of page tables owned by an L4. With page table isolation, the PML4 is
+/// followed by the user page table.
+pub const PML4_TABLE_COUNT: usize = if cfg!(feature = "kpti") { 2 } else { 1 };
 
 #[derive(Debug)]
 pub struct L4 {
@@ -10,36 +20,100 @@ pub struct L4 {
     pub linked_task: Option<StoredCap>,
 
     is_derived: bool,
+    /// PCID used the last time the address space was switched to.
+    pcid: u16,
 }
 
 impl L4 {
+    /// Create a new L4. With five-level paging, it is mapped by an [`L5`] and does
+    /// not map the kernel.
     #[allow(unused_mut)]
     pub fn new(mut boxed: Boxed<PML4Table>) -> Self {
         #[cfg(not(test))]
-        unsafe {
-            let current_page_table_paddr: u64 = utils::cr3().into();
-            let current_page_table: &PML4 = &*(current_page_table_paddr as *const _);
+        if !la57::is_active() {
+            // Task address spaces do not map low memory, so the table is read
+            // through the physical memory window.
+            let current_page_table: &PML4 = unsafe { utils::cr3().to_paddr_global().as_mut_ptr() };
             boxed[510] = current_page_table[510];
             boxed[511] = current_page_table[511];
         }
+        // Five-level paging is not used with page table isolation.
+        #[cfg(feature = "kpti")]
+        {
+            let [_, user_table] = isolated_tables(&mut boxed);
+            kpti::initialize_user_table(user_table);
+        }
 
         Self {
             is_derived: false,
             linked_task: None,
             page_data: boxed,
             child_paging_item: None,
+            pcid: 0,
         }
     }
 
+    /// Switch to the address space. The TLB entries of the address space
+    /// are kept if it still owns its PCID.
     pub fn switch_to(&mut self) {
-        use crate::arch::paging;
+        #[cfg(feature = "kpti")]
+        {
+            let [table, user_table] = isolated_tables(&mut self.page_data);
+            kpti::update_user_table(table, user_table);
+        }
+        self.pcid = unsafe { pcid::switch_to(self.page_data.paddr_global().to_paddr(), self.pcid) };
+    }
 
-        unsafe {
-            paging::utils::switch_to(self.page_data.paddr_global().to_paddr());
+    /// Invalidate the TLB entry of `vaddr` in this address space. Must be
+    /// called after a mapping is removed or changed.
+    pub fn invalidate(&self, vaddr: VAddr) {
+        pcid::invalidate(self.page_data.paddr_global().to_paddr(), self.pcid, vaddr);
+    }
+
+    /// Physical address of the page table that holds the entry for `vaddr` at the
+    /// level of the given page type.
+    fn leaf_table(&self, vaddr: VAddr, page_type: u8) -> Option<PAddr> {
+        let l3_entry = self.page_data[pml4_index(vaddr)];
+        if !l3_entry.is_present() {
+            return None;
         }
+        let l3_paddr = l3_entry.get_address();
+        if page_type == 2 {
+            return Some(l3_paddr);
+        }
+
+        let l3: &PDPTTable = unsafe { l3_paddr.to_paddr_global().as_mut_ptr() };
+        let l2_entry = l3[pdpt_index(vaddr)];
+        if !l2_entry.is_present() {
+            return None;
+        }
+        let l2_paddr = l2_entry.get_address();
+        if page_type == 3 {
+            return Some(l2_paddr);
+        }
+
+        let l2: &PDTable = unsafe { l2_paddr.to_paddr_global().as_mut_ptr() };
+        let l1_entry = l2[pd_index(vaddr)];
+        if !l1_entry.is_present() {
+            return None;
+        }
+        Some(l1_entry.get_address())
+    }
+
+    /// Give up the PCID of the address space. Must be called when the
+    /// address space is deleted.
+    pub fn release_pcid(&mut self) {
+        pcid::release(self.page_data.paddr_global().to_paddr(), self.pcid);
+        self.pcid = 0;
     }
 }
 
+/// The PML4 and the user page table that follows it.
+#[cfg(feature = "kpti")]
+fn isolated_tables(table: &mut PML4Table) -> &mut [PML4; 2] {
+    unsafe { &mut *(table as *mut PML4Table as *mut [PML4; 2]) }
+}
+
 impl CapAccessorMut<'_, L4> {
     /**
     Map the given raw page in the provided L4 table at the given virtual address.
@@ -119,6 +193,12 @@ impl CapAccessorMut<'_, L4> {
             if perms.contains(MapPermissions::CACHE_DISABLE) {
                 target_perms |= PDPTEntry::CACHE_DISABLE;
             }
+            if perms.contains(MapPermissions::WRITE_THROUGH) {
+                target_perms |= PDPTEntry::WRITE_THROUGH;
+            }
+            if perms.contains(MapPermissions::WRITE_COMBINING) {
+                target_perms |= PDPTEntry::PAT;
+            }
             return pdpt_cap.l3_map_huge_page(
                 pdpt_index,
                 &mut raw_page.as_huge_page_mut().unwrap(),
@@ -177,6 +257,12 @@ impl CapAccessorMut<'_, L4> {
             if perms.contains(MapPermissions::CACHE_DISABLE) {
                 target_perms |= PDEntry::CACHE_DISABLE;
             }
+            if perms.contains(MapPermissions::WRITE_THROUGH) {
+                target_perms |= PDEntry::WRITE_THROUGH;
+            }
+            if perms.contains(MapPermissions::WRITE_COMBINING) {
+                target_perms |= PDEntry::PAT;
+            }
             return pd_cap.l2_map_large_page(
                 pd_index,
                 &mut raw_page.as_large_page_mut().unwrap(),
@@ -228,6 +314,12 @@ impl CapAccessorMut<'_, L4> {
         if perms.contains(MapPermissions::CACHE_DISABLE) {
             target_perms |= PTEntry::CACHE_DISABLE;
         }
+        if perms.contains(MapPermissions::WRITE_THROUGH) {
+            target_perms |= PTEntry::WRITE_THROUGH;
+        }
+        if perms.contains(MapPermissions::WRITE_COMBINING) {
+            target_perms |= PTEntry::PAT;
+        }
         pt_cap.l1_map_base_page(
             pt_index,
             &mut raw_page.as_base_page_mut().unwrap(),
@@ -236,6 +328,74 @@ impl CapAccessorMut<'_, L4> {
     }
 }
 
+/// Clear the entry of a raw page in its page table and remove the page from the
+/// paging tree. The page table must be `$table`.
+macro_rules! unmap_page {
+    ($raw_page: expr, $table: expr, $page: ident, $parent: ident, $index: expr, $entry: ty) => {
+        paste! {{
+            let parent = $raw_page
+                .[<$page _parent>]()
+                .ok_or(CapabilityErrors::MemoryNotMapped)?;
+            let mut parent = parent.[<as_ $parent _mut>]()?;
+            let page = $raw_page.[<as_ $page>]()?;
+            let page_paddr = page.start_paddr().to_paddr();
+            let mapped_index = page.mapped_index;
+            core::mem::drop(page);
+            let entry = parent.page_data[$index];
+            // Another mapping of the page might be in the same table.
+            if parent.start_paddr().to_paddr() != $table
+                || mapped_index != Some($index)
+                || !entry.is_present()
+                || entry.get_address() != page_paddr
+            {
+                return Err(CapabilityErrors::MemoryNotMapped);
+            }
+
+            parent.page_data[$index] = <$entry>::empty();
+            $raw_page.[<$page _unlink_paging>](&mut parent);
+        }}
+    };
+}
+
+impl CapAccessorMut<'_, L4> {
+    /**
+    Unmap the raw page mapped at the given virtual address and invalidate it in the
+    TLB. `raw_page` must be a mapping of the page in the page table that holds the
+    entry, usually the capability that was used to map it.
+    */
+    pub fn l4_unmap(&mut self, vaddr: VAddr, raw_page: &StoredCap) -> Result<(), CapabilityErrors> {
+        self.unmap_page(vaddr, raw_page)?;
+        self.invalidate(vaddr);
+        Ok(())
+    }
+
+    /// Unmap the raw page like [`Self::l4_unmap`], but leave the TLB to the caller.
+    pub(super) fn unmap_page(
+        &mut self,
+        vaddr: VAddr,
+        raw_page: &StoredCap,
+    ) -> Result<(), CapabilityErrors> {
+        let page_type = {
+            match &raw_page.borrow().capability_data {
+                CapabilityEnum::BasePage(_) => 4,
+                CapabilityEnum::LargePage(_) => 3,
+                CapabilityEnum::HugePage(_) => 2,
+                _ => return Err(CapabilityErrors::CapabilityMismatch),
+            }
+        };
+        let table = self
+            .leaf_table(vaddr, page_type)
+            .ok_or(CapabilityErrors::MemoryNotMapped)?;
+
+        match page_type {
+            4 => unmap_page!(raw_page, table, base_page, l1, pt_index(vaddr), PTEntry),
+            3 => unmap_page!(raw_page, table, large_page, l2, pd_index(vaddr), PDEntry),
+            _ => unmap_page!(raw_page, table, huge_page, l3, pdpt_index(vaddr), PDPTEntry),
+        }
+        Ok(())
+    }
+}
+
 impl StoredCap {
     /**
     Copy an l4 into the provided cpool.
@@ -250,6 +410,8 @@ impl StoredCap {
             is_derived: true,
             linked_task: None,
             page_data: unsafe { l4_accessor.page_data.unsafe_clone() },
+            // Both capabilities share the page tables and so the PCID.
+            pcid: l4_accessor.pcid,
         };
         core::mem::drop(l4_accessor);
 
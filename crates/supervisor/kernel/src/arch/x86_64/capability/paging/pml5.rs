@@ -0,0 +1,366 @@
+/*!
+Address spaces with five-level paging.
+
+With five-level paging, an [`L5`] is the root of an address space. Each entry of
+the user half of its PML5 maps an [`L4`] that covers 256 TiB. These L4s are created
+when the first page in their range is mapped. Unlike the lower levels, they are not
+linked into the paging tree, but kept in the table that follows the PML5. Pages are
+mapped, unmapped and copied on write by the L4 that covers their address.
+*/
+
+use relic_abi::cap::CapabilityErrors;
+
+use super::{
+    cow::{clone_l4_table, CloneCount, ClonePools, PageCopy},
+    *,
+};
+use crate::{
+    addr::{PAddr, VAddr},
+    arch::paging::{pcid, utils},
+    util::boxed::Boxed,
+};
+
+/// Number of entries in the user half of a PML5.
+const USER_ENTRIES: usize = 256;
+
+/// The PML5 of an [`L5`] and the [`L4`] mapped by each entry of its user half.
+#[derive(Debug)]
+#[repr(C, align(4096))]
+pub struct PML5Table {
+    pub entries: PML5,
+    pub children: [Option<StoredCap>; USER_ENTRIES],
+}
+
+#[derive(Debug)]
+pub struct L5 {
+    pub page_data: Boxed<PML5Table>,
+    pub linked_task: Option<StoredCap>,
+
+    is_derived: bool,
+    /// PCID used the last time the address space was switched to.
+    pcid: u16,
+}
+
+impl L5 {
+    #[allow(unused_mut)]
+    pub fn new(mut boxed: Boxed<PML5Table>) -> Self {
+        #[cfg(not(test))]
+        unsafe {
+            // The kernel half is a single PML4 that is shared by all address spaces.
+            let current_page_table: &PML5 = utils::cr3().to_paddr_global().as_mut_ptr();
+            boxed.entries[511] = current_page_table[511];
+        }
+
+        Self {
+            is_derived: false,
+            linked_task: None,
+            page_data: boxed,
+            pcid: 0,
+        }
+    }
+
+    pub fn start_paddr(&self) -> PAddrGlobal {
+        self.page_data.paddr_global()
+    }
+
+    /// Switch to the address space. The TLB entries of the address space
+    /// are kept if it still owns its PCID.
+    pub fn switch_to(&mut self) {
+        self.pcid = unsafe { pcid::switch_to(self.start_paddr().to_paddr(), self.pcid) };
+    }
+
+    /// Invalidate the TLB entry of `vaddr` in this address space. Must be
+    /// called after a mapping is removed or changed.
+    pub fn invalidate(&self, vaddr: VAddr) {
+        pcid::invalidate(self.start_paddr().to_paddr(), self.pcid, vaddr);
+    }
+
+    /// Give up the PCID of the address space. Must be called when the
+    /// address space is deleted.
+    pub fn release_pcid(&mut self) {
+        pcid::release(self.start_paddr().to_paddr(), self.pcid);
+        self.pcid = 0;
+    }
+
+    /// The L4 that covers `vaddr`.
+    fn child(&self, vaddr: VAddr) -> Result<StoredCap, CapabilityErrors> {
+        self.page_data
+            .children
+            .get(pml5_index(vaddr))
+            .cloned()
+            .flatten()
+            .ok_or(CapabilityErrors::MemoryNotMapped)
+    }
+}
+
+impl StoredCap {
+    /**
+    Create an L5 from untyped memory and store it in the provided cpool. Returns
+    the created capability and its index in the cpool.
+    */
+    pub fn pml5_retype_from(
+        untyped: &mut CapAccessorMut<'_, UntypedMemory>,
+        cpool_to_store_in: &mut Cpool,
+    ) -> Result<(StoredCap, usize), CapabilityErrors> {
+        let mut result_index = 0;
+
+        let result = untyped.derive(None, false, |memory: *mut PML5Table| {
+            unsafe {
+                core::ptr::write_bytes(memory, 0, 1);
+            }
+            let boxed = unsafe { Boxed::new((memory as u64).into()) };
+
+            let stored_index = cpool_to_store_in.get_free_index()?;
+            let cap = cpool_to_store_in.write_to_if_empty(
+                stored_index,
+                Capability {
+                    capability_data: CapabilityEnum::L5(L5::new(boxed)),
+                    ..Default::default()
+                },
+            )?;
+
+            result_index = stored_index;
+            Ok(cap)
+        })?;
+
+        Ok((result, result_index))
+    }
+
+    /**
+    Copy an l5 into the provided cpool.
+    */
+    pub fn l5_copy(
+        source_l5: &StoredCap,
+        cpool_to_store_in: &StoredCap,
+    ) -> Result<(StoredCap, usize), CapabilityErrors> {
+        let l5_accessor = source_l5.as_l5_mut()?;
+        let new_l5 = L5 {
+            is_derived: true,
+            linked_task: None,
+            page_data: unsafe { l5_accessor.page_data.unsafe_clone() },
+            // Both capabilities share the page tables and so the PCID.
+            pcid: l5_accessor.pcid,
+        };
+        core::mem::drop(l5_accessor);
+
+        let new_l5_cap = Capability {
+            capability_data: CapabilityEnum::L5(new_l5),
+            ..Default::default()
+        };
+
+        let mut cpool = cpool_to_store_in.as_cpool_mut()?;
+        let free_index = cpool.get_free_index()?;
+        let result = cpool.write_to_if_empty(free_index, new_l5_cap)?;
+
+        source_l5.insert_next_mem_item(&result);
+        Ok((result, free_index))
+    }
+
+    /**
+    Clone the address space of `source` into a new L5 stored in the provided cpool.
+    Every L4 is cloned like in [`StoredCap::l4_clone`] and stored in the clone pools
+    with the lower tables. Returns the new L5 and its index in the cpool.
+    */
+    pub fn l5_clone(
+        source: &StoredCap,
+        untyped: &mut CapAccessorMut<'_, UntypedMemory>,
+        cpool: &mut CapAccessorMut<'_, Cpool>,
+    ) -> Result<(StoredCap, usize), CapabilityErrors> {
+        let source_l5 = source.as_l5()?;
+        let mut count = CloneCount::default();
+        for l4 in source_l5.page_data.children.iter().flatten() {
+            count.l4_tables += 1;
+            count.add_children(l4.as_l4()?.child_paging_item.clone())?;
+        }
+        let root = (
+            core::mem::size_of::<PML5Table>(),
+            core::mem::align_of::<PML5Table>(),
+        );
+        ClonePools::check(untyped, cpool, root, count)?;
+
+        let (clone, clone_index) = StoredCap::pml5_retype_from(untyped, cpool)?;
+        let mut pools = ClonePools::new(untyped, cpool, count)?;
+        let mut clone_l5 = clone.as_l5_mut()?;
+
+        for index in 0..USER_ENTRIES {
+            let source_l4 = match source_l5.page_data.children[index].clone() {
+                Some(l4) => l4,
+                None => continue,
+            };
+            let (clone_l4, _) = pools.store(|pool| StoredCap::pml4_retype_from(untyped, pool))?;
+            let mut clone_l4 = clone_l4.as_l4_mut()?;
+            let flags = PML5Entry::from_bits_truncate(source_l5.page_data.entries[index].bits());
+            clone_l5.page_data.entries[index] =
+                PML5Entry::new(clone_l4.start_paddr().to_paddr(), flags);
+            clone_l5.page_data.children[index] = Some(clone_l4.cap().clone());
+            clone_l4_table(
+                &mut source_l4.as_l4_mut()?,
+                &mut clone_l4,
+                untyped,
+                &mut pools,
+            )?;
+        }
+
+        // Writable pages of the source are read only now.
+        pcid::invalidate_all();
+        core::mem::drop(clone_l5);
+        Ok((clone, clone_index))
+    }
+}
+
+impl CapAccessorMut<'_, L5> {
+    /**
+    Map the given raw page at the given virtual address like [`CapAccessorMut::l4_map`].
+    The L4 that covers the address is created in `untyped` and stored in
+    `store_cpool` if it does not exist yet.
+    */
+    pub fn l5_map(
+        &mut self,
+        vaddr: VAddr,
+        raw_page: &StoredCap,
+        untyped: &mut CapAccessorMut<'_, UntypedMemory>,
+        store_cpool: &mut CapAccessorMut<'_, Cpool>,
+        search_cpool: Option<&mut CapAccessorMut<'_, Cpool>>,
+        perms: MapPermissions,
+    ) -> Result<(), CapabilityErrors> {
+        let index = pml5_index(vaddr);
+        if index >= USER_ENTRIES {
+            return Err(CapabilityErrors::InvalidMemoryAddress);
+        }
+
+        let l4 = match self.page_data.children[index].clone() {
+            Some(l4) => l4,
+            None => {
+                let (l4, _) = StoredCap::pml4_retype_from(untyped, store_cpool)?;
+                let paddr: PAddr = l4.as_l4()?.start_paddr().to_paddr();
+                let flags = PML5Entry::PRESENT | PML5Entry::USERSPACE | PML5Entry::READ_WRITE;
+                self.page_data.entries[index] = PML5Entry::new(paddr, flags);
+                self.page_data.children[index] = Some(l4.clone());
+                l4
+            }
+        };
+
+        let mut l4_accessor = l4.as_l4_mut()?;
+        l4_accessor.l4_map(vaddr, raw_page, untyped, store_cpool, search_cpool, perms)
+    }
+
+    /// Unmap the raw page mapped at the given virtual address like
+    /// [`CapAccessorMut::l4_unmap`].
+    pub fn l5_unmap(&mut self, vaddr: VAddr, raw_page: &StoredCap) -> Result<(), CapabilityErrors> {
+        let l4 = self.child(vaddr)?;
+        l4.as_l4_mut()?.unmap_page(vaddr, raw_page)?;
+        self.invalidate(vaddr);
+        Ok(())
+    }
+
+    /// Resolve a write to the copy-on-write page mapped at `vaddr` like
+    /// [`CapAccessorMut::l4_copy_on_write`].
+    pub fn l5_copy_on_write(
+        &mut self,
+        vaddr: VAddr,
+        untyped: &mut UntypedMemory,
+        cpool: &mut Cpool,
+    ) -> Result<(), CapabilityErrors> {
+        let l4 = self.child(vaddr)?;
+        l4.as_l4_mut()?
+            .copy_page(vaddr, PageCopy::Write, untyped, cpool)?;
+        self.invalidate(vaddr);
+        Ok(())
+    }
+
+    /// Make the page mapped at `vaddr` private to this address space like
+    /// [`CapAccessorMut::l4_make_private`].
+    pub fn l5_make_private(
+        &mut self,
+        vaddr: VAddr,
+        untyped: &mut UntypedMemory,
+        cpool: &mut Cpool,
+    ) -> Result<(), CapabilityErrors> {
+        let l4 = self.child(vaddr)?;
+        l4.as_l4_mut()?
+            .copy_page(vaddr, PageCopy::Private, untyped, cpool)?;
+        self.invalidate(vaddr);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        arch::{
+            paging::la57,
+            user_memory::{copy_from_user, copy_to_user, UserPtr},
+        },
+        capability::test_fixture::Fixture,
+    };
+
+    #[test]
+    fn test_l5_map() {
+        la57::set_active(true);
+        let fixture = Fixture::new();
+        let mut untyped = fixture.untyped();
+        let mut cpool = fixture.cpool();
+
+        let l5 = StoredCap::address_space_retype_from(&mut untyped, &mut cpool).unwrap();
+        let low_page =
+            StoredCap::base_page_retype_from::<[u8; 4096]>(&mut untyped, &mut cpool, true).unwrap();
+        let high_page =
+            StoredCap::base_page_retype_from::<[u8; 4096]>(&mut untyped, &mut cpool, true).unwrap();
+        let low_vaddr: VAddr = 0x1000u64.into();
+        let high_vaddr: VAddr = 0x1_0000_0000_1000u64.into();
+        for (vaddr, page) in [(low_vaddr, &low_page), (high_vaddr, &high_page)].iter() {
+            l5.0.address_space_map(
+                *vaddr,
+                &page.0,
+                &mut untyped,
+                &mut cpool,
+                None,
+                MapPermissions::WRITE,
+            )
+            .unwrap();
+        }
+
+        // Each 256 TiB of the user half gets its own L4.
+        {
+            let l5_0 = l5.0.as_l5().unwrap();
+            let children = &l5_0.page_data.children;
+            assert!(children[0].is_some() && children[1].is_some() && children[2].is_none());
+            assert!(l5_0.page_data.entries[1].is_present());
+            assert!(l5_0.page_data.entries[1].is_user_mode_allowed());
+            assert!(!l5_0.page_data.entries[2].is_present());
+        }
+
+        // The kernel half is never mapped from user mode.
+        assert_matches!(
+            l5.0.address_space_map(
+                0x100_0000_0000_0000u64.into(),
+                &low_page.0,
+                &mut untyped,
+                &mut cpool,
+                None,
+                MapPermissions::WRITE,
+            ),
+            Err(CapabilityErrors::InvalidMemoryAddress)
+        );
+
+        // User memory is found by walking through the PML5.
+        let root = l5.0.address_space_paddr().unwrap();
+        let ptr = UserPtr::<u64>::new(high_vaddr + 8u64).unwrap();
+        copy_to_user(root, ptr, &0xC0FFEE).unwrap();
+        assert_eq!(
+            &high_page.0.as_base_page().unwrap().page_data_raw()[8..16],
+            &0xC0FFEEu64.to_le_bytes()
+        );
+        assert_eq!(
+            copy_from_user(root, UserPtr::<u64>::new(low_vaddr).unwrap()).unwrap(),
+            0
+        );
+
+        l5.0.address_space_unmap(high_vaddr, &high_page.0).unwrap();
+        assert_matches!(
+            copy_from_user(root, ptr),
+            Err(CapabilityErrors::InvalidMemoryAddress)
+        );
+    }
+}
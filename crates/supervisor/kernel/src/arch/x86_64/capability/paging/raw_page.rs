@@ -1,7 +1,7 @@
 use relic_abi::cap::CapabilityErrors;
 
 use super::*;
-use crate::util::boxed::Boxed;
+use crate::{addr::PAddr, arch::paging::pcid, util::boxed::Boxed};
 
 #[derive(Debug)]
 pub struct RawPageActual<const SIZE: usize> {
@@ -10,13 +10,18 @@ pub struct RawPageActual<const SIZE: usize> {
 
     pub next_paging_item: Option<StoredCap>,
     pub prev_paging_item: Option<StoredCap>,
+    /// Index of the entry that maps the page in its page table. Other mapping
+    /// capabilities of the page might be mapped in the same table.
+    pub mapped_index: Option<usize>,
+    /// Whether the page was retyped from device untyped memory.
+    pub is_device_memory: bool,
 }
 
 #[derive(Debug)]
 struct Inner<const SIZE: usize>([u8; SIZE]);
 
 macro_rules! raw_page_impl {
-    ($name: ty, $size: tt) => {
+    ($name: ty, $size: tt, $parent: ty, $entry: ty) => {
         paste! {
             impl StoredCap {
                 pub fn [<$name:snake _retype_from>]<T: 'static>(
@@ -27,6 +32,7 @@ macro_rules! raw_page_impl {
                     assert!(core::mem::size_of::<T>() <= $size);
                     assert!(core::mem::align_of::<T>() <= $size);
                     let mut result_index = 0;
+                    let is_device_memory = untyped.is_device_memory();
 
                     let cap = untyped.derive(Some($size), true, |memory: *mut Inner<$size>| {
                         if zero_out {
@@ -44,8 +50,10 @@ macro_rules! raw_page_impl {
                                 capability_data: CapabilityEnum::$name($name {
                                     next_paging_item: None,
                                     prev_paging_item: None,
+                                    mapped_index: None,
                                     linked_task: None,
                                     page_data: boxed,
+                                    is_device_memory,
                                 }),
                                 ..Default::default()
                             },
@@ -57,14 +65,346 @@ macro_rules! raw_page_impl {
 
                     Ok((cap, result_index))
                 }
+
+                /**
+                Create a mapping capability for the same page in the provided cpool. The
+                copy can be mapped into another page table with its own permissions. It is
+                tracked in the memory derivation tree right after the source.
+
+                Returns the Stored capability and the target index.
+                */
+                pub fn [<$name:snake _copy>](
+                    source: &StoredCap,
+                    cpool_to_store_in: &mut Cpool,
+                ) -> Result<(StoredCap, usize), CapabilityErrors> {
+                    let source_accessor = source.[<as_ $name:snake>]()?;
+                    let new_page = $name {
+                        page_data: unsafe { source_accessor.page_data.unsafe_clone() },
+                        linked_task: None,
+                        next_paging_item: None,
+                        prev_paging_item: None,
+                        mapped_index: None,
+                        is_device_memory: source_accessor.is_device_memory,
+                    };
+                    core::mem::drop(source_accessor);
+
+                    let free_index = cpool_to_store_in.get_free_index()?;
+                    let result = cpool_to_store_in.write_to_if_empty(
+                        free_index,
+                        Capability {
+                            capability_data: CapabilityEnum::$name(new_page),
+                            ..Default::default()
+                        },
+                    )?;
+
+                    source.insert_next_mem_item(&result);
+                    Ok((result, free_index))
+                }
+
+                /**
+                Find the page table that the page is mapped in by walking back through
+                its siblings in the paging tree.
+                */
+                pub fn [<$name:snake _parent>](&self) -> Option<StoredCap> {
+                    let mut current = self.[<as_ $name:snake>]().ok()?.prev_paging_item.clone();
+                    while let Some(item) = current {
+                        if let CapabilityEnum::$parent(_) = item.borrow().capability_data {
+                            return Some(item);
+                        }
+                        current = item.borrow_mut().get_prev_paging_item_mut().clone();
+                    }
+                    None
+                }
+
+                /**
+                Remove the page from the paging tree of `parent`. The page table entry
+                must be cleared by the caller.
+                */
+                pub fn [<$name:snake _unlink_paging>](&self, parent: &mut CapAccessorMut<'_, $parent>) {
+                    let (prev, next) = {
+                        let mut page = self.[<as_ $name:snake _mut>]().unwrap();
+                        page.mapped_index = None;
+                        (page.prev_paging_item.take(), page.next_paging_item.take())
+                    };
+
+                    if let Some(next) = &next {
+                        *next.borrow_mut().get_prev_paging_item_mut() = prev.clone();
+                    }
+                    match prev {
+                        Some(prev) if prev.as_ptr() == parent.cap().as_ptr() => {
+                            parent.child_paging_item = next;
+                        }
+                        Some(prev) => *prev.borrow_mut().get_next_paging_item_mut() = next,
+                        None => {}
+                    }
+                }
+
+                /**
+                Remove the page from the page table it is mapped in, if any. Only the
+                entry of this capability is cleared, as other mappings of the page might
+                be in the same table. Returns whether the page was mapped.
+                */
+                fn [<$name:snake _unmap_any>](&self) -> bool {
+                    let parent = match self.[<$name:snake _parent>]() {
+                        Some(parent) => parent,
+                        None => return false,
+                    };
+                    let mut parent = parent.[<as_ $parent:snake _mut>]().unwrap();
+                    let index = self.[<as_ $name:snake>]().unwrap().mapped_index;
+
+                    if let Some(index) = index {
+                        parent.page_data[index] = <$entry>::empty();
+                    }
+                    self.[<$name:snake _unlink_paging>](&mut parent);
+                    true
+                }
+
+                /**
+                Unmap and delete every mapping capability copied from this page. The
+                page itself is left untouched. Fails if the page is a copy itself, if one
+                of the copies is used by a task, or if a capability next to the copies is
+                borrowed, as it cannot be told apart from a copy then.
+
+                Copies are found through the memory derivation tree, where they are kept
+                right after their source. The paging tree only links the items of one page
+                table, so it cannot find copies that are mapped in other address spaces.
+                */
+                pub fn [<$name:snake _revoke>](&self) -> Result<(), CapabilityErrors> {
+                    let page_paddr = self.[<as_ $name:snake>]()?.start_paddr();
+                    let is_copy_of_page = |cap: &StoredCap| match cap.try_borrow() {
+                        Ok(cap) => Ok(matches!(
+                            &cap.capability_data,
+                            CapabilityEnum::$name(page) if page.start_paddr() == page_paddr
+                        )),
+                        Err(_) => Err(CapabilityErrors::CapabilityAlreadyInUse),
+                    };
+
+                    if let Some(prev) = self.borrow().prev_mem_item.as_ref() {
+                        if is_copy_of_page(prev)? {
+                            return Err(CapabilityErrors::CapabilityIsDerived);
+                        }
+                    }
+
+                    let mut copies: heapless::Vec<StoredCap, 256> = heapless::Vec::new();
+                    let mut current = self.borrow().next_mem_item.clone();
+                    while let Some(copy) = current.clone() {
+                        if !is_copy_of_page(&copy)? {
+                            break;
+                        }
+                        if copy.[<as_ $name:snake>]()?.linked_task.is_some() {
+                            return Err(CapabilityErrors::CapabilityAlreadyOccupied);
+                        }
+                        current = copy.borrow().next_mem_item.clone();
+                        copies
+                            .push(copy)
+                            .map_err(|_| CapabilityErrors::CapabilitySlotsFull)?;
+                    }
+
+                    let mut unmapped = false;
+                    for copy in copies.iter() {
+                        unmapped |= copy.[<$name:snake _unmap_any>]();
+                    }
+
+                    // Unlink the copies from the memory derivation tree and delete them.
+                    unsafe {
+                        if let Some(next) = &current {
+                            (*next.as_ptr()).prev_mem_item = Some(self.clone());
+                        }
+                        (*self.as_ptr()).next_mem_item = current;
+                    }
+                    for copy in copies {
+                        // The page memory is owned by this capability.
+                        core::mem::forget(core::mem::replace(
+                            &mut *copy.borrow_mut(),
+                            Capability::new(),
+                        ));
+                    }
+
+                    // The copies might be mapped into any address space.
+                    if unmapped {
+                        pcid::invalidate_all();
+                    }
+                    Ok(())
+                }
+
+                /**
+                Whether another mapping capability of the same page is mapped. Copies
+                are kept next to each other in the memory derivation tree.
+                */
+                fn [<$name:snake _is_shared>](&self) -> bool {
+                    let page_paddr = match self.[<as_ $name:snake>]() {
+                        Ok(page) => page.start_paddr(),
+                        Err(_) => return false,
+                    };
+                    // Whether the capability is mapped, if it is a copy of the page.
+                    let is_mapped = |cap: &StoredCap| match cap.try_borrow() {
+                        Ok(cap) => match &cap.capability_data {
+                            CapabilityEnum::$name(page) if page.start_paddr() == page_paddr => {
+                                Some(page.prev_paging_item.is_some())
+                            }
+                            _ => None,
+                        },
+                        Err(_) => None,
+                    };
+
+                    let mut first = self.clone();
+                    loop {
+                        let prev = first.borrow().prev_mem_item.clone();
+                        match prev.filter(|prev| is_mapped(prev).is_some()) {
+                            Some(prev) => first = prev,
+                            None => break,
+                        }
+                    }
+
+                    let mut current = Some(first);
+                    while let Some(page) = current {
+                        match is_mapped(&page) {
+                            Some(true) if page.as_ptr() != self.as_ptr() => return true,
+                            Some(_) => {}
+                            None => break,
+                        }
+                        current = page.borrow().next_mem_item.clone();
+                    }
+                    false
+                }
+
+                /**
+                Map a copy of the page at `index` of `clone_table` for a copy-on-write
+                clone of `source_table`, where the page is mapped at the same index. A
+                writable page becomes read only in both tables and is marked copy-on-write.
+                Pages linked to a task stay writable in the source, so only the clone
+                copies them.
+
+                Device memory and pages that are not mapped write-back cached stay shared
+                and writable in both tables, as a copy in memory would no longer reach the
+                device or the memory type of the mapping.
+                */
+                pub fn [<$name:snake _clone_mapping>](
+                    &self,
+                    source_table: &mut CapAccessorMut<'_, $parent>,
+                    clone_table: &mut CapAccessorMut<'_, $parent>,
+                    index: usize,
+                    cpool: &mut Cpool,
+                ) -> Result<(), CapabilityErrors> {
+                    let (copy, _) = StoredCap::[<$name:snake _copy>](self, cpool)?;
+
+                    let entry = source_table.page_data[index];
+                    let mut flags = <$entry>::from_bits_truncate(entry.bits());
+                    let memory_types =
+                        <$entry>::WRITE_THROUGH | <$entry>::CACHE_DISABLE | <$entry>::PAT;
+                    let is_shared = self.[<as_ $name:snake>]()?.is_device_memory
+                        || flags.intersects(memory_types);
+                    if flags.contains(<$entry>::READ_WRITE) && !is_shared {
+                        flags.remove(<$entry>::READ_WRITE);
+                        flags.insert(<$entry>::COPY_ON_WRITE);
+                        if self.[<as_ $name:snake>]()?.linked_task.is_none() {
+                            source_table.page_data[index] = <$entry>::new(entry.get_address(), flags);
+                        }
+                    }
+
+                    let mut copy = copy.[<as_ $name:snake _mut>]()?;
+                    clone_table.[<$parent:lower _map_ $name:snake>](index, &mut copy, Some(flags))
+                }
+
+                /**
+                Resolve a write to the copy-on-write page mapped at `index` of `table`. If
+                the page is still mapped by another capability, its content is copied into a
+                new page allocated from `untyped` and stored in `cpool`, which replaces this
+                page in the table. Otherwise the page is made writable again. The TLB entry
+                must be invalidated by the caller.
+                */
+                pub fn [<$name:snake _copy_on_write>](
+                    &self,
+                    table: &mut CapAccessorMut<'_, $parent>,
+                    index: usize,
+                    untyped: &mut UntypedMemory,
+                    cpool: &mut Cpool,
+                ) -> Result<(), CapabilityErrors> {
+                    let entry = table.page_data[index];
+                    if !entry.contains(<$entry>::COPY_ON_WRITE) {
+                        return Err(CapabilityErrors::MemoryNotMapped);
+                    }
+                    let mut flags = <$entry>::from_bits_truncate(entry.bits());
+                    flags.remove(<$entry>::COPY_ON_WRITE);
+                    flags.insert(<$entry>::READ_WRITE);
+
+                    if !self.[<$name:snake _is_shared>]() {
+                        table.page_data[index] = <$entry>::new(entry.get_address(), flags);
+                        return Ok(());
+                    }
+                    self.[<$name:snake _replace_with_copy>](table, index, flags, untyped, cpool)
+                }
+
+                /**
+                Make sure that the page mapped at `index` of `table` is not mapped by any
+                other capability, so that writing to it through its physical memory only
+                changes this mapping. Copy-on-write pages are resolved like a write, and
+                other shared pages are copied with their permissions kept. The TLB entry
+                must be invalidated by the caller.
+                */
+                pub fn [<$name:snake _make_private>](
+                    &self,
+                    table: &mut CapAccessorMut<'_, $parent>,
+                    index: usize,
+                    untyped: &mut UntypedMemory,
+                    cpool: &mut Cpool,
+                ) -> Result<(), CapabilityErrors> {
+                    let entry = table.page_data[index];
+                    if entry.contains(<$entry>::COPY_ON_WRITE) {
+                        return self.[<$name:snake _copy_on_write>](table, index, untyped, cpool);
+                    }
+                    if !self.[<$name:snake _is_shared>]() {
+                        return Ok(());
+                    }
+                    let flags = <$entry>::from_bits_truncate(entry.bits());
+                    self.[<$name:snake _replace_with_copy>](table, index, flags, untyped, cpool)
+                }
+
+                /// Replace the page at `index` of `table` by a copy of its content that is
+                /// allocated from `untyped`, stored in `cpool` and mapped with `flags`.
+                fn [<$name:snake _replace_with_copy>](
+                    &self,
+                    table: &mut CapAccessorMut<'_, $parent>,
+                    index: usize,
+                    flags: $entry,
+                    untyped: &mut UntypedMemory,
+                    cpool: &mut Cpool,
+                ) -> Result<(), CapabilityErrors> {
+                    let (page, _) =
+                        StoredCap::[<$name:snake _retype_from>]::<[u8; $size]>(untyped, cpool, false)?;
+                    let mut page = page.[<as_ $name:snake _mut>]()?;
+                    page.page_data_mut_raw()
+                        .copy_from_slice(self.[<as_ $name:snake>]()?.page_data_raw());
+
+                    table.page_data[index] = <$entry>::empty();
+                    self.[<$name:snake _unlink_paging>](table);
+                    table.[<$parent:lower _map_ $name:snake>](index, &mut page, Some(flags))
+                }
             }
         }
     };
 }
 
-raw_page_impl!(BasePage, 0x1000);
-raw_page_impl!(LargePage, 0x20_0000);
-raw_page_impl!(HugePage, 0x4000_0000);
+raw_page_impl!(BasePage, 0x1000, L1, PTEntry);
+raw_page_impl!(LargePage, 0x20_0000, L2, PDEntry);
+raw_page_impl!(HugePage, 0x4000_0000, L3, PDPTEntry);
+
+impl StoredCap {
+    /**
+    Physical address and length of the raw page, whatever its size. Fails with
+    [`CapabilityErrors::CapabilityMismatch`] if the capability is not a raw page.
+    */
+    pub fn raw_page_physical(&self) -> Result<(PAddr, usize), CapabilityErrors> {
+        if let Ok(page) = self.as_base_page() {
+            return Ok((page.start_paddr().to_paddr(), page.length()));
+        }
+        if let Ok(page) = self.as_large_page() {
+            return Ok((page.start_paddr().to_paddr(), page.length()));
+        }
+        let page = self.as_huge_page()?;
+        Ok((page.start_paddr().to_paddr(), page.length()))
+    }
+}
 
 impl<const SIZE: usize> RawPageActual<SIZE> {
     pub fn start_paddr(&self) -> PAddrGlobal {
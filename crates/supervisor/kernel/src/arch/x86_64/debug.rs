@@ -0,0 +1,272 @@
+/*!
+Debug exception support for the GDB stub.
+
+The breakpoint (`#BP`) and debug (`#DB`) exceptions save every general purpose
+register into a [`TrapFrame`] so that the stub can inspect and modify the state
+of the interrupted code before resuming it.
+*/
+
+use relic_abi::{cap::CapabilityErrors, task::TaskRegisters};
+use x86_64::registers::{
+    debug::{
+        BreakpointCondition, BreakpointSize, DebugAddressRegister, DebugAddressRegisterNumber, Dr0,
+        Dr1, Dr2, Dr3, Dr6, Dr6Flags, Dr7, Dr7Flags,
+    },
+    rflags::RFlags,
+};
+
+pub use crate::arch::interrupts::trap::TrapFrame;
+use crate::{
+    addr::VAddr,
+    arch::{interrupts::InterruptIndex, task::registers::USER_RFLAGS},
+};
+
+/// Exception vector of the debug exception.
+pub const DEBUG_VECTOR: u64 = 1;
+
+/// Exception vector of the breakpoint exception.
+pub const BREAKPOINT_VECTOR: u64 = 3;
+
+/// Vector of the interrupt of the serial port, on which GDB interrupts the execution.
+pub const SERIAL_VECTOR: u64 = InterruptIndex::Serial as u64;
+
+/// Opcode of the `int3` instruction.
+pub const BREAKPOINT_INSTRUCTION: u8 = 0xCC;
+
+impl TrapFrame {
+    /// Update the frame from the registers sent by GDB. Segments cannot be changed
+    /// and only the status flags and the trap flag of rflags can be changed.
+    pub fn set_from_gdb(&mut self, regs: &GdbRegisters) -> Result<(), CapabilityErrors> {
+        let [rax, rbx, rcx, rdx, rsi, rdi, rbp, rsp, r8, r9, r10, r11, r12, r13, r14, r15, rip] =
+            regs.gprs;
+        if self.is_user_mode() {
+            VAddr::new(rip).validate_user_mode()?;
+            VAddr::new(rsp).validate_user_mode()?;
+        }
+
+        self.rax = rax;
+        self.rbx = rbx;
+        self.rcx = rcx;
+        self.rdx = rdx;
+        self.rsi = rsi;
+        self.rdi = rdi;
+        self.rbp = rbp;
+        self.rsp = rsp;
+        self.r8 = r8;
+        self.r9 = r9;
+        self.r10 = r10;
+        self.r11 = r11;
+        self.r12 = r12;
+        self.r13 = r13;
+        self.r14 = r14;
+        self.r15 = r15;
+        self.rip = rip;
+        let user_flags = USER_RFLAGS.bits();
+        self.rflags = (self.rflags & !user_flags) | (regs.eflags as u64 & user_flags);
+        Ok(())
+    }
+
+    /// Set or clear the trap flag so that the CPU stops after the next instruction.
+    pub fn set_single_step(&mut self, enable: bool) {
+        if enable {
+            self.rflags |= RFlags::TRAP_FLAG.bits();
+        } else {
+            self.rflags &= !RFlags::TRAP_FLAG.bits();
+        }
+    }
+
+    /// Set the resume flag so that an instruction breakpoint at the current
+    /// instruction is not hit again.
+    pub fn set_resume_flag(&mut self) {
+        self.rflags |= RFlags::RESUME_FLAG.bits();
+    }
+}
+
+/// Core registers in the order used by GDB for x86_64.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct GdbRegisters {
+    /// rax, rbx, rcx, rdx, rsi, rdi, rbp, rsp, r8 - r15 and rip.
+    pub gprs: [u64; 17],
+    pub eflags: u32,
+    /// cs, ss, ds, es, fs and gs.
+    pub segments: [u32; 6],
+}
+
+impl GdbRegisters {
+    /// Size of the registers in the `g` packet.
+    pub const SIZE: usize = 17 * 8 + 7 * 4;
+
+    /// Encode the registers in target byte order.
+    pub fn to_bytes(&self) -> [u8; Self::SIZE] {
+        let mut out = [0u8; Self::SIZE];
+        for (i, reg) in self.gprs.iter().enumerate() {
+            out[i * 8..(i + 1) * 8].copy_from_slice(&reg.to_le_bytes());
+        }
+        let mut offset = 17 * 8;
+        for reg in core::iter::once(&self.eflags).chain(self.segments.iter()) {
+            out[offset..offset + 4].copy_from_slice(&reg.to_le_bytes());
+            offset += 4;
+        }
+        out
+    }
+
+    /// Decode the registers from a `G` packet. GDB might send more registers
+    /// than the core registers, which are ignored.
+    pub fn from_bytes(data: &[u8]) -> Option<Self> {
+        if data.len() < Self::SIZE {
+            return None;
+        }
+
+        let mut regs = Self::default();
+        let read_u32 = |offset: usize| {
+            let mut bytes = [0u8; 4];
+            bytes.copy_from_slice(&data[offset..offset + 4]);
+            u32::from_le_bytes(bytes)
+        };
+        for (i, reg) in regs.gprs.iter_mut().enumerate() {
+            let mut bytes = [0u8; 8];
+            bytes.copy_from_slice(&data[i * 8..(i + 1) * 8]);
+            *reg = u64::from_le_bytes(bytes);
+        }
+        regs.eflags = read_u32(17 * 8);
+        for (i, reg) in regs.segments.iter_mut().enumerate() {
+            *reg = read_u32(17 * 8 + 4 * (i + 1));
+        }
+        Some(regs)
+    }
+
+    /// Convert to task registers, keeping the TCB location of `current`.
+    pub fn to_task_registers(&self, current: &TaskRegisters) -> TaskRegisters {
+        let [rax, rbx, rcx, rdx, rsi, rdi, rbp, rsp, r8, r9, r10, r11, r12, r13, r14, r15, rip] =
+            self.gprs;
+        TaskRegisters {
+            rax,
+            rbx,
+            rcx,
+            rdx,
+            rsi,
+            rdi,
+            rbp,
+            rsp,
+            r8,
+            r9,
+            r10,
+            r11,
+            r12,
+            r13,
+            r14,
+            r15,
+            rip,
+            rflags: self.eflags as u64,
+            fs: current.fs,
+        }
+    }
+}
+
+impl From<&TrapFrame> for GdbRegisters {
+    fn from(frame: &TrapFrame) -> Self {
+        Self {
+            gprs: [
+                frame.rax, frame.rbx, frame.rcx, frame.rdx, frame.rsi, frame.rdi, frame.rbp,
+                frame.rsp, frame.r8, frame.r9, frame.r10, frame.r11, frame.r12, frame.r13,
+                frame.r14, frame.r15, frame.rip,
+            ],
+            eflags: frame.rflags as u32,
+            segments: [frame.cs as u32, frame.ss as u32, 0, 0, 0, 0],
+        }
+    }
+}
+
+impl From<&TaskRegisters> for GdbRegisters {
+    fn from(regs: &TaskRegisters) -> Self {
+        Self {
+            gprs: [
+                regs.rax, regs.rbx, regs.rcx, regs.rdx, regs.rsi, regs.rdi, regs.rbp, regs.rsp,
+                regs.r8, regs.r9, regs.r10, regs.r11, regs.r12, regs.r13, regs.r14, regs.r15,
+                regs.rip,
+            ],
+            eflags: regs.rflags as u32,
+            segments: [0; 6],
+        }
+    }
+}
+
+/// Condition that triggers a hardware breakpoint.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HardwareBreakpointKind {
+    Execute,
+    Write,
+    Access,
+}
+
+/// Number of hardware breakpoints supported by the CPU.
+pub const HARDWARE_BREAKPOINT_COUNT: usize = 4;
+
+/// Enable the hardware breakpoint in the given debug register. Breakpoints are
+/// per core and apply to every address space.
+pub fn set_hardware_breakpoint(
+    index: usize,
+    addr: u64,
+    kind: HardwareBreakpointKind,
+    len: usize,
+) -> Result<(), ()> {
+    let n = DebugAddressRegisterNumber::new(index as u8).ok_or(())?;
+    let (condition, size) = match kind {
+        // Instruction breakpoints must use a length of 1.
+        HardwareBreakpointKind::Execute => (
+            BreakpointCondition::InstructionExecution,
+            BreakpointSize::Length1B,
+        ),
+        HardwareBreakpointKind::Write => (
+            BreakpointCondition::DataWrites,
+            BreakpointSize::new(len).ok_or(())?,
+        ),
+        HardwareBreakpointKind::Access => (
+            BreakpointCondition::DataReadsWrites,
+            BreakpointSize::new(len).ok_or(())?,
+        ),
+    };
+    if addr % len.max(1) as u64 != 0 {
+        return Err(());
+    }
+
+    match n {
+        DebugAddressRegisterNumber::Dr0 => Dr0::write(addr),
+        DebugAddressRegisterNumber::Dr1 => Dr1::write(addr),
+        DebugAddressRegisterNumber::Dr2 => Dr2::write(addr),
+        DebugAddressRegisterNumber::Dr3 => Dr3::write(addr),
+    }
+    let mut dr7 = Dr7::read();
+    dr7.set_condition(n, condition);
+    dr7.set_size(n, size);
+    dr7.insert_flags(Dr7Flags::local_breakpoint_enable(n));
+    Dr7::write(dr7);
+    Ok(())
+}
+
+/// Disable the hardware breakpoint in the given debug register.
+pub fn clear_hardware_breakpoint(index: usize) {
+    if let Some(n) = DebugAddressRegisterNumber::new(index as u8) {
+        let mut dr7 = Dr7::read();
+        dr7.remove_flags(Dr7Flags::local_breakpoint_enable(n));
+        Dr7::write(dr7);
+    }
+}
+
+/// Read and reset the debug status. The CPU never clears the status by itself.
+pub fn take_debug_status() -> Dr6Flags {
+    let status = Dr6::read();
+    unsafe { asm!("mov dr6, {0}", in(reg) 0u64, options(nomem, nostack, preserves_flags)) };
+    status
+}
+
+/// Index of the hardware breakpoint that caused the debug exception, if any.
+pub fn triggered_hardware_breakpoint(status: Dr6Flags) -> Option<usize> {
+    (0..HARDWARE_BREAKPOINT_COUNT).find(|index| status.bits() & (1 << index) != 0)
+}
+
+/// Stop the current core and hand it over to the debugger.
+#[inline(always)]
+pub fn breakpoint() {
+    x86_64::instructions::interrupts::int3();
+}
@@ -0,0 +1,329 @@
+/*!
+Memory mapped IO regions of devices.
+
+The regions are collected at boot from the BOOTBOOT memory map, the ACPI tables
+and the memory BARs of PCI devices. Regions that the kernel uses itself, like the
+local APIC and the IO APICs, are excluded again. The rest is given to sigma as
+device untyped capabilities, so that user drivers can map them.
+*/
+
+use heapless::Vec;
+use relic_abi::bootstrap::MAX_DEVICE_MEMORY_REGIONS;
+use relic_utils::align;
+use spin::Mutex;
+use x86_64::instructions::port::Port;
+
+use crate::{arch::globals, util::memory_region::MemoryRegion};
+
+const PAGE_SIZE: u64 = globals::BASE_PAGE_LENGTH as u64;
+
+/// Page aligned device memory regions that never overlap.
+#[derive(Debug)]
+pub struct DeviceRegions {
+    /// Sorted by their start.
+    regions: Vec<MemoryRegion, MAX_DEVICE_MEMORY_REGIONS>,
+}
+
+/// Start and end of a region. (Exclusive)
+fn bounds(region: &MemoryRegion) -> (u64, u64) {
+    let start: u64 = region.start_paddr().into();
+    (start, start + region.length() as u64)
+}
+
+fn region(start: u64, end: u64) -> MemoryRegion {
+    MemoryRegion::new(start.into(), (end - start) as usize)
+}
+
+impl DeviceRegions {
+    pub const fn new() -> Self {
+        Self {
+            regions: Vec::new(),
+        }
+    }
+
+    /**
+    Add the pages that contain the given range. Overlapping and adjacent regions
+    are merged. Regions that the kernel cannot map are dropped.
+    */
+    pub fn add(&mut self, start: u64, length: u64) {
+        if length == 0 {
+            return;
+        }
+        let mut end = align::align_up(start + length, PAGE_SIZE);
+        let mut start = align::align_down(start, PAGE_SIZE);
+        if end > globals::MEM_MAP_SIZE {
+            warn!(target: "device_memory", "Region {:x}..{:x} is not mapped by the kernel", start, end);
+            return;
+        }
+
+        self.regions.retain(|other| {
+            let (other_start, other_end) = bounds(other);
+            if other_start <= end && start <= other_end {
+                start = core::cmp::min(start, other_start);
+                end = core::cmp::max(end, other_end);
+                false
+            } else {
+                true
+            }
+        });
+
+        if self.regions.push(region(start, end)).is_err() {
+            warn!(target: "device_memory", "Too many regions, dropped {:x}..{:x}", start, end);
+        }
+        self.regions
+            .sort_unstable_by_key(|region| region.start_paddr());
+    }
+
+    /**
+    Remove the pages that contain the given range. Splitting a region in two needs
+    room for another region. Without it, the part above the range is dropped as
+    well and returned as the error.
+    */
+    pub fn exclude(&mut self, start: u64, length: u64) -> Result<(), MemoryRegion> {
+        if length == 0 {
+            return Ok(());
+        }
+        let end = align::align_up(start + length, PAGE_SIZE);
+        let start = align::align_down(start, PAGE_SIZE);
+
+        let splits = self.regions.iter().any(|other| {
+            let (other_start, other_end) = bounds(other);
+            other_start < start && end < other_end
+        });
+        let no_room = splits && self.regions.len() == self.regions.capacity();
+
+        // Pushing cannot fail, as there is room for every piece that is kept.
+        let mut remaining = Vec::new();
+        let mut dropped = None;
+        for other in &self.regions {
+            let (other_start, other_end) = bounds(other);
+            if other_end <= start || end <= other_start {
+                let _ = remaining.push(*other);
+                continue;
+            }
+            if other_start < start {
+                let _ = remaining.push(region(other_start, start));
+            }
+            if end < other_end {
+                if no_room {
+                    dropped = Some(region(end, other_end));
+                } else {
+                    let _ = remaining.push(region(end, other_end));
+                }
+            }
+        }
+        self.regions = remaining;
+        dropped.map_or(Ok(()), Err)
+    }
+
+    pub fn regions(&self) -> &[MemoryRegion] {
+        &self.regions
+    }
+}
+
+impl Default for DeviceRegions {
+    #[inline]
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+static DEVICE_REGIONS: Mutex<DeviceRegions> = Mutex::new(DeviceRegions::new());
+
+/// Add a device memory region found at boot.
+pub fn add_region(start: u64, length: u64) {
+    debug!(target: "device_memory", "Add region {:x} with {} bytes", start, length);
+    DEVICE_REGIONS.lock().add(start, length)
+}
+
+/// Remove a region that must not be given to user space.
+pub fn exclude_region(start: u64, length: u64) {
+    debug!(target: "device_memory", "Exclude region {:x} with {} bytes", start, length);
+    if let Err(dropped) = DEVICE_REGIONS.lock().exclude(start, length) {
+        warn!(target: "device_memory", "Too many regions, dropped {:?}", dropped);
+    }
+}
+
+/// Take the regions that were found.
+pub fn take() -> DeviceRegions {
+    core::mem::take(&mut *DEVICE_REGIONS.lock())
+}
+
+const PCI_CONFIG_ADDRESS: u16 = 0xCF8;
+const PCI_CONFIG_DATA: u16 = 0xCFC;
+
+const PCI_COMMAND: u8 = 0x04;
+const PCI_COMMAND_MEMORY: u32 = 0b10;
+const PCI_HEADER_TYPE: u8 = 0x0C;
+const PCI_BAR_0: u8 = 0x10;
+
+/// A function of a PCI device.
+#[derive(Debug, Clone, Copy)]
+struct PciFunction {
+    bus: u8,
+    device: u8,
+    function: u8,
+}
+
+impl PciFunction {
+    fn read(self, offset: u8) -> u32 {
+        unsafe {
+            Port::new(PCI_CONFIG_ADDRESS).write(self.address(offset));
+            Port::new(PCI_CONFIG_DATA).read()
+        }
+    }
+
+    fn write(self, offset: u8, value: u32) {
+        unsafe {
+            Port::new(PCI_CONFIG_ADDRESS).write(self.address(offset));
+            Port::new(PCI_CONFIG_DATA).write(value);
+        }
+    }
+
+    fn address(self, offset: u8) -> u32 {
+        0x8000_0000
+            | (self.bus as u32) << 16
+            | (self.device as u32) << 11
+            | (self.function as u32) << 8
+            | (offset & 0xFC) as u32
+    }
+
+    fn exists(self) -> bool {
+        self.read(0) & 0xFFFF != 0xFFFF
+    }
+
+    fn header_type(self) -> u8 {
+        (self.read(PCI_HEADER_TYPE) >> 16) as u8
+    }
+
+    /// Write all ones to the BAR and read back the bits that are implemented.
+    fn size_mask(self, offset: u8) -> u32 {
+        let value = self.read(offset);
+        self.write(offset, 0xFFFF_FFFF);
+        let mask = self.read(offset);
+        self.write(offset, value);
+        mask
+    }
+
+    /// Add the memory BARs of the function. IO BARs and BARs that are not
+    /// assigned are skipped.
+    fn add_memory_bars(self) {
+        let bar_count = match self.header_type() & 0x7F {
+            0 => 6,
+            // PCI to PCI bridge.
+            1 => 2,
+            _ => return,
+        };
+
+        // Decoding is disabled while the BARs are sized.
+        let command = self.read(PCI_COMMAND) & 0xFFFF;
+        self.write(PCI_COMMAND, command & !PCI_COMMAND_MEMORY);
+
+        let mut index = 0;
+        while index < bar_count {
+            let offset = PCI_BAR_0 + index * 4;
+            let value = self.read(offset);
+            index += 1;
+            if value & 1 != 0 {
+                continue;
+            }
+
+            let is_64bit = (value >> 1) & 0b11 == 0b10;
+            let mut base = (value & !0xF) as u64;
+            let mut mask = (self.size_mask(offset) & !0xF) as u64 | 0xFFFF_FFFF_0000_0000;
+            if is_64bit && index < bar_count {
+                let high_offset = PCI_BAR_0 + index * 4;
+                base |= (self.read(high_offset) as u64) << 32;
+                mask = (mask & 0xFFFF_FFFF) | (self.size_mask(high_offset) as u64) << 32;
+                index += 1;
+            }
+
+            // BARs that are not implemented read back as zero.
+            let is_implemented = mask != 0xFFFF_FFFF_0000_0000;
+            if base != 0 && is_implemented {
+                add_region(base, (!mask).wrapping_add(1));
+            }
+        }
+
+        self.write(PCI_COMMAND, command);
+    }
+}
+
+/// Add the memory BARs of all PCI devices. The devices are found with the legacy
+/// configuration ports.
+pub fn scan_pci() {
+    for bus in 0..=255 {
+        for device in 0..32 {
+            let first = PciFunction {
+                bus,
+                device,
+                function: 0,
+            };
+            if !first.exists() {
+                continue;
+            }
+
+            let functions = if first.header_type() & 0x80 != 0 {
+                8
+            } else {
+                1
+            };
+            for function in 0..functions {
+                let pci_function = PciFunction {
+                    bus,
+                    device,
+                    function,
+                };
+                if pci_function.exists() {
+                    pci_function.add_memory_bars();
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bounds_of(regions: &DeviceRegions) -> std::vec::Vec<(u64, u64)> {
+        regions.regions().iter().map(bounds).collect()
+    }
+
+    #[test]
+    fn test_add_and_exclude() {
+        let mut regions = DeviceRegions::new();
+        regions.add(0xFEB0_0010, 0x100);
+        regions.add(0xFE00_0000, 0x4000);
+        regions.add(0xFE00_4000, 0x1000);
+        regions.add(0xFE00_2000, 0x8000);
+        assert_eq!(
+            bounds_of(&regions),
+            [(0xFE00_0000, 0xFE00_A000), (0xFEB0_0000, 0xFEB0_1000)]
+        );
+
+        regions.exclude(0xFE00_3000, 0x10).unwrap();
+        regions.exclude(0xFEB0_0000, 0x1000).unwrap();
+        assert_eq!(
+            bounds_of(&regions),
+            [(0xFE00_0000, 0xFE00_3000), (0xFE00_4000, 0xFE00_A000)]
+        );
+    }
+
+    #[test]
+    fn test_exclude_when_full() {
+        let mut regions = DeviceRegions::new();
+        for index in 0..MAX_DEVICE_MEMORY_REGIONS as u64 {
+            regions.add(index * 0x10_0000, 0x3000);
+        }
+
+        // The region cannot be split, so its upper part is dropped with the excluded page.
+        assert_matches!(
+            regions.exclude(0x1000, 0x1000),
+            Err(dropped) if bounds(&dropped) == (0x2000, 0x3000)
+        );
+        assert_eq!(regions.regions().len(), MAX_DEVICE_MEMORY_REGIONS);
+        assert_eq!(bounds(&regions.regions()[0]), (0, 0x1000));
+        assert_eq!(bounds(&regions.regions()[1]), (0x10_0000, 0x10_3000));
+    }
+}
@@ -1,4 +1,3 @@
-use relic_utils::align;
 use x86_64::{
     instructions::{
         segmentation::{load_ss, set_cs},
@@ -12,21 +11,45 @@ use x86_64::{
     VirtAddr,
 };
 
-use super::globals;
-
-const SPECIAL_STACK_SIZES: usize = 4096 * 5;
+use super::stack;
+use crate::capability::{IoPortRange, IO_PORT_COUNT};
 
 pub const DOUBLE_FAULT_IST_INDEX: usize = 0;
-#[thread_local]
-static DOUBLE_FAULT_STACK: [u8; SPECIAL_STACK_SIZES] = [0; SPECIAL_STACK_SIZES]; // TODO: Stack Protection.
-#[thread_local]
-static PRIVILEGE_0_STACK: [u8; SPECIAL_STACK_SIZES] = [0; SPECIAL_STACK_SIZES]; // TODO: Stack Protection.
+
+/// I/O permission bitmap. A cleared bit allows user mode to use the port. The CPU
+/// reads two bytes at a time, so the bitmap ends with a byte that has all bits set.
+#[repr(C)]
+struct IoBitmap([u8; IO_PORT_COUNT as usize / 8 + 1]);
+
+/// Offset of the I/O permission bitmap in the TSS.
+const IO_BITMAP_OFFSET: u16 = core::mem::size_of::<TaskStateSegment>() as u16;
+
+/// Offset that is beyond the limit of the TSS. All ports are denied with it.
+const IO_BITMAP_DISABLED: u16 = u16::MAX;
+
+/// Per core data used by the CPU to enter the kernel. It is page aligned so that
+/// page table isolation can map it into user page tables without its neighbours.
+/// The entry stacks are guarded and live with the kernel stacks, see [`stack`].
+#[repr(C, align(4096))]
+struct EntryArea {
+    tss: TaskStateSegment,
+    /// Follows the TSS directly, at [`IO_BITMAP_OFFSET`].
+    io_bitmap: IoBitmap,
+    gdt: GlobalDescriptorTable,
+}
 
 #[thread_local]
-static mut TSS: TaskStateSegment = TaskStateSegment::new();
+static mut ENTRY_AREA: EntryArea = EntryArea {
+    tss: TaskStateSegment::new(),
+    io_bitmap: IoBitmap([0xFF; IO_PORT_COUNT as usize / 8 + 1]),
+    gdt: GlobalDescriptorTable::new(),
+};
 
+/// Task whose ports are allowed in the I/O permission bitmap of this core, and the
+/// number of its port ranges. Ranges are only ever added to a task.
 #[thread_local]
-static mut GDT: GlobalDescriptorTable = GlobalDescriptorTable::new();
+static mut IO_BITMAP_OWNER: Option<(u64, usize)> = None;
+
 static mut GLOBAL_GDT: GlobalDescriptorTable = GlobalDescriptorTable::new(); // Temporary GDT
 
 #[thread_local]
@@ -54,12 +77,15 @@ impl SegmentSelectors {
     }
 }
 
-/// Per Core GDT initialization logic.
-pub fn initialize_gdt() {
+/// Per Core GDT initialization logic. The stacks of `core` must be mapped.
+pub fn initialize_gdt(core: usize) {
     unsafe {
-        TSS.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX] =
-            VirtAddr::new(get_stack_align_for_array(&DOUBLE_FAULT_STACK));
-        TSS.privilege_stack_table[0] = VirtAddr::new(get_stack_align_for_array(&PRIVILEGE_0_STACK));
+        ENTRY_AREA.tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX] =
+            VirtAddr::new(stack::double_fault_stack(core).end);
+        ENTRY_AREA.tss.privilege_stack_table[0] = VirtAddr::new(stack::privilege_0_stack(core).end);
+        ENTRY_AREA.tss.iomap_base = IO_BITMAP_DISABLED;
+        // Syscalls enter on the same stack, it is not in use while user mode runs.
+        super::tls::set_syscall_stack(privilege_0_stack_top());
 
         let mut gdt = GlobalDescriptorTable::new();
         let kernel_code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
@@ -68,9 +94,9 @@ pub fn initialize_gdt() {
         let user_data_selector = gdt.add_entry(Descriptor::user_data_segment());
         let user_code_selector = gdt.add_entry(Descriptor::user_code_segment());
 
-        let tss_selector = gdt.add_entry(Descriptor::tss_segment(&TSS));
+        let tss_selector = gdt.add_entry(tss_segment_with_io_bitmap(&ENTRY_AREA.tss));
         GLOBAL_GDT = gdt.clone();
-        GDT = gdt;
+        ENTRY_AREA.gdt = gdt;
 
         let selectors = SegmentSelectors {
             kernel_code_selector,
@@ -79,7 +105,7 @@ pub fn initialize_gdt() {
             user_data_selector,
             tss_selector,
         };
-        GDT.load();
+        ENTRY_AREA.gdt.load();
         set_cs(selectors.kernel_code_selector);
         load_ss(selectors.kernel_data_selector);
         load_tss(selectors.tss_selector);
@@ -92,13 +118,76 @@ pub fn initialize_gdt() {
         )
         .unwrap();
 
-        x86_64::registers::model_specific::SFMask::write(RFlags::INTERRUPT_FLAG);
+        // The trap flag is masked so that single stepping over a syscall does
+        // not trap on the kernel entry. The alignment check flag would lift SMAP.
+        x86_64::registers::model_specific::SFMask::write(
+            RFlags::INTERRUPT_FLAG | RFlags::TRAP_FLAG | RFlags::ALIGNMENT_CHECK,
+        );
 
         GLOBAL_SELECTORS = selectors.clone();
         SELECTORS = selectors;
     }
 }
 
+/// TSS descriptor whose limit includes the I/O permission bitmap after the TSS.
+fn tss_segment_with_io_bitmap(tss: &'static TaskStateSegment) -> Descriptor {
+    match Descriptor::tss_segment(tss) {
+        Descriptor::SystemSegment(low, high) => {
+            let limit = (IO_BITMAP_OFFSET as usize + core::mem::size_of::<IoBitmap>() - 1) as u64;
+            let low =
+                (low & !0xFFFF & !(0xF << 48)) | (limit & 0xFFFF) | ((limit >> 16) & 0xF) << 48;
+            Descriptor::SystemSegment(low, high)
+        }
+        descriptor => descriptor,
+    }
+}
+
+/**
+Allow the task with the given ID to use the ports in `ports` from user mode on this
+core. All other ports are denied. The bitmap is only rebuilt when the ports differ
+from the ones of the task that ran before.
+*/
+pub fn load_io_permissions(task_id: u64, ports: &[IoPortRange]) {
+    unsafe {
+        if ports.is_empty() {
+            ENTRY_AREA.tss.iomap_base = IO_BITMAP_DISABLED;
+            return;
+        }
+
+        ENTRY_AREA.tss.iomap_base = IO_BITMAP_OFFSET;
+        if IO_BITMAP_OWNER == Some((task_id, ports.len())) {
+            return;
+        }
+
+        let bitmap = &mut ENTRY_AREA.io_bitmap.0;
+        bitmap.fill(0xFF);
+        for range in ports {
+            for port in range.first() as usize..=range.last() as usize {
+                bitmap[port / 8] &= !(1 << (port % 8));
+            }
+        }
+        IO_BITMAP_OWNER = Some((task_id, ports.len()));
+    }
+}
+
+/// Code and stack segment selectors used by user mode.
+pub fn user_segment_selectors() -> (SegmentSelector, SegmentSelector) {
+    unsafe { (SELECTORS.user_code_selector, SELECTORS.user_data_selector) }
+}
+
+/// Top of the stack the CPU switches to when user mode is interrupted or makes a
+/// syscall. It is unused while user mode runs.
+pub fn privilege_0_stack_top() -> u64 {
+    unsafe { ENTRY_AREA.tss.privilege_stack_table[0].as_u64() }
+}
+
+/// Address range of the entry area of this core.
+#[cfg(feature = "kpti")]
+pub fn entry_area_range() -> core::ops::Range<u64> {
+    let start = unsafe { &ENTRY_AREA as *const EntryArea as u64 };
+    start..start + core::mem::size_of::<EntryArea>() as u64
+}
+
 /// Temporary GDT to be used by AP Cores.
 pub fn load_global_gdt() {
     unsafe {
@@ -108,9 +197,3 @@ pub fn load_global_gdt() {
         load_tss(GLOBAL_SELECTORS.tss_selector);
     }
 }
-
-fn get_stack_align_for_array<'a>(array: &'a [u8]) -> u64 {
-    let last_entry_addr = &array[array.len() - 1] as *const u8 as usize;
-    let high_aligned_addr = align::align_down(last_entry_addr, globals::STACK_ALIGN);
-    high_aligned_addr as u64
-}
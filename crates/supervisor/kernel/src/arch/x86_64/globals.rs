@@ -4,6 +4,14 @@ use log::Level;
 pub const DEFAULT_LOG_LEVEL: Level = Level::Info;
 pub const EXTRA_LOGS: [&'static str; 1] = ["bootstrap"];
 
+/// Stop at boot and wait for GDB to connect to the serial port, enabled by the
+/// `gdb-wait-on-boot` feature.
+pub const GDB_WAIT_ON_BOOT: bool = cfg!(feature = "gdb-wait-on-boot");
+
+/// Use five-level paging if the CPU supports it, enabled by the `la57` feature.
+/// Not supported with page table isolation.
+pub const FIVE_LEVEL_PAGING: bool = cfg!(feature = "la57");
+
 /// Size of stack used as an intermediate stack when bootstrapping the system.
 /// This stack is hardcoded as an array in the binary.
 pub const BSP_TEMP_STACK_SIZE_BYTES: usize = 4096 * 4;
@@ -26,6 +34,8 @@ pub const KERNEL_STACK_START: usize = 0xFFFF_FF80_0000_0000;
 /// Basic page length in x86_64 (4 KiB).
 pub const BASE_PAGE_LENGTH: usize = 4096; // 4 KiB
 
-pub const SIGMA_TLS_IMAGE_START: u64 = 0x7000_000_0000;
-pub const SIGMA_BUFFER_START: u64 = 0x6000_000_0000;
-pub const SIGMA_VGA_START: u64 = 0x5000_000_0000;
+/// Regions of sigma that userspace needs to know about.
+pub use relic_abi::bootstrap::{
+    SIGMA_BUFFER_START, SIGMA_INITRD_START, SIGMA_STACK_PAGES, SIGMA_STACK_START, SIGMA_VGA_START,
+    USER_STACK_GUARD_PAGES,
+};
@@ -1,13 +1,13 @@
-use ::acpi::{AcpiTables, InterruptModel};
+use ::acpi::{AcpiTables, HpetInfo, InterruptModel};
 use x86_64::{
-    instructions::port::Port,
-    structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode},
+    instructions::port::Port, structures::idt::InterruptDescriptorTable, PrivilegeLevel, VirtAddr,
 };
 
-use crate::arch::{gdt, globals, interrupts::acpi::MemoryHandler};
+use crate::arch::{device_memory, gdt, globals, interrupts::acpi::MemoryHandler};
 
 pub mod acpi;
 pub mod apic;
+pub mod trap;
 
 /// Index of interrupts. This is the index where IRQs are raised
 /// on PIC.
@@ -19,6 +19,7 @@ pub enum InterruptIndex {
     Spurious,
     Error,
     HpetTimer, // 36
+    Serial,
 }
 
 impl InterruptIndex {
@@ -33,74 +34,65 @@ impl InterruptIndex {
     }
 }
 
-static mut IDT: InterruptDescriptorTable = InterruptDescriptorTable::new();
+/// Spurious interrupt vector of the local APIC after reset.
+const SPURIOUS_VECTOR: usize = 0xFF;
+
+/// The IDT is page aligned so that page table isolation can map it into user page
+/// tables without its neighbours.
+#[repr(C, align(4096))]
+struct PageAlignedIdt(InterruptDescriptorTable);
+
+static mut IDT: PageAlignedIdt = PageAlignedIdt(InterruptDescriptorTable::new());
 
 pub fn initialize_idt() {
+    // Every handler enters through a trampoline in `trap` that switches to the
+    // kernel page table with page table isolation.
     unsafe {
-        IDT.double_fault
-            .set_handler_fn(double_fault_handler)
+        let idt = &mut IDT.0;
+        idt.double_fault
+            .set_handler_addr(VirtAddr::new(trap::double_fault_entry as u64))
             .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX as u16);
-        IDT.page_fault.set_handler_fn(page_fault_handler);
-
-        IDT.general_protection_fault.set_handler_fn(unhandled_fault);
-        IDT.invalid_opcode.set_handler_fn(unhandled_fault_noerr);
-
-        IDT.load();
+        idt.page_fault
+            .set_handler_addr(VirtAddr::new(trap::page_fault_entry as u64));
+
+        idt.general_protection_fault
+            .set_handler_addr(VirtAddr::new(trap::general_protection_entry as u64));
+        idt.invalid_opcode
+            .set_handler_addr(VirtAddr::new(trap::invalid_opcode_entry as u64));
+
+        // Debug exceptions are handled by the GDB stub. `int3` is allowed from user mode.
+        idt.debug
+            .set_handler_addr(VirtAddr::new(trap::debug_entry as u64));
+        idt.breakpoint
+            .set_handler_addr(VirtAddr::new(trap::breakpoint_entry as u64))
+            .set_privilege_level(PrivilegeLevel::Ring3);
+
+        // User mode runs with interrupts enabled. The timer preempts tasks.
+        idt[InterruptIndex::Timer.as_usize()]
+            .set_handler_addr(VirtAddr::new(trap::timer_entry as u64));
+        idt[InterruptIndex::Keyboard.as_usize()]
+            .set_handler_addr(VirtAddr::new(trap::keyboard_entry as u64));
+        idt[InterruptIndex::Serial.as_usize()]
+            .set_handler_addr(VirtAddr::new(trap::serial_entry as u64));
+        idt[SPURIOUS_VECTOR].set_handler_addr(VirtAddr::new(trap::spurious_entry as u64));
+
+        IDT.0.load();
     }
 }
 
-/// Handler than be used for non-standard faults.
-extern "x86-interrupt" fn unhandled_fault(stack_frame: InterruptStackFrame, error_code: u64) {
-    error!(
-        target: "unhandled_fault",
-        "EXCEPTION: Unhandled FAULT\n{:#?}\nError Code: {}",
-        stack_frame,
-        error_code
-    );
-
-    loop {
-        x86_64::instructions::hlt();
-    }
+/// Address range of the IDT.
+#[cfg(feature = "kpti")]
+pub fn idt_range() -> core::ops::Range<u64> {
+    let start = unsafe { &IDT as *const PageAlignedIdt as u64 };
+    start..start + core::mem::size_of::<PageAlignedIdt>() as u64
 }
 
-/// Handler than be used for non-standard faults.
-extern "x86-interrupt" fn unhandled_fault_noerr(stack_frame: InterruptStackFrame) {
-    error!(target: "unhandled_fault_noerr", "EXCEPTION: Unhandled FAULT\n{:#?}", stack_frame);
-    loop {
-        x86_64::instructions::hlt();
-    }
-}
-
-extern "x86-interrupt" fn double_fault_handler(
-    stack_frame: InterruptStackFrame,
-    error_code: u64,
-) -> ! {
-    error!(
-        target: "DoubleFaultHandler",
-        "EXCEPTION: DOUBLE FAULT\n{:#?}\nError Code: {}",
-        stack_frame,
-        error_code
-    );
-    loop {
-        x86_64::instructions::hlt();
-    }
-}
-
-extern "x86-interrupt" fn page_fault_handler(
-    stack_frame: InterruptStackFrame,
-    error_code: PageFaultErrorCode,
-) {
-    use x86_64::registers::control::Cr2;
-    error!(
-        target: "PageFaultHandler",
-        "EXCEPTION: PAGE FAULT\r\n{:#?}\r\nError Code: {:?}\r\nAccessed Address: {:?}",
-        stack_frame,
-        error_code,
-        Cr2::read()
-    );
-    loop {
-        x86_64::instructions::hlt();
-    }
+/// Keyboard input is not used yet. The scancode is read so that the controller
+/// can raise the next interrupt.
+fn keyboard_interrupt() {
+    let mut data: Port<u8> = Port::new(0x60);
+    unsafe { data.read() };
+    apic::end_of_interrupt();
 }
 
 /// Loads the interrupt mappings and returns the number of AP cores.
@@ -125,7 +117,18 @@ pub fn load_interrupts_bsp() -> Result<(), &'static str> {
     let platform_info = acpi_tables
         .platform_info()
         .or(Err("Cannot load ACPI platform_info"))?;
+    if let Ok(hpet) = HpetInfo::new(&acpi_tables) {
+        device_memory::add_region(hpet.base_address as u64, globals::BASE_PAGE_LENGTH as u64);
+    }
+
     if let InterruptModel::Apic(apic) = platform_info.interrupt_model {
+        // The APICs are used by the kernel and never given to user space.
+        let page_length = globals::BASE_PAGE_LENGTH as u64;
+        device_memory::exclude_region(apic.local_apic_address, page_length);
+        for io_apic in apic.io_apics.iter() {
+            device_memory::exclude_region(io_apic.address as u64, page_length);
+        }
+
         info!(target:"interrupts", "Enable local APIC");
         self::apic::initialize_lapic();
         info!(target:"interrupts", "Local APIC ready");
@@ -28,9 +28,9 @@ pub fn initialize_lapic() {
     //TODO: Make sure the TPR (Task Priority Register) is set (so it won't block/postpone lower priority IRQs)
     // Enable local apic
     {
-        let spurios_vector = lapic_instance.spurious_interrupt_vector();
-        let mut val = spurios_vector.read();
-        val.enable_apic_software(true);
+        lapic_instance
+            .spurious_interrupt_vector()
+            .update(|val| val.enable_apic_software(true));
     }
 
     // Enable timer
@@ -55,6 +55,13 @@ pub fn initialize_lapic() {
     }
 }
 
+/// Signal the end of the current interrupt to the local APIC.
+pub fn end_of_interrupt() {
+    const EOI_REGISTER_OFFSET: u64 = 0xB0;
+    let eoi = read_lapic_base().as_u64() + globals::MEM_MAP_OFFSET_LOCATION + EOI_REGISTER_OFFSET;
+    unsafe { core::ptr::write_volatile(eoi as *mut u32, 0) };
+}
+
 /// Startup the IOApic. This is usually run on only one of the processor because IOApic is
 /// shared among multiple cores.
 pub fn initialize_ioapic(apic: Apic) {
@@ -74,6 +81,13 @@ pub fn initialize_ioapic(apic: Apic) {
         entry.set_masked(false);
         entry.set_vector(super::InterruptIndex::Keyboard.as_u8());
     });
+
+    // The first serial port, on which GDB can interrupt the execution.
+    ioapic.update_redirection_table_entry(4, |entry| {
+        entry.set_destination(id);
+        entry.set_masked(false);
+        entry.set_vector(super::InterruptIndex::Serial.as_u8());
+    });
 }
 
 /// Get the LApic Base address.
@@ -0,0 +1,313 @@
+/*!
+Interrupt entry points that save the complete register state.
+
+Exceptions and interrupts that need access to the interrupted code, like the
+debugger, preemption of tasks or page faults, enter through [`trap_common`] which saves every
+general purpose register into a [`TrapFrame`] before calling [`trap_handler`].
+
+Every other handler enters the same way, as the entry points live in the
+`.trampoline` section that page table isolation maps into user page tables.
+*/
+
+use x86_64::{
+    registers::{
+        control::Cr2,
+        model_specific::{FsBase, KernelGsBase},
+    },
+    structures::idt::PageFaultErrorCode,
+};
+
+use crate::{
+    arch::{
+        debug::{BREAKPOINT_VECTOR, DEBUG_VECTOR, SERIAL_VECTOR},
+        interrupts::{apic, InterruptIndex},
+        stack, task, user_memory,
+    },
+    capability::{Scheduler, TaskStatus},
+};
+
+/// Register state of the interrupted code. The layout matches the stack
+/// built by [`trap_common`].
+#[repr(C)]
+#[derive(Debug, Clone)]
+pub struct TrapFrame {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+
+    pub vector: u64,
+    pub error_code: u64,
+
+    // Pushed by the CPU.
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+
+impl TrapFrame {
+    /// Whether user mode was interrupted.
+    pub fn is_user_mode(&self) -> bool {
+        self.cs & 0b11 == 0b11
+    }
+}
+
+/// Vector of the local APIC timer.
+const TIMER_VECTOR: u64 = InterruptIndex::Timer as u64;
+
+/// Vector of the keyboard interrupt.
+const KEYBOARD_VECTOR: u64 = InterruptIndex::Keyboard as u64;
+
+/// Spurious interrupt vector of the local APIC.
+const SPURIOUS_VECTOR: u64 = super::SPURIOUS_VECTOR as u64;
+
+/// Vector of invalid opcode exceptions.
+const INVALID_OPCODE_VECTOR: u64 = 6;
+
+/// Vector of double faults.
+const DOUBLE_FAULT_VECTOR: u64 = 8;
+
+/// Vector of general protection faults.
+const GENERAL_PROTECTION_VECTOR: u64 = 13;
+
+/// Vector of page faults.
+const PAGE_FAULT_VECTOR: u64 = 14;
+
+macro_rules! trap_entry {
+    ($name: ident, $vector: expr) => {
+        /// Entry point that pushes an empty error code and the vector.
+        #[naked]
+        #[link_section = ".trampoline"]
+        pub(super) unsafe extern "C" fn $name() {
+            asm!("
+                push 0
+                push {0}
+                jmp {1}
+            ", const $vector, sym trap_common, options(noreturn));
+        }
+    };
+    ($name: ident, $vector: expr, error_code) => {
+        /// Entry point for exceptions that push an error code. Pushes the vector.
+        #[naked]
+        #[link_section = ".trampoline"]
+        pub(super) unsafe extern "C" fn $name() {
+            asm!("
+                push {0}
+                jmp {1}
+            ", const $vector, sym trap_common, options(noreturn));
+        }
+    };
+}
+
+trap_entry!(debug_entry, DEBUG_VECTOR);
+trap_entry!(breakpoint_entry, BREAKPOINT_VECTOR);
+trap_entry!(timer_entry, TIMER_VECTOR);
+trap_entry!(keyboard_entry, KEYBOARD_VECTOR);
+trap_entry!(serial_entry, SERIAL_VECTOR);
+trap_entry!(spurious_entry, SPURIOUS_VECTOR);
+trap_entry!(invalid_opcode_entry, INVALID_OPCODE_VECTOR);
+trap_entry!(double_fault_entry, DOUBLE_FAULT_VECTOR, error_code);
+trap_entry!(
+    general_protection_entry,
+    GENERAL_PROTECTION_VECTOR,
+    error_code
+);
+trap_entry!(page_fault_entry, PAGE_FAULT_VECTOR, error_code);
+
+/// Save all registers into a [`TrapFrame`] and call [`trap_handler`].
+#[naked]
+#[link_section = ".trampoline"]
+unsafe extern "C" fn trap_common() {
+    // The CPU aligns the stack before pushing the interrupt frame, so the
+    // stack is aligned again after pushing the 17 values of the trap frame.
+    // Only user mode runs on the user page table, which is checked through the
+    // code segment at offset 144 of the trap frame.
+    asm!(concat!("
+        push rax
+        push rbx
+        push rcx
+        push rdx
+        push rsi
+        push rdi
+        push rbp
+        push r8
+        push r9
+        push r10
+        push r11
+        push r12
+        push r13
+        push r14
+        push r15
+        test qword ptr [rsp + 144], 3
+        jz 2f
+        ", kpti_switch_to_kernel!("rax"), "
+    2:
+        mov rdi, rsp
+        cld
+        call {0}
+        test qword ptr [rsp + 144], 3
+        jz 3f
+        ", kpti_switch_to_user!("rax"), "
+    3:
+        pop r15
+        pop r14
+        pop r13
+        pop r12
+        pop r11
+        pop r10
+        pop r9
+        pop r8
+        pop rbp
+        pop rdi
+        pop rsi
+        pop rdx
+        pop rcx
+        pop rbx
+        pop rax
+        add rsp, 16
+        iretq
+    "), sym trap_handler, options(noreturn));
+}
+
+extern "C" fn trap_handler(frame: &mut TrapFrame) {
+    // Kernel CPU locals are only available once the kernel TCB is loaded.
+    let user_fs = if frame.is_user_mode() {
+        let fs = FsBase::read();
+        FsBase::write(KernelGsBase::read());
+        user_memory::clear_user_access();
+        Some(fs.as_u64())
+    } else {
+        None
+    };
+
+    match frame.vector {
+        DEBUG_VECTOR | BREAKPOINT_VECTOR => {
+            if !crate::gdb::handle_trap(frame) {
+                // Only user mode traps are left to the task, which cannot handle them.
+                let fs = user_fs.expect("Kernel trap was not handled by the debugger");
+                let status = TaskStatus::Faulted(frame.vector);
+                task::registers::preempt_user_task(frame, fs, status);
+            }
+        }
+        TIMER_VECTOR => {
+            task::timer_tick();
+            apic::end_of_interrupt();
+            if let Some(fs) = user_fs {
+                // Does not return. The task is resumed by the scheduler.
+                task::registers::preempt_user_task(frame, fs, TaskStatus::Preempted);
+            }
+        }
+        PAGE_FAULT_VECTOR => {
+            let error_code = PageFaultErrorCode::from_bits_truncate(frame.error_code);
+            let write_to_present_page =
+                PageFaultErrorCode::PROTECTION_VIOLATION | PageFaultErrorCode::CAUSED_BY_WRITE;
+            let user_fault = error_code.contains(write_to_present_page)
+                || !error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION);
+            match user_fs {
+                // Writes to copy-on-write pages are resolved by the scheduler. Faults on
+                // missing pages exit the task, like overflows of the user stack.
+                Some(fs) if user_fault => {
+                    let status = TaskStatus::PageFaulted(Cr2::read().as_u64().into());
+                    task::registers::preempt_user_task(frame, fs, status);
+                }
+                Some(fs) => {
+                    let status = TaskStatus::Faulted(frame.vector);
+                    task::registers::preempt_user_task(frame, fs, status);
+                }
+                None => unhandled_page_fault(frame, error_code),
+            }
+        }
+        KEYBOARD_VECTOR => super::keyboard_interrupt(),
+        SERIAL_VECTOR => {
+            let interrupted = crate::gdb::take_interrupt();
+            apic::end_of_interrupt();
+            if interrupted {
+                // GDB stops the interrupted code, which resumes once GDB continues.
+                crate::gdb::handle_trap(frame);
+            }
+        }
+        // Spurious interrupts must not be acknowledged.
+        SPURIOUS_VECTOR => {}
+        DOUBLE_FAULT_VECTOR => double_fault(frame),
+        INVALID_OPCODE_VECTOR | GENERAL_PROTECTION_VECTOR => match user_fs {
+            Some(fs) => {
+                let status = TaskStatus::Faulted(frame.vector);
+                task::registers::preempt_user_task(frame, fs, status);
+            }
+            None => unhandled_fault(frame),
+        },
+        vector => panic!("Unexpected trap vector {}", vector),
+    }
+
+    if let Some(fs) = user_fs {
+        FsBase::write(x86_64::VirtAddr::new(fs));
+    }
+}
+
+/// Handler for faults that cannot be recovered from.
+fn unhandled_fault(frame: &TrapFrame) -> ! {
+    error!(
+        target: "unhandled_fault",
+        "EXCEPTION: Unhandled FAULT {}\n{:#?}",
+        frame.vector,
+        frame
+    );
+    loop {
+        x86_64::instructions::hlt();
+    }
+}
+
+/// Handler for double faults. A kernel stack overflow faults on a guard page, and the
+/// page fault cannot be pushed onto the stack either.
+fn double_fault(frame: &TrapFrame) -> ! {
+    let fault_address = Cr2::read().as_u64();
+    let stack = match stack::overflowed_stack(fault_address) {
+        Some(stack) => stack,
+        None => unhandled_fault(frame),
+    };
+
+    match Scheduler::active().and_then(Scheduler::running_task_id) {
+        Some(task_id) => error!(
+            target: "double_fault",
+            "EXCEPTION: {} stack overflow in task {} at {:#x}",
+            stack,
+            task_id,
+            frame.rip
+        ),
+        None => error!(
+            target: "double_fault",
+            "EXCEPTION: {} stack overflow in the kernel at {:#x}",
+            stack,
+            frame.rip
+        ),
+    }
+    loop {
+        x86_64::instructions::hlt();
+    }
+}
+
+fn unhandled_page_fault(frame: &TrapFrame, error_code: PageFaultErrorCode) -> ! {
+    error!(
+        target: "PageFaultHandler",
+        "EXCEPTION: PAGE FAULT\r\n{:#?}\r\nError Code: {:?}\r\nAccessed Address: {:?}",
+        frame,
+        error_code,
+        Cr2::read()
+    );
+    loop {
+        x86_64::instructions::hlt();
+    }
+}
@@ -0,0 +1,65 @@
+/*!
+Page table switches of the kernel entry and exit trampolines.
+
+With the `kpti` feature, user mode runs on a user page table that only maps the
+entry points of the kernel, see `paging::kpti`. The trampolines in
+the `.trampoline` section switch to the kernel page table before anything else
+is accessed and back right before returning to user mode. Without the feature, the
+macros expand to nothing.
+*/
+
+/// Assembly that switches from the user page table to the kernel page table.
+/// Clobbers the given register and the flags.
+#[cfg(feature = "kpti")]
+#[rustfmt::skip]
+macro_rules! kpti_switch_to_kernel {
+    ($scratch: literal) => {
+        concat!(
+            "mov ", $scratch, ", cr3\n",
+            // Clear `USER_TABLE_BIT` and `USER_PCID_BIT`.
+            "and ", $scratch, ", ~0x1800\n",
+            "test ", $scratch, ", 0xFFF\n",
+            "jz 4f\n",
+            // Keep the TLB entries of the PCID.
+            "bts ", $scratch, ", 63\n",
+            "4:\n",
+            "mov cr3, ", $scratch, "\n"
+        )
+    };
+}
+
+/// Assembly that switches from the kernel page table to the user page table.
+/// Clobbers the given register and the flags.
+#[cfg(feature = "kpti")]
+#[rustfmt::skip]
+macro_rules! kpti_switch_to_user {
+    ($scratch: literal) => {
+        concat!(
+            "mov ", $scratch, ", cr3\n",
+            "test ", $scratch, ", 0xFFF\n",
+            "jz 5f\n",
+            // Set `USER_PCID_BIT` and keep the TLB entries. They are flushed
+            // by `prepare_user_return` when they are stale.
+            "or ", $scratch, ", 0x800\n",
+            "bts ", $scratch, ", 63\n",
+            "5:\n",
+            // Set `USER_TABLE_BIT`.
+            "or ", $scratch, ", 0x1000\n",
+            "mov cr3, ", $scratch, "\n"
+        )
+    };
+}
+
+#[cfg(not(feature = "kpti"))]
+macro_rules! kpti_switch_to_kernel {
+    ($scratch: literal) => {
+        ""
+    };
+}
+
+#[cfg(not(feature = "kpti"))]
+macro_rules! kpti_switch_to_user {
+    ($scratch: literal) => {
+        ""
+    };
+}
@@ -1,12 +1,22 @@
 /// Bootstrap logic for architecture
 pub mod bootstrap;
 
+/// Debug exception support for the GDB stub.
+pub mod debug;
+
+/// Memory mapped IO regions of devices.
+pub mod device_memory;
+
 /// Global Descriptor Table.
 pub mod gdt;
 
 /// Arch level configuration.
 pub mod globals;
 
+/// Page table switches of the kernel entry points.
+#[macro_use]
+mod kpti;
+
 /// Interrupt support.
 pub mod interrupts;
 
@@ -18,17 +28,28 @@ pub mod capability;
 /// Serial port controller.
 pub mod serial;
 
+/// Kernel stacks and their guard pages.
+pub mod stack;
+
 /// Runtime for threads.
 pub mod task;
 
 pub mod tls;
 
+/// Checked access to user memory.
+pub mod user_memory;
+
 use relic_abi::cap::CapabilityErrors;
 
 use crate::{
     addr::{PAddr, PAddrGlobal, VAddr},
     arch::{
-        paging::table::{pd_index, pdpt_index, pml4_index, pt_index, PD, PDPT, PML4, PT},
+        paging::{
+            la57,
+            table::{
+                pd_index, pdpt_index, pml4_index, pml5_index, pt_index, PD, PDPT, PML4, PML5, PT,
+            },
+        },
         serial::SerialLogger,
     },
 };
@@ -37,14 +58,6 @@ use crate::{
 /// Architecture level logs for x86_64.
 pub static LOGGER: SerialLogger = SerialLogger;
 
-#[cfg(test)]
-const MEM_SIZE: usize = 1 << 25;
-#[cfg(not(test))]
-const MEM_SIZE: usize = 1 << 16;
-
-#[global_allocator]
-static GLOBAL_ALLOC: static_alloc::Bump<[u8; MEM_SIZE]> = static_alloc::Bump::uninit(); // 64KB
-
 pub mod cpu_locals {
     pub use super::interrupts::apic::LAPIC;
     pub use super::interrupts::apic::PROCESSOR_ID;
@@ -59,26 +72,45 @@ impl PAddrGlobal {
 }
 
 impl VAddr {
+    /// Whether the address is canonical and in the upper half. The width of
+    /// addresses depends on the paging mode.
     pub fn validate_kernel_mode(self) -> Result<(), CapabilityErrors> {
         let val: u64 = self.into();
-        let val = val >> 47;
-        if val == 0b1111_1111_1111_1111_1 {
+        let shift = la57::address_bits() - 1;
+        if val >> shift == u64::MAX >> shift {
             Ok(())
         } else {
             Err(CapabilityErrors::InvalidMemoryAddress)
         }
     }
 
+    /// Whether the address is canonical and in the lower half.
     pub fn validate_user_mode(self) -> Result<(), CapabilityErrors> {
         let val: u64 = self.into();
-        let val = val >> 47;
-        if val == 0 {
+        if val >> (la57::address_bits() - 1) == 0 {
             Ok(())
         } else {
             Err(CapabilityErrors::InvalidMemoryAddress)
         }
     }
 
+    /// Translate a vaddr to paddr in the address space with the top-level table
+    /// at `root`. It is a PML5 with five-level paging.
+    pub fn translate_in(self, root: PAddr) -> Option<PAddr> {
+        let root = if la57::is_active() {
+            let l5: &PML5 = unsafe { root.to_paddr_global().as_mut_ptr() };
+            let l4_entry = l5[pml5_index(self)];
+            if !l4_entry.is_present() {
+                None?
+            }
+            l4_entry.get_address()
+        } else {
+            root
+        };
+        let l4: &PML4 = unsafe { root.to_paddr_global().as_mut_ptr() };
+        self.translate(l4)
+    }
+
     /// Translate a vaddr to paddr in given level4 page.
     pub fn translate(self, l4: &PML4) -> Option<PAddr> {
         let addr_mapping = |addr: PAddr| {
@@ -100,7 +132,7 @@ impl VAddr {
                 None?
             }
             let l2_paddr = l2_entry.get_address();
-            if l2_entry.is_pat() {
+            if l2_entry.is_page() {
                 // Huge page
                 let l2_paddr_u64: u64 = l2_paddr.into();
                 return Some((l2_paddr_u64 | (vaddr_u64 & 0x3FFF_FFFF)).into());
@@ -112,7 +144,7 @@ impl VAddr {
                 None?
             }
             let l1_paddr = l1_entry.get_address();
-            if l1_entry.is_pat() {
+            if l1_entry.is_page() {
                 // Large page
                 let l1_paddr_u64: u64 = l1_paddr.into();
                 return Some((l1_paddr_u64 | (vaddr_u64 & 0x1F_FFFF)).into());
@@ -144,5 +176,11 @@ mod tests {
         assert!(VAddr::new(0xFFFF_FFFF_FFFF_FFFF)
             .validate_kernel_mode()
             .is_ok());
+
+        // Tests run with four-level paging.
+        assert!(VAddr::new(0x8000_0000_0000).validate_user_mode().is_err());
+        assert!(VAddr::new(0xFF00_0000_0000_0000)
+            .validate_kernel_mode()
+            .is_err());
     }
 }
@@ -0,0 +1,176 @@
+/*!
+Kernel page-table isolation (KPTI).
+
+Without isolation, every user address space maps the whole kernel, including the
+physical memory window, and only the supervisor bit of the entries protects it.
+With isolation, user mode runs on a separate user page table that maps the user
+half of the address space and, of the kernel, only what the CPU needs to enter it:
+the entry trampolines, the IDT, and the entry area with the GDT and TSS and the
+entry stacks of every core.
+
+The user page table is the page following the PML4 of the address space, so the
+trampolines switch between both by flipping [`USER_TABLE_BIT`] in CR3. With PCIDs,
+the user page table is tagged with the PCID of the address space with
+[`USER_PCID_BIT`] set, so switching does not flush the TLB.
+*/
+
+use crate::{
+    addr::{PAddr, VAddr},
+    arch::{
+        gdt,
+        globals::MEM_MAP_OFFSET_LOCATION,
+        interrupts,
+        paging::{pcid, table::*, utils},
+        stack,
+    },
+};
+
+/// Bit of CR3 that selects the user page table following the PML4.
+pub const USER_TABLE_BIT: u64 = 1 << 12;
+
+/// Bit of the PCID that tags the TLB entries of the user page table.
+pub const USER_PCID_BIT: u16 = 1 << 11;
+
+/// Number of page tables available for the kernel half of the user page tables.
+const KERNEL_TABLE_COUNT: usize = 16;
+
+extern "C" {
+    static __trampoline_start: u8;
+    static __trampoline_end: u8;
+}
+
+#[repr(C, align(4096))]
+struct KernelTables([[u64; 512]; KERNEL_TABLE_COUNT]);
+
+/// Page tables that map the kernel half of the user page tables.
+static mut KERNEL_TABLES: KernelTables = KernelTables([[0; 512]; KERNEL_TABLE_COUNT]);
+static mut KERNEL_TABLES_USED: usize = 0;
+
+#[repr(align(4096))]
+struct UserKernelHalf([PML4Entry; 512]);
+
+/// Template of the user page tables. Only the kernel half is used.
+static mut USER_KERNEL_HALF: UserKernelHalf = UserKernelHalf([PML4Entry::empty(); 512]);
+
+/// Physical address of a kernel virtual address.
+unsafe fn kernel_paddr(vaddr: VAddr) -> PAddr {
+    let current_page_table_paddr: u64 = utils::cr3().into();
+    let current_page_table: &PML4 =
+        &*((current_page_table_paddr + MEM_MAP_OFFSET_LOCATION) as *const _);
+    vaddr
+        .translate(current_page_table)
+        .expect("Kernel address is not mapped")
+}
+
+/// Take a zeroed page table from [`KERNEL_TABLES`]. Returns its physical address.
+unsafe fn allocate_table() -> PAddr {
+    let table = KERNEL_TABLES
+        .0
+        .get_mut(KERNEL_TABLES_USED)
+        .expect("Not enough page tables for page table isolation");
+    KERNEL_TABLES_USED += 1;
+    kernel_paddr(VAddr::new(table as *mut [u64; 512] as u64))
+}
+
+/// Get the table referenced by the entry, creating it if it is not present.
+macro_rules! next_table {
+    ($entry: expr, $entry_type: ident, $table: ty) => {{
+        if !$entry.is_present() {
+            $entry = $entry_type::new(
+                allocate_table(),
+                $entry_type::PRESENT | $entry_type::READ_WRITE,
+            );
+        }
+        let paddr: u64 = $entry.get_address().into();
+        &mut *((paddr + MEM_MAP_OFFSET_LOCATION) as *mut $table)
+    }};
+}
+
+/// Map the kernel pages from `start` to `end` at the same addresses into the kernel
+/// half of the user page tables.
+unsafe fn map_kernel_range(start: u64, end: u64, flags: PTEntry) {
+    let mut page = start & !0xFFF;
+    while page < end {
+        let vaddr = VAddr::new(page);
+        let pdpt = next_table!(USER_KERNEL_HALF.0[pml4_index(vaddr)], PML4Entry, PDPT);
+        let pd = next_table!(pdpt[pdpt_index(vaddr)], PDPTEntry, PD);
+        let pt = next_table!(pd[pd_index(vaddr)], PDEntry, PT);
+        pt[pt_index(vaddr)] = PTEntry::new(kernel_paddr(vaddr), PTEntry::PRESENT | flags);
+        page += 0x1000;
+    }
+}
+
+/**
+Map the entry points of the kernel into the user page tables. Must be called on
+every core once its GDT and IDT are initialized and before user page tables are
+created.
+*/
+pub fn initialize(core: usize) {
+    unsafe {
+        let trampoline_start = &__trampoline_start as *const u8 as u64;
+        let trampoline_end = &__trampoline_end as *const u8 as u64;
+        map_kernel_range(trampoline_start, trampoline_end, PTEntry::empty());
+
+        let idt = interrupts::idt_range();
+        map_kernel_range(idt.start, idt.end, PTEntry::EXECUTE_DISABLE);
+
+        // The CPU sets accessed and busy bits in the GDT.
+        let entry_area = gdt::entry_area_range();
+        map_kernel_range(
+            entry_area.start,
+            entry_area.end,
+            PTEntry::READ_WRITE | PTEntry::EXECUTE_DISABLE,
+        );
+
+        // The guard pages between the stacks stay unmapped.
+        for entry_stack in &[
+            stack::double_fault_stack(core),
+            stack::privilege_0_stack(core),
+        ] {
+            map_kernel_range(
+                entry_stack.start,
+                entry_stack.end,
+                PTEntry::READ_WRITE | PTEntry::EXECUTE_DISABLE,
+            );
+        }
+    }
+}
+
+/// Initialize the kernel half of a new user page table.
+pub fn initialize_user_table(user_table: &mut PML4) {
+    unsafe { user_table[256..].copy_from_slice(&USER_KERNEL_HALF.0[256..]) };
+}
+
+/// Copy the user half of the kernel page table into the user page table. Mappings
+/// are only changed by the kernel, so this is done when switching to the address space.
+pub fn update_user_table(kernel_table: &PML4, user_table: &mut PML4) {
+    user_table[..256].copy_from_slice(&kernel_table[..256]);
+}
+
+/// Flush the TLB entries of the user page table of the current address space if
+/// they are stale. Must be called before returning to user mode.
+pub fn prepare_user_return() {
+    if pcid::take_user_flush() {
+        unsafe { asm!("call {0}", sym flush_user_tlb, out("rax") _, out("rdx") _) };
+    }
+}
+
+/// Flush the TLB entries of the user PCID by switching to the user page table
+/// without keeping them.
+#[naked]
+#[link_section = ".trampoline"]
+unsafe extern "C" fn flush_user_tlb() {
+    asm!(
+        "
+        mov rax, cr3
+        mov rdx, rax
+        or rax, {0}
+        mov cr3, rax
+        bts rdx, 63
+        mov cr3, rdx
+        ret
+    ",
+        const USER_TABLE_BIT | USER_PCID_BIT as u64,
+        options(noreturn)
+    );
+}
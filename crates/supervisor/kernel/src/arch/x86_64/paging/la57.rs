@@ -0,0 +1,230 @@
+/*!
+Five-level paging (LA57).
+
+With five-level paging, linear addresses are 57 bits wide and a PML5 is the
+root of every address space. BOOTBOOT starts the kernel with four-level paging,
+and the paging mode can only be changed while paging is disabled. The switch is
+done at boot by a trampoline that runs from identity mapped memory below 4 GiB.
+It leaves long mode through compatibility mode, sets CR4.LA57 and enables paging
+again with the new PML5.
+
+The boot PML5 maps the boot PML4 at entry 0, which keeps the identity mapping of
+the loader. Entry 511 maps a PML4 with the kernel entries of the boot PML4. As
+the upper bits of kernel addresses are set, they stay the same in both modes.
+*/
+
+// Page table isolation keeps a user PML4 next to every address space, which has no
+// counterpart for a PML5.
+#[cfg(all(feature = "kpti", feature = "la57"))]
+compile_error!("The kpti and la57 features cannot be enabled together");
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use x86_64::registers::control::{Cr4, Cr4Flags};
+
+use crate::{
+    addr::PAddr,
+    arch::paging::table::{PML4Entry, PML5Entry, PML4, PML5},
+};
+
+/// The trampoline and the PML5 must be below this address.
+pub const TRAMPOLINE_LIMIT: u64 = 0x1_0000_0000;
+
+/// Offset of the entry point in the trampoline.
+const TRAMPOLINE_ENTRY: u64 = 0x40;
+
+/// Bit of CR4 that enables five-level paging.
+const CR4_LA57: u64 = 1 << 12;
+
+// Tests run in parallel and pick the paging mode each for themselves.
+#[cfg_attr(test, thread_local)]
+static LA57_ACTIVE: AtomicBool = AtomicBool::new(false);
+
+extern "C" {
+    static __la57_trampoline_start: u8;
+    static __la57_trampoline_end: u8;
+}
+
+// The trampoline starts with its data. The entry point at `TRAMPOLINE_ENTRY` is
+// called with the PML5 in rdi and the address of the copy in rsi. The temporary
+// GDT has a 64-bit code segment at 0x08, a 32-bit code segment at 0x10 and a
+// data segment at 0x18.
+global_asm!(
+    "
+    .intel_syntax noprefix
+    .section .rodata.la57_trampoline, \"a\"
+    .global __la57_trampoline_start
+    .global __la57_trampoline_end
+    .balign 8
+__la57_trampoline_start:
+    // 0x00: GDT
+    .quad 0
+    .quad 0x00AF9A000000FFFF
+    .quad 0x00CF9A000000FFFF
+    .quad 0x00CF92000000FFFF
+    // 0x20: GDT pointer
+    .short 4 * 8 - 1
+    .quad 0
+    .balign 8
+    // 0x30: Far pointer to the 64-bit code
+    .long 0
+    .short 0x08
+    .balign 8
+    // 0x38: Stack pointer of the caller
+    .quad 0
+
+    // 0x40: Entry point
+    .code64
+    push rbx
+    push rbp
+    push r12
+    push r13
+    push r14
+    push r15
+    mov [rsi + 0x38], rsp
+
+    mov [rsi + 0x22], rsi
+    lgdt [rsi + 0x20]
+    lea rax, [rip + la57_long_mode]
+    mov [rsi + 0x30], eax
+    mov ax, 0x18
+    mov ds, ax
+    mov es, ax
+    mov ss, ax
+    push 0x10
+    lea rax, [rip + la57_compatibility_mode]
+    push rax
+    retfq
+
+    .code32
+la57_compatibility_mode:
+    mov eax, cr0
+    and eax, 0x7FFFFFFF
+    mov cr0, eax
+    mov eax, cr4
+    or eax, 0x1000
+    mov cr4, eax
+    mov cr3, edi
+    mov eax, cr0
+    or eax, 0x80000000
+    mov cr0, eax
+    jmp fword ptr [esi + 0x30]
+
+    .code64
+la57_long_mode:
+    mov esi, esi
+    mov rsp, [rsi + 0x38]
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop rbp
+    pop rbx
+    ret
+__la57_trampoline_end:
+    .previous
+    .att_syntax prefix
+    "
+);
+
+/// Whether the CPU supports five-level paging.
+pub fn is_supported() -> bool {
+    let extended_features = unsafe { core::arch::x86_64::__cpuid_count(7, 0) };
+    extended_features.ecx & (1 << 16) != 0
+}
+
+/// Whether five-level paging is used. The root of address spaces is a PML5 then.
+pub fn is_active() -> bool {
+    LA57_ACTIVE.load(Ordering::Relaxed)
+}
+
+/// Pretend that five-level paging is used or not on the current thread.
+#[cfg(test)]
+pub fn set_active(active: bool) {
+    LA57_ACTIVE.store(active, Ordering::Relaxed);
+}
+
+/// Width of linear addresses in bits.
+pub fn address_bits() -> u32 {
+    if is_active() {
+        57
+    } else {
+        48
+    }
+}
+
+/**
+Switch from the four-level paging of the loader to five-level paging.
+
+`pml5` and `trampoline` are free pages below [`TRAMPOLINE_LIMIT`] and
+`kernel_pml4` is a free page that becomes the kernel half of all address spaces.
+
+# Safety
+Must be called once on the bootstrap core with the boot PML4 active, before the
+GDT of the kernel is loaded. The loader must identity map the pages below
+[`TRAMPOLINE_LIMIT`].
+*/
+pub unsafe fn enable(pml5: PAddr, kernel_pml4: PAddr, trampoline: PAddr) {
+    let boot_pml4_paddr = super::utils::cr3();
+    let boot_pml4: &PML4 = boot_pml4_paddr.to_paddr_global().as_mut_ptr();
+
+    let kernel_half: &mut PML4 = kernel_pml4.to_paddr_global().as_mut_ptr();
+    *kernel_half = [PML4Entry::empty(); 512];
+    kernel_half[510] = boot_pml4[510];
+    kernel_half[511] = boot_pml4[511];
+
+    let root: &mut PML5 = pml5.to_paddr_global().as_mut_ptr();
+    *root = [PML5Entry::empty(); 512];
+    let flags = PML5Entry::PRESENT | PML5Entry::READ_WRITE;
+    root[0] = PML5Entry::new(boot_pml4_paddr, flags);
+    root[511] = PML5Entry::new(kernel_pml4, flags);
+
+    let start = &__la57_trampoline_start as *const u8;
+    let length = &__la57_trampoline_end as *const u8 as usize - start as usize;
+    let trampoline_global = trampoline.to_paddr_global();
+    core::ptr::copy_nonoverlapping(start, trampoline_global.as_raw_ptr(), length);
+
+    // The identity mapping of the loader might be accessible from user mode.
+    let cr4 = Cr4::read();
+    let switch_flags = Cr4Flags::PCID
+        | Cr4Flags::SUPERVISOR_MODE_EXECUTION_PROTECTION
+        | Cr4Flags::SUPERVISOR_MODE_ACCESS_PREVENTION;
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        Cr4::write(cr4 - switch_flags);
+
+        let raw_trampoline: u64 = trampoline.into();
+        let entry: extern "C" fn(u64, u64) =
+            core::mem::transmute(raw_trampoline + TRAMPOLINE_ENTRY);
+        entry(pml5.into(), raw_trampoline);
+
+        Cr4::write(Cr4::read() | (cr4 & switch_flags));
+    });
+
+    LA57_ACTIVE.store(Cr4::read_raw() & CR4_LA57 != 0, Ordering::Relaxed);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::addr::VAddr;
+
+    #[test]
+    fn test_user_addresses() {
+        set_active(false);
+        assert_eq!(address_bits(), 48);
+        assert!(VAddr::new(0x7FFF_FFFF_FFFF).validate_user_mode().is_ok());
+        assert!(VAddr::new(0x8000_0000_0000).validate_user_mode().is_err());
+
+        // The user half reaches up to bit 56 with five-level paging.
+        set_active(true);
+        assert_eq!(address_bits(), 57);
+        assert!(VAddr::new(0x8000_0000_0000).validate_user_mode().is_ok());
+        assert!(VAddr::new(0xFF_FFFF_FFFF_FFFF).validate_user_mode().is_ok());
+        assert!(VAddr::new(0x100_0000_0000_0000)
+            .validate_user_mode()
+            .is_err());
+        assert!(VAddr::new(0xFF00_0000_0000_0000)
+            .validate_user_mode()
+            .is_err());
+    }
+}
@@ -4,6 +4,19 @@ pub mod table;
 /// Utilities for paging
 pub mod utils;
 
+/// Process context identifiers for tagged TLBs.
+pub mod pcid;
+
+/// Memory types of pages.
+pub mod pat;
+
+/// Five-level paging.
+pub mod la57;
+
+/// Kernel page-table isolation.
+#[cfg(feature = "kpti")]
+pub mod kpti;
+
 /// MAXPHYADDR, which is at most 52; (use CPUID for finding system value).
 pub const MAXPHYADDR: u64 = 52;
 
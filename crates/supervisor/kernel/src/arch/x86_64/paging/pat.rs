@@ -0,0 +1,78 @@
+/*!
+Page attribute table (PAT).
+
+The memory type of a page is selected by the PAT, PCD and PWT bits of its entry,
+which index the eight entries of the IA32_PAT MSR. The table is programmed like
+the power-on default, except that entry 4 is write-combining instead of
+write-back. So pages only get the new memory type when the PAT bit is set alone:
+
+| PAT | PCD | PWT | Memory type     |
+|-----|-----|-----|-----------------|
+| 0   | 0   | 0   | Write-back      |
+| 0   | 0   | 1   | Write-through   |
+| 0   | 1   | 0   | Uncached minus  |
+| 0   | 1   | 1   | Uncacheable     |
+| 1   | 0   | 0   | Write-combining |
+*/
+
+use x86_64::registers::model_specific::Msr;
+
+const IA32_PAT: u32 = 0x277;
+
+const UNCACHEABLE: u64 = 0x00;
+const WRITE_COMBINING: u64 = 0x01;
+const WRITE_THROUGH: u64 = 0x04;
+const WRITE_BACK: u64 = 0x06;
+const UNCACHED_MINUS: u64 = 0x07;
+
+/// Memory types of the PAT entries, starting with entry 0.
+const PAT_ENTRIES: [u64; 8] = [
+    WRITE_BACK,
+    WRITE_THROUGH,
+    UNCACHED_MINUS,
+    UNCACHEABLE,
+    WRITE_COMBINING,
+    WRITE_THROUGH,
+    UNCACHED_MINUS,
+    UNCACHEABLE,
+];
+
+/**
+Program the PAT of the current core.
+
+No page may be mapped with the PAT bit set yet. Only entry 4 differs from the
+power-on default, so the caches and the TLB do not have to be flushed.
+*/
+pub fn init() {
+    unsafe {
+        Msr::new(IA32_PAT).write(pat_value());
+    }
+}
+
+/// Value of the IA32_PAT MSR, one byte per entry.
+fn pat_value() -> u64 {
+    PAT_ENTRIES
+        .iter()
+        .enumerate()
+        .fold(0, |value, (index, memory_type)| {
+            value | memory_type << (index * 8)
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pat_value() {
+        /// Value of the IA32_PAT MSR at power-on.
+        const POWER_ON_DEFAULT: u64 = 0x0007_0406_0007_0406;
+
+        assert_eq!(pat_value(), 0x0007_0401_0007_0406);
+        // Only entry 4 differs, which no page selects before the PAT is programmed.
+        assert_eq!(
+            pat_value() ^ POWER_ON_DEFAULT,
+            (WRITE_COMBINING ^ WRITE_BACK) << 32
+        );
+    }
+}
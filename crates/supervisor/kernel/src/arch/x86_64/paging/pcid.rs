@@ -0,0 +1,324 @@
+/*!
+Process context identifiers (PCIDs).
+
+With PCIDs enabled, TLB entries are tagged with the PCID of the address space
+that created them, so switching between address spaces does not flush the TLB.
+This is only correct as long as a PCID is used by a single address space on a
+core. Every core hands out its PCIDs round robin and takes them away from the
+previous owner once they run out. An address space whose PCID was taken gets a
+new one on its next switch, which flushes the stale entries of the recycled PCID.
+
+With page table isolation, the user page table of an address space uses its PCID
+with [`kpti::USER_PCID_BIT`] set. Its entries are flushed on the way back to user
+mode when they may be stale.
+*/
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use x86_64::registers::control::{Cr4, Cr4Flags};
+
+#[cfg(feature = "kpti")]
+use crate::arch::paging::kpti;
+use crate::{
+    addr::{PAddr, VAddr},
+    arch::paging::utils,
+};
+
+/// Number of PCIDs handed out on every core. PCID 0 is left to the boot page tables.
+const PCID_COUNT: usize = 64;
+
+static PCID_SUPPORTED: AtomicBool = AtomicBool::new(false);
+static INVPCID_SUPPORTED: AtomicBool = AtomicBool::new(false);
+
+/// Owners of the PCIDs of a core, identified by the physical address of their PML4.
+struct PcidTable {
+    owners: [u64; PCID_COUNT],
+    next: usize,
+    /// PCIDs whose user page table entries must be flushed, one bit per PCID.
+    #[cfg(feature = "kpti")]
+    user_flush: u64,
+}
+
+#[thread_local]
+static mut PCID_TABLE: PcidTable = PcidTable::new();
+
+/// How an address is flushed from the TLB entries of a PCID.
+#[derive(Debug, PartialEq, Eq)]
+enum Invalidation {
+    /// The PCID is the current one, the address is flushed directly.
+    Current,
+    /// The address is flushed from the PCID with INVPCID.
+    Pcid,
+    /// The PCID was taken from the address space, which gets a flushed PCID on
+    /// its next switch.
+    Released,
+}
+
+impl PcidTable {
+    const fn new() -> Self {
+        Self {
+            owners: [0; PCID_COUNT],
+            next: 1,
+            #[cfg(feature = "kpti")]
+            user_flush: 0,
+        }
+    }
+
+    /// Find the PCID owned by the PML4. `hint` is the PCID it had last time.
+    fn find(&self, hint: u16, pml4: u64) -> Option<u16> {
+        let hint = hint as usize;
+        if hint != 0 && hint < PCID_COUNT && self.owners[hint] == pml4 {
+            return Some(hint as u16);
+        }
+
+        (1..PCID_COUNT)
+            .find(|&pcid| self.owners[pcid] == pml4)
+            .map(|pcid| pcid as u16)
+    }
+
+    /// Take the next PCID from its owner.
+    fn allocate(&mut self, pml4: u64) -> u16 {
+        let pcid = self.next;
+        self.next = if pcid + 1 == PCID_COUNT { 1 } else { pcid + 1 };
+        self.owners[pcid] = pml4;
+        #[cfg(feature = "kpti")]
+        {
+            self.user_flush |= 1 << pcid;
+        }
+        pcid as u16
+    }
+
+    /**
+    Find how to flush an address from the PCID owned by the PML4. Returns `None` if
+    nothing of the address space is cached on this core. Without INVPCID, only the
+    current PCID can be flushed and any other one is taken from its owner.
+    */
+    fn invalidation(
+        &mut self,
+        hint: u16,
+        pml4: u64,
+        current_pcid: u16,
+        invpcid: bool,
+    ) -> Option<(u16, Invalidation)> {
+        let pcid = self.find(hint, pml4)?;
+
+        // The user page table of the address space caches the address too.
+        #[cfg(feature = "kpti")]
+        if !invpcid {
+            self.user_flush |= 1 << pcid;
+        }
+
+        let invalidation = if current_pcid == pcid {
+            Invalidation::Current
+        } else if invpcid {
+            Invalidation::Pcid
+        } else {
+            self.owners[pcid as usize] = 0;
+            Invalidation::Released
+        };
+        Some((pcid, invalidation))
+    }
+}
+
+/**
+Detect whether PCIDs can be used. CR4.PCIDE can only be set while the PCID
+bits of CR3 are clear. The result is used by the rest of this module, so this
+must be called before PCIDs are enabled in CR4.
+*/
+pub fn detect() -> bool {
+    let features = unsafe { core::arch::x86_64::__cpuid(1) };
+    let extended_features = unsafe { core::arch::x86_64::__cpuid_count(7, 0) };
+
+    let pcid = features.ecx & (1 << 17) != 0 && utils::current_pcid() == 0;
+    let invpcid = pcid && extended_features.ebx & (1 << 10) != 0;
+    PCID_SUPPORTED.store(pcid, Ordering::Relaxed);
+    INVPCID_SUPPORTED.store(invpcid, Ordering::Relaxed);
+    pcid
+}
+
+/**
+Switch to the PML4 at `pml4`. `pcid` is the PCID the address space was switched
+to last time. Returns the PCID that is now in use.
+
+# Safety
+`pml4` must point to a valid PML4 page table.
+*/
+pub unsafe fn switch_to(pml4: PAddr, pcid: u16) -> u16 {
+    if !PCID_SUPPORTED.load(Ordering::Relaxed) {
+        utils::switch_to(pml4);
+        return 0;
+    }
+
+    let raw_pml4: u64 = pml4.into();
+    match PCID_TABLE.find(pcid, raw_pml4) {
+        Some(pcid) => {
+            utils::switch_to_pcid(pml4, pcid, false);
+            pcid
+        }
+        None => {
+            // The PCID might hold entries of its previous owner.
+            let pcid = PCID_TABLE.allocate(raw_pml4);
+            utils::switch_to_pcid(pml4, pcid, true);
+            pcid
+        }
+    }
+}
+
+/// Invalidate `vaddr` in the address space of the PML4 at `pml4`.
+pub fn invalidate(pml4: PAddr, pcid: u16, vaddr: VAddr) {
+    // Privileged instructions cannot run in unit tests.
+    if cfg!(test) {
+        return;
+    }
+
+    if !PCID_SUPPORTED.load(Ordering::Relaxed) {
+        // Other address spaces are flushed when switching to them.
+        if unsafe { utils::cr3() } == pml4 {
+            utils::flush(vaddr);
+        }
+        return;
+    }
+
+    let raw_pml4: u64 = pml4.into();
+    let invpcid = INVPCID_SUPPORTED.load(Ordering::Relaxed);
+    let invalidation =
+        unsafe { PCID_TABLE.invalidation(pcid, raw_pml4, utils::current_pcid(), invpcid) };
+    let (pcid, invalidation) = match invalidation {
+        Some(invalidation) => invalidation,
+        // Nothing of this address space is cached on this core.
+        None => return,
+    };
+
+    #[cfg(feature = "kpti")]
+    if invpcid {
+        unsafe { utils::flush_pcid_address(pcid | kpti::USER_PCID_BIT, vaddr) };
+    }
+
+    match invalidation {
+        Invalidation::Current => utils::flush(vaddr),
+        Invalidation::Pcid => unsafe { utils::flush_pcid_address(pcid, vaddr) },
+        Invalidation::Released => {}
+    }
+}
+
+/// Invalidate the TLB entries of every address space on this core, including
+/// global ones.
+pub fn invalidate_all() {
+    if cfg!(test) {
+        return;
+    }
+
+    let cr4 = Cr4::read();
+    if cr4.contains(Cr4Flags::PAGE_GLOBAL) {
+        // Toggling global pages flushes every PCID.
+        unsafe {
+            Cr4::write(cr4 - Cr4Flags::PAGE_GLOBAL);
+            Cr4::write(cr4);
+        }
+    } else {
+        utils::flush_all();
+        // Global pages are disabled with page table isolation. Every address space
+        // gets a flushed PCID on its next switch.
+        #[cfg(feature = "kpti")]
+        unsafe {
+            PCID_TABLE.owners = [0; PCID_COUNT];
+        }
+    }
+}
+
+/// Whether the user page table entries of the current address space must be
+/// flushed before returning to user mode. Clears the request.
+#[cfg(feature = "kpti")]
+pub fn take_user_flush() -> bool {
+    let pcid = utils::current_pcid() as usize;
+    unsafe {
+        let pending = PCID_TABLE.user_flush & (1 << pcid) != 0;
+        PCID_TABLE.user_flush &= !(1 << pcid);
+        pending
+    }
+}
+
+/// Give up the PCID of a deleted address space. Its stale entries are flushed
+/// when the PCID is handed out again.
+pub fn release(pml4: PAddr, pcid: u16) {
+    let raw_pml4: u64 = pml4.into();
+    unsafe {
+        if let Some(pcid) = PCID_TABLE.find(pcid, raw_pml4) {
+            PCID_TABLE.owners[pcid as usize] = 0;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// PML4 of the address space with the given number.
+    fn pml4(index: usize) -> u64 {
+        0x10_0000 + index as u64 * 0x1000
+    }
+
+    #[test]
+    fn test_recycle() {
+        let mut table = PcidTable::new();
+        for index in 1..PCID_COUNT {
+            assert_eq!(table.allocate(pml4(index)), index as u16);
+        }
+        for index in 1..PCID_COUNT {
+            assert_eq!(table.find(0, pml4(index)), Some(index as u16));
+        }
+
+        // Once all are handed out, the PCIDs are taken from their owners in order.
+        assert_eq!(table.allocate(pml4(PCID_COUNT)), 1);
+        assert_eq!(table.allocate(pml4(PCID_COUNT + 1)), 2);
+        assert_eq!(table.find(1, pml4(1)), None);
+        assert_eq!(table.find(2, pml4(2)), None);
+        assert_eq!(table.find(1, pml4(PCID_COUNT)), Some(1));
+        assert_eq!(table.find(3, pml4(3)), Some(3));
+
+        // An old hint finds the PCID where it moved to.
+        assert_eq!(table.allocate(pml4(1)), 3);
+        assert_eq!(table.find(1, pml4(1)), Some(3));
+        assert_eq!(table.find(3, pml4(3)), None);
+    }
+
+    #[test]
+    fn test_invalidation() {
+        let mut table = PcidTable::new();
+        let current = table.allocate(pml4(1));
+        let other = table.allocate(pml4(2));
+
+        assert_eq!(table.invalidation(0, pml4(3), current, false), None);
+        assert_eq!(
+            table.invalidation(current, pml4(1), current, false),
+            Some((current, Invalidation::Current))
+        );
+        assert_eq!(
+            table.invalidation(other, pml4(2), current, true),
+            Some((other, Invalidation::Pcid))
+        );
+        assert_eq!(table.find(other, pml4(2)), Some(other));
+
+        // Without INVPCID, the address space must get a flushed PCID on its next switch.
+        assert_eq!(
+            table.invalidation(other, pml4(2), current, false),
+            Some((other, Invalidation::Released))
+        );
+        assert_eq!(table.find(other, pml4(2)), None);
+        assert_eq!(table.invalidation(other, pml4(2), current, false), None);
+        assert_eq!(table.allocate(pml4(2)), 3);
+    }
+
+    #[cfg(feature = "kpti")]
+    #[test]
+    fn test_user_flush() {
+        let mut table = PcidTable::new();
+        let pcid = table.allocate(pml4(1));
+        table.user_flush = 0;
+
+        table.invalidation(pcid, pml4(1), pcid, true);
+        assert_eq!(table.user_flush, 0);
+        table.invalidation(pcid, pml4(1), pcid, false);
+        assert_eq!(table.user_flush, 1 << pcid);
+    }
+}
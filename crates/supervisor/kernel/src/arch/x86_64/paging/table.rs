@@ -18,6 +18,9 @@ use crate::addr::{PAddr, VAddr};
 use super::ADDRESS_MASK;
 use crate::arch::globals::BASE_PAGE_LENGTH;
 
+/// A PML5 table. Only used with five-level paging.
+pub type PML5 = [PML5Entry; 512];
+
 /// A PML4 table.
 /// In practice this has only 4 entries but it still needs to be the size of a 4K page.
 pub type PML4 = [PML4Entry; 512];
@@ -31,6 +34,12 @@ pub type PD = [PDEntry; 512];
 /// A page table.
 pub type PT = [PTEntry; 512];
 
+/// Given virtual address calculate corresponding entry in PML5.
+#[inline]
+pub fn pml5_index(addr: VAddr) -> usize {
+    ((addr.into(): usize) >> 48) & 0b111111111
+}
+
 /// Given virtual address calculate corresponding entry in PML4.
 #[inline]
 pub fn pml4_index(addr: VAddr) -> usize {
@@ -55,6 +64,65 @@ pub fn pt_index(addr: VAddr) -> usize {
     ((addr.into(): usize) >> 12) & 0b111111111
 }
 
+bitflags! {
+    /// PML5 Entry bits description.
+    pub struct PML5Entry: u64 {
+        /// Present; must be 1 to reference a PML4 table
+        const PRESENT       = bit!(0);
+        /// Read/write; if 0, writes may not be allowed to the 256-TByte region
+        /// controlled by this entry
+        const READ_WRITE      = bit!(1);
+        /// User/supervisor; if 0, user-mode accesses are not allowed
+        /// to the 256-TByte region controlled by this entry.
+        const USERSPACE      = bit!(2);
+        /// Page-level write-through; indirectly determines the memory type used to
+        /// access the PML4 table referenced by this entry.
+        const WRITE_THROUGH     = bit!(3);
+        /// Page-level cache disable; indirectly determines the memory type used to
+        /// access the PML4 table referenced by this entry.
+        const CACHE_DISABLE     = bit!(4);
+        /// Accessed; indicates whether this entry has been used for linear-address translation.
+        const ACCESSED       = bit!(5);
+        /// If IA32_EFER.NXE = 1, execute-disable
+        /// If 1, instruction fetches are not allowed from the 256-TByte region.
+        const EXECUTE_DISABLE      = bit!(63);
+    }
+}
+
+impl PML5Entry {
+    /// Creates a new PML5Entry.
+    ///
+    /// # Arguments
+    ///
+    ///  * `pml4` - The physical address of the PML4 table.
+    ///  * `flags`- Additional flags for the entry.
+    pub fn new(pml4: PAddr, flags: PML5Entry) -> PML5Entry {
+        assert!((pml4.into(): usize) % BASE_PAGE_LENGTH == 0);
+        PML5Entry {
+            bits: (pml4.into(): u64) | flags.bits,
+        }
+    }
+
+    /// Retrieves the physical address in this entry.
+    pub fn get_address(self) -> PAddr {
+        PAddr::from(self.bits & ADDRESS_MASK)
+    }
+
+    check_flag!(doc = "Is page present?", is_present, PRESENT);
+    check_flag!(doc = "Read/write; if 0, writes may not be allowed to the 256-TByte region, controlled by this entry",
+                is_writeable, READ_WRITE);
+    check_flag!(doc = "User/supervisor; if 0, user-mode accesses are not allowed to the 256-TByte region controlled by this entry.",
+                is_user_mode_allowed, USERSPACE);
+    check_flag!(
+        doc =
+            "Accessed; indicates whether this entry has been used for linear-address translation.",
+        is_accessed,
+        ACCESSED
+    );
+    check_flag!(doc = "If IA32_EFER.NXE = 1, execute-disable. If 1, instruction fetches are not allowed from the 256-TByte region.",
+                is_instruction_fetching_disabled, EXECUTE_DISABLE);
+}
+
 bitflags! {
     /// PML4 Entry bits description.
     pub struct PML4Entry: u64 {
@@ -143,6 +211,11 @@ bitflags! {
         /// Global; if HUGE_PAGE && CR4.PGE = 1, determines whether the translation is global; ignored otherwise
         /// if not HUGE_PAGE this is ignored.
         const GLOBAL       = bit!(8);
+        /// Ignored by the processor. If HUGE_PAGE marks a read only page that is copied on the first write.
+        const COPY_ON_WRITE       = bit!(9);
+        /// PAT; if HUGE_PAGE selects the memory type together with WRITE_THROUGH and CACHE_DISABLE.
+        /// if not HUGE_PAGE this is part of the address.
+        const PAT       = bit!(12);
         /// If IA32_EFER.NXE = 1, execute-disable
         /// If 1, instruction fetches are not allowed from the 512-GByte region.
         const EXECUTE_DISABLE      = bit!(63);
@@ -165,7 +238,11 @@ impl PDPTEntry {
 
     /// Retrieves the physical address in this entry.
     pub fn get_address(self) -> PAddr {
-        PAddr::from(self.bits & ADDRESS_MASK)
+        if self.contains(PDPTEntry::HUGE_PAGE) {
+            PAddr::from(self.bits & ADDRESS_MASK & !PDPTEntry::PAT.bits)
+        } else {
+            PAddr::from(self.bits & ADDRESS_MASK)
+        }
     }
 
     check_flag!(doc = "Is page present?", is_present, PRESENT);
@@ -189,8 +266,10 @@ impl PDPTEntry {
         is_accessed,
         ACCESSED
     );
+    check_flag!(doc = "Page size; if set this entry maps a 1-GByte page; otherwise, this entry references a page directory.",
+                is_page, HUGE_PAGE);
     check_flag!(doc = "Indirectly determines the memory type used to access the 1-GByte page referenced by this entry. if not HUGE_PAGE this is ignored.",
-                is_pat, HUGE_PAGE);
+                is_pat, PAT);
     check_flag!(doc = "If IA32_EFER.NXE = 1, execute-disable. If 1, instruction fetches are not allowed from the 512-GByte region.",
                 is_instruction_fetching_disabled, EXECUTE_DISABLE);
 }
@@ -219,6 +298,11 @@ bitflags! {
         /// Global; if LARGE_PAGE && CR4.PGE = 1, determines whether the translation is global; ignored otherwise
         /// if not LARGE_PAGE this is ignored.
         const GLOBAL       = bit!(8);
+        /// Ignored by the processor. If LARGE_PAGE marks a read only page that is copied on the first write.
+        const COPY_ON_WRITE       = bit!(9);
+        /// PAT; if LARGE_PAGE selects the memory type together with WRITE_THROUGH and CACHE_DISABLE.
+        /// if not LARGE_PAGE this is part of the address.
+        const PAT       = bit!(12);
         /// If IA32_EFER.NXE = 1, execute-disable
         /// If 1, instruction fetches are not allowed from the 512-GByte region.
         const EXECUTE_DISABLE      = bit!(63);
@@ -241,7 +325,11 @@ impl PDEntry {
 
     /// Retrieves the physical address in this entry.
     pub fn get_address(self) -> PAddr {
-        PAddr::from(self.bits & ADDRESS_MASK)
+        if self.contains(PDEntry::LARGE_PAGE) {
+            PAddr::from(self.bits & ADDRESS_MASK & !PDEntry::PAT.bits)
+        } else {
+            PAddr::from(self.bits & ADDRESS_MASK)
+        }
     }
 
     check_flag!(
@@ -272,7 +360,7 @@ impl PDEntry {
     check_flag!(doc = "Global; if LARGE_PAGE && CR4.PGE = 1, determines whether the translation is global; ignored otherwise if not LARGE_PAGE this is ignored.",
                 is_global, GLOBAL);
     check_flag!(doc = "Indirectly determines the memory type used to access the 2-MByte page referenced by this entry. if not LARGE_PAGE this is ignored.",
-                is_pat, LARGE_PAGE);
+                is_pat, PAT);
     check_flag!(doc = "If IA32_EFER.NXE = 1, execute-disable. If 1, instruction fetches are not allowed from the 2-Mbyte region.",
                 is_instruction_fetching_disabled, EXECUTE_DISABLE);
 }
@@ -294,8 +382,12 @@ bitflags! {
         const ACCESSED       = bit!(5);
         /// Dirty; indicates whether software has written to the 4-KByte page referenced by this entry.
         const DIRTY       = bit!(6);
+        /// PAT; selects the memory type together with WRITE_THROUGH and CACHE_DISABLE.
+        const PAT       = bit!(7);
         /// Global; if CR4.PGE = 1, determines whether the translation is global (see Section 4.10); ignored otherwise
         const GLOBAL       = bit!(8);
+        /// Ignored by the processor. Marks a read only page that is copied on the first write.
+        const COPY_ON_WRITE       = bit!(9);
         /// If IA32_EFER.NXE = 1, execute-disable
         /// If 1, instruction fetches are not allowed from the 512-GByte region.
         const EXECUTE_DISABLE      = bit!(63);
@@ -346,6 +438,8 @@ impl PTEntry {
                 is_dirty, DIRTY);
     check_flag!(doc = "Global; if PT_PS && CR4.PGE = 1, determines whether the translation is global; ignored otherwise if not PT_PS this is ignored.",
                 is_global, GLOBAL);
+    check_flag!(doc = "Indirectly determines the memory type used to access the 4-KByte page referenced by this entry.",
+                is_pat, PAT);
     check_flag!(doc = "If IA32_EFER.NXE = 1, execute-disable. If 1, instruction fetches are not allowed from the 4-KByte region.",
                 is_instruction_fetching_disabled, EXECUTE_DISABLE);
 }
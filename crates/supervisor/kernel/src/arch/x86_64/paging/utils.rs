@@ -1,17 +1,35 @@
 use crate::addr::{PAddr, VAddr};
 
+/// Bits of CR3 that hold the PCID when PCIDs are enabled.
+const CR3_PCID_MASK: u64 = 0xFFF;
+
+/// Bit of CR3 that keeps the TLB entries of the new PCID when switching.
+const CR3_NO_FLUSH: u64 = 1 << 63;
+
 /// Contains page-table root pointer.
 #[inline]
 pub unsafe fn cr3() -> PAddr {
+    (cr3_raw() & !CR3_PCID_MASK).into()
+}
+
+/// Raw value of CR3 including the PCID.
+#[inline]
+pub fn cr3_raw() -> u64 {
     let ret: u64;
-    asm!("mov {0}, cr3", out(reg) ret, options(readonly, preserves_flags));
-    ret.into()
+    unsafe { asm!("mov {0}, cr3", out(reg) ret, options(nomem, nostack, preserves_flags)) };
+    ret
+}
+
+/// PCID of the current address space. Always 0 when PCIDs are disabled.
+#[inline]
+pub fn current_pcid() -> u16 {
+    (cr3_raw() & CR3_PCID_MASK) as u16
 }
 
 /// Switch page-table PML4 pointer.
 #[inline]
-unsafe fn cr3_write(val: PAddr) {
-    asm!("mov cr3, {0}", in(reg) val.into(): u64)
+unsafe fn cr3_write(val: u64) {
+    asm!("mov cr3, {0}", in(reg) val)
 }
 
 /// Invalidate the given address in the TLB using the `invlpg` instruction.
@@ -25,10 +43,23 @@ pub fn flush(vaddr: VAddr) {
     unsafe { asm!("invlpg [{0}]", in(reg) vaddr.into(): u64, options(nostack)) }
 }
 
-/// Invalidate the TLB completely by reloading the CR3 register.
+/// Invalidate the given address for the given PCID using the `invpcid` instruction.
+///
+/// # Safety
+/// The processor must support `invpcid` and PCIDs must be enabled.
+#[inline]
+pub unsafe fn flush_pcid_address(pcid: u16, vaddr: VAddr) {
+    // Individual-address invalidation.
+    const INVPCID_ADDRESS: u64 = 0;
+    let descriptor: [u64; 2] = [pcid as u64, vaddr.into()];
+    asm!("invpcid {0}, [{1}]", in(reg) INVPCID_ADDRESS, in(reg) &descriptor, options(nostack, preserves_flags));
+}
+
+/// Invalidate the TLB completely by reloading the CR3 register. Only
+/// the entries of the current PCID are invalidated.
 #[inline]
 pub fn flush_all() {
-    unsafe { cr3_write(cr3()) }
+    unsafe { cr3_write(cr3_raw() & !CR3_NO_FLUSH) }
 }
 
 /// Switch to a PML4 page table.
@@ -39,3 +70,18 @@ pub fn flush_all() {
 pub unsafe fn switch_to(paddr: PAddr) {
     cr3_write(paddr.into());
 }
+
+/// Switch to a PML4 page table tagged with the given PCID. Unless `flush`
+/// is set, the TLB entries of the PCID are kept.
+///
+/// # Safety
+/// `paddr` must point to a valid PML4 page table and PCIDs must be enabled.
+#[inline]
+pub unsafe fn switch_to_pcid(paddr: PAddr, pcid: u16, flush: bool) {
+    let mut value: u64 = paddr.into();
+    value |= pcid as u64 & CR3_PCID_MASK;
+    if !flush {
+        value |= CR3_NO_FLUSH;
+    }
+    cr3_write(value);
+}
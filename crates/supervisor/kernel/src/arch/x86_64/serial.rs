@@ -1,10 +1,14 @@
 use log::{Log, Metadata, Record};
-use spin::Mutex;
+use spin::{Mutex, MutexGuard};
 use uart_16550::SerialPort;
+use x86_64::instructions::port::Port;
+
+/// I/O port of the first serial controller.
+const COM1: u16 = 0x3F8;
 
 lazy_static! {
     static ref SERIAL1: Mutex<SerialPort> = {
-        let mut serial_port = unsafe { SerialPort::new(0x3F8) };
+        let mut serial_port = unsafe { SerialPort::new(COM1) };
         serial_port.init();
         Mutex::new(serial_port)
     };
@@ -37,6 +41,70 @@ macro_rules! serial_println {
         concat!($fmt, "\r\n"), $($arg)*));
 }
 
+/// Exclusive raw access to the serial port. Bytes are transferred as is
+/// without any translation, which is needed by the GDB stub.
+pub struct RawSerial<'a> {
+    _guard: MutexGuard<'a, SerialPort>,
+    data: Port<u8>,
+    line_status: Port<u8>,
+}
+
+impl RawSerial<'_> {
+    /// Lock the serial port for raw access, waiting until it is free.
+    pub fn lock() -> RawSerial<'static> {
+        Self::new(SERIAL1.lock())
+    }
+
+    /// Lock the serial port for raw access if it is free. Fails if the port is in
+    /// use, possibly by the code that was interrupted on this core.
+    pub fn try_lock() -> Option<RawSerial<'static>> {
+        SERIAL1.try_lock().map(Self::new)
+    }
+
+    fn new(guard: MutexGuard<'static, SerialPort>) -> RawSerial<'static> {
+        RawSerial {
+            _guard: guard,
+            data: Port::new(COM1),
+            line_status: Port::new(COM1 + 5),
+        }
+    }
+
+    /// Send a single byte, waiting until the transmitter is ready.
+    pub fn send(&mut self, byte: u8) {
+        unsafe {
+            while self.line_status.read() & 0x20 == 0 {
+                core::hint::spin_loop();
+            }
+            self.data.write(byte);
+        }
+    }
+
+    /// Receive a single byte, waiting until one is available.
+    pub fn receive(&mut self) -> u8 {
+        unsafe {
+            while self.line_status.read() & 0x01 == 0 {
+                core::hint::spin_loop();
+            }
+            self.data.read()
+        }
+    }
+}
+
+/**
+Read the bytes that were received and pass them to `f`, which clears the receive
+interrupt. The port is read without the lock, as the interrupted code might hold
+it to send.
+*/
+pub fn take_received(mut f: impl FnMut(u8)) {
+    let mut data: Port<u8> = Port::new(COM1);
+    let mut line_status: Port<u8> = Port::new(COM1 + 5);
+    unsafe {
+        while line_status.read() & 0x01 != 0 {
+            f(data.read());
+        }
+    }
+}
+
 /// A logger implementation to pass logs into SerialLogging Interface.
 pub struct SerialLogger;
 
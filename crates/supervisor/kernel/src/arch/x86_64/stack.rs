@@ -0,0 +1,99 @@
+/*!
+Layout of the kernel stacks and their guard pages.
+
+Every core owns a slot of `KERNEL_STACK_NUM_PAGES + 1` 2 MiB pages starting at
+[`KERNEL_STACK_START`]. The first 2 MiB of the slot are mapped with 4 KiB pages and
+hold the entry stacks of the core, each with an unmapped guard page below:
+
+```text
+page 0                         guard
+pages 1..=ENTRY_STACK_PAGES    double fault stack
+next page                      guard
+next ENTRY_STACK_PAGES pages   privilege 0 stack
+rest of the first 2 MiB        guard of the kernel stack
+```
+
+The remaining 2 MiB pages hold the kernel stack of the core. A stack overflow
+touches a guard page, and as the page fault cannot be pushed onto the stack either,
+the CPU raises a double fault that runs on the double fault stack.
+*/
+
+use core::ops::Range;
+
+use super::globals::{BASE_PAGE_LENGTH, KERNEL_STACK_NUM_PAGES, KERNEL_STACK_START};
+
+const LARGE_PAGE_LENGTH: usize = 2 * 1024 * 1024;
+
+/// Number of 4 KiB pages of each entry stack.
+pub const ENTRY_STACK_PAGES: usize = 5;
+
+/// Number of 2 MiB pages reserved for every core.
+pub const SLOT_PAGES: usize = KERNEL_STACK_NUM_PAGES + 1;
+
+/// Number of 4 KiB pages in the first 2 MiB of a slot that are mapped.
+pub const ENTRY_STACKS_MAPPED_PAGES: usize = 2 * (ENTRY_STACK_PAGES + 1);
+
+fn slot_start(core: usize) -> usize {
+    KERNEL_STACK_START + core * SLOT_PAGES * LARGE_PAGE_LENGTH
+}
+
+/// Stack used by double faults on the given core.
+pub fn double_fault_stack(core: usize) -> Range<u64> {
+    let start = slot_start(core) + BASE_PAGE_LENGTH;
+    start as u64..(start + ENTRY_STACK_PAGES * BASE_PAGE_LENGTH) as u64
+}
+
+/// Stack used when user mode enters the kernel on the given core.
+pub fn privilege_0_stack(core: usize) -> Range<u64> {
+    let start = slot_start(core) + (ENTRY_STACK_PAGES + 2) * BASE_PAGE_LENGTH;
+    start as u64..(start + ENTRY_STACK_PAGES * BASE_PAGE_LENGTH) as u64
+}
+
+/// Kernel stack of the given core.
+pub fn kernel_stack(core: usize) -> Range<u64> {
+    let start = slot_start(core) + LARGE_PAGE_LENGTH;
+    start as u64..(start + KERNEL_STACK_NUM_PAGES * LARGE_PAGE_LENGTH) as u64
+}
+
+/// Name of the stack whose guard page contains `vaddr`, if any.
+pub fn overflowed_stack(vaddr: u64) -> Option<&'static str> {
+    let offset = vaddr.checked_sub(KERNEL_STACK_START as u64)? as usize;
+    let offset_in_slot = offset % (SLOT_PAGES * LARGE_PAGE_LENGTH);
+    if offset_in_slot >= LARGE_PAGE_LENGTH {
+        return None;
+    }
+
+    match offset_in_slot / BASE_PAGE_LENGTH {
+        0 => Some("double fault"),
+        page if page == ENTRY_STACK_PAGES + 1 => Some("privilege 0"),
+        page if page >= ENTRY_STACKS_MAPPED_PAGES => Some("kernel"),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_guard_pages() {
+        for core in 0..2 {
+            let double_fault = double_fault_stack(core);
+            let privilege_0 = privilege_0_stack(core);
+            let kernel = kernel_stack(core);
+
+            assert_eq!(
+                overflowed_stack(double_fault.start - 1),
+                Some("double fault")
+            );
+            assert_eq!(overflowed_stack(privilege_0.start - 1), Some("privilege 0"));
+            assert_eq!(overflowed_stack(kernel.start - 1), Some("kernel"));
+            assert_eq!(overflowed_stack(double_fault.start), None);
+            assert_eq!(overflowed_stack(privilege_0.end - 1), None);
+            assert_eq!(overflowed_stack(kernel.end - 1), None);
+        }
+        // The guard of the kernel stack starts right after the privilege 0 stack.
+        assert_eq!(overflowed_stack(privilege_0_stack(0).end), Some("kernel"));
+        assert_eq!(overflowed_stack(KERNEL_STACK_START as u64 - 1), None);
+    }
+}
@@ -1,3 +1,5 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+
 pub mod registers;
 
 /// Enum that represents user return info.
@@ -6,3 +8,29 @@ pub enum UserReturn {
     SystemCall,
     Interrupt,
 }
+
+/// Read the timestamp counter of the current core.
+#[inline]
+pub fn read_timestamp_counter() -> u64 {
+    unsafe { core::arch::x86_64::_rdtsc() }
+}
+
+/// Number of timer interrupts since boot.
+static TIMER_TICKS: AtomicU64 = AtomicU64::new(0);
+
+/// Number of timer interrupts since boot. Used as the unit of time for scheduling.
+pub fn timer_ticks() -> u64 {
+    TIMER_TICKS.load(Ordering::Relaxed)
+}
+
+/// Account a timer interrupt.
+pub(super) fn timer_tick() {
+    TIMER_TICKS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Halt the core until the next interrupt. The kernel otherwise runs with
+/// interrupts disabled.
+pub fn wait_for_interrupt() {
+    x86_64::instructions::interrupts::enable_and_hlt();
+    x86_64::instructions::interrupts::disable();
+}
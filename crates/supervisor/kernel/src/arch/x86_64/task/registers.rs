@@ -3,13 +3,33 @@ Registers for the architecture.
 */
 
 use crossbeam_utils::atomic::AtomicCell;
-use relic_abi::{cap::CapabilityErrors, syscall::SystemCall};
+use relic_abi::{cap::CapabilityErrors, syscall::SystemCall, task::TaskRegisters};
 use x86_64::{
-    registers::model_specific::{FsBase, KernelGsBase, LStar},
+    registers::{
+        model_specific::{FsBase, KernelGsBase, LStar},
+        rflags::RFlags,
+    },
     VirtAddr,
 };
 
-use crate::capability::TaskStatus;
+use crate::{
+    addr::VAddr,
+    arch::{gdt, interrupts::trap::TrapFrame, tls},
+    capability::TaskStatus,
+};
+
+/// Flags of rflags that can be changed on behalf of user mode: the status flags and
+/// the trap flag.
+pub const USER_RFLAGS: RFlags = RFlags::from_bits_truncate(
+    RFlags::CARRY_FLAG.bits()
+        | RFlags::PARITY_FLAG.bits()
+        | RFlags::AUXILIARY_CARRY_FLAG.bits()
+        | RFlags::ZERO_FLAG.bits()
+        | RFlags::SIGN_FLAG.bits()
+        | RFlags::TRAP_FLAG.bits()
+        | RFlags::DIRECTION_FLAG.bits()
+        | RFlags::OVERFLOW_FLAG.bits(),
+);
 
 /// Set of registers in the architecture.
 #[derive(Debug, Getters, Setters, Clone)]
@@ -77,11 +97,81 @@ impl Registers {
     pub fn switch_to(&mut self, syscall_data: Option<(CapabilityErrors, u64, u64)>) -> TaskStatus {
         user_switching_fn(self, syscall_data)
     }
+
+    /// Get the user visible representation of the registers.
+    pub fn to_task_registers(&self) -> TaskRegisters {
+        TaskRegisters {
+            rax: self.rax,
+            rbx: self.rbx,
+            rcx: self.rcx,
+            rdx: self.rdx,
+            rsi: self.rsi,
+            rdi: self.rdi,
+            rbp: self.rbp,
+            rsp: self.rsp,
+            r8: self.r8,
+            r9: self.r9,
+            r10: self.r10,
+            r11: self.r11,
+            r12: self.r12,
+            r13: self.r13,
+            r14: self.r14,
+            r15: self.r15,
+            rip: self.rip,
+            rflags: self.rflags,
+            fs: self.fs,
+        }
+    }
+
+    /// Place the result of a syscall in the registers that `sysret` returns it in.
+    pub fn set_syscall_result(&mut self, error: CapabilityErrors, data_1: u64, data_2: u64) {
+        self.rax = error.to_u64();
+        self.rdi = data_1;
+        self.r8 = data_2;
+    }
+
+    /// Update the registers from the user visible representation. The
+    /// instruction pointer, stack pointer and TCB location must be user mode
+    /// addresses. Only the status flags and the trap flag of rflags can be changed.
+    pub fn set_from_task_registers(
+        &mut self,
+        regs: &TaskRegisters,
+    ) -> Result<(), CapabilityErrors> {
+        VAddr::new(regs.rip).validate_user_mode()?;
+        VAddr::new(regs.rsp).validate_user_mode()?;
+        VAddr::new(regs.fs).validate_user_mode()?;
+
+        let user_flags = USER_RFLAGS.bits();
+
+        self.rax = regs.rax;
+        self.rbx = regs.rbx;
+        self.rcx = regs.rcx;
+        self.rdx = regs.rdx;
+        self.rsi = regs.rsi;
+        self.rdi = regs.rdi;
+        self.rbp = regs.rbp;
+        self.rsp = regs.rsp;
+        self.r8 = regs.r8;
+        self.r9 = regs.r9;
+        self.r10 = regs.r10;
+        self.r11 = regs.r11;
+        self.r12 = regs.r12;
+        self.r13 = regs.r13;
+        self.r14 = regs.r14;
+        self.r15 = regs.r15;
+        self.rip = regs.rip;
+        self.rflags = (self.rflags & !user_flags) | (regs.rflags & user_flags);
+        self.fs = regs.fs;
+        Ok(())
+    }
 }
 
 impl Default for Registers {
     fn default() -> Self {
-        Self::empty()
+        let mut registers = Self::empty();
+        // User mode always runs with interrupts enabled so that it can be preempted.
+        registers.rflags = RFlags::INTERRUPT_FLAG.bits();
+        registers
     }
 }
 
@@ -124,12 +214,15 @@ fn user_switching_fn(
         in(reg) &registers.mmx);
         }
         FsBase::write(VirtAddr::new(registers.fs));
+        #[cfg(feature = "kpti")]
+        crate::arch::paging::kpti::prepare_user_return();
         unsafe {
             asm!("
             mov rsp, rdx
             mov rbp, rsi
-            sysretq
+            jmp {0}
         ",
+        sym sysret_to_user,
         in("rdi") data.1, in("rsi") registers.rbp, in("rax") cap_error,
         in("rcx") registers.rip, in("rdx") registers.rsp,
         in("r8") data.2, in("r9") registers.r9, in("r10") registers.r10,
@@ -137,7 +230,49 @@ fn user_switching_fn(
         in("r14") registers.r14, in("r15") registers.r15)
         };
     } else {
-        todo!()
+        // Resume a task that was interrupted. Unlike sysret, iretq restores every register.
+        unsafe {
+            asm!("
+            FXRSTOR [{0}]
+        ",
+        in(reg) &registers.mmx);
+        }
+        FsBase::write(VirtAddr::new(registers.fs));
+        #[cfg(feature = "kpti")]
+        crate::arch::paging::kpti::prepare_user_return();
+        let (code_selector, stack_selector) = gdt::user_segment_selectors();
+        // The interrupt frame is built on the stack that interrupts from user mode use,
+        // as the kernel stack is not mapped in the user page table. Offsets are from
+        // the `repr(C)` layout of `Registers`.
+        unsafe {
+            asm!("
+            mov rsp, rsi
+            push rax
+            push qword ptr [rdi + 120]
+            push qword ptr [rdi + 136]
+            push rdx
+            push qword ptr [rdi + 128]
+            mov rsi, [rdi + 8]
+            mov rdx, [rdi + 16]
+            mov rcx, [rdi + 24]
+            mov r8, [rdi + 32]
+            mov r9, [rdi + 40]
+            mov rax, [rdi + 48]
+            mov r10, [rdi + 56]
+            mov r11, [rdi + 64]
+            mov rbx, [rdi + 72]
+            mov r12, [rdi + 80]
+            mov r13, [rdi + 88]
+            mov r14, [rdi + 96]
+            mov r15, [rdi + 104]
+            mov rbp, [rdi + 112]
+            mov rdi, [rdi]
+            jmp {0}
+        ",
+        sym iret_to_user,
+        in("rdi") registers as *const Registers, in("rsi") gdt::privilege_0_stack_top(),
+        in("rax") stack_selector.0 as u64, in("rdx") code_selector.0 as u64, options(noreturn))
+        };
     }
 
     unsafe {
@@ -150,24 +285,67 @@ fn user_switching_fn(
             out("r13") _, out("r14") _, out("r15") _,
         );
         *registers = REGISTERS.clone();
-        debug!(target: "user_future", "Thread returned from usermode by making a syscall.");
+        debug!(target: "user_future", "Thread returned from usermode.");
     }
 
     NEXT_STATE.take()
 }
 
+/// Return to user mode with `sysretq`. The user stack is already loaded.
+#[naked]
+#[link_section = ".trampoline"]
+unsafe extern "C" fn sysret_to_user() {
+    // rdx is not returned to user mode. It is cleared to not leak the page table.
+    // rbx and rsi are cleared to not leak kernel values.
+    asm!(
+        concat!(
+            kpti_switch_to_user!("rdx"),
+            "xor edx, edx\n",
+            "xor ebx, ebx\n",
+            "xor esi, esi\n",
+            "sysretq"
+        ),
+        options(noreturn)
+    );
+}
+
+/// Return to user mode with `iretq`. The interrupt frame is already on the stack.
+#[naked]
+#[link_section = ".trampoline"]
+unsafe extern "C" fn iret_to_user() {
+    asm!(
+        concat!(
+            "push rax\n",
+            kpti_switch_to_user!("rax"),
+            "pop rax\n",
+            "iretq"
+        ),
+        options(noreturn)
+    );
+}
+
 // Syscall: rcx -> rdi (IP) ... rdi -> info
 /// Syscall entry function. This function jumps to [`syscall_entry_fn_2`] after
 /// storing stack information.
 #[inline(never)]
 #[naked]
+#[link_section = ".trampoline"]
 unsafe extern "C" fn syscall_entry_fn() {
     // naked to retrieve the values and not corrupt stack. We want to read the stack information here.
-    asm!("
+    // User mode saves rbx around syscalls, so it is free to switch page tables.
+    // The user stack cannot be used with SMAP, so the kernel stack is loaded from
+    // the TCB, which is the kernel GS base until `swapgs`. The stack is aligned like
+    // after a call.
+    asm!(concat!("
         mov r10, rsp
         mov rax, rbp
+        ", kpti_switch_to_kernel!("rbx"), "
+        swapgs
+        mov rsp, gs:[{1}]
+        swapgs
+        sub rsp, 8
         jmp {0}
-    ", sym syscall_entry_fn_2, options(noreturn));
+    "), sym syscall_entry_fn_2, const tls::TCB_SYSCALL_STACK_OFFSET, options(noreturn));
 }
 
 /// This is used to store the register state and provide it back to the kernel stack.
@@ -177,6 +355,45 @@ static mut REGISTERS: Registers = Registers::empty();
 #[thread_local]
 static NEXT_STATE: AtomicCell<TaskStatus> = AtomicCell::new(TaskStatus::Unknown);
 
+/// Save the state of the interrupted user task and switch back to the kernel stack
+/// of [`user_switching_fn`]. The task returns with the given status, like
+/// [`TaskStatus::Preempted`].
+pub fn preempt_user_task(frame: &TrapFrame, user_fs: u64, status: TaskStatus) -> ! {
+    unsafe {
+        REGISTERS.rax = frame.rax;
+        REGISTERS.rbx = frame.rbx;
+        REGISTERS.rcx = frame.rcx;
+        REGISTERS.rdx = frame.rdx;
+        REGISTERS.rsi = frame.rsi;
+        REGISTERS.rdi = frame.rdi;
+        REGISTERS.rbp = frame.rbp;
+        REGISTERS.r8 = frame.r8;
+        REGISTERS.r9 = frame.r9;
+        REGISTERS.r10 = frame.r10;
+        REGISTERS.r11 = frame.r11;
+        REGISTERS.r12 = frame.r12;
+        REGISTERS.r13 = frame.r13;
+        REGISTERS.r14 = frame.r14;
+        REGISTERS.r15 = frame.r15;
+        REGISTERS.rip = frame.rip;
+        REGISTERS.rsp = frame.rsp;
+        REGISTERS.rflags = frame.rflags;
+        REGISTERS.fs = user_fs;
+
+        asm!("FXSAVE [{0}]", in(reg) &mut REGISTERS.mmx);
+
+        NEXT_STATE.store(status);
+
+        let (rsp, rbp) = THREAD_SWITCH_RSP_RBP;
+        asm!(
+            "
+            mov rbp, {1}
+            mov rsp, {0}
+            jmp user_fn_resume_point
+        ", in(reg) rsp, in(reg) rbp, options(noreturn));
+    }
+}
+
 /// Store the register information and stitch back to kernel stack.
 unsafe extern "C" fn syscall_entry_fn_2(
     a: u64,
@@ -211,6 +428,18 @@ unsafe extern "C" fn syscall_entry_fn_2(
     REGISTERS.r14 = r14;
     REGISTERS.r15 = r15;
     REGISTERS.rflags = rflags;
+    // The remaining registers are clobbered by the syscall. They are cleared so that
+    // they match the state that sysret returns with.
+    REGISTERS.rax = 0;
+    REGISTERS.rbx = 0;
+    REGISTERS.rcx = user_stored_ip as u64;
+    REGISTERS.rdx = 0;
+    REGISTERS.rsi = 0;
+    REGISTERS.rdi = 0;
+    REGISTERS.r8 = 0;
+    REGISTERS.r9 = 0;
+    REGISTERS.r10 = 0;
+    REGISTERS.r11 = rflags;
 
     asm!("FXSAVE [{0}]", in(reg) &mut REGISTERS.mmx);
 
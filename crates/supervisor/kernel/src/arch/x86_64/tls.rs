@@ -14,9 +14,17 @@ use crate::{
 use heapless::Vec;
 use relic_utils::align;
 
+/// Size of the TCB: the pointer to itself followed by the syscall stack top.
+const TCB_SIZE: usize = 16;
+
+/// Offset of the top of the stack used by syscall entry in the TCB. Syscall entry reads
+/// it through the kernel GS base, as FS still belongs to user mode.
+pub const TCB_SYSCALL_STACK_OFFSET: usize = 8;
+
 extern "C" {
     static mut __tdata_start: usize;
     static mut __tdata_end: usize;
+    static mut __tdata_align: usize;
     static mut __tbss_start: usize;
     static mut __tbss_end: usize;
     static mut __tbss_align: usize;
@@ -49,15 +57,20 @@ pub fn initialize_tls(free_regions: &mut Vec<MemoryRegion, 32>) {
             &__tdata_end as *const usize as usize - &__tdata_start as *const usize as usize;
         total_size = &__tbss_end as *const usize as usize - &__tdata_start as *const usize as usize;
 
-        total_size = align::align_up(total_size, &__tbss_align as *const _ as usize);
-        let start_paddr = allocate_data(total_size + 8, &__tbss_align as *const _ as usize);
+        // The TLS segment is aligned to the largest alignment of its sections.
+        let tls_align = core::cmp::max(
+            &__tdata_align as *const _ as usize,
+            &__tbss_align as *const _ as usize,
+        );
+        total_size = align::align_up(total_size, tls_align);
+        let start_paddr = allocate_data(total_size + TCB_SIZE, tls_align);
         let start_vaddr = paddr_to_vaddr(start_paddr);
 
         load_tls_data(
             start_vaddr.into(),
             &__tdata_start as *const usize as *const u8,
             tdata_size,
-            total_size + 8, // Add 8 bytes to store TCB pointer.
+            total_size + TCB_SIZE,
         );
 
         start_vaddr.into()
@@ -74,6 +87,12 @@ pub fn initialize_tls(free_regions: &mut Vec<MemoryRegion, 32>) {
     info!(target: "initialize_tls", "TLS Pointer is set to {:x?}. Size is {:?} bytes", fs_ptr, total_size);
 }
 
+/// Set the top of the stack that syscall entry switches to on this core.
+pub fn set_syscall_stack(stack_top: u64) {
+    let tcb = x86_64::registers::model_specific::FsBase::read().as_u64();
+    unsafe { *((tcb + TCB_SYSCALL_STACK_OFFSET as u64) as *mut u64) = stack_top };
+}
+
 /// Load TLS data into memory and return its physical address.
 /// All sizes are in bytes.
 /// # Arguments
@@ -91,7 +110,7 @@ pub unsafe fn load_tls_data(
     tdata_size: usize,
     total_size: usize,
 ) {
-    // We add 8 bytes to have storage to store fs pointer.
+    // The bytes after the template hold the TCB.
     ptr::copy(start_addr, vaddr_location_to_store as *mut u8, tdata_size);
     ptr::write_bytes(
         ((vaddr_location_to_store as usize) + tdata_size) as *mut u8,
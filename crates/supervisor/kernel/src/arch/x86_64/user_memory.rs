@@ -0,0 +1,412 @@
+/*!
+Checked access to user memory.
+
+Addresses that come from user mode are never dereferenced directly. They are
+validated through the page tables of the task's address space: every page must be mapped
+for user mode, and writable from user mode for writes. Copies into the active
+address space go through the user mapping between `stac` and `clac`, so that SMAP
+only lets the kernel touch user pages here. Other address spaces are accessed
+through the physical memory window.
+
+Syscall payloads in the task buffer are copied with [`copy_from_user`] and
+[`copy_to_user`] at the address the task gave for its buffer, and the loader fills
+new address spaces with [`load_user_bytes`].
+
+Address spaces are passed as the physical address of their top-level table, which
+is a PML5 with five-level paging and a PML4 otherwise.
+*/
+
+use core::{
+    marker::PhantomData,
+    mem::{size_of, MaybeUninit},
+    ptr,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use relic_abi::cap::CapabilityErrors;
+use relic_utils::align;
+use x86_64::registers::control::Cr4Flags;
+
+use crate::{
+    addr::{PAddr, VAddr},
+    arch::{
+        globals::BASE_PAGE_LENGTH,
+        paging::{la57, table::*, utils},
+    },
+};
+
+/// Whether SMAP is enabled, which makes `stac` and `clac` available.
+static SMAP_ENABLED: AtomicBool = AtomicBool::new(false);
+
+/**
+Detect SMEP and SMAP and return the CR4 flags that enable them. The result is
+used by the rest of this module, so the flags must be written to CR4 before any
+user memory is accessed.
+*/
+pub fn detect_protection() -> Cr4Flags {
+    let extended_features = unsafe { core::arch::x86_64::__cpuid_count(7, 0) };
+
+    let mut flags = Cr4Flags::empty();
+    if extended_features.ebx & (1 << 7) != 0 {
+        flags |= Cr4Flags::SUPERVISOR_MODE_EXECUTION_PROTECTION;
+    }
+    if extended_features.ebx & (1 << 20) != 0 {
+        flags |= Cr4Flags::SUPERVISOR_MODE_ACCESS_PREVENTION;
+        SMAP_ENABLED.store(true, Ordering::Relaxed);
+    }
+    flags
+}
+
+/// Forbid access to user pages again. User mode can set RFLAGS.AC, which is
+/// kept when it is interrupted.
+pub fn clear_user_access() {
+    if SMAP_ENABLED.load(Ordering::Relaxed) {
+        unsafe { asm!("clac", options(nomem, nostack)) };
+    }
+}
+
+/// Run `f` with access to user pages allowed.
+fn with_user_access<R>(f: impl FnOnce() -> R) -> R {
+    let smap = SMAP_ENABLED.load(Ordering::Relaxed);
+    if smap {
+        unsafe { asm!("stac", options(nomem, nostack)) };
+    }
+    let result = f();
+    if smap {
+        unsafe { asm!("clac", options(nomem, nostack)) };
+    }
+    result
+}
+
+/// Pointer to a `T` in the user half of an address space. The pointer is only
+/// checked to be in the user half; the mapping is checked on every access.
+pub struct UserPtr<T> {
+    vaddr: VAddr,
+    _marker: PhantomData<*mut T>,
+}
+
+impl<T> Clone for UserPtr<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for UserPtr<T> {}
+
+impl<T> UserPtr<T> {
+    /// Create a pointer to a `T` at `vaddr`. Fails if the `T` does not lie
+    /// completely in the user half.
+    pub fn new(vaddr: VAddr) -> Result<Self, CapabilityErrors> {
+        check_user_range(vaddr, size_of::<T>())?;
+        Ok(Self {
+            vaddr,
+            _marker: PhantomData,
+        })
+    }
+
+    pub fn vaddr(&self) -> VAddr {
+        self.vaddr
+    }
+}
+
+/// Read a `T` from the address space at `root`.
+pub fn copy_from_user<T: Copy>(root: PAddr, src: UserPtr<T>) -> Result<T, CapabilityErrors> {
+    let mut value = MaybeUninit::<T>::uninit();
+    let bytes =
+        unsafe { core::slice::from_raw_parts_mut(value.as_mut_ptr() as *mut u8, size_of::<T>()) };
+    copy_from_user_bytes(root, src.vaddr, bytes)?;
+    Ok(unsafe { value.assume_init() })
+}
+
+/// Write `value` to the address space at `root`.
+pub fn copy_to_user<T: Copy>(
+    root: PAddr,
+    dst: UserPtr<T>,
+    value: &T,
+) -> Result<(), CapabilityErrors> {
+    let bytes =
+        unsafe { core::slice::from_raw_parts(value as *const T as *const u8, size_of::<T>()) };
+    copy_to_user_bytes(root, dst.vaddr, bytes)
+}
+
+/// Fill `dst` with the user memory at `src` in the address space at `root`.
+pub fn copy_from_user_bytes(
+    root: PAddr,
+    src: VAddr,
+    dst: &mut [u8],
+) -> Result<(), CapabilityErrors> {
+    access_user_range(root, src, dst.len(), false, |offset, user, len| unsafe {
+        ptr::copy_nonoverlapping(user, dst[offset..].as_mut_ptr(), len)
+    })
+}
+
+/// Copy `src` to the user memory at `dst` in the address space at `root`. The pages
+/// must be writable from user mode, so copy-on-write pages are rejected as well.
+pub fn copy_to_user_bytes(root: PAddr, dst: VAddr, src: &[u8]) -> Result<(), CapabilityErrors> {
+    access_user_range(root, dst, src.len(), true, |offset, user, len| unsafe {
+        ptr::copy_nonoverlapping(src[offset..].as_ptr(), user, len)
+    })
+}
+
+/**
+Copy `src` to the user memory at `dst` in the address space at `root`, which must not
+be the active one. Unlike [`copy_to_user_bytes`], read-only user pages are written
+too. This is used to fill the pages of a new address space, like its code.
+
+Each page is translated once and the pages before an unmapped page are written, as
+the new address space is not visible to anyone yet.
+*/
+pub fn load_user_bytes(root: PAddr, dst: VAddr, src: &[u8]) -> Result<(), CapabilityErrors> {
+    debug_assert!(!is_active(root));
+    check_user_range(dst, src.len())?;
+    access_physical_range(root, dst, src.len(), false, |offset, user, len| unsafe {
+        ptr::copy_nonoverlapping(src[offset..].as_ptr(), user, len)
+    })
+}
+
+/**
+Validate `len` bytes at `vaddr` in the address space at `root` and call `access` with
+the offset into the range, a pointer to the memory and the length that the pointer
+covers. Nothing is accessed if any page fails the validation.
+*/
+fn access_user_range(
+    root: PAddr,
+    vaddr: VAddr,
+    len: usize,
+    write: bool,
+    mut access: impl FnMut(usize, *mut u8, usize),
+) -> Result<(), CapabilityErrors> {
+    check_user_range(vaddr, len)?;
+    if len == 0 {
+        return Ok(());
+    }
+
+    let start: usize = vaddr.into();
+    let mut page = align::align_down(start, BASE_PAGE_LENGTH);
+    while page < start + len {
+        user_paddr(root, VAddr::from(page), write)?;
+        page += BASE_PAGE_LENGTH;
+    }
+
+    if is_active(root) {
+        with_user_access(|| access(0, start as *mut u8, len));
+        return Ok(());
+    }
+
+    access_physical_range(root, vaddr, len, write, access)
+}
+
+/// Call `access` for every page of the range like [`access_user_range`], but through
+/// the physical memory window. Stops at the first page that fails the walk.
+fn access_physical_range(
+    root: PAddr,
+    vaddr: VAddr,
+    len: usize,
+    write: bool,
+    mut access: impl FnMut(usize, *mut u8, usize),
+) -> Result<(), CapabilityErrors> {
+    let start: usize = vaddr.into();
+    let mut offset = 0;
+    while offset < len {
+        let current = start + offset;
+        let chunk = core::cmp::min(len - offset, BASE_PAGE_LENGTH - current % BASE_PAGE_LENGTH);
+        let paddr = user_paddr(root, VAddr::from(current), write)?;
+        access(
+            offset,
+            unsafe { paddr.to_paddr_global().as_raw_ptr() },
+            chunk,
+        );
+        offset += chunk;
+    }
+    Ok(())
+}
+
+/// Whether the address space at `root` is the one the CPU is using.
+fn is_active(root: PAddr) -> bool {
+    if cfg!(test) {
+        return false;
+    }
+    unsafe { utils::cr3() == root }
+}
+
+/// Check that `len` bytes at `vaddr` lie in the user half.
+fn check_user_range(vaddr: VAddr, len: usize) -> Result<(), CapabilityErrors> {
+    vaddr.validate_user_mode()?;
+    if len == 0 {
+        return Ok(());
+    }
+    let start: u64 = vaddr.into();
+    let last = start
+        .checked_add(len as u64 - 1)
+        .ok_or(CapabilityErrors::InvalidMemoryAddress)?;
+    VAddr::new(last).validate_user_mode()
+}
+
+/// Fail the walk unless the entry is present and accessible from user mode.
+macro_rules! user_entry {
+    ($entry: expr, $write: expr) => {{
+        let entry = $entry;
+        if !entry.is_present() || !entry.is_user_mode_allowed() || ($write && !entry.is_writeable())
+        {
+            return Err(CapabilityErrors::InvalidMemoryAddress);
+        }
+        entry
+    }};
+}
+
+/// Physical address of `vaddr` in the address space at `root` if every level of the
+/// walk allows user mode access, and writes when `write` is set.
+fn user_paddr(root: PAddr, vaddr: VAddr, write: bool) -> Result<PAddr, CapabilityErrors> {
+    let vaddr_u64: u64 = vaddr.into();
+
+    let l4: &PML4 = if la57::is_active() {
+        let l5: &PML5 = unsafe { root.to_paddr_global().as_mut_ptr() };
+        let l4_entry = user_entry!(l5[pml5_index(vaddr)], write);
+        unsafe { l4_entry.get_address().to_paddr_global().as_mut_ptr() }
+    } else {
+        unsafe { root.to_paddr_global().as_mut_ptr() }
+    };
+    let l3_entry = user_entry!(l4[pml4_index(vaddr)], write);
+    let l3: &PDPT = unsafe { l3_entry.get_address().to_paddr_global().as_mut_ptr() };
+    let l2_entry = user_entry!(l3[pdpt_index(vaddr)], write);
+    if l2_entry.is_page() {
+        let paddr: u64 = l2_entry.get_address().into();
+        return Ok((paddr | (vaddr_u64 & 0x3FFF_FFFF)).into());
+    }
+
+    let l2: &PD = unsafe { l2_entry.get_address().to_paddr_global().as_mut_ptr() };
+    let l1_entry = user_entry!(l2[pd_index(vaddr)], write);
+    if l1_entry.is_page() {
+        let paddr: u64 = l1_entry.get_address().into();
+        return Ok((paddr | (vaddr_u64 & 0x1F_FFFF)).into());
+    }
+
+    let l1: &PT = unsafe { l1_entry.get_address().to_paddr_global().as_mut_ptr() };
+    let l0_entry = user_entry!(l1[pt_index(vaddr)], write);
+    let paddr: u64 = l0_entry.get_address().into();
+    Ok((paddr | (vaddr_u64 & 0xFFF)).into())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::capability::{test_fixture::Fixture, *};
+
+    use super::*;
+
+    #[test]
+    fn test_load_user_bytes() {
+        let fixture = Fixture::new();
+        let mut untyped = fixture.untyped();
+        let mut cpool = fixture.cpool();
+
+        let l4 = StoredCap::pml4_retype_from(&mut untyped, &mut cpool).unwrap();
+        let writable_page =
+            StoredCap::base_page_retype_from::<[u8; 4096]>(&mut untyped, &mut cpool, true).unwrap();
+        let read_only_page =
+            StoredCap::base_page_retype_from::<[u8; 4096]>(&mut untyped, &mut cpool, true).unwrap();
+        let mut l4_0 = l4.0.as_l4_mut().unwrap();
+        l4_0.l4_map(
+            0x1000u64.into(),
+            &writable_page.0,
+            &mut untyped,
+            &mut cpool,
+            None,
+            MapPermissions::WRITE,
+        )
+        .unwrap();
+        l4_0.l4_map(
+            0x2000u64.into(),
+            &read_only_page.0,
+            &mut untyped,
+            &mut cpool,
+            None,
+            MapPermissions::empty(),
+        )
+        .unwrap();
+        let root = l4_0.start_paddr().to_paddr();
+
+        // A copy across the page boundary reaches both pages, read-only or not.
+        let data = [1u8, 2, 3, 4];
+        load_user_bytes(root, 0x1FFEu64.into(), &data).unwrap();
+        assert_eq!(
+            &writable_page.0.as_base_page().unwrap().page_data_raw()[0xFFE..],
+            &[1, 2]
+        );
+        assert_eq!(
+            &read_only_page.0.as_base_page().unwrap().page_data_raw()[..2],
+            &[3, 4]
+        );
+
+        assert_matches!(
+            load_user_bytes(root, 0x2FFEu64.into(), &data),
+            Err(CapabilityErrors::InvalidMemoryAddress)
+        );
+        assert!(load_user_bytes(root, 0x7FFF_FFFF_FFFEu64.into(), &data).is_err());
+        assert!(load_user_bytes(root, 0xFFFF_FF00_0000_0000u64.into(), &data).is_err());
+    }
+
+    #[test]
+    fn test_user_copies() {
+        let fixture = Fixture::new();
+        let mut untyped = fixture.untyped();
+        let mut cpool = fixture.cpool();
+
+        let l4 = StoredCap::pml4_retype_from(&mut untyped, &mut cpool).unwrap();
+        let writable_page =
+            StoredCap::base_page_retype_from::<[u8; 4096]>(&mut untyped, &mut cpool, true).unwrap();
+        let read_only_page =
+            StoredCap::base_page_retype_from::<[u8; 4096]>(&mut untyped, &mut cpool, true).unwrap();
+        let mut l4_0 = l4.0.as_l4_mut().unwrap();
+        l4_0.l4_map(
+            0x1000u64.into(),
+            &writable_page.0,
+            &mut untyped,
+            &mut cpool,
+            None,
+            MapPermissions::WRITE,
+        )
+        .unwrap();
+        l4_0.l4_map(
+            0x2000u64.into(),
+            &read_only_page.0,
+            &mut untyped,
+            &mut cpool,
+            None,
+            MapPermissions::empty(),
+        )
+        .unwrap();
+        let root = l4_0.start_paddr().to_paddr();
+
+        // A copy across the page boundary reaches both pages.
+        let data = [1u8, 2, 3, 4];
+        load_user_bytes(root, 0x1FFEu64.into(), &data).unwrap();
+        let mut read_back = [0u8; 4];
+        copy_from_user_bytes(root, 0x1FFEu64.into(), &mut read_back).unwrap();
+        assert_eq!(data, read_back);
+
+        let ptr = UserPtr::<u64>::new(0x1008u64.into()).unwrap();
+        copy_to_user(root, ptr, &0xDEAD_BEEF).unwrap();
+        assert_eq!(copy_from_user(root, ptr).unwrap(), 0xDEAD_BEEF);
+        assert_eq!(
+            &writable_page.0.as_base_page().unwrap().page_data_raw()[8..16],
+            &0xDEAD_BEEFu64.to_le_bytes()
+        );
+
+        // Writes need the whole range to be writable from user mode, and nothing
+        // is written when a page fails.
+        assert_matches!(
+            copy_to_user_bytes(root, 0x1FFEu64.into(), &[5, 6, 7, 8]),
+            Err(CapabilityErrors::InvalidMemoryAddress)
+        );
+        assert_eq!(
+            &writable_page.0.as_base_page().unwrap().page_data_raw()[0xFFE..],
+            &[1, 2]
+        );
+        assert_matches!(
+            copy_from_user_bytes(root, 0x2FFEu64.into(), &mut read_back),
+            Err(CapabilityErrors::InvalidMemoryAddress)
+        );
+        assert!(UserPtr::<u64>::new(0x7FFF_FFFF_FFFCu64.into()).is_err());
+        assert!(UserPtr::<u8>::new(0xFFFF_FF00_0000_0000u64.into()).is_err());
+    }
+}
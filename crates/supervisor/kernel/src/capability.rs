@@ -28,10 +28,16 @@ use std::{
 use crate::{addr::PAddrGlobal, arch::capability::paging::*, util::unsafe_ref::UnsafeRef};
 
 mod cpool;
+mod io_port;
+mod sched_context;
 pub mod task;
+#[cfg(test)]
+pub mod test_fixture;
 mod untyped;
 
 pub use cpool::*;
+pub use io_port::*;
+pub use sched_context::*;
 pub use task::*;
 pub use untyped::*;
 
@@ -51,7 +57,11 @@ pub enum CapabilityEnum {
     /// empty capability locations.
     EmptyCap,
 
-    /// Level4 paging capability. Also denotes an address space. See [`L4`]
+    /// Level5 paging capability. Denotes an address space with five-level
+    /// paging. See [`L5`]
+    L5(L5),
+    /// Level4 paging capability. Also denotes an address space with four-level
+    /// paging. See [`L4`]
     L4(L4),
     /// Level3 paging capability. See [`L3`]
     L3(L3),
@@ -75,6 +85,12 @@ pub enum CapabilityEnum {
 
     /// Kernel thread support. See [`Task`].
     Task(Task),
+
+    /// Processor time budget for a task. See [`SchedContext`].
+    SchedContext(SchedContext),
+
+    /// Access to a range of I/O ports. See [`IoPortRange`].
+    IoPortRange(IoPortRange),
 }
 
 /// Smallest page size: 0x1000 bytes.
@@ -316,6 +332,7 @@ macro_rules! cap_create {
 
 cap_create!(UntypedMemory);
 cap_create!(Cpool);
+cap_create!(L5);
 cap_create!(L4);
 cap_create!(L3);
 cap_create!(L2);
@@ -324,6 +341,8 @@ cap_create!(BasePage);
 cap_create!(LargePage);
 cap_create!(HugePage);
 cap_create!(Task);
+cap_create!(SchedContext);
+cap_create!(IoPortRange);
 
 bitflags! {
     /// Permissions when mapping paging into virtual memory.
@@ -335,5 +354,10 @@ bitflags! {
         const EXECUTE   = 0b0000_0100;
         /// Disable caching for this page. Useful for device backed memory.
         const CACHE_DISABLE = 0b0000_1000;
+        /// Writes go through the cache to memory. Ignored if `CACHE_DISABLE` is set.
+        const WRITE_THROUGH = 0b0001_0000;
+        /// Writes are not cached but combined in write buffers. Useful for frame
+        /// buffers. Ignored if `CACHE_DISABLE` or `WRITE_THROUGH` is set.
+        const WRITE_COMBINING = 0b0010_0000;
     }
 }
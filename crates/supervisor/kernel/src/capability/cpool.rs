@@ -81,6 +81,20 @@ impl CpoolInner {
         Err(CapabilityErrors::CapabilitySlotsFull)
     }
 
+    /**
+    Count the free slots in the cpool. Like [`Self::get_free_index`], borrowed slots
+    are not counted.
+    */
+    pub fn free_slot_count(&self) -> usize {
+        self.unsafe_data
+            .iter()
+            .filter(|cap| {
+                cap.try_borrow()
+                    .map_or(false, |cap| matches!(cap.capability_data, CapabilityEnum::EmptyCap))
+            })
+            .count()
+    }
+
     /**
     Lookup a stored capability given a [`CAddr`]. This acts as if the the current
     cpool is the root cpool.
@@ -0,0 +1,143 @@
+/*!
+I/O port range capability support.
+
+An I/O port range grants access to consecutive I/O ports. Tasks that are granted
+a range can use the ports from user mode, so that drivers of legacy devices do
+not have to run in the kernel. The root range covers all ports and is given to
+sigma at boot. Smaller ranges are derived from it.
+*/
+use relic_abi::cap::CapabilityErrors;
+
+use crate::capability::{Capability, CapabilityEnum, Cpool, StoredCap};
+
+/// Number of I/O ports.
+pub const IO_PORT_COUNT: u64 = 0x1_0000;
+
+/**
+I/O port range kernel object. The range is stored directly in the capability.
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IoPortRange {
+    first: u16,
+    last: u16,
+}
+
+impl IoPortRange {
+    /**
+    Bootstrap the I/O port range capability that covers all ports.
+
+    # Safety
+    Only one such capability may be created at boot.
+    */
+    pub unsafe fn bootstrap() -> Capability {
+        Capability {
+            capability_data: CapabilityEnum::IoPortRange(IoPortRange {
+                first: 0,
+                last: (IO_PORT_COUNT - 1) as u16,
+            }),
+            ..Default::default()
+        }
+    }
+
+    /// First port in the range.
+    pub fn first(&self) -> u16 {
+        self.first
+    }
+
+    /// Last port in the range. (Inclusive)
+    pub fn last(&self) -> u16 {
+        self.last
+    }
+
+    /// Whether all ports of `other` are in this range.
+    pub fn contains(&self, other: &IoPortRange) -> bool {
+        self.first <= other.first && other.last <= self.last
+    }
+
+    /// The range of `count` ports from `first_port`. It must be part of this range.
+    pub fn sub_range(&self, first_port: u64, count: u64) -> Result<IoPortRange, CapabilityErrors> {
+        let end = first_port
+            .checked_add(count)
+            .ok_or(CapabilityErrors::IoPortRangeInvalid)?;
+        if count == 0 || first_port < self.first as u64 || end > self.last as u64 + 1 {
+            return Err(CapabilityErrors::IoPortRangeInvalid);
+        }
+
+        Ok(IoPortRange {
+            first: first_port as u16,
+            last: (end - 1) as u16,
+        })
+    }
+}
+
+impl StoredCap {
+    /**
+    Create an I/O port range capability for `count` ports from `first_port` and store
+    it in the provided cpool. The ports must be part of `source`.
+    Returns the created capability and its index in the cpool.
+    */
+    pub fn io_port_range_derive(
+        source: &IoPortRange,
+        first_port: u64,
+        count: u64,
+        cpool_to_store_in: &mut Cpool,
+    ) -> Result<(StoredCap, usize), CapabilityErrors> {
+        let range = source.sub_range(first_port, count)?;
+        let index = cpool_to_store_in.get_free_index()?;
+        let location = cpool_to_store_in.write_to_if_empty(
+            index,
+            Capability {
+                capability_data: CapabilityEnum::IoPortRange(range),
+                ..Default::default()
+            },
+        )?;
+
+        Ok((location, index))
+    }
+
+    /// Copy the I/O port range capability into the provided cpool.
+    pub fn io_port_range_copy(
+        source: &StoredCap,
+        cpool_to_store_in: &mut Cpool,
+    ) -> Result<(StoredCap, usize), CapabilityErrors> {
+        let range = *source.as_io_port_range()?;
+        let count = range.last as u64 - range.first as u64 + 1;
+        StoredCap::io_port_range_derive(&range, range.first as u64, count, cpool_to_store_in)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sub_range() {
+        let all = IoPortRange {
+            first: 0,
+            last: 0xFFFF,
+        };
+        let keyboard = all.sub_range(0x60, 5).unwrap();
+        assert_eq!((keyboard.first(), keyboard.last()), (0x60, 0x64));
+        assert!(all.contains(&keyboard));
+
+        let last = all.sub_range(0xFFFF, 1).unwrap();
+        assert_eq!((last.first(), last.last()), (0xFFFF, 0xFFFF));
+
+        assert_matches!(
+            keyboard.sub_range(0x64, 2),
+            Err(CapabilityErrors::IoPortRangeInvalid)
+        );
+        assert_matches!(
+            keyboard.sub_range(0x5F, 1),
+            Err(CapabilityErrors::IoPortRangeInvalid)
+        );
+        assert_matches!(
+            all.sub_range(0, 0),
+            Err(CapabilityErrors::IoPortRangeInvalid)
+        );
+        assert_matches!(
+            all.sub_range(u64::MAX, 2),
+            Err(CapabilityErrors::IoPortRangeInvalid)
+        );
+    }
+}
@@ -0,0 +1,175 @@
+/*!
+Scheduling context capability support.
+
+A scheduling context limits the processor time of a task. It grants a budget
+of timer ticks that can be consumed in every period. A task whose budget is
+exhausted is throttled by the scheduler until the budget is replenished at the
+start of the next period. Tasks without a scheduling context are not limited.
+*/
+use core::ops::{Deref, DerefMut};
+
+use relic_abi::cap::CapabilityErrors;
+
+use crate::{
+    capability::{Capability, CapabilityEnum, Cpool, StoredCap, UntypedMemory},
+    util::boxed::Boxed,
+};
+
+/**
+Scheduling context kernel object. The accounting data is stored in the
+descriptor to keep the capability object small.
+*/
+#[derive(Debug)]
+pub struct SchedContext {
+    descriptor: Boxed<SchedContextDescriptor>,
+    /**
+    The task that is limited by this scheduling context.
+    */
+    pub linked_task: Option<StoredCap>,
+}
+
+impl Deref for SchedContext {
+    type Target = SchedContextDescriptor;
+
+    fn deref(&self) -> &Self::Target {
+        &self.descriptor
+    }
+}
+
+impl DerefMut for SchedContext {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.descriptor
+    }
+}
+
+/**
+Budget accounting for a scheduling context. All values are in timer ticks.
+*/
+#[derive(Debug, Getters)]
+pub struct SchedContextDescriptor {
+    /// Ticks that can be consumed in every period.
+    #[getset(get = "pub")]
+    budget: u64,
+    /// Length of a period.
+    #[getset(get = "pub")]
+    period: u64,
+    /// Ticks left in the current period.
+    #[getset(get = "pub")]
+    remaining: u64,
+    /// Tick at which the current period started.
+    period_start: u64,
+}
+
+impl SchedContextDescriptor {
+    /// Start a new period if the current one is over. Periods that passed
+    /// entirely are skipped, so unused budget is never accumulated.
+    pub fn replenish(&mut self, now: u64) {
+        let elapsed = now.saturating_sub(self.period_start);
+        if elapsed >= self.period {
+            self.period_start = now - elapsed % self.period;
+            self.remaining = self.budget;
+        }
+    }
+
+    /// Charge the ticks consumed by the linked task.
+    pub fn charge(&mut self, ticks: u64, now: u64) {
+        self.remaining = self.remaining.saturating_sub(ticks);
+        self.replenish(now);
+    }
+
+    /// Whether the linked task may run at the given tick.
+    pub fn has_budget(&mut self, now: u64) -> bool {
+        self.replenish(now);
+        self.remaining > 0
+    }
+}
+
+impl StoredCap {
+    /**
+    Create a scheduling context from untyped memory and store it in the provided
+    cpool. The budget must not be zero and must not exceed the period. The first
+    period starts at `now`. Returns the created capability and its index in the cpool.
+    */
+    pub fn sched_context_retype_from(
+        untyped: &mut UntypedMemory,
+        cpool_to_store_in: &mut Cpool,
+        budget: u64,
+        period: u64,
+        now: u64,
+    ) -> Result<(StoredCap, usize), CapabilityErrors> {
+        if budget == 0 || budget > period {
+            return Err(CapabilityErrors::SchedContextParametersInvalid);
+        }
+
+        let mut result_index = 0;
+
+        let location = untyped.derive(None, false, |desc| {
+            unsafe {
+                core::ptr::write(
+                    desc,
+                    SchedContextDescriptor {
+                        budget,
+                        period,
+                        remaining: budget,
+                        period_start: now,
+                    },
+                )
+            };
+
+            let boxed = unsafe { Boxed::new((desc as u64).into()) };
+            let cpool_location_to_store = cpool_to_store_in.get_free_index()?;
+
+            let location = cpool_to_store_in.write_to_if_empty(
+                cpool_location_to_store,
+                Capability {
+                    capability_data: CapabilityEnum::SchedContext(SchedContext {
+                        descriptor: boxed,
+                        linked_task: None,
+                    }),
+                    ..Default::default()
+                },
+            )?;
+
+            result_index = cpool_location_to_store;
+            Ok(location)
+        })?;
+
+        Ok((location, result_index))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn descriptor(budget: u64, period: u64) -> SchedContextDescriptor {
+        SchedContextDescriptor {
+            budget,
+            period,
+            remaining: budget,
+            period_start: 0,
+        }
+    }
+
+    #[test]
+    fn test_sched_context_budget() {
+        let mut desc = descriptor(2, 10);
+        assert!(desc.has_budget(0));
+
+        desc.charge(1, 1);
+        assert!(desc.has_budget(1));
+        desc.charge(3, 4);
+        assert!(!desc.has_budget(9));
+
+        // Replenished at the start of the next period.
+        assert!(desc.has_budget(10));
+        assert_eq!(desc.remaining, 2);
+
+        // Skipped periods do not accumulate budget.
+        desc.charge(2, 12);
+        assert!(!desc.has_budget(19));
+        assert!(desc.has_budget(35));
+        assert_eq!(desc.period_start, 30);
+        assert_eq!(desc.remaining, 2);
+    }
+}
@@ -4,15 +4,28 @@ Task capability support.
 Support for kernel threads.
 */
 use core::ops::Deref;
-use std::{cell::RefCell, ops::DerefMut, sync::atomic::AtomicU64};
+use std::{
+    cell::{Cell, RefCell},
+    ops::{DerefMut, Range},
+    sync::atomic::AtomicU64,
+};
 
-use relic_abi::{cap::CapabilityErrors, syscall::SystemCall};
+use heapless::Vec;
+use relic_abi::{
+    cap::CapabilityErrors,
+    syscall::{SystemCall, TASK_EXIT_CODE_FAULT},
+    task::{TaskInfo, TaskRegisters, TaskState},
+};
 
 use crate::{
     addr::VAddr,
-    arch::{capability::paging::L4, task::registers::Registers},
+    arch::{
+        gdt,
+        task::{read_timestamp_counter, registers::Registers, timer_ticks},
+    },
     capability::{
-        BasePage, CapAccessorMut, Capability, CapabilityEnum, Cpool, StoredCap, UntypedMemory,
+        BasePage, CapAccessorMut, Capability, CapabilityEnum, Cpool, IoPortRange, SchedContext,
+        StoredCap, UntypedMemory,
     },
     util::boxed::Boxed,
 };
@@ -41,6 +54,35 @@ pub enum TaskStatus {
     */
     SyscalledReadyToResume(CapabilityErrors, u64, u64),
 
+    /**
+    The task was interrupted by the timer and is ready to resume.
+    */
+    Preempted,
+
+    /**
+    The task faulted on a missing page or wrote to a present page that it
+    cannot write to at the given address. Resolved by the scheduler for
+    copy-on-write pages.
+    */
+    PageFaulted(VAddr),
+
+    /**
+    The task raised the exception with the given vector, which it cannot
+    recover from. The scheduler exits the task.
+    */
+    Faulted(u64),
+
+    /**
+    The task is waiting for another task to exit.
+    */
+    WaitingForTaskExit,
+
+    /**
+    The task has exited with the given exit code. It is never
+    scheduled again.
+    */
+    Exited(u64),
+
     /**
     Unknown task state.
     */
@@ -78,26 +120,86 @@ impl DerefMut for Task {
 pub struct TaskDescriptor {
     #[getset(get = "pub")]
     cpool: Option<StoredCap>,
+    #[getset(get = "pub")]
     top_level_table: Option<StoredCap>,
     #[getset(get = "pub")]
     task_buffer: Option<StoredCap>,
+    /// Scheduling context limiting the processor time of the task.
+    #[getset(get = "pub")]
+    sched_context: Option<StoredCap>,
+    /// Untyped memory that copies of copy-on-write pages are allocated from.
+    #[getset(get = "pub")]
+    fault_untyped: Option<StoredCap>,
+    /// I/O ports that the task can use from user mode.
+    #[getset(get = "pub")]
+    io_ports: Vec<IoPortRange, MAX_TASK_IO_PORT_RANGES>,
 
     /// Register state for the thread. Only valid
     /// when thread is not running.
     #[getset(get, set)]
     runtime: Registers,
 
+    /// The registers were written while the task was not running, so it has to
+    /// resume with `iretq` from the full register state instead of `sysret`.
+    full_resume: bool,
+
     #[getset(get = "pub", set = "pub")]
     status: TaskStatus,
 
     #[getset(get = "pub", set = "pub")]
     priority: u8,
 
+    #[getset(get = "pub")]
     task_id: u64,
+
+    /// CPU time consumed by the task in timestamp counter cycles.
+    #[getset(get = "pub")]
+    cpu_time_cycles: u64,
+
+    /// Unmapped region below the user stack. Faults in it are reported as stack overflows.
+    #[getset(get = "pub", set = "pub")]
+    stack_guard: Option<Range<VAddr>>,
+
+    /// Head of the list of tasks waiting for this task to exit. The waiting
+    /// tasks are not in the scheduler and are linked through their
+    /// `next_task_item`.
+    exit_waiter: Option<StoredCap>,
+
+    /// Task that this task waits for to exit, to find join cycles.
+    joined_task: Option<StoredCap>,
+}
+
+/// Capabilities that the memory of a task is managed with.
+#[derive(Clone)]
+pub struct TaskMemory {
+    pub top_level_table: StoredCap,
+    pub cpool: StoredCap,
+    /// Untyped memory that copies of pages are allocated from.
+    pub fault_untyped: Option<StoredCap>,
 }
 
+impl TaskMemory {
+    /// Make the page mapped at `vaddr` private to the task, see
+    /// [`StoredCap::address_space_make_private`].
+    pub fn make_private(&self, vaddr: VAddr) -> Result<(), CapabilityErrors> {
+        let untyped = self
+            .fault_untyped
+            .as_ref()
+            .ok_or(CapabilityErrors::CapabilitySearchFailed)?;
+        let mut untyped = untyped.as_untyped_memory_mut()?;
+        let mut cpool = self.cpool.as_cpool_mut()?;
+        self.top_level_table
+            .address_space_make_private(vaddr, &mut untyped, &mut cpool)
+    }
+}
+
+// Tests run in parallel, so each of them numbers its tasks from 1.
+#[cfg_attr(test, thread_local)]
 static TASK_ID: AtomicU64 = AtomicU64::new(1);
 
+/// Maximum number of I/O port ranges granted to a task.
+pub const MAX_TASK_IO_PORT_RANGES: usize = 8;
+
 impl StoredCap {
     pub fn task_retype_from(
         untyped: &mut UntypedMemory,
@@ -118,6 +220,14 @@ impl StoredCap {
                         cpool: None,
                         top_level_table: None,
                         task_buffer: None,
+                        sched_context: None,
+                        fault_untyped: None,
+                        io_ports: Vec::new(),
+                        cpu_time_cycles: 0,
+                        stack_guard: None,
+                        full_resume: false,
+                        exit_waiter: None,
+                        joined_task: None,
                     },
                 )
             };
@@ -162,19 +272,18 @@ impl CapAccessorMut<'_, Task> {
         Ok(())
     }
 
+    /// Set the address space of the task. It must be the root of an address space
+    /// in the active paging mode.
     pub fn task_set_top_level_table(
         &mut self,
-        l4: &mut CapAccessorMut<'_, L4>,
+        top_level_table: &StoredCap,
     ) -> Result<(), CapabilityErrors> {
         if self.top_level_table.is_some() {
             Err(CapabilityErrors::CapabilityAlreadyOccupied)?
         }
-        if l4.linked_task.is_some() {
-            Err(CapabilityErrors::CapabilityAlreadyOccupied)?
-        }
 
-        l4.linked_task = Some(self.cap().clone());
-        self.top_level_table = Some(l4.cap().clone());
+        top_level_table.address_space_link_task(self.cap())?;
+        self.top_level_table = Some(top_level_table.clone());
 
         Ok(())
     }
@@ -196,6 +305,129 @@ impl CapAccessorMut<'_, Task> {
         self.task_buffer = Some(task_buffer.cap().clone());
         Ok(())
     }
+
+    pub fn task_set_sched_context(
+        &mut self,
+        sched_context: &mut CapAccessorMut<'_, SchedContext>,
+    ) -> Result<(), CapabilityErrors> {
+        if self.sched_context.is_some() {
+            Err(CapabilityErrors::CapabilityAlreadyOccupied)?
+        }
+
+        if sched_context.linked_task.is_some() {
+            Err(CapabilityErrors::CapabilityAlreadyOccupied)?
+        }
+
+        sched_context.linked_task = Some(self.cap().clone());
+        self.sched_context = Some(sched_context.cap().clone());
+        Ok(())
+    }
+
+    /// Nominate the untyped memory for copies of copy-on-write pages. Replaces
+    /// the previously nominated untyped memory.
+    pub fn task_set_fault_untyped(&mut self, untyped: &CapAccessorMut<'_, UntypedMemory>) {
+        self.fault_untyped = Some(untyped.cap().clone());
+    }
+
+    /**
+    Schedule an inactive task for the first time. The task needs a cpool and a
+    top level table. It is marked as preempted so that it cannot be started twice
+    while it waits for its first run, and so that it starts with every register
+    written by its creator, which sysret cannot restore.
+    */
+    pub fn task_start(&mut self, scheduler: &Scheduler) -> Result<(), CapabilityErrors> {
+        if !matches!(self.status, TaskStatus::Inactive)
+            || self.cpool.is_none()
+            || self.top_level_table.is_none()
+        {
+            return Err(CapabilityErrors::TaskInvalidState);
+        }
+
+        self.set_status(TaskStatus::Preempted);
+        scheduler.add_task_with_priority(self);
+        Ok(())
+    }
+
+    /**
+    Allow the task to use the ports of the I/O port range from user mode. Fails with
+    [`CapabilityErrors::CapabilitySlotsFull`] once [`MAX_TASK_IO_PORT_RANGES`] ranges
+    are granted.
+    */
+    pub fn task_grant_io_ports(&mut self, range: &IoPortRange) -> Result<(), CapabilityErrors> {
+        if self.io_ports.iter().any(|granted| granted.contains(range)) {
+            return Ok(());
+        }
+
+        self.io_ports
+            .push(*range)
+            .or(Err(CapabilityErrors::CapabilitySlotsFull))
+    }
+
+    /**
+    Exit the task with the provided exit code. The task is not scheduled again
+    and its task buffer is unlinked so that it can be reused. All tasks waiting
+    for this task to exit are scheduled with the exit code as the result.
+    */
+    pub fn task_exit(&mut self, exit_code: u64, scheduler: &Scheduler) {
+        self.set_status(TaskStatus::Exited(exit_code));
+
+        if let Some(task_buffer) = self.task_buffer.take() {
+            task_buffer.as_base_page_mut().unwrap().linked_task = None;
+        }
+
+        let mut next_waiter = self.exit_waiter.take();
+        while let Some(waiter) = next_waiter {
+            let mut waiter_task = waiter.as_task_mut().unwrap();
+            next_waiter = waiter_task.next_task_item.take();
+            waiter_task.joined_task = None;
+            waiter_task.set_status(TaskStatus::SyscalledReadyToResume(
+                CapabilityErrors::None,
+                exit_code,
+                0,
+            ));
+            scheduler.add_task_with_priority(&mut waiter_task);
+        }
+    }
+
+    /**
+    Register `waiter` to be resumed when this task exits. If the task has already
+    exited, the exit code is returned and the waiter is left untouched.
+
+    A task cannot join itself, a task that waits for it to exit, or a task that
+    was never started. These fail with [`CapabilityErrors::TaskInvalidState`].
+    */
+    pub fn task_add_exit_waiter(
+        &mut self,
+        waiter: &mut CapAccessorMut<'_, Task>,
+    ) -> Result<Option<u64>, CapabilityErrors> {
+        match self.status {
+            TaskStatus::Exited(exit_code) => return Ok(Some(exit_code)),
+            TaskStatus::Inactive | TaskStatus::Unknown => {
+                return Err(CapabilityErrors::TaskInvalidState)
+            }
+            _ => {}
+        }
+        if self.task_id == waiter.task_id {
+            return Err(CapabilityErrors::TaskInvalidState);
+        }
+
+        // Follow the tasks that this task waits for. Joining fails if the waiter
+        // is one of them, as no task of the cycle would ever exit.
+        let mut next_joined = self.joined_task.clone();
+        while let Some(joined) = next_joined {
+            if joined.as_ptr() == waiter.cap().as_ptr() || joined.as_ptr() == self.cap().as_ptr() {
+                return Err(CapabilityErrors::TaskInvalidState);
+            }
+            next_joined = joined.as_task()?.joined_task.clone();
+        }
+
+        waiter.set_status(TaskStatus::WaitingForTaskExit);
+        waiter.prev_task_item = None;
+        waiter.next_task_item = self.exit_waiter.take();
+        waiter.joined_task = Some(self.cap().clone());
+        self.exit_waiter = Some(waiter.cap().clone());
+        Ok(None)
+    }
 }
 
 impl TaskDescriptor {
@@ -224,22 +456,139 @@ impl TaskDescriptor {
         let mut current_status = TaskStatus::Active;
         core::mem::swap(&mut self.status, &mut current_status);
 
-        if let Some(pml4) = self.top_level_table.clone() {
-            let mut l4 = pml4
-                .as_l4_mut()
-                .expect("Task's top level page table is not PML4");
-            l4.switch_to();
+        if let Some(top_level_table) = self.top_level_table.clone() {
+            top_level_table
+                .address_space_switch_to()
+                .expect("Task's top level page table is not the root of an address space");
         } else {
-            panic!("Cannot start task without pml4");
+            panic!("Cannot start task without top level page table");
         }
 
         let syscall_info = match current_status {
-            TaskStatus::Inactive => Some((CapabilityErrors::None, 0, 0)),
-            TaskStatus::SyscalledReadyToResume(a, b, c) => Some((a, b, c)),
+            TaskStatus::Inactive if !self.full_resume => Some((CapabilityErrors::None, 0, 0)),
+            TaskStatus::SyscalledReadyToResume(a, b, c) if !self.full_resume => Some((a, b, c)),
+            TaskStatus::SyscalledReadyToResume(a, b, c) => {
+                self.runtime.set_syscall_result(a, b, c);
+                None
+            }
             _ => None,
         };
+        self.full_resume = false;
+
+        gdt::load_io_permissions(self.task_id, &self.io_ports);
+
+        let start_cycles = read_timestamp_counter();
+        let start_ticks = timer_ticks();
+        let result = self.runtime.switch_to(syscall_info);
+        self.cpu_time_cycles += read_timestamp_counter() - start_cycles;
+
+        if let Some(sched_context) = &self.sched_context {
+            let now = timer_ticks();
+            let mut sched_context = sched_context.as_sched_context_mut().unwrap();
+            sched_context.charge(now - start_ticks, now);
+        }
+        result
+    }
+
+    /// Whether the task has processor time left in its scheduling context.
+    /// Tasks without a scheduling context are never throttled.
+    pub fn has_budget(&self) -> bool {
+        self.sched_context.as_ref().map_or(true, |sched_context| {
+            let mut sched_context = sched_context.as_sched_context_mut().unwrap();
+            sched_context.has_budget(timer_ticks())
+        })
+    }
+
+    /**
+    Resolve a write fault of the task on a copy-on-write page. The copy is allocated
+    from the nominated fault untyped memory and stored in the cpool of the task.
+    Fails with [`CapabilityErrors::MemoryNotMapped`] for missing pages and for
+    writes to read only pages that are not copy-on-write.
+    */
+    pub fn handle_page_fault(&mut self, vaddr: VAddr) -> Result<(), CapabilityErrors> {
+        let untyped = self
+            .fault_untyped
+            .clone()
+            .ok_or(CapabilityErrors::CapabilitySearchFailed)?;
+        let cpool = self
+            .cpool
+            .clone()
+            .ok_or(CapabilityErrors::CapabilitySearchFailed)?;
+        let top_level_table = self
+            .top_level_table
+            .clone()
+            .ok_or(CapabilityErrors::CapabilitySearchFailed)?;
+
+        let mut untyped = untyped.as_untyped_memory_mut()?;
+        let mut cpool = cpool.as_cpool_mut()?;
+        top_level_table.address_space_copy_on_write(vaddr, &mut untyped, &mut cpool)
+    }
+
+    /// Capabilities that the memory of the task is managed with, once it has an
+    /// address space and a cpool.
+    pub fn memory(&self) -> Option<TaskMemory> {
+        Some(TaskMemory {
+            top_level_table: self.top_level_table.clone()?,
+            cpool: self.cpool.clone()?,
+            fault_untyped: self.fault_untyped.clone(),
+        })
+    }
+
+    /// Get the user visible information about the task.
+    pub fn task_info(&self) -> TaskInfo {
+        let (state, exit_code) = match self.status {
+            TaskStatus::Active => (TaskState::Running, 0),
+            TaskStatus::Inactive => (TaskState::Inactive, 0),
+            TaskStatus::SyscalledAndWaiting(_) | TaskStatus::WaitingForTaskExit => {
+                (TaskState::Blocked, 0)
+            }
+            TaskStatus::SyscalledReadyToResume(..)
+            | TaskStatus::Preempted
+            | TaskStatus::PageFaulted(_)
+            | TaskStatus::Faulted(_) => (TaskState::Ready, 0),
+            TaskStatus::Exited(exit_code) => (TaskState::Exited, exit_code),
+            TaskStatus::Unknown => (TaskState::Unknown, 0),
+        };
+
+        TaskInfo {
+            task_id: self.task_id,
+            state,
+            exit_code,
+            priority: self.priority,
+            cpu_time_cycles: self.cpu_time_cycles,
+        }
+    }
+
+    /// Read the register state of the task.
+    pub fn read_registers(&self) -> TaskRegisters {
+        match self.status {
+            // Report the result of the syscall the task is about to return from.
+            TaskStatus::SyscalledReadyToResume(a, b, c) => {
+                let mut runtime = self.runtime.clone();
+                runtime.set_syscall_result(a, b, c);
+                runtime.to_task_registers()
+            }
+            _ => self.runtime.to_task_registers(),
+        }
+    }
 
-        self.runtime.switch_to(syscall_info)
+    /// Write the register state of the task. Fails if the task is running or
+    /// has exited. The task resumes with every written register.
+    pub fn write_registers(&mut self, regs: &TaskRegisters) -> Result<(), CapabilityErrors> {
+        match self.status {
+            TaskStatus::Active | TaskStatus::Exited(_) => Err(CapabilityErrors::TaskInvalidState),
+            // The written registers replace the result of the finished syscall.
+            TaskStatus::SyscalledReadyToResume(..) => {
+                self.runtime.set_from_task_registers(regs)?;
+                self.status = TaskStatus::Preempted;
+                Ok(())
+            }
+            _ => {
+                self.runtime.set_from_task_registers(regs)?;
+                self.full_resume = true;
+                Ok(())
+            }
+        }
     }
 }
 
@@ -250,8 +599,27 @@ impl TaskDescriptor {
 /// will be moved to even ones when all even ones are done.
 pub struct Scheduler {
     current_list: [RefCell<Capability>; 32],
+
+    /// Tasks that used up the budget of their scheduling context. They are
+    /// linked through `next_task_item` and released once replenished.
+    throttled_list: RefCell<Capability>,
+
+    /// Task to run before any queued task. Used to return from a syscall that
+    /// did not block without going through the run queue.
+    direct_resume: Cell<Option<StoredCap>>,
+
+    /// ID of the task that is currently running or whose syscall is being processed.
+    running_task_id: Cell<Option<u64>>,
+
+    /// Memory of the running task. The task itself is borrowed while it runs, so
+    /// the debugger that stops it uses these to copy its pages.
+    running_task_memory: RefCell<Option<TaskMemory>>,
 }
 
+/// Scheduler that is running on the current core.
+#[thread_local]
+static ACTIVE_SCHEDULER: Cell<Option<&'static Scheduler>> = Cell::new(None);
+
 impl Scheduler {
     pub const fn new() -> Self {
         const REFCELL_MARKER_TASK: RefCell<Capability> = RefCell::new(Capability {
@@ -265,11 +633,54 @@ impl Scheduler {
         });
         Self {
             current_list: [REFCELL_MARKER_TASK; 32],
+            throttled_list: REFCELL_MARKER_TASK,
+            direct_resume: Cell::new(None),
+            running_task_id: Cell::new(None),
+            running_task_memory: RefCell::new(None),
+        }
+    }
+
+    /// Get the scheduler running on the current core, if any.
+    pub fn active() -> Option<&'static Scheduler> {
+        ACTIVE_SCHEDULER.get()
+    }
+
+    /// ID of the task that is currently running on this scheduler.
+    pub fn running_task_id(&self) -> Option<u64> {
+        self.running_task_id.get()
+    }
+
+    /// Memory of the task that is currently running on this scheduler.
+    pub fn running_task_memory(&self) -> Option<TaskMemory> {
+        self.running_task_memory.try_borrow().ok()?.clone()
+    }
+
+    /// Call the given function for every task waiting in the scheduler. The running
+    /// task and the tasks blocked outside of the scheduler are not visited.
+    pub fn for_each_task<F: FnMut(&StoredCap)>(&self, mut func: F) {
+        for list in self.current_list.iter().chain(Some(&self.throttled_list)) {
+            let mut current = list
+                .try_borrow_mut()
+                .ok()
+                .and_then(|mut l| l.get_next_task_item_mut().clone());
+            while let Some(task) = current {
+                func(&task);
+                current = task.as_task().ok().and_then(|t| t.next_task_item.clone());
+            }
         }
     }
 
-    /// Add a task with the given priority.
+    /// Add a task with the given priority. A task without budget is throttled
+    /// instead.
     pub fn add_task_with_priority(&self, new_task: &mut CapAccessorMut<'_, Task>) {
+        if !new_task.has_budget() {
+            let mut list_accessor = self.throttled_list.borrow_mut();
+            new_task.prev_task_item = None;
+            new_task.next_task_item = list_accessor.get_next_task_item_mut().take();
+            *list_accessor.get_next_task_item_mut() = Some(new_task.cap().clone());
+            return;
+        }
+
         let task_priority = new_task.priority as usize;
         assert!(task_priority < 16);
 
@@ -289,8 +700,49 @@ impl Scheduler {
         }
     }
 
+    /// Run the task next on this core, ahead of the tasks in the run queue. The
+    /// task is queued instead if a task of the same or a higher priority is ready,
+    /// so that it does not keep those from running. A task without budget is
+    /// throttled.
+    pub fn resume_directly(&self, task: &mut CapAccessorMut<'_, Task>) {
+        if !task.has_budget() || self.has_ready_task(task.priority) {
+            self.add_task_with_priority(task);
+            return;
+        }
+
+        let previous = self.direct_resume.replace(Some(task.cap().clone()));
+        debug_assert!(previous.is_none(), "Only one task can be resumed directly");
+    }
+
+    /// Whether a task of the given priority or a higher one waits in the run queue.
+    fn has_ready_task(&self, priority: u8) -> bool {
+        self.current_list[priority as usize * 2..]
+            .iter()
+            .any(|list| list.borrow_mut().get_next_task_item_mut().is_some())
+    }
+
+    /// Schedule the throttled tasks whose budget has been replenished.
+    fn release_throttled_tasks(&self) {
+        let mut current = self
+            .throttled_list
+            .borrow_mut()
+            .get_next_task_item_mut()
+            .take();
+        while let Some(task) = current {
+            let mut task_writer = task.as_task_mut().unwrap();
+            current = task_writer.next_task_item.take();
+            // Tasks that are still out of budget are throttled again.
+            self.add_task_with_priority(&mut task_writer);
+        }
+    }
+
     /// Get the next task to run.
     pub fn get_task_to_run(&self) -> Option<StoredCap> {
+        if let Some(task) = self.direct_resume.take() {
+            return Some(task);
+        }
+
+        self.release_throttled_tasks();
         for i in (0..=15usize).rev() {
             let mut current_queue_item = self.current_list[i * 2]
                 .borrow_mut()
@@ -330,16 +782,21 @@ impl Scheduler {
     }
 
     pub fn run_forever(&self) -> ! {
+        // This function never returns, so the scheduler lives for the rest of the program.
+        ACTIVE_SCHEDULER.set(Some(unsafe { &*(self as *const Scheduler) }));
         loop {
             let task = self.get_task_to_run();
             if let Some(task_cap) = task {
                 let mut desc = task_cap.as_task_mut().unwrap();
+                self.running_task_id.set(Some(desc.task_id));
+                *self.running_task_memory.borrow_mut() = desc.memory();
                 let result_status = {
                     let task_status = desc.status.clone();
 
                     let result_status = match task_status {
                         TaskStatus::Inactive => desc.switch_to(),
                         TaskStatus::SyscalledReadyToResume(..) => desc.switch_to(),
+                        TaskStatus::Preempted => desc.switch_to(),
                         default => panic!("Cannot run a task in '{:?}' state", default),
                     };
                     result_status
@@ -349,10 +806,69 @@ impl Scheduler {
                     TaskStatus::SyscalledAndWaiting(data) => {
                         crate::syscall_processor::process_syscall(&mut desc, data, self)
                     }
+                    TaskStatus::Preempted => {
+                        desc.set_status(TaskStatus::Preempted);
+                        self.add_task_with_priority(&mut desc);
+                    }
+                    TaskStatus::PageFaulted(vaddr) => {
+                        let is_stack_guard = desc
+                            .stack_guard
+                            .as_ref()
+                            .map_or(false, |guard| guard.contains(&vaddr));
+                        if is_stack_guard {
+                            warn!(
+                                target: "scheduler",
+                                "Stack overflow in task {} at {:?}",
+                                desc.task_id,
+                                vaddr
+                            );
+                            desc.task_exit(TASK_EXIT_CODE_FAULT, self);
+                        } else {
+                            match desc.handle_page_fault(vaddr) {
+                                Ok(()) => {
+                                    // The faulting instruction is retried.
+                                    desc.set_status(TaskStatus::Preempted);
+                                    self.resume_directly(&mut desc);
+                                }
+                                Err(CapabilityErrors::MemoryNotMapped) => {
+                                    warn!(
+                                        target: "scheduler",
+                                        "Task {} cannot access the memory at {:?}",
+                                        desc.task_id,
+                                        vaddr
+                                    );
+                                    desc.task_exit(TASK_EXIT_CODE_FAULT, self);
+                                }
+                                // Like a missing fault untyped memory or a full cpool.
+                                Err(e) => {
+                                    warn!(
+                                        target: "scheduler",
+                                        "Task {} cannot copy the page at {:?}: {:?}",
+                                        desc.task_id,
+                                        vaddr,
+                                        e
+                                    );
+                                    desc.task_exit(TASK_EXIT_CODE_FAULT, self);
+                                }
+                            }
+                        }
+                    }
+                    TaskStatus::Faulted(vector) => {
+                        warn!(
+                            target: "scheduler",
+                            "Task {} faulted with exception {}",
+                            desc.task_id,
+                            vector
+                        );
+                        desc.task_exit(TASK_EXIT_CODE_FAULT, self);
+                    }
                     default => panic!("Cannot result in this result state: {:?}", default),
                 };
+                self.running_task_id.set(None);
+                *self.running_task_memory.borrow_mut() = None;
             } else {
-                // Sleep
+                // Sleep until the timer replenishes a throttled task.
+                crate::arch::task::wait_for_interrupt();
             }
         }
     }
@@ -362,7 +878,10 @@ impl Scheduler {
 mod tests {
     use std::mem::MaybeUninit;
 
-    use crate::{addr::PAddrGlobal, capability::CpoolInner};
+    use crate::{
+        addr::PAddrGlobal,
+        capability::{test_fixture::Fixture, CpoolInner},
+    };
 
     use super::*;
 
@@ -428,4 +947,225 @@ mod tests {
             assert_eq!(1, next_task_val.descriptor.task_id);
         }
     }
+
+    #[test]
+    fn test_join() {
+        let fixture = Fixture::new();
+        let mut untyped = fixture.untyped();
+        let mut cpool = fixture.cpool();
+        let scheduler = Scheduler::new();
+
+        let tasks: Vec<StoredCap> = (0..4)
+            .map(|_| {
+                StoredCap::task_retype_from(&mut untyped, &mut cpool, 5)
+                    .unwrap()
+                    .0
+            })
+            .collect();
+        for task in &tasks[..3] {
+            task.as_task_mut()
+                .unwrap()
+                .set_status(TaskStatus::Preempted);
+        }
+        let join = |task: &StoredCap, waiter: &StoredCap| {
+            task.as_task_mut()
+                .unwrap()
+                .task_add_exit_waiter(&mut waiter.as_task_mut().unwrap())
+        };
+
+        // The last task was never started.
+        assert_eq!(
+            join(&tasks[3], &tasks[0]),
+            Err(CapabilityErrors::TaskInvalidState)
+        );
+
+        // The second task waits for the first, the third for the second.
+        assert_eq!(join(&tasks[0], &tasks[1]), Ok(None));
+        assert_eq!(join(&tasks[1], &tasks[2]), Ok(None));
+        assert_matches!(
+            *tasks[1].as_task().unwrap().status(),
+            TaskStatus::WaitingForTaskExit
+        );
+
+        // The first task would wait for itself.
+        assert_eq!(
+            join(&tasks[1], &tasks[0]),
+            Err(CapabilityErrors::TaskInvalidState)
+        );
+        assert_eq!(
+            join(&tasks[2], &tasks[0]),
+            Err(CapabilityErrors::TaskInvalidState)
+        );
+
+        // Exiting wakes the second task with the exit code only.
+        tasks[0].as_task_mut().unwrap().task_exit(7, &scheduler);
+        let next_task = scheduler.get_task_to_run().unwrap();
+        assert_eq!(next_task.as_ptr(), tasks[1].as_ptr());
+        assert!(scheduler.get_task_to_run().is_none());
+        assert_matches!(
+            *tasks[1].as_task().unwrap().status(),
+            TaskStatus::SyscalledReadyToResume(CapabilityErrors::None, 7, 0)
+        );
+
+        // Joining an exited task returns its exit code right away.
+        assert_eq!(join(&tasks[0], &tasks[1]), Ok(Some(7)));
+        assert_eq!(join(&tasks[0], &tasks[3]), Ok(Some(7)));
+
+        // Exiting the second task wakes the third one.
+        tasks[1].as_task_mut().unwrap().task_exit(9, &scheduler);
+        let next_task = scheduler.get_task_to_run().unwrap();
+        assert_eq!(next_task.as_ptr(), tasks[2].as_ptr());
+        assert_eq!(tasks[0].as_task().unwrap().task_info().exit_code, 7);
+    }
+
+    #[test]
+    fn test_resume_directly() {
+        let fixture = Fixture::new();
+        let mut untyped = fixture.untyped();
+        let mut cpool = fixture.cpool();
+        let scheduler = Scheduler::new();
+
+        let low = StoredCap::task_retype_from(&mut untyped, &mut cpool, 3)
+            .unwrap()
+            .0;
+        let caller = StoredCap::task_retype_from(&mut untyped, &mut cpool, 5)
+            .unwrap()
+            .0;
+        let other = StoredCap::task_retype_from(&mut untyped, &mut cpool, 5)
+            .unwrap()
+            .0;
+
+        // Tasks of a lower priority do not keep the caller from resuming directly.
+        scheduler.add_task_with_priority(&mut low.as_task_mut().unwrap());
+        scheduler.resume_directly(&mut caller.as_task_mut().unwrap());
+        assert_eq!(
+            scheduler.get_task_to_run().unwrap().as_ptr(),
+            caller.as_ptr()
+        );
+
+        // A ready task of the same priority runs first.
+        scheduler.add_task_with_priority(&mut other.as_task_mut().unwrap());
+        scheduler.resume_directly(&mut caller.as_task_mut().unwrap());
+        assert_eq!(
+            scheduler.get_task_to_run().unwrap().as_ptr(),
+            other.as_ptr()
+        );
+        assert_eq!(
+            scheduler.get_task_to_run().unwrap().as_ptr(),
+            caller.as_ptr()
+        );
+        assert_eq!(scheduler.get_task_to_run().unwrap().as_ptr(), low.as_ptr());
+        assert!(scheduler.get_task_to_run().is_none());
+    }
+
+    #[test]
+    fn test_write_registers() {
+        let fixture = Fixture::new();
+        let mut untyped = fixture.untyped();
+        let mut cpool = fixture.cpool();
+        let task = StoredCap::task_retype_from(&mut untyped, &mut cpool, 5)
+            .unwrap()
+            .0;
+        let mut task = task.as_task_mut().unwrap();
+
+        let mut regs = task.read_registers();
+        regs.rip = 0x40_1000;
+        regs.rsp = 0x7000_0000;
+        regs.rax = 42;
+
+        // Registers of a running or exited task cannot be written.
+        for status in [TaskStatus::Active, TaskStatus::Exited(0)].iter() {
+            task.set_status(status.clone());
+            assert_eq!(
+                task.write_registers(&regs),
+                Err(CapabilityErrors::TaskInvalidState)
+            );
+        }
+        assert_eq!(task.read_registers().rax, 0);
+
+        // A task that was stopped anywhere resumes with all of the written registers.
+        let stopped = [
+            TaskStatus::Preempted,
+            TaskStatus::PageFaulted(VAddr::new(0x1000)),
+            TaskStatus::Faulted(13),
+        ];
+        for status in stopped.iter() {
+            task.set_status(status.clone());
+            task.full_resume = false;
+            assert_eq!(task.write_registers(&regs), Ok(()));
+            assert!(task.full_resume);
+            assert_eq!(
+                core::mem::discriminant(&task.status),
+                core::mem::discriminant(status)
+            );
+            assert_eq!(task.read_registers().rax, 42);
+            assert_eq!(task.read_registers().rip, 0x40_1000);
+        }
+
+        // The written registers replace the result of a finished syscall.
+        task.set_status(TaskStatus::SyscalledReadyToResume(
+            CapabilityErrors::None,
+            1,
+            2,
+        ));
+        regs.rax = 7;
+        assert_eq!(task.write_registers(&regs), Ok(()));
+        assert_matches!(task.status, TaskStatus::Preempted);
+        assert_eq!(task.read_registers().rax, 7);
+
+        // Kernel addresses are rejected.
+        regs.rip = 0xFFFF_8000_0000_0000;
+        assert_eq!(
+            task.write_registers(&regs),
+            Err(CapabilityErrors::InvalidMemoryAddress)
+        );
+    }
+
+    #[test]
+    fn test_retype_without_memory() {
+        let fixture = Fixture::new();
+        let mut untyped = fixture.untyped();
+        let mut cpool = fixture.cpool();
+        let scheduler = Scheduler::new();
+
+        let waiter = StoredCap::task_retype_from(&mut untyped, &mut cpool, 5).unwrap();
+        let exiting = StoredCap::task_retype_from(&mut untyped, &mut cpool, 5).unwrap();
+        let free_space = untyped.get_free_space();
+        untyped.allocate(free_space as usize, 1).unwrap();
+        let free_slots = cpool.free_slot_count();
+
+        // Kernel objects grow from the untyped memory of the caller, not from the heap.
+        assert_matches!(
+            StoredCap::task_retype_from(&mut untyped, &mut cpool, 5),
+            Err(CapabilityErrors::MemoryNotSufficient)
+        );
+        assert_matches!(
+            StoredCap::cpool_retype_from(&mut untyped, &mut cpool),
+            Err(CapabilityErrors::MemoryNotSufficient)
+        );
+        assert_matches!(
+            StoredCap::sched_context_retype_from(&mut untyped, &mut cpool, 10, 100, 0),
+            Err(CapabilityErrors::MemoryNotSufficient)
+        );
+        assert_eq!(cpool.free_slot_count(), free_slots);
+
+        // Waiting for a task and waking the waiters only links the tasks together.
+        let mut waiter_task = waiter.0.as_task_mut().unwrap();
+        let mut exiting_task = exiting.0.as_task_mut().unwrap();
+        exiting_task.set_status(TaskStatus::Active);
+        assert_eq!(
+            exiting_task.task_add_exit_waiter(&mut waiter_task),
+            Ok(None)
+        );
+        core::mem::drop(waiter_task);
+        exiting_task.task_exit(3, &scheduler);
+        core::mem::drop(exiting_task);
+
+        let next_task = scheduler.get_task_to_run().unwrap();
+        assert_eq!(next_task.as_ptr(), waiter.0.as_ptr());
+        assert_eq!(
+            next_task.as_task().unwrap().task_info().state,
+            TaskState::Ready
+        );
+    }
 }
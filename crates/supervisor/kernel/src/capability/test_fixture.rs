@@ -0,0 +1,71 @@
+/*!
+Capabilities that kernel tests start from: untyped memory on the heap of the test
+process and an empty root cpool.
+*/
+
+use std::{cell::RefCell, mem::MaybeUninit};
+
+use super::*;
+use crate::util::boxed::Boxed;
+
+/// Length of the untyped memory of a fixture.
+pub const UNTYPED_LENGTH: usize = 0x20_0000 * 5;
+
+pub struct Fixture {
+    /// Slots of the root cpool, to check where capabilities are stored.
+    pub cpool_inner: Box<CpoolInner>,
+    /// Own the capabilities that `untyped` and `cpool` point to.
+    _untyped_ref: Box<RefCell<Capability>>,
+    _cpool_ref: Box<RefCell<Capability>>,
+    untyped: StoredCap,
+    cpool: StoredCap,
+}
+
+impl Fixture {
+    pub fn new() -> Self {
+        // The memory is leaked, as the capabilities point into it until the test ends.
+        let raw_memory: Box<MaybeUninit<[u8; UNTYPED_LENGTH]>> = Box::new_uninit();
+        let addr = PAddrGlobal::new(Box::into_raw(raw_memory) as u64);
+        let untyped_ref = Box::new(RefCell::new(unsafe {
+            UntypedMemory::bootstrap(addr, UNTYPED_LENGTH, false)
+        }));
+
+        const NONE_INNER: RefCell<Capability> = RefCell::new(Capability::new());
+        let cpool_inner = Box::new(CpoolInner {
+            unsafe_data: [NONE_INNER; 256],
+        });
+        let cpool_ref = Box::new(RefCell::new(Capability {
+            capability_data: CapabilityEnum::Cpool(Cpool {
+                is_derived: false,
+                linked_task: None,
+                cpool_data: unsafe {
+                    Boxed::new(PAddrGlobal::new(&*cpool_inner as *const CpoolInner as u64))
+                },
+            }),
+            ..Default::default()
+        }));
+
+        Self {
+            untyped: unsafe { UnsafeRef::from_raw(&*untyped_ref) },
+            cpool: unsafe { UnsafeRef::from_raw(&*cpool_ref) },
+            cpool_inner,
+            _untyped_ref: untyped_ref,
+            _cpool_ref: cpool_ref,
+        }
+    }
+
+    pub fn untyped(&self) -> CapAccessorMut<'_, UntypedMemory> {
+        self.untyped.as_untyped_memory_mut().unwrap()
+    }
+
+    pub fn cpool(&self) -> CapAccessorMut<'_, Cpool> {
+        self.cpool.as_cpool_mut().unwrap()
+    }
+}
+
+impl Default for Fixture {
+    #[inline]
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -15,6 +15,7 @@ use relic_abi::cap::CapabilityErrors;
 use relic_utils::align;
 
 use super::*;
+use crate::arch::globals::BASE_PAGE_LENGTH;
 
 /// Untyped memory capability. Denotes a piece of physical memory
 /// owned by the object.
@@ -98,6 +99,13 @@ impl UntypedMemory {
         self.untyped_flags_1.length()
     }
 
+    /**
+    Get the physical address where the untyped memory starts.
+    */
+    pub fn start_paddr(&self) -> PAddrGlobal {
+        self.start_paddr
+    }
+
     /**
     Returns a flags describing whether this memory belongs to devices.
     In such cases, this cannot be further allocated into non pages.
@@ -135,6 +143,25 @@ impl UntypedMemory {
         paddr + length <= self.start_paddr + self.untyped_flags_1.length()
     }
 
+    /**
+    Check whether regions of the provided `(length, alignment, count)` can be allocated
+    one after another in the current region, like by consecutive calls to [`Self::allocate`].
+    */
+    pub fn can_allocate_all(&self, regions: &[(usize, usize, usize)]) -> bool {
+        let end = self.start_paddr + self.untyped_flags_1.length();
+        let mut watermark = self.watermark;
+        for &(length, alignment, count) in regions {
+            for _ in 0..count {
+                let paddr: PAddrGlobal = align::align_up(watermark.into(), alignment).into();
+                if paddr + length > end {
+                    return false;
+                }
+                watermark = paddr + length;
+            }
+        }
+        true
+    }
+
     /**
     Derive and allocate a memory region to a capability that
     requires memory region.
@@ -156,19 +183,40 @@ impl UntypedMemory {
     where
         F: FnOnce(*mut T) -> Result<StoredCap, CapabilityErrors>,
     {
-        if !may_be_device_memory && self.is_device_memory() {
-            Err(CapabilityErrors::DeviceMemoryConflict)?;
-        }
-
-        let length = core::mem::size_of::<T>();
         let alignment = if let Some(align_val) = alignment {
             align_val
         } else {
             core::mem::align_of::<T>()
         };
+        self.derive_region(
+            core::mem::size_of::<T>(),
+            alignment,
+            may_be_device_memory,
+            |paddr| f(unsafe { paddr.as_raw_ptr() }),
+        )
+    }
+
+    /**
+    Derive a memory region of `length` bytes like [`Self::derive`]. The provided
+    function is given the start of the region.
+    */
+    pub fn derive_region<F>(
+        &mut self,
+        length: usize,
+        alignment: usize,
+        may_be_device_memory: bool,
+        f: F,
+    ) -> Result<StoredCap, CapabilityErrors>
+    where
+        F: FnOnce(PAddrGlobal) -> Result<StoredCap, CapabilityErrors>,
+    {
+        if !may_be_device_memory && self.is_device_memory() {
+            Err(CapabilityErrors::DeviceMemoryConflict)?;
+        }
+
         let paddr = self.allocate(length, alignment)?;
 
-        let f_result = f(unsafe { paddr.0.as_raw_ptr() });
+        let f_result = f(paddr.0);
         match f_result {
             Ok(f_success) => {
                 let mut fs_write = f_success.borrow_mut();
@@ -192,3 +240,33 @@ impl UntypedMemory {
         }
     }
 }
+
+impl StoredCap {
+    /**
+    Create untyped memory for `length` bytes of `untyped` and store it in the provided
+    cpool. The length is rounded up to base pages and the new memory belongs to
+    devices if `untyped` does. Returns the capability and its index in the cpool.
+    */
+    pub fn untyped_retype_from(
+        untyped: &mut UntypedMemory,
+        length: usize,
+        cpool_to_store_in: &mut Cpool,
+    ) -> Result<(StoredCap, usize), CapabilityErrors> {
+        let length = align::align_up(length, BASE_PAGE_LENGTH);
+        let is_device_memory = untyped.is_device_memory();
+        let mut result_index = 0;
+
+        let result = untyped.derive_region(length, BASE_PAGE_LENGTH, true, |paddr| {
+            // The region is allocated from `untyped` and only owned by the new capability.
+            let stored_index = cpool_to_store_in.get_free_index()?;
+            let cap = cpool_to_store_in.write_to_if_empty(stored_index, unsafe {
+                UntypedMemory::bootstrap(paddr, length, is_device_memory)
+            })?;
+
+            result_index = stored_index;
+            Ok(cap)
+        })?;
+
+        Ok((result, result_index))
+    }
+}
@@ -5,7 +5,6 @@ use relic_abi::{
 
 use crate::{
     addr::{PAddr, PAddrGlobal, VAddr},
-    arch::capability::paging::L4,
     bootboot::bootboot,
     capability::{CapAccessorMut, Cpool, MapPermissions, StoredCap, UntypedMemory},
 };
@@ -47,7 +46,7 @@ impl FrameBuffer {
         &self,
         untyped: &mut CapAccessorMut<'_, UntypedMemory>,
         cpool_to_store: &mut CapAccessorMut<'_, Cpool>,
-        l4: &mut CapAccessorMut<'_, L4>,
+        top_level_table: &StoredCap,
         bootstrap_info: &mut BootstrapInfo,
     ) {
         info!(target: "graphics", "Bootstrap VGA frame buffer");
@@ -98,7 +97,7 @@ impl FrameBuffer {
             let mut search_store = false;
             let mut retry_count = 0;
             while retry_count < 2 {
-                let result = l4.l4_map(
+                let result = top_level_table.address_space_map(
                     vga_virt_addr + (page * crate::arch::globals::BASE_PAGE_LENGTH),
                     &raw_page.0,
                     untyped,
@@ -108,7 +107,7 @@ impl FrameBuffer {
                     } else {
                         None
                     },
-                    MapPermissions::WRITE | MapPermissions::CACHE_DISABLE,
+                    MapPermissions::WRITE | MapPermissions::WRITE_COMBINING,
                 );
 
                 match result.err() {
@@ -118,19 +117,20 @@ impl FrameBuffer {
                             current_cpool =
                                 StoredCap::cpool_retype_from(untyped, cpool_to_store).unwrap();
                             info!(target: "graphics", "CPool created at index: {}", current_cpool.1);
-                            l4.l4_map(
-                                vga_virt_addr + (page * crate::arch::globals::BASE_PAGE_LENGTH),
-                                &raw_page.0,
-                                untyped,
-                                &mut current_cpool.0.as_cpool_mut().unwrap(),
-                                if search_store {
-                                    Some(cpool_to_store)
-                                } else {
-                                    None
-                                },
-                                MapPermissions::WRITE | MapPermissions::CACHE_DISABLE,
-                            )
-                            .unwrap();
+                            top_level_table
+                                .address_space_map(
+                                    vga_virt_addr + (page * crate::arch::globals::BASE_PAGE_LENGTH),
+                                    &raw_page.0,
+                                    untyped,
+                                    &mut current_cpool.0.as_cpool_mut().unwrap(),
+                                    if search_store {
+                                        Some(cpool_to_store)
+                                    } else {
+                                        None
+                                    },
+                                    MapPermissions::WRITE | MapPermissions::WRITE_COMBINING,
+                                )
+                                .unwrap();
                             break;
                         }
                         CapabilityErrors::CapabilitySearchFailedPartial
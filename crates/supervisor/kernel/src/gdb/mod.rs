@@ -0,0 +1,633 @@
+/*!
+GDB remote serial protocol stub.
+
+Whenever the kernel raises a breakpoint or debug exception, the stub takes over
+the serial port and serves GDB until the execution is resumed. GDB also stops the
+running code with Ctrl-C, which arrives through the receive interrupt of the port. This attaches GDB
+until it detaches. While GDB is attached, user mode also traps into the stub at
+the breakpoints of GDB and after single steps that GDB requested. Other traps of
+user mode are faults that exit the task. Tasks in the scheduler are exposed as
+threads and memory is accessed through the address space of the selected thread.
+
+Log output shares the serial port and is ignored by GDB as it is not framed.
+*/
+
+/// Packet framing and encoding helpers.
+pub mod packet;
+
+use core::fmt::Write;
+
+use heapless::{String, Vec};
+use spin::Mutex;
+
+use crate::{
+    addr::{PAddr, VAddr},
+    arch::{
+        debug::{
+            self, GdbRegisters, HardwareBreakpointKind, TrapFrame, BREAKPOINT_INSTRUCTION,
+            BREAKPOINT_VECTOR, DEBUG_VECTOR, HARDWARE_BREAKPOINT_COUNT, SERIAL_VECTOR,
+        },
+        globals::BASE_PAGE_LENGTH,
+        paging::utils::cr3,
+        serial::{self, RawSerial},
+    },
+    capability::{Scheduler, StoredCap, TaskMemory},
+};
+use packet::{
+    decode_hex_bytes, hex_value, parse_hex, parse_thread_id, push_hex_bytes, receive_packet,
+    send_packet, split_once, PacketBuffer, Response, PACKET_SIZE,
+};
+
+/// Thread ID reported for the kernel when no task is running.
+pub const KERNEL_THREAD_ID: u64 = 0x7FFF_FFFF;
+
+/// Byte that GDB sends to interrupt the execution on Ctrl-C.
+const INTERRUPT_BYTE: u8 = 0x03;
+
+/// Maximum number of software breakpoints.
+const MAX_SOFTWARE_BREAKPOINTS: usize = 32;
+
+/// Maximum number of threads reported to GDB.
+const MAX_THREADS: usize = 64;
+
+/// Maximum number of bytes read with one `m` packet so that the reply fits in a packet.
+const MAX_MEMORY_READ: usize = (PACKET_SIZE - 4) / 2;
+
+/// A software breakpoint replaces the first instruction byte with `int3`. The page
+/// of a task is made private first, so that other address spaces do not trap.
+#[derive(Debug, Clone, Copy)]
+struct SoftwareBreakpoint {
+    /// Physical address of the root page table of the address space.
+    address_space: PAddr,
+    addr: u64,
+    original: u8,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct HardwareBreakpoint {
+    addr: u64,
+    kind: HardwareBreakpointKind,
+}
+
+/// A thread as seen by GDB.
+enum Thread {
+    /// The code that raised the debug exception.
+    Stopped,
+    /// A task waiting in the scheduler.
+    Task(StoredCap),
+}
+
+/// What to do after processing a packet.
+enum Action {
+    Reply,
+    Resume { step: bool },
+    Detach { reply: bool },
+}
+
+struct GdbStub {
+    /// Thread used for register and memory operations. `None` is the stopped thread.
+    selected_thread: Option<u64>,
+    software_breakpoints: Vec<SoftwareBreakpoint, MAX_SOFTWARE_BREAKPOINTS>,
+    hardware_breakpoints: [Option<HardwareBreakpoint>; HARDWARE_BREAKPOINT_COUNT],
+    /// GDB resumed the execution and waits for a stop reply.
+    running: bool,
+    /// GDB is attached, which happens when the kernel traps into the stub.
+    attached: bool,
+    /// Thread that GDB resumed with a single step.
+    stepping_thread: Option<u64>,
+}
+
+static GDB_STUB: Mutex<GdbStub> = Mutex::new(GdbStub::new());
+
+/**
+Serve GDB until it resumes the code that raised the debug exception. Returns false
+without stopping if the trap of user mode is not for GDB, see [`GdbStub::stop`].
+*/
+pub fn handle_trap(frame: &mut TrapFrame) -> bool {
+    let mut stub = GDB_STUB.lock();
+    let mut stop_reply: String<64> = String::new();
+    if !stub.stop(frame, &mut stop_reply) {
+        return false;
+    }
+
+    // User mode cannot hold the serial port, but the interrupted kernel code might.
+    let conn = if frame.is_user_mode() {
+        Some(RawSerial::lock())
+    } else {
+        RawSerial::try_lock()
+    };
+    let mut conn = match conn {
+        Some(conn) => conn,
+        None => {
+            // GDB cannot be served, so the breakpoints would trap forever.
+            stub.detach(frame);
+            return true;
+        }
+    };
+    if !stub.running {
+        info!(target: "gdb", "Stopped at {:#x}. Waiting for GDB.", frame.rip);
+    }
+
+    if stub.running {
+        send_packet(&mut conn, stop_reply.as_bytes());
+        stub.running = false;
+    }
+    stub.selected_thread = None;
+
+    let mut buffer = PacketBuffer::new();
+    let mut response = Response::new();
+    loop {
+        receive_packet(&mut conn, &mut buffer);
+        response.clear();
+        match stub.process(&buffer, frame, &stop_reply, &mut response) {
+            Action::Reply => send_packet(&mut conn, response.as_bytes()),
+            Action::Resume { step } => {
+                frame.set_single_step(step);
+                stub.stepping_thread = if step {
+                    Some(stopped_thread_id())
+                } else {
+                    None
+                };
+                stub.running = true;
+                return true;
+            }
+            Action::Detach { reply } => {
+                if reply {
+                    send_packet(&mut conn, b"OK");
+                }
+                stub.detach(frame);
+                return true;
+            }
+        }
+    }
+}
+
+/**
+Take the bytes received on the serial port and return whether GDB asked to interrupt
+the execution. The code that was interrupted is then stopped with [`handle_trap`].
+Other bytes are dropped, as GDB only sends packets while the stub serves it.
+*/
+pub fn take_interrupt() -> bool {
+    let mut interrupted = false;
+    serial::take_received(|byte| interrupted |= byte == INTERRUPT_BYTE);
+    interrupted
+}
+
+/// Stop the current core and wait for GDB.
+pub fn breakpoint() {
+    debug::breakpoint();
+}
+
+impl GdbStub {
+    const fn new() -> Self {
+        Self {
+            selected_thread: None,
+            software_breakpoints: Vec::new(),
+            hardware_breakpoints: [None; HARDWARE_BREAKPOINT_COUNT],
+            running: false,
+            attached: false,
+            stepping_thread: None,
+        }
+    }
+
+    /// Remove every breakpoint and resume without GDB.
+    fn detach(&mut self, frame: &mut TrapFrame) {
+        self.remove_all_breakpoints();
+        frame.set_single_step(false);
+        self.stepping_thread = None;
+        self.running = false;
+        self.attached = false;
+    }
+
+    /**
+    Find out why the core stopped and build the stop reply. Returns whether the trap
+    is for GDB. Traps of the kernel and interrupts by GDB always are and attach GDB.
+    User mode only traps into GDB at its breakpoints or after a single step of the task
+    that GDB requested, which needs GDB to be attached.
+    */
+    fn stop(&mut self, frame: &mut TrapFrame, reply: &mut String<64>) -> bool {
+        let interrupted = frame.vector == SERIAL_VECTOR;
+        let _ = reply.push_str(if interrupted { "T02" } else { "T05" });
+        let requested = match frame.vector {
+            BREAKPOINT_VECTOR => {
+                // `int3` is a trap, so the instruction pointer is after the breakpoint.
+                let addr = frame.rip.wrapping_sub(1);
+                let address_space = current_address_space();
+                let is_ours = self
+                    .software_breakpoints
+                    .iter()
+                    .any(|b| b.address_space == address_space && b.addr == addr);
+                if is_ours {
+                    frame.rip = addr;
+                    let _ = reply.push_str("swbreak:;");
+                }
+                is_ours
+            }
+            DEBUG_VECTOR => {
+                let status = debug::take_debug_status();
+                let hit = debug::triggered_hardware_breakpoint(status)
+                    .and_then(|index| self.hardware_breakpoints[index]);
+                match hit {
+                    Some(HardwareBreakpoint {
+                        kind: HardwareBreakpointKind::Execute,
+                        ..
+                    }) => {
+                        // Do not hit the same breakpoint again when resuming.
+                        frame.set_resume_flag();
+                        let _ = reply.push_str("hwbreak:;");
+                    }
+                    Some(HardwareBreakpoint {
+                        addr,
+                        kind: HardwareBreakpointKind::Write,
+                    }) => {
+                        let _ = write!(reply, "watch:{:x};", addr);
+                    }
+                    Some(HardwareBreakpoint {
+                        addr,
+                        kind: HardwareBreakpointKind::Access,
+                    }) => {
+                        let _ = write!(reply, "awatch:{:x};", addr);
+                    }
+                    // Single step.
+                    None => {}
+                }
+                let stepped = hit.is_none() && self.stepping_thread == Some(stopped_thread_id());
+                if stepped {
+                    self.stepping_thread = None;
+                }
+                hit.is_some() || stepped
+            }
+            _ => false,
+        };
+        let _ = write!(reply, "thread:{:x};", stopped_thread_id());
+
+        if frame.is_user_mode() && !interrupted {
+            // Breakpoints and single steps only exist while GDB is attached.
+            return self.attached && requested;
+        }
+        self.attached = true;
+        true
+    }
+
+    /// Process a packet. Unsupported packets get an empty response.
+    fn process(
+        &mut self,
+        packet: &[u8],
+        frame: &mut TrapFrame,
+        stop_reply: &str,
+        response: &mut Response,
+    ) -> Action {
+        let (command, args) = match packet.split_first() {
+            Some((command, args)) => (*command, args),
+            None => return Action::Reply,
+        };
+
+        let result = match command {
+            b'?' => response.push_str(stop_reply),
+            b'g' => self.read_registers(frame, response),
+            b'G' => self.write_registers(frame, args, response),
+            b'm' => self.read_memory(args, response),
+            b'M' => self.write_memory(args, response),
+            b'c' | b's' => {
+                if !args.is_empty() {
+                    match parse_hex(args) {
+                        Some(addr) => frame.rip = addr,
+                        None => return error(response),
+                    }
+                }
+                return Action::Resume {
+                    step: command == b's',
+                };
+            }
+            b'D' => return Action::Detach { reply: true },
+            b'k' => return Action::Detach { reply: false },
+            b'H' => self.select_thread(args, response),
+            b'T' => match parse_thread_id(args) {
+                Some(id) if find_thread(id).is_some() => response.push_str("OK"),
+                _ => Err(()),
+            },
+            b'Z' => self.update_breakpoint(true, args, response),
+            b'z' => self.update_breakpoint(false, args, response),
+            b'q' => query(args, response),
+            _ => Ok(()),
+        };
+
+        match result {
+            Ok(()) => Action::Reply,
+            Err(()) => error(response),
+        }
+    }
+
+    fn selected(&self) -> Result<Thread, ()> {
+        find_thread(self.selected_thread).ok_or(())
+    }
+
+    fn select_thread(&mut self, args: &[u8], response: &mut Response) -> Result<(), ()> {
+        let (op, id) = args.split_first().ok_or(())?;
+        let id = parse_thread_id(id).ok_or(())?;
+        if find_thread(id).is_none() {
+            return Err(());
+        }
+        // Execution always resumes the stopped thread, so only `Hg` has an effect.
+        if *op == b'g' {
+            self.selected_thread = id;
+        }
+        response.push_str("OK")
+    }
+
+    fn read_registers(&self, frame: &TrapFrame, response: &mut Response) -> Result<(), ()> {
+        let regs = match self.selected()? {
+            Thread::Stopped => GdbRegisters::from(frame),
+            Thread::Task(task) => {
+                GdbRegisters::from(&task.as_task().map_err(|_| ())?.read_registers())
+            }
+        };
+        push_hex_bytes(response, &regs.to_bytes())
+    }
+
+    fn write_registers(
+        &self,
+        frame: &mut TrapFrame,
+        args: &[u8],
+        response: &mut Response,
+    ) -> Result<(), ()> {
+        let mut data = [0u8; PACKET_SIZE / 2];
+        let data = decode_hex_bytes(args, &mut data).ok_or(())?;
+        let regs = GdbRegisters::from_bytes(data).ok_or(())?;
+        match self.selected()? {
+            Thread::Stopped => frame.set_from_gdb(&regs).map_err(|_| ())?,
+            Thread::Task(task) => {
+                let mut task = task.as_task_mut().map_err(|_| ())?;
+                let current = task.read_registers();
+                task.write_registers(&regs.to_task_registers(&current))
+                    .map_err(|_| ())?;
+            }
+        }
+        response.push_str("OK")
+    }
+
+    fn read_memory(&self, args: &[u8], response: &mut Response) -> Result<(), ()> {
+        let (addr, len) = split_once(args, b',').ok_or(())?;
+        let addr = parse_hex(addr).ok_or(())?;
+        let len = (parse_hex(len).ok_or(())? as usize).min(MAX_MEMORY_READ);
+        let address_space = address_space(&self.selected()?).ok_or(())?;
+
+        for i in 0..len as u64 {
+            match byte_ptr(address_space, addr.wrapping_add(i)) {
+                Some(ptr) => push_hex_bytes(response, &[unsafe { *ptr }])?,
+                // Partial reads are allowed, but at least one byte must be read.
+                None if i == 0 => return Err(()),
+                None => break,
+            }
+        }
+        Ok(())
+    }
+
+    fn write_memory(&self, args: &[u8], response: &mut Response) -> Result<(), ()> {
+        let (location, data) = split_once(args, b':').ok_or(())?;
+        let (addr, len) = split_once(location, b',').ok_or(())?;
+        let addr = parse_hex(addr).ok_or(())?;
+        let len = parse_hex(len).ok_or(())? as usize;
+        if data.len() != len * 2 {
+            return Err(());
+        }
+        let thread = self.selected()?;
+        let address_space = address_space(&thread).ok_or(())?;
+
+        for (i, pair) in data.chunks(2).enumerate() {
+            let value = (hex_value(pair[0]).ok_or(())? << 4) | hex_value(pair[1]).ok_or(())?;
+            let addr = addr.wrapping_add(i as u64);
+            if i == 0 || addr % BASE_PAGE_LENGTH as u64 == 0 {
+                make_private(&thread, addr)?;
+            }
+            let ptr = byte_ptr(address_space, addr).ok_or(())?;
+            unsafe { *ptr = value };
+        }
+        response.push_str("OK")
+    }
+
+    /// Insert or remove a breakpoint from a `Z` or `z` packet.
+    fn update_breakpoint(
+        &mut self,
+        insert: bool,
+        args: &[u8],
+        response: &mut Response,
+    ) -> Result<(), ()> {
+        let (kind, rest) = split_once(args, b',').ok_or(())?;
+        let (addr, len) = split_once(rest, b',').ok_or(())?;
+        // Ignore the conditions that might follow the length.
+        let len = split_once(len, b';').map(|(len, _)| len).unwrap_or(len);
+        let addr = parse_hex(addr).ok_or(())?;
+        let len = parse_hex(len).ok_or(())? as usize;
+
+        let hardware_kind = match kind {
+            b"0" => {
+                let thread = self.selected()?;
+                let address_space = address_space(&thread).ok_or(())?;
+                if insert {
+                    make_private(&thread, addr)?;
+                    self.insert_software_breakpoint(address_space, addr)?;
+                } else {
+                    self.remove_software_breakpoint(address_space, addr);
+                }
+                return response.push_str("OK");
+            }
+            b"1" => HardwareBreakpointKind::Execute,
+            b"2" => HardwareBreakpointKind::Write,
+            b"4" => HardwareBreakpointKind::Access,
+            // Read watchpoints are not supported by the hardware.
+            _ => return Ok(()),
+        };
+
+        let breakpoint = HardwareBreakpoint {
+            addr,
+            kind: hardware_kind,
+        };
+        if insert {
+            if !self.hardware_breakpoints.contains(&Some(breakpoint)) {
+                let index = self
+                    .hardware_breakpoints
+                    .iter()
+                    .position(Option::is_none)
+                    .ok_or(())?;
+                debug::set_hardware_breakpoint(index, addr, hardware_kind, len)?;
+                self.hardware_breakpoints[index] = Some(breakpoint);
+            }
+        } else if let Some(index) = self
+            .hardware_breakpoints
+            .iter()
+            .position(|b| *b == Some(breakpoint))
+        {
+            debug::clear_hardware_breakpoint(index);
+            self.hardware_breakpoints[index] = None;
+        }
+        response.push_str("OK")
+    }
+
+    fn insert_software_breakpoint(&mut self, address_space: PAddr, addr: u64) -> Result<(), ()> {
+        let exists = self
+            .software_breakpoints
+            .iter()
+            .any(|b| b.address_space == address_space && b.addr == addr);
+        if exists {
+            return Ok(());
+        }
+
+        let ptr = byte_ptr(address_space, addr).ok_or(())?;
+        let breakpoint = SoftwareBreakpoint {
+            address_space,
+            addr,
+            original: unsafe { *ptr },
+        };
+        self.software_breakpoints.push(breakpoint).map_err(|_| ())?;
+        unsafe { *ptr = BREAKPOINT_INSTRUCTION };
+        Ok(())
+    }
+
+    fn remove_software_breakpoint(&mut self, address_space: PAddr, addr: u64) {
+        let index = self
+            .software_breakpoints
+            .iter()
+            .position(|b| b.address_space == address_space && b.addr == addr);
+        if let Some(index) = index {
+            let breakpoint = self.software_breakpoints.swap_remove(index);
+            if let Some(ptr) = byte_ptr(breakpoint.address_space, breakpoint.addr) {
+                unsafe { *ptr = breakpoint.original };
+            }
+        }
+    }
+
+    fn remove_all_breakpoints(&mut self) {
+        while let Some(breakpoint) = self.software_breakpoints.pop() {
+            if let Some(ptr) = byte_ptr(breakpoint.address_space, breakpoint.addr) {
+                unsafe { *ptr = breakpoint.original };
+            }
+        }
+        for (index, breakpoint) in self.hardware_breakpoints.iter_mut().enumerate() {
+            if breakpoint.take().is_some() {
+                debug::clear_hardware_breakpoint(index);
+            }
+        }
+    }
+}
+
+/// Process a `q` packet.
+fn query(args: &[u8], response: &mut Response) -> Result<(), ()> {
+    if args.starts_with(b"Supported") {
+        write!(response, "PacketSize={:x};swbreak+;hwbreak+", PACKET_SIZE).map_err(|_| ())
+    } else if args == b"fThreadInfo" {
+        response.push('m')?;
+        for (i, id) in thread_ids().iter().enumerate() {
+            if i != 0 {
+                response.push(',')?;
+            }
+            write!(response, "{:x}", id).map_err(|_| ())?;
+        }
+        Ok(())
+    } else if args == b"sThreadInfo" {
+        response.push('l')
+    } else if args == b"C" {
+        write!(response, "QC{:x}", stopped_thread_id()).map_err(|_| ())
+    } else if args == b"Attached" {
+        response.push('1')
+    } else if let Some(id) = args.strip_prefix(b"ThreadExtraInfo,") {
+        let id = parse_thread_id(id).ok_or(())?;
+        let description = match find_thread(id).ok_or(())? {
+            Thread::Stopped if stopped_thread_id() == KERNEL_THREAD_ID => "Kernel",
+            Thread::Stopped => "Running",
+            Thread::Task(_) => "Ready",
+        };
+        push_hex_bytes(response, description.as_bytes())
+    } else {
+        Ok(())
+    }
+}
+
+fn error(response: &mut Response) -> Action {
+    response.clear();
+    let _ = response.push_str("E01");
+    Action::Reply
+}
+
+/// ID of the thread that raised the debug exception.
+fn stopped_thread_id() -> u64 {
+    Scheduler::active()
+        .and_then(Scheduler::running_task_id)
+        .unwrap_or(KERNEL_THREAD_ID)
+}
+
+/// IDs of all the threads, starting with the stopped thread.
+fn thread_ids() -> Vec<u64, MAX_THREADS> {
+    let mut ids = Vec::new();
+    let _ = ids.push(stopped_thread_id());
+    if let Some(scheduler) = Scheduler::active() {
+        scheduler.for_each_task(|task| {
+            if let Ok(task) = task.as_task() {
+                let _ = ids.push(*task.task_id());
+            }
+        });
+    }
+    ids
+}
+
+fn find_thread(id: Option<u64>) -> Option<Thread> {
+    let id = match id {
+        Some(id) if id != stopped_thread_id() => id,
+        _ => return Some(Thread::Stopped),
+    };
+
+    let mut found = None;
+    Scheduler::active()?.for_each_task(|task| {
+        let is_match = task.as_task().map(|t| *t.task_id() == id).unwrap_or(false);
+        if is_match {
+            found = Some(task.clone());
+        }
+    });
+    found.map(Thread::Task)
+}
+
+/// Root page table of the active address space.
+fn current_address_space() -> PAddr {
+    let root: u64 = unsafe { cr3() }.into();
+    // Lower bits of CR3 are flags.
+    PAddr::new(root & !0xFFF)
+}
+
+/// Root page table of the address space of the thread.
+fn address_space(thread: &Thread) -> Option<PAddr> {
+    match thread {
+        Thread::Stopped => Some(current_address_space()),
+        Thread::Task(task) => {
+            let task = task.as_task().ok()?;
+            let top_level_table = task.top_level_table().clone()?;
+            top_level_table.address_space_paddr().ok()
+        }
+    }
+}
+
+/// Memory of the task of the thread.
+fn task_memory(thread: &Thread) -> Option<TaskMemory> {
+    match thread {
+        Thread::Stopped => Scheduler::active()?.running_task_memory(),
+        Thread::Task(task) => task.as_task().ok()?.memory(),
+    }
+}
+
+/// Make the page at `addr` private to the task of the thread before writing to it
+/// through its physical memory, so that other address spaces do not see the write.
+/// The kernel is the same in every address space.
+fn make_private(thread: &Thread, addr: u64) -> Result<(), ()> {
+    let vaddr = VAddr::new(addr);
+    if vaddr.validate_user_mode().is_err() {
+        return Ok(());
+    }
+    let memory = task_memory(thread).ok_or(())?;
+    memory.make_private(vaddr).map_err(|_| ())
+}
+
+/// Get a pointer to the byte at `addr` in the given address space. The pointer is
+/// accessed through the kernel mapping, so page permissions do not apply.
+fn byte_ptr(address_space: PAddr, addr: u64) -> Option<*mut u8> {
+    let paddr = VAddr::new(addr).translate_in(address_space)?;
+    Some(unsafe { paddr.to_paddr_global().as_raw_ptr() })
+}
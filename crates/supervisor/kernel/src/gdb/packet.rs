@@ -0,0 +1,212 @@
+/*!
+Packet framing of the GDB remote serial protocol.
+
+A packet is sent as `$<data>#<checksum>` where the checksum is the modulo 256
+sum of the data bytes as two hex digits. Every packet is acknowledged by the
+receiver with `+`, or `-` to request a retransmission.
+*/
+
+use heapless::{String, Vec};
+
+/// Maximum size of a packet exchanged with GDB.
+pub const PACKET_SIZE: usize = 0x800;
+
+/// Incoming packet data.
+pub type PacketBuffer = Vec<u8, PACKET_SIZE>;
+
+/// Outgoing packet data.
+pub type Response = String<PACKET_SIZE>;
+
+/// Byte stream to the debugger.
+pub trait Connection {
+    fn read(&mut self) -> u8;
+    fn write(&mut self, byte: u8);
+}
+
+impl Connection for crate::arch::serial::RawSerial<'_> {
+    fn read(&mut self) -> u8 {
+        self.receive()
+    }
+
+    fn write(&mut self, byte: u8) {
+        self.send(byte)
+    }
+}
+
+/// Receive the next valid packet into `buffer`. Bytes outside of packets are ignored
+/// and packets with a bad checksum are rejected so that GDB sends them again.
+pub fn receive_packet<C: Connection>(conn: &mut C, buffer: &mut PacketBuffer) {
+    loop {
+        while conn.read() != b'$' {}
+
+        buffer.clear();
+        let mut checksum = 0u8;
+        let mut overflow = false;
+        loop {
+            let byte = conn.read();
+            if byte == b'#' {
+                break;
+            }
+            checksum = checksum.wrapping_add(byte);
+            overflow |= buffer.push(byte).is_err();
+        }
+
+        let expected = [conn.read(), conn.read()];
+        if !overflow && parse_hex(&expected) == Some(checksum as u64) {
+            conn.write(b'+');
+            return;
+        }
+        conn.write(b'-');
+    }
+}
+
+/// Send a packet and wait until GDB acknowledges it.
+pub fn send_packet<C: Connection>(conn: &mut C, data: &[u8]) {
+    let checksum = data.iter().fold(0u8, |sum, b| sum.wrapping_add(*b));
+    loop {
+        conn.write(b'$');
+        for byte in data {
+            conn.write(*byte);
+        }
+        conn.write(b'#');
+        conn.write(hex_char(checksum >> 4));
+        conn.write(hex_char(checksum & 0xF));
+
+        match conn.read() {
+            b'+' => return,
+            // Retransmit on anything else.
+            _ => {}
+        }
+    }
+}
+
+/// Convert the lower nibble to a lower case hex character.
+pub fn hex_char(nibble: u8) -> u8 {
+    b"0123456789abcdef"[(nibble & 0xF) as usize]
+}
+
+/// Convert a hex character to its value.
+pub fn hex_value(c: u8) -> Option<u8> {
+    match c {
+        b'0'..=b'9' => Some(c - b'0'),
+        b'a'..=b'f' => Some(c - b'a' + 10),
+        b'A'..=b'F' => Some(c - b'A' + 10),
+        _ => None,
+    }
+}
+
+/// Parse a big endian hex number as used for addresses and lengths.
+pub fn parse_hex(data: &[u8]) -> Option<u64> {
+    if data.is_empty() || data.len() > 16 {
+        return None;
+    }
+    data.iter()
+        .try_fold(0u64, |acc, c| Some((acc << 4) | hex_value(*c)? as u64))
+}
+
+/// Parse a thread id. `0` (any thread) and `-1` (all threads) are returned as `None`.
+pub fn parse_thread_id(data: &[u8]) -> Option<Option<u64>> {
+    if data == b"-1" {
+        return Some(None);
+    }
+    match parse_hex(data)? {
+        0 => Some(None),
+        id => Some(Some(id)),
+    }
+}
+
+/// Decode hex encoded bytes into `out`. Returns `None` if the data is not
+/// valid hex or does not fit.
+pub fn decode_hex_bytes<'a>(data: &[u8], out: &'a mut [u8]) -> Option<&'a [u8]> {
+    if data.len() % 2 != 0 || data.len() / 2 > out.len() {
+        return None;
+    }
+    for (i, pair) in data.chunks(2).enumerate() {
+        out[i] = (hex_value(pair[0])? << 4) | hex_value(pair[1])?;
+    }
+    Some(&out[..data.len() / 2])
+}
+
+/// Append the bytes as hex to the response.
+pub fn push_hex_bytes(response: &mut Response, data: &[u8]) -> Result<(), ()> {
+    for byte in data {
+        response.push(hex_char(byte >> 4) as char)?;
+        response.push(hex_char(byte & 0xF) as char)?;
+    }
+    Ok(())
+}
+
+/// Split `data` at the first occurrence of `separator`.
+pub fn split_once(data: &[u8], separator: u8) -> Option<(&[u8], &[u8])> {
+    let index = data.iter().position(|c| *c == separator)?;
+    Some((&data[..index], &data[index + 1..]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct MockConnection {
+        input: &'static [u8],
+        output: alloc::vec::Vec<u8>,
+    }
+
+    impl Connection for MockConnection {
+        fn read(&mut self) -> u8 {
+            let (first, rest) = self.input.split_first().expect("Input exhausted");
+            self.input = rest;
+            *first
+        }
+
+        fn write(&mut self, byte: u8) {
+            self.output.push(byte);
+        }
+    }
+
+    #[test]
+    fn test_receive_packet() {
+        let mut conn = MockConnection {
+            input: b"+junk$g#00$g#67",
+            output: alloc::vec::Vec::new(),
+        };
+        let mut buffer = PacketBuffer::new();
+        receive_packet(&mut conn, &mut buffer);
+        assert_eq!(&buffer[..], b"g");
+        assert_eq!(&conn.output[..], b"-+");
+    }
+
+    #[test]
+    fn test_send_packet() {
+        let mut conn = MockConnection {
+            input: b"-+",
+            output: alloc::vec::Vec::new(),
+        };
+        send_packet(&mut conn, b"OK");
+        assert_eq!(&conn.output[..], b"$OK#9a$OK#9a");
+    }
+
+    #[test]
+    fn test_hex() {
+        assert_eq!(parse_hex(b"ffffff8000001000"), Some(0xffff_ff80_0000_1000));
+        assert_eq!(parse_hex(b"1A"), Some(0x1a));
+        assert_eq!(parse_hex(b""), None);
+        assert_eq!(parse_hex(b"1g"), None);
+        assert_eq!(parse_hex(b"10000000000000000"), None);
+
+        assert_eq!(parse_thread_id(b"-1"), Some(None));
+        assert_eq!(parse_thread_id(b"0"), Some(None));
+        assert_eq!(parse_thread_id(b"2a"), Some(Some(0x2a)));
+
+        let mut out = [0u8; 4];
+        assert_eq!(decode_hex_bytes(b"cc90", &mut out), Some(&[0xcc, 0x90][..]));
+        assert_eq!(decode_hex_bytes(b"cc9", &mut out), None);
+        assert_eq!(decode_hex_bytes(b"0011223344", &mut out), None);
+
+        let mut response = Response::new();
+        push_hex_bytes(&mut response, &[0xde, 0xad, 0x01]).unwrap();
+        assert_eq!(response.as_str(), "dead01");
+
+        assert_eq!(split_once(b"1000,4", b','), Some((&b"1000"[..], &b"4"[..])));
+        assert_eq!(split_once(b"1000", b','), None);
+    }
+}
@@ -0,0 +1,182 @@
+//! Buddy allocator for the pages of the kernel heap.
+
+use core::ptr::NonNull;
+
+use heapless::Vec;
+use relic_utils::align;
+
+use crate::util::memory_region::MemoryRegion;
+
+/// Size of the smallest block.
+pub const PAGE_SIZE: usize = 4096;
+
+/// Blocks have a size of `PAGE_SIZE << order` and are aligned to their size.
+pub const MAX_ORDER: usize = 8;
+
+/// Size of the largest block. The allocator grows by blocks of this size.
+pub const MAX_BLOCK_SIZE: usize = PAGE_SIZE << MAX_ORDER;
+
+/// Number of memory regions the allocator can grow into.
+const MAX_REGIONS: usize = 8;
+
+/// A free block. It is stored in the block itself.
+struct FreeBlock {
+    next: Option<NonNull<FreeBlock>>,
+}
+
+pub struct BuddyAllocator {
+    free_lists: [Option<NonNull<FreeBlock>>; MAX_ORDER + 1],
+
+    /// Memory that is not part of the allocator yet.
+    regions: Vec<MemoryRegion, MAX_REGIONS>,
+
+    /// Bytes that the allocator took from `regions`.
+    grown_bytes: usize,
+
+    /// Bytes of the blocks that are allocated.
+    allocated_bytes: usize,
+}
+
+// The free blocks are only accessed with the allocator.
+unsafe impl Send for BuddyAllocator {}
+
+impl BuddyAllocator {
+    pub const fn new() -> Self {
+        Self {
+            free_lists: [None; MAX_ORDER + 1],
+            regions: Vec::new(),
+            grown_bytes: 0,
+            allocated_bytes: 0,
+        }
+    }
+
+    /// Add memory that the allocator grows into once it runs out of blocks.
+    /// Returns the region if the allocator cannot track more regions.
+    pub fn add_region(&mut self, region: MemoryRegion) -> Result<(), MemoryRegion> {
+        self.regions.push(region)
+    }
+
+    /// Add a free block of the largest order at `block`, which must be aligned to
+    /// [`MAX_BLOCK_SIZE`].
+    pub fn add_block(&mut self, block: usize) {
+        assert_eq!(block % MAX_BLOCK_SIZE, 0);
+        self.push(block, MAX_ORDER);
+        self.grown_bytes += MAX_BLOCK_SIZE;
+    }
+
+    pub fn grown_bytes(&self) -> usize {
+        self.grown_bytes
+    }
+
+    pub fn allocated_bytes(&self) -> usize {
+        self.allocated_bytes
+    }
+
+    /// Smallest order of a block that holds `size` bytes.
+    pub fn order_for(size: usize) -> Option<usize> {
+        (0..=MAX_ORDER).find(|order| PAGE_SIZE << order >= size)
+    }
+
+    /// Allocate a block of the given order. Returns its address in the physical
+    /// memory window.
+    pub fn allocate(&mut self, order: usize) -> Option<usize> {
+        assert!(order <= MAX_ORDER);
+        let mut current = loop {
+            match (order..=MAX_ORDER).find(|&order| self.free_lists[order].is_some()) {
+                Some(current) => break current,
+                None if self.grow() => {}
+                None => return None,
+            }
+        };
+
+        let block = self.pop(current).unwrap();
+        // Return the upper halves until the block has the right size.
+        while current > order {
+            current -= 1;
+            self.push(block + (PAGE_SIZE << current), current);
+        }
+
+        self.allocated_bytes += PAGE_SIZE << order;
+        Some(block)
+    }
+
+    /// Free a block returned by [`Self::allocate`] with the same order.
+    pub fn deallocate(&mut self, block: usize, order: usize) {
+        self.allocated_bytes -= PAGE_SIZE << order;
+
+        let mut block = block;
+        let mut order = order;
+        // Merge with the buddy as long as it is free.
+        while order < MAX_ORDER {
+            let buddy = block ^ (PAGE_SIZE << order);
+            if !self.remove(buddy, order) {
+                break;
+            }
+            block = core::cmp::min(block, buddy);
+            order += 1;
+        }
+        self.push(block, order);
+    }
+
+    /// Take a block of the largest order from the regions.
+    fn grow(&mut self) -> bool {
+        for index in 0..self.regions.len() {
+            let region = &mut self.regions[index];
+            let region_start: usize = region.start_paddr().into();
+            let region_end = region_start + region.length();
+            let start = align::align_up(region_start, MAX_BLOCK_SIZE);
+            if start + MAX_BLOCK_SIZE > region_end {
+                continue;
+            }
+
+            if start + MAX_BLOCK_SIZE == region_end {
+                self.regions.swap_remove(index);
+            } else {
+                region.move_up((start + MAX_BLOCK_SIZE).into());
+            }
+
+            let block: u64 = crate::addr::PAddr::from(start).to_paddr_global().into();
+            self.push(block as usize, MAX_ORDER);
+            self.grown_bytes += MAX_BLOCK_SIZE;
+            return true;
+        }
+
+        false
+    }
+
+    fn push(&mut self, block: usize, order: usize) {
+        let block_ptr = block as *mut FreeBlock;
+        unsafe {
+            block_ptr.write(FreeBlock {
+                next: self.free_lists[order],
+            })
+        };
+        self.free_lists[order] = NonNull::new(block_ptr);
+    }
+
+    fn pop(&mut self, order: usize) -> Option<usize> {
+        let block = self.free_lists[order]?;
+        self.free_lists[order] = unsafe { block.as_ref().next };
+        Some(block.as_ptr() as usize)
+    }
+
+    /// Remove the block from the free list of the order if it is free.
+    fn remove(&mut self, block: usize, order: usize) -> bool {
+        let mut link = &mut self.free_lists[order];
+        while let Some(mut current) = *link {
+            if current.as_ptr() as usize == block {
+                *link = unsafe { current.as_ref().next };
+                return true;
+            }
+            link = unsafe { &mut current.as_mut().next };
+        }
+        false
+    }
+}
+
+impl Default for BuddyAllocator {
+    #[inline]
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,359 @@
+/*!
+Kernel heap.
+
+Small allocations are served by slab caches of power of two sizes, larger ones
+directly by a buddy allocator of pages. The slabs take their pages from the buddy
+allocator as well, which grows in blocks of [`buddy::MAX_BLOCK_SIZE`] into the
+memory regions that were reserved for the heap at boot.
+
+Code that can handle running out of memory, like syscalls, allocates with
+[`KernelHeap::try_allocate`] or [`try_reserve`] and gets an error instead of
+reaching the allocation error handler. [`try_reserve`] also grows the heap from
+the untyped memory of the caller once the boot regions are used up.
+
+Most syscalls do not use the heap at all. Tasks, cpools and scheduling contexts are
+retyped from the untyped memory of the caller and fail with
+[`CapabilityErrors::MemoryNotSufficient`] when it is used up, and the run queues and
+the tasks waiting for an exit are linked through the tasks themselves.
+*/
+
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    mem::size_of,
+    ptr::NonNull,
+};
+
+use heapless::Vec;
+use relic_abi::cap::CapabilityErrors;
+use spin::Mutex;
+
+use crate::{capability::UntypedMemory, util::memory_region::MemoryRegion};
+
+pub mod buddy;
+pub mod slab;
+
+use buddy::{BuddyAllocator, MAX_BLOCK_SIZE};
+use slab::{CacheStatistics, SlabCache};
+
+/// Object sizes of the slab caches. Larger allocations take whole blocks.
+pub const SLAB_SIZES: [usize; 8] = [16, 32, 64, 128, 256, 512, 1024, 2048];
+
+/// Memory reserved for the kernel heap at boot. The heap grows beyond it with [`grow_from`].
+pub const HEAP_RESERVE_SIZE: usize = 16 * 1024 * 1024;
+
+/// The kernel heap used by `alloc`.
+#[cfg_attr(not(test), global_allocator)]
+pub static KERNEL_HEAP: KernelHeap = KernelHeap::new();
+
+/// Statistics of the kernel heap.
+#[derive(Debug, Copy, Clone)]
+pub struct HeapStatistics {
+    /// Statistics of every slab cache, in the order of [`SLAB_SIZES`].
+    pub caches: [CacheStatistics; SLAB_SIZES.len()],
+    /// Bytes of memory the heap took from its regions.
+    pub grown_bytes: usize,
+    /// Bytes of the pages that are allocated, including slabs.
+    pub allocated_page_bytes: usize,
+}
+
+struct HeapInner {
+    buddy: BuddyAllocator,
+    caches: [SlabCache; SLAB_SIZES.len()],
+}
+
+pub struct KernelHeap {
+    inner: Mutex<HeapInner>,
+}
+
+/// Where an allocation of a layout is served from.
+enum SizeClass {
+    /// Index of the slab cache.
+    Slab(usize),
+    /// Order of the buddy block.
+    Block(usize),
+}
+
+impl SizeClass {
+    fn of(layout: Layout) -> Option<SizeClass> {
+        let size = core::cmp::max(layout.size(), layout.align());
+        match SLAB_SIZES.iter().position(|&slab_size| slab_size >= size) {
+            Some(index) => Some(SizeClass::Slab(index)),
+            None => BuddyAllocator::order_for(size).map(SizeClass::Block),
+        }
+    }
+}
+
+impl KernelHeap {
+    pub const fn new() -> Self {
+        Self {
+            inner: Mutex::new(HeapInner {
+                buddy: BuddyAllocator::new(),
+                caches: [
+                    SlabCache::new(SLAB_SIZES[0]),
+                    SlabCache::new(SLAB_SIZES[1]),
+                    SlabCache::new(SLAB_SIZES[2]),
+                    SlabCache::new(SLAB_SIZES[3]),
+                    SlabCache::new(SLAB_SIZES[4]),
+                    SlabCache::new(SLAB_SIZES[5]),
+                    SlabCache::new(SLAB_SIZES[6]),
+                    SlabCache::new(SLAB_SIZES[7]),
+                ],
+            }),
+        }
+    }
+
+    /// Add memory that the heap can grow into.
+    pub fn add_region(&self, region: MemoryRegion) -> Result<(), MemoryRegion> {
+        self.inner.lock().buddy.add_region(region)
+    }
+
+    /// Add a free block of [`MAX_BLOCK_SIZE`] bytes at `block`, aligned to its size.
+    pub fn add_block(&self, block: usize) {
+        self.inner.lock().buddy.add_block(block)
+    }
+
+    /// Allocate memory for `layout`. Fails with [`CapabilityErrors::MemoryNotSufficient`]
+    /// when the heap cannot grow any further.
+    pub fn try_allocate(&self, layout: Layout) -> Result<NonNull<u8>, CapabilityErrors> {
+        let mut inner = self.inner.lock();
+        let inner = &mut *inner;
+        let allocation = match SizeClass::of(layout) {
+            Some(SizeClass::Slab(index)) => inner.caches[index].allocate(&mut inner.buddy),
+            Some(SizeClass::Block(order)) => inner
+                .buddy
+                .allocate(order)
+                .and_then(|block| NonNull::new(block as *mut u8)),
+            None => None,
+        };
+        allocation.ok_or(CapabilityErrors::MemoryNotSufficient)
+    }
+
+    /// Free memory returned by [`Self::try_allocate`] for the same layout.
+    ///
+    /// # Safety
+    /// `ptr` must have been allocated by this heap with `layout` and must not be used anymore.
+    pub unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        let mut inner = self.inner.lock();
+        let inner = &mut *inner;
+        match SizeClass::of(layout) {
+            Some(SizeClass::Slab(index)) => inner.caches[index].deallocate(ptr, &mut inner.buddy),
+            Some(SizeClass::Block(order)) => inner.buddy.deallocate(ptr.as_ptr() as usize, order),
+            None => unreachable!("Allocations of {:?} always fail", layout),
+        }
+    }
+
+    pub fn statistics(&self) -> HeapStatistics {
+        let inner = self.inner.lock();
+        let mut caches = [CacheStatistics::default(); SLAB_SIZES.len()];
+        for (statistics, cache) in caches.iter_mut().zip(inner.caches.iter()) {
+            *statistics = cache.statistics();
+        }
+        HeapStatistics {
+            caches,
+            grown_bytes: inner.buddy.grown_bytes(),
+            allocated_page_bytes: inner.buddy.allocated_bytes(),
+        }
+    }
+}
+
+impl Default for KernelHeap {
+    #[inline]
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+unsafe impl GlobalAlloc for KernelHeap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        self.try_allocate(layout)
+            .map_or(core::ptr::null_mut(), NonNull::as_ptr)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.deallocate(NonNull::new_unchecked(ptr), layout)
+    }
+}
+
+/**
+Reserve [`HEAP_RESERVE_SIZE`] bytes of the boot memory regions for the kernel heap.
+Must be called before anything is allocated.
+*/
+pub fn initialize(free_regions: &mut Vec<MemoryRegion, 32>) {
+    for region in free_regions.iter_mut() {
+        // Leave some of the region, as a region cannot be allocated to its very end.
+        if region.length() <= HEAP_RESERVE_SIZE + MAX_BLOCK_SIZE {
+            continue;
+        }
+        if let Some(paddr) = region.try_allocate(HEAP_RESERVE_SIZE, MAX_BLOCK_SIZE) {
+            KERNEL_HEAP
+                .add_region(MemoryRegion::new(paddr, HEAP_RESERVE_SIZE))
+                .unwrap();
+            return;
+        }
+    }
+
+    panic!("Not enough memory for the kernel heap");
+}
+
+/**
+Grow the kernel heap by a block of [`MAX_BLOCK_SIZE`] bytes taken from `untyped`.
+The block stays with the heap, like the kernel objects retyped from `untyped`.
+*/
+pub fn grow_from(untyped: &mut UntypedMemory) -> Result<(), CapabilityErrors> {
+    if untyped.is_device_memory() {
+        return Err(CapabilityErrors::DeviceMemoryConflict);
+    }
+
+    let (paddr, _) = untyped.allocate(MAX_BLOCK_SIZE, MAX_BLOCK_SIZE)?;
+    let block: u64 = paddr.into();
+    KERNEL_HEAP.add_block(block as usize);
+    Ok(())
+}
+
+/**
+Reserve room for exactly `additional` more elements in `vec`. While the heap is out
+of memory, it grows from `untyped`. Fails with [`CapabilityErrors::MemoryNotSufficient`]
+instead of reaching the allocation error handler.
+*/
+pub fn try_reserve<T>(
+    vec: &mut alloc::vec::Vec<T>,
+    additional: usize,
+    untyped: &mut UntypedMemory,
+) -> Result<(), CapabilityErrors> {
+    while vec.try_reserve_exact(additional).is_err() {
+        // Growing the heap only helps allocations that fit into a block.
+        let fits = vec
+            .len()
+            .checked_add(additional)
+            .and_then(|length| length.checked_mul(size_of::<T>()))
+            .map_or(false, |size| size <= MAX_BLOCK_SIZE);
+        if !fits {
+            return Err(CapabilityErrors::MemoryNotSufficient);
+        }
+        grow_from(untyped)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{buddy::PAGE_SIZE, *};
+
+    /// Heap that grows into `blocks` blocks of memory.
+    fn test_heap(blocks: usize) -> KernelHeap {
+        let layout = Layout::from_size_align(blocks * MAX_BLOCK_SIZE, MAX_BLOCK_SIZE).unwrap();
+        let memory = unsafe { std::alloc::alloc(layout) };
+        assert!(!memory.is_null());
+        let heap = KernelHeap::new();
+        heap.add_region(MemoryRegion::new((memory as usize).into(), layout.size()))
+            .unwrap();
+        heap
+    }
+
+    #[test]
+    fn test_slab_allocations() {
+        let heap = test_heap(1);
+        let layout = Layout::from_size_align(24, 8).unwrap();
+
+        let objects: std::vec::Vec<_> = (0..200)
+            .map(|_| heap.try_allocate(layout).unwrap())
+            .collect();
+        for object in &objects {
+            assert_eq!(object.as_ptr() as usize % 32, 0);
+        }
+        let statistics = heap.statistics();
+        assert_eq!(statistics.caches[1].objects_in_use, 200);
+        assert_eq!(statistics.caches[1].slabs, 2);
+        assert_eq!(statistics.grown_bytes, MAX_BLOCK_SIZE);
+
+        for object in objects {
+            unsafe { heap.deallocate(object, layout) };
+        }
+        let statistics = heap.statistics();
+        assert_eq!(statistics.caches[1].objects_in_use, 0);
+        assert_eq!(statistics.caches[1].slabs, 0);
+        assert_eq!(statistics.allocated_page_bytes, 0);
+    }
+
+    #[test]
+    fn test_block_allocations() {
+        let heap = test_heap(2);
+        let layout = Layout::from_size_align(3 * PAGE_SIZE, PAGE_SIZE).unwrap();
+
+        let first = heap.try_allocate(layout).unwrap();
+        let second = heap.try_allocate(layout).unwrap();
+        assert_eq!(first.as_ptr() as usize % (4 * PAGE_SIZE), 0);
+        assert_eq!(heap.statistics().allocated_page_bytes, 8 * PAGE_SIZE);
+
+        // The freed blocks merge back, so the whole memory can be taken in the largest blocks.
+        unsafe {
+            heap.deallocate(first, layout);
+            heap.deallocate(second, layout);
+        }
+        let largest = Layout::from_size_align(MAX_BLOCK_SIZE, PAGE_SIZE).unwrap();
+        let blocks = [
+            heap.try_allocate(largest).unwrap(),
+            heap.try_allocate(largest).unwrap(),
+        ];
+        assert_matches!(
+            heap.try_allocate(largest),
+            Err(CapabilityErrors::MemoryNotSufficient)
+        );
+        assert_eq!(heap.statistics().grown_bytes, 2 * MAX_BLOCK_SIZE);
+        for block in blocks.iter() {
+            unsafe { heap.deallocate(*block, largest) };
+        }
+
+        let too_large = Layout::from_size_align(2 * MAX_BLOCK_SIZE, PAGE_SIZE).unwrap();
+        assert!(heap.try_allocate(too_large).is_err());
+        assert_eq!(heap.statistics().caches[0].failures, 0);
+    }
+
+    #[test]
+    fn test_add_block() {
+        let heap = KernelHeap::new();
+        let layout = Layout::from_size_align(64, 64).unwrap();
+        assert_matches!(
+            heap.try_allocate(layout),
+            Err(CapabilityErrors::MemoryNotSufficient)
+        );
+
+        let block = Layout::from_size_align(MAX_BLOCK_SIZE, MAX_BLOCK_SIZE).unwrap();
+        let memory = unsafe { std::alloc::alloc(block) };
+        assert!(!memory.is_null());
+        heap.add_block(memory as usize);
+
+        let object = heap.try_allocate(layout).unwrap();
+        assert!((memory as usize..memory as usize + MAX_BLOCK_SIZE)
+            .contains(&(object.as_ptr() as usize)));
+        assert_eq!(heap.statistics().grown_bytes, MAX_BLOCK_SIZE);
+    }
+
+    #[test]
+    fn test_fill_heap() {
+        let heap = test_heap(1);
+        let layout = Layout::from_size_align(SLAB_SIZES[7], 8).unwrap();
+
+        let mut objects = std::vec::Vec::new();
+        let error = loop {
+            match heap.try_allocate(layout) {
+                Ok(object) => objects.push(object),
+                Err(error) => break error,
+            }
+        };
+        assert_matches!(error, CapabilityErrors::MemoryNotSufficient);
+        let statistics = heap.statistics();
+        assert_eq!(statistics.caches[7].failures, 1);
+        assert_eq!(statistics.caches[7].objects_in_use, objects.len());
+        assert_eq!(statistics.allocated_page_bytes, MAX_BLOCK_SIZE);
+        assert_matches!(
+            heap.try_allocate(Layout::from_size_align(PAGE_SIZE, PAGE_SIZE).unwrap()),
+            Err(CapabilityErrors::MemoryNotSufficient)
+        );
+
+        // Freed objects are handed out again.
+        let object = objects.pop().unwrap();
+        unsafe { heap.deallocate(object, layout) };
+        assert_eq!(heap.try_allocate(layout).unwrap(), object);
+    }
+}
@@ -0,0 +1,158 @@
+//! Slab caches for the small allocations of the kernel heap.
+
+use core::{mem::size_of, ptr::NonNull};
+
+use relic_utils::align;
+
+use super::buddy::{BuddyAllocator, PAGE_SIZE};
+
+/// A slab is a single page that starts with this header, followed by the objects.
+struct SlabHeader {
+    /// Next slab of the cache that has free objects.
+    next: Option<NonNull<SlabHeader>>,
+    /// First free object of the slab.
+    free: Option<NonNull<FreeObject>>,
+    /// Number of objects of the slab that are allocated.
+    in_use: usize,
+}
+
+/// A free object. It is stored in the object itself.
+struct FreeObject {
+    next: Option<NonNull<FreeObject>>,
+}
+
+/// Statistics of a slab cache.
+#[derive(Debug, Copy, Clone, Default)]
+pub struct CacheStatistics {
+    /// Size of the objects of the cache.
+    pub object_size: usize,
+    /// Number of pages used by the cache.
+    pub slabs: usize,
+    /// Number of objects that are allocated.
+    pub objects_in_use: usize,
+    /// Number of allocations since boot.
+    pub allocations: u64,
+    /// Number of allocations that failed as the heap could not grow.
+    pub failures: u64,
+}
+
+/// Cache of objects of a single size. Slabs that have free objects are linked
+/// together, full slabs are only found through their objects.
+pub struct SlabCache {
+    partial: Option<NonNull<SlabHeader>>,
+    statistics: CacheStatistics,
+}
+
+// The slabs are only accessed with the cache.
+unsafe impl Send for SlabCache {}
+
+impl SlabCache {
+    /// Create a cache for objects of `object_size` bytes, which must be a power of two.
+    pub const fn new(object_size: usize) -> Self {
+        Self {
+            partial: None,
+            statistics: CacheStatistics {
+                object_size,
+                slabs: 0,
+                objects_in_use: 0,
+                allocations: 0,
+                failures: 0,
+            },
+        }
+    }
+
+    pub fn statistics(&self) -> CacheStatistics {
+        self.statistics
+    }
+
+    /// Allocate an object. Its alignment is the object size.
+    pub fn allocate(&mut self, buddy: &mut BuddyAllocator) -> Option<NonNull<u8>> {
+        let mut slab = match self.partial {
+            Some(slab) => slab,
+            None => match buddy.allocate(0) {
+                Some(page) => self.add_slab(page),
+                None => {
+                    self.statistics.failures += 1;
+                    return None;
+                }
+            },
+        };
+
+        let header = unsafe { slab.as_mut() };
+        let object = header
+            .free
+            .expect("Slabs in the partial list have free objects");
+        header.free = unsafe { object.as_ref().next };
+        header.in_use += 1;
+        if header.free.is_none() {
+            // The slab is the first partial one.
+            self.partial = header.next.take();
+        }
+
+        self.statistics.objects_in_use += 1;
+        self.statistics.allocations += 1;
+        Some(object.cast())
+    }
+
+    /// Free an object returned by [`Self::allocate`] of this cache. Empty slabs
+    /// are returned to `buddy`.
+    pub fn deallocate(&mut self, object: NonNull<u8>, buddy: &mut BuddyAllocator) {
+        let page = object.as_ptr() as usize & !(PAGE_SIZE - 1);
+        let mut slab = NonNull::new(page as *mut SlabHeader).unwrap();
+        let header = unsafe { slab.as_mut() };
+
+        let was_full = header.free.is_none();
+        let object = object.cast::<FreeObject>();
+        unsafe { object.as_ptr().write(FreeObject { next: header.free }) };
+        header.free = Some(object);
+        header.in_use -= 1;
+        self.statistics.objects_in_use -= 1;
+
+        if header.in_use == 0 {
+            if !was_full {
+                self.remove_partial(slab);
+            }
+            buddy.deallocate(page, 0);
+            self.statistics.slabs -= 1;
+        } else if was_full {
+            header.next = self.partial;
+            self.partial = Some(slab);
+        }
+    }
+
+    /// Turn the page into a slab with every object free and add it to the partial list.
+    fn add_slab(&mut self, page: usize) -> NonNull<SlabHeader> {
+        let object_size = self.statistics.object_size;
+        let first_object = align::align_up(size_of::<SlabHeader>(), object_size);
+
+        let mut free = None;
+        for offset in (first_object..PAGE_SIZE).step_by(object_size).rev() {
+            let object = (page + offset) as *mut FreeObject;
+            unsafe { object.write(FreeObject { next: free }) };
+            free = NonNull::new(object);
+        }
+
+        let slab = page as *mut SlabHeader;
+        unsafe {
+            slab.write(SlabHeader {
+                next: self.partial,
+                free,
+                in_use: 0,
+            })
+        };
+        self.partial = NonNull::new(slab);
+        self.statistics.slabs += 1;
+        self.partial.unwrap()
+    }
+
+    fn remove_partial(&mut self, slab: NonNull<SlabHeader>) {
+        let mut link = &mut self.partial;
+        while let Some(mut current) = *link {
+            if current == slab {
+                *link = unsafe { current.as_ref().next };
+                return;
+            }
+            link = unsafe { &mut current.as_mut().next };
+        }
+    }
+}
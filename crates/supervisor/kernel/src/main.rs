@@ -12,12 +12,14 @@
 #![feature(const_fn)]
 #![feature(const_raw_ptr_to_usize_cast)]
 #![feature(dispatch_from_dyn)]
+#![feature(global_asm)]
 #![feature(maybe_uninit_extra)]
 #![feature(naked_functions)]
 #![feature(option_get_or_insert_default)]
 #![feature(result_flattening)]
 #![feature(thread_local)]
 #![feature(trace_macros)]
+#![feature(try_reserve)]
 #![feature(type_ascription)]
 #![feature(unsize)]
 
@@ -25,17 +27,22 @@ use crate::{
     addr::{PAddrGlobal, VAddr},
     arch::globals::{self, BASE_PAGE_LENGTH},
     capability::{
-        CapAccessorMut, Capability, CapabilityEnum, Cpool, CpoolInner, MapPermissions, Scheduler,
-        StoredCap, UntypedMemory,
+        CapAccessorMut, Capability, CapabilityEnum, Cpool, CpoolInner, IoPortRange, MapPermissions,
+        Scheduler, StoredCap, UntypedMemory,
     },
     logging::UnifiedLogger,
-    ramdisk::{elf_loader::DefaultElfLoader, ustar::UStarArchive},
+    ramdisk::{
+        elf_loader::{DefaultElfLoader, ElfLoadError},
+        initrd::Initrd,
+    },
     relic_utils::align,
     util::{boxed::Boxed, memory_region::MemoryRegion},
 };
-use elfloader::ElfBinary;
 use heapless::Vec;
-use relic_abi::{bootstrap::BootstrapInfo, syscall::TaskBuffer};
+use relic_abi::{
+    bootstrap::{BootstrapInfo, DeviceMemoryInfo},
+    syscall::TaskBuffer,
+};
 use std::{cell::RefCell, panic::PanicInfo};
 
 extern crate alloc;
@@ -81,6 +88,12 @@ pub mod util;
 
 pub mod capability;
 
+/// Kernel heap.
+pub mod heap;
+
+/// GDB remote serial protocol stub.
+pub mod gdb;
+
 /// Logic to process syscalls.
 pub mod syscall_processor;
 
@@ -115,6 +128,7 @@ fn _panic_handler(info: &PanicInfo) -> ! {
 
 #[cfg_attr(target_os = "none", alloc_error_handler)]
 fn _alloc_error_handler(layout: alloc::alloc::Layout) -> ! {
+    error!("Kernel heap: {:?}", heap::KERNEL_HEAP.statistics());
     panic!("allocation error: {:?}", layout)
 }
 
@@ -123,9 +137,14 @@ fn _alloc_error_handler(layout: alloc::alloc::Layout) -> ! {
 pub fn main_bsp(free_regions: Vec<MemoryRegion, 32>) -> ! {
     info!(target: "main", "Free regions found: {:?}", free_regions);
 
+    if globals::GDB_WAIT_ON_BOOT {
+        gdb::breakpoint();
+    }
+
     let mut bootstrap_info = BootstrapInfo {
         top_level_pml4: 0.into(),
         free_mem_regions: (0.into(), 0.into()),
+        virtual_address_bits: arch::paging::la57::address_bits(),
         ..Default::default()
     };
     const NONE_INNER: RefCell<Capability> = RefCell::new(Capability::new());
@@ -162,6 +181,40 @@ pub fn main_bsp(free_regions: Vec<MemoryRegion, 32>) -> ! {
     }
     bootstrap_info.free_mem_regions.1 = (free_regions.len() as u8 - 1).into();
 
+    // Device memory follows the free regions in the root cpool.
+    let device_regions = arch::device_memory::take();
+    info!(target: "main", "Device memory regions found: {:?}", device_regions.regions());
+    for (offset, (region, info)) in device_regions
+        .regions()
+        .iter()
+        .zip(bootstrap_info.device_memory.iter_mut())
+        .enumerate()
+    {
+        let index = free_regions.len() + offset;
+        let untyped = unsafe {
+            UntypedMemory::bootstrap(
+                region.start_paddr().to_paddr_global(),
+                region.length(),
+                true,
+            )
+        };
+        root_cpool
+            .write_to_if_empty(index, untyped)
+            .expect("Failed to create device memory capabilities.");
+        *info = DeviceMemoryInfo {
+            caddr: (index as u8).into(),
+            paddr: region.start_paddr().into(),
+            length: region.length() as u64,
+        };
+    }
+    bootstrap_info.device_memory_count = device_regions.regions().len();
+
+    let io_ports_index = free_regions.len() + device_regions.regions().len();
+    root_cpool
+        .write_to_if_empty(io_ports_index, unsafe { IoPortRange::bootstrap() })
+        .expect("Failed to create the I/O port capability.");
+    bootstrap_info.io_ports = (io_ports_index as u8).into();
+
     let cpool_cap = Capability {
         capability_data: CapabilityEnum::Cpool(root_cpool),
         ..Default::default()
@@ -172,63 +225,54 @@ pub fn main_bsp(free_regions: Vec<MemoryRegion, 32>) -> ! {
 
     let root_cpool = root_cpool_stored.as_cpool_mut().unwrap();
     let untyped = root_cpool.lookup((largest_index as u8).into()).unwrap();
-    let (task_cap, _pml4, _root_cpool) = load_sigma(
+    let scheduler = Scheduler::new();
+    match load_sigma(
         root_cpool,
         untyped.as_untyped_memory_mut().unwrap(),
         bootstrap_info,
-    );
-
-    let scheduler = Scheduler::new();
-    scheduler.add_task_with_priority(&mut task_cap.as_task_mut().unwrap());
+    ) {
+        Ok((task_cap, _top_level_table, _root_cpool)) => {
+            scheduler.add_task_with_priority(&mut task_cap.as_task_mut().unwrap())
+        }
+        Err(error) => error!(target: "main", "Failed to load sigma: {:?}", error),
+    }
     scheduler.run_forever()
 }
 
-// Return task, pml4
+// Return task, top level table
 fn load_sigma(
-    cpool_cap: CapAccessorMut<'_, Cpool>,
-    untyped_cap: CapAccessorMut<'_, UntypedMemory>,
+    mut cpool_cap: CapAccessorMut<'_, Cpool>,
+    mut untyped_cap: CapAccessorMut<'_, UntypedMemory>,
     mut bootstrap_info: BootstrapInfo,
-) -> (StoredCap, StoredCap, StoredCap) {
-    let ramdisk: UStarArchive;
-    let binary = {
-        unsafe {
-            let initrd_ptr =
-                (bootboot::bootboot.initrd_ptr + globals::MEM_MAP_OFFSET_LOCATION) as *const u8;
-            ramdisk = UStarArchive::new(initrd_ptr, bootboot::bootboot.initrd_size as usize);
-            info!(target: "load_sigma", "Initrd image is {}", ramdisk);
-
-            let file_name = "./userspace/relic-sigma";
-            let file = ramdisk.lookup(file_name).expect("Sigma file not found");
-            let binary = ElfBinary::new("relic-sigma", file).expect("Cannot read the binary");
-            binary
-        }
-    };
+) -> Result<(StoredCap, StoredCap, StoredCap), ElfLoadError> {
+    let initrd = Initrd::new_from_bootboot().decompress(&mut untyped_cap, &mut cpool_cap)?;
+    let ramdisk = initrd.archive();
+    info!(target: "load_sigma", "Initrd image is {}", ramdisk);
+
+    let file_name = "./userspace/relic-sigma";
+    let file = ramdisk.lookup(file_name).ok_or(ElfLoadError::NotFound)?;
     let mut loader =
-        DefaultElfLoader::new(VAddr::new(0), cpool_cap, &mut bootstrap_info, untyped_cap);
-    binary.load(&mut loader).expect("Binary loading failed");
+        DefaultElfLoader::new(VAddr::new(0), cpool_cap, &mut bootstrap_info, untyped_cap)?;
+    let binary = loader.load_binary("relic-sigma", file)?;
     let loc: u64 = loader.exe_section_location().into();
     info!(target: "load_sigma",
             "Sigma project loaded. Use comand `add-symbol-file ../../x86_64-relic-user/debug/relic-sigma  0x{:x}`",
             loc);
-    let (mut untyped_cap, mut cpool_cap, pml4_cap_stored, tls_info) = loader.unwrap();
+    let (mut untyped_cap, mut cpool_cap, top_level_table, tls_info) = loader.unwrap();
     bootstrap_info.tls_info = tls_info;
 
-    let mut pml4_cap = pml4_cap_stored.as_l4_mut().unwrap();
-    let pml4 = pml4_cap.cap().clone();
-
     info!(target: "load_sigma", "Loading kernel stack");
-    let user_stack_start: u64 = 0x6FFF_000_0000;
-    let num_pages = 10usize;
+    let user_stack_start: u64 = globals::SIGMA_STACK_START;
+    let num_pages = globals::SIGMA_STACK_PAGES;
     for page_index in 0..num_pages {
         DefaultElfLoader::map_empty_page(
-            &mut pml4_cap,
+            &top_level_table,
             &mut untyped_cap,
             None,
             &mut cpool_cap,
             VAddr::new(user_stack_start + (BASE_PAGE_LENGTH * page_index) as u64),
             MapPermissions::WRITE,
-        )
-        .unwrap();
+        )?;
     }
     let user_stack_end: VAddr = align::align_down(
         user_stack_start + num_pages as u64 * BASE_PAGE_LENGTH as u64 - 1,
@@ -243,27 +287,33 @@ fn load_sigma(
         bootstrap.bootstrap_and_map(
             &mut untyped_cap,
             &mut cpool_cap,
-            &mut pml4_cap,
+            &top_level_table,
             &mut bootstrap_info,
         );
     }
 
+    info!(target: "load_sigma", "Map initrd");
+    initrd.bootstrap_and_map(
+        &mut untyped_cap,
+        &mut cpool_cap,
+        &top_level_table,
+        &mut bootstrap_info,
+    )?;
+
     info!(target: "load_sigma", "Loading TaskBuffers");
     let buffer_start: u64 = arch::globals::SIGMA_BUFFER_START;
     let (buffer_cap, ind) =
-        StoredCap::base_page_retype_from::<TaskBuffer>(&mut untyped_cap, &mut cpool_cap, true)
-            .unwrap();
+        StoredCap::base_page_retype_from::<TaskBuffer>(&mut untyped_cap, &mut cpool_cap, true)?;
     info!(target: "load_sigma", "TaskBufferCap is stored at index {}", ind);
-    pml4_cap
-        .l4_map(
+    top_level_table
+        .address_space_map(
             buffer_start.into(),
             &buffer_cap,
             &mut untyped_cap,
             &mut cpool_cap,
             None,
             MapPermissions::WRITE,
-        )
-        .unwrap();
+        )?;
 
     let mut buffer = buffer_cap.as_base_page_mut().unwrap();
     buffer.page_data_mut::<TaskBuffer>().self_address = buffer_start;
@@ -275,23 +325,24 @@ fn load_sigma(
         .unwrap();
 
     let (task_cap, _task_cap_index) =
-        StoredCap::task_retype_from(&mut untyped_cap, &mut cpool_cap, 15).unwrap();
+        StoredCap::task_retype_from(&mut untyped_cap, &mut cpool_cap, 15)?;
     let mut task_cap_write = task_cap.as_task_mut().unwrap();
 
     task_cap_write.set_instruction_pointer(binary.entry_point().into());
     task_cap_write.set_stack_pointer(user_stack_end);
+    let guard_size = (globals::USER_STACK_GUARD_PAGES * BASE_PAGE_LENGTH) as u64;
+    task_cap_write
+        .set_stack_guard(Some((user_stack_start - guard_size).into()..user_stack_start.into()));
     task_cap_write.set_status(capability::TaskStatus::Inactive);
 
     task_cap_write.set_tcb_location(buffer_start.into());
 
-    task_cap_write.task_set_cpool(&mut cpool_cap).unwrap();
-    task_cap_write
-        .task_set_top_level_table(&mut pml4_cap)
-        .unwrap();
-    task_cap_write.task_set_task_buffer(&mut buffer).unwrap();
+    task_cap_write.task_set_cpool(&mut cpool_cap)?;
+    task_cap_write.task_set_top_level_table(&top_level_table)?;
+    task_cap_write.task_set_task_buffer(&mut buffer)?;
 
     info!(target: "load_sigma", "Sigma task Cap: {:?}", task_cap);
 
     core::mem::drop(task_cap_write);
-    (task_cap, pml4, cpool_cap.cap().clone())
+    Ok((task_cap, top_level_table, cpool_cap.cap().clone()))
 }
@@ -1,18 +1,94 @@
 //! The default ELF loader for the kernel.
 
-use elfloader::{ElfLoader, Flags, LoadableHeaders, Rela, TypeRela64, P64};
+use alloc::vec::Vec;
+use core::ops::Range;
+
+use elfloader::{ElfBinary, ElfLoader, Flags, LoadableHeaders, Rela, TypeRela64, P64};
 use relic_abi::{
     bootstrap::{BootstrapInfo, TlsInfo},
     cap::CapabilityErrors,
 };
-use relic_utils::align;
+use relic_utils::{
+    align,
+    decompress::DecompressError,
+    elf::{self, RelocationError},
+};
 
 use crate::{
     addr::VAddr,
-    arch::{capability::paging::L4, globals},
+    arch::{globals, user_memory},
     capability::*,
+    heap,
 };
 
+/// Reasons why a binary cannot be loaded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ElfLoadError {
+    /// The binary is not in the initrd.
+    NotFound,
+    /// The binary is malformed. Holds the message of the parser.
+    InvalidBinary(&'static str),
+    /// A segment is not in the user half of the address space.
+    SegmentOutOfBounds,
+    /// Data or a relocation is outside of the mapped segments.
+    WriteOutOfBounds,
+    /// The relocation type with the given number is not supported.
+    UnsupportedRelocation(u32),
+    /// A relocation refers to the undefined symbol with the given index.
+    UndefinedSymbol(u32),
+    /// A TLS relocation is used without a TLS segment.
+    MissingTlsSegment,
+    /// The binary has more than one TLS segment.
+    MultipleTlsSegments,
+    /// Creating or mapping the memory of the binary failed.
+    Capability(CapabilityErrors),
+    /// The compressed initrd cannot be decompressed.
+    InvalidInitrd(DecompressError),
+}
+
+impl ElfLoadError {
+    /// Message for the `elfloader` crate, which only passes strings through.
+    fn message(self) -> &'static str {
+        match self {
+            ElfLoadError::NotFound => "Binary not found",
+            ElfLoadError::InvalidBinary(message) => message,
+            ElfLoadError::SegmentOutOfBounds => "Segment is not in the user half",
+            ElfLoadError::WriteOutOfBounds => "Write is outside of the mapped segments",
+            ElfLoadError::UnsupportedRelocation(_) => "Unsupported relocation",
+            ElfLoadError::UndefinedSymbol(_) => "Relocation against an undefined symbol",
+            ElfLoadError::MissingTlsSegment => "TLS relocation without a TLS segment",
+            ElfLoadError::MultipleTlsSegments => "Multiple TLS segments",
+            ElfLoadError::Capability(_) => "Failed to map the binary",
+            ElfLoadError::InvalidInitrd(_) => "Failed to decompress the initrd",
+        }
+    }
+}
+
+impl From<CapabilityErrors> for ElfLoadError {
+    fn from(error: CapabilityErrors) -> Self {
+        ElfLoadError::Capability(error)
+    }
+}
+
+impl From<DecompressError> for ElfLoadError {
+    fn from(error: DecompressError) -> Self {
+        ElfLoadError::InvalidInitrd(error)
+    }
+}
+
+impl From<RelocationError> for ElfLoadError {
+    fn from(error: RelocationError) -> Self {
+        match error {
+            RelocationError::Unsupported(kind) => ElfLoadError::UnsupportedRelocation(kind),
+            RelocationError::InvalidSymbol(_) => {
+                ElfLoadError::InvalidBinary("Symbol index is out of bounds")
+            }
+            RelocationError::UndefinedSymbol(index) => ElfLoadError::UndefinedSymbol(index),
+            RelocationError::MissingTls => ElfLoadError::MissingTlsSegment,
+        }
+    }
+}
+
 /// Default ELF loader class for the sigma space.
 #[derive(CopyGetters, Getters, MutGetters)]
 pub struct DefaultElfLoader<'a> {
@@ -23,7 +99,7 @@ pub struct DefaultElfLoader<'a> {
     untyped: CapAccessorMut<'a, UntypedMemory>,
 
     /// Get the root page table capability.
-    pml4: StoredCap,
+    top_level_table: StoredCap,
 
     current_user_data_pool: StoredCap,
 
@@ -32,6 +108,15 @@ pub struct DefaultElfLoader<'a> {
     exe_section_location: VAddr,
 
     tls_info: TlsInfo,
+
+    /// Page aligned ranges mapped by `allocate`, sorted and merged when adjacent.
+    allocated: Vec<Range<usize>>,
+
+    /// Dynamic symbol table of the binary.
+    dynamic_symbols: Vec<u8>,
+
+    /// The first error of the current load, which is reported by `load_binary`.
+    error: Option<ElfLoadError>,
 }
 
 impl<'a> DefaultElfLoader<'a> {
@@ -43,7 +128,12 @@ impl<'a> DefaultElfLoader<'a> {
         StoredCap,
         TlsInfo,
     ) {
-        (self.untyped, self.cpool, self.pml4, self.tls_info)
+        (
+            self.untyped,
+            self.cpool,
+            self.top_level_table,
+            self.tls_info,
+        )
     }
 
     pub fn new(
@@ -51,25 +141,235 @@ impl<'a> DefaultElfLoader<'a> {
         mut cpool: CapAccessorMut<'a, Cpool>,
         bootstrap_info: &mut BootstrapInfo,
         mut untyped: CapAccessorMut<'a, UntypedMemory>,
-    ) -> DefaultElfLoader<'a> {
-        let pml4 = StoredCap::pml4_retype_from(&mut untyped, &mut cpool).unwrap();
-        bootstrap_info.top_level_pml4 = (pml4.1 as u8).into();
+    ) -> Result<DefaultElfLoader<'a>, ElfLoadError> {
+        let top_level_table = StoredCap::address_space_retype_from(&mut untyped, &mut cpool)?;
+        bootstrap_info.top_level_pml4 = (top_level_table.1 as u8).into();
 
-        let user_data_pool = StoredCap::cpool_retype_from(&mut untyped, &mut cpool).unwrap();
+        let user_data_pool = StoredCap::cpool_retype_from(&mut untyped, &mut cpool)?;
 
-        DefaultElfLoader {
+        Ok(DefaultElfLoader {
             vbase,
             exe_section_location: 1u64.into(),
             cpool,
             untyped,
-            pml4: pml4.0,
+            top_level_table: top_level_table.0,
             tls_info: Default::default(),
             current_user_data_pool: user_data_pool.0,
+            allocated: Vec::new(),
+            dynamic_symbols: Vec::new(),
+            error: None,
+        })
+    }
+
+    /// Load the ELF binary in `data` into the address space. Returns the parsed
+    /// binary, which holds the entry point.
+    pub fn load_binary<'b>(
+        &mut self,
+        name: &'b str,
+        data: &'b [u8],
+    ) -> Result<ElfBinary<'b>, ElfLoadError> {
+        let binary = ElfBinary::new(name, data).map_err(ElfLoadError::InvalidBinary)?;
+        let dynamic_symbols =
+            elf::dynamic_symbol_table(data).map_err(ElfLoadError::InvalidBinary)?;
+        self.dynamic_symbols.clear();
+        heap::try_reserve(
+            &mut self.dynamic_symbols,
+            dynamic_symbols.len(),
+            &mut self.untyped,
+        )?;
+        self.dynamic_symbols.extend_from_slice(dynamic_symbols);
+        self.error = None;
+
+        binary.load(self).map_err(|message| {
+            self.error
+                .take()
+                .unwrap_or(ElfLoadError::InvalidBinary(message))
+        })?;
+        Ok(binary)
+    }
+
+    /// Remember `error` for `load_binary` and return its message for the
+    /// `elfloader` crate.
+    fn fail(&mut self, error: ElfLoadError) -> &'static str {
+        self.error.get_or_insert(error);
+        error.message()
+    }
+
+    /// Value that a relocation of type `kind` writes. The TLS block ends at the thread
+    /// pointer, like in the layout of `relic_std::tls`.
+    fn relocation_value(
+        &self,
+        kind: u32,
+        symbol_index: u32,
+        addend: u64,
+    ) -> Result<u64, ElfLoadError> {
+        let tls_block_size = if self.tls_info.tls_loaded {
+            Some(align::align_up(
+                self.tls_info.total_size,
+                core::cmp::max(self.tls_info.tls_align, 1),
+            ))
+        } else {
+            None
+        };
+
+        Ok(elf::relocation_value(
+            kind,
+            symbol_index,
+            addend,
+            &self.dynamic_symbols,
+            self.vbase.into(),
+            tls_block_size,
+        )?)
+    }
+
+    /// Map a zeroed page of a segment at `vaddr`. A new user data pool is created
+    /// when the current one is full.
+    fn map_segment_page(
+        &mut self,
+        vaddr: VAddr,
+        permissions: MapPermissions,
+    ) -> Result<(), CapabilityErrors> {
+        let r = Self::map_empty_page(
+            &self.top_level_table,
+            &mut self.untyped,
+            Some(&mut self.cpool),
+            &mut self.current_user_data_pool.as_cpool_mut()?,
+            vaddr,
+            permissions,
+        );
+        if r != Err(CapabilityErrors::CapabilitySlotsFull) {
+            return r;
+        }
+
+        let user_data_pool = StoredCap::cpool_retype_from(&mut self.untyped, &mut self.cpool)?;
+        self.current_user_data_pool = user_data_pool.0;
+        Self::map_empty_page(
+            &self.top_level_table,
+            &mut self.untyped,
+            Some(&mut self.cpool),
+            &mut self.current_user_data_pool.as_cpool_mut()?,
+            vaddr,
+            permissions,
+        )
+    }
+
+    /// Whether `range` lies in the segments mapped by `allocate`.
+    fn is_allocated(&self, range: Range<usize>) -> bool {
+        range.start == range.end
+            || self
+                .allocated
+                .iter()
+                .any(|allocated| allocated.start <= range.start && range.end <= allocated.end)
+    }
+
+    /// Address `offset` bytes after the base of the binary.
+    fn vaddr_at(&self, offset: u64) -> Result<VAddr, ElfLoadError> {
+        let vbase: u64 = self.vbase.into();
+        vbase
+            .checked_add(offset)
+            .map(VAddr::from)
+            .ok_or(ElfLoadError::SegmentOutOfBounds)
+    }
+
+    /// Page aligned range of a segment of `mem_size` bytes at `virtual_addr`. Fails if
+    /// the segment is not in the user half.
+    fn segment_range(
+        &self,
+        virtual_addr: u64,
+        mem_size: u64,
+    ) -> Result<Range<usize>, ElfLoadError> {
+        let start: usize = self.vaddr_at(virtual_addr)?.into();
+        let end = start
+            .checked_add(mem_size as usize)
+            .filter(|end| {
+                VAddr::from(end.saturating_sub(1))
+                    .validate_user_mode()
+                    .is_ok()
+            })
+            .ok_or(ElfLoadError::SegmentOutOfBounds)?;
+
+        Ok(align::align_down(start, globals::BASE_PAGE_LENGTH)
+            ..align::align_up(end, globals::BASE_PAGE_LENGTH))
+    }
+
+    /// Map the pages of the page aligned segments. Segments may share pages, like the
+    /// last page of the code and the first page of the data. They are split at every
+    /// boundary, so that each page is mapped once with the permissions of all segments
+    /// that contain it.
+    fn map_segments(
+        &mut self,
+        segments: &[(Range<usize>, MapPermissions)],
+    ) -> Result<(), ElfLoadError> {
+        let mut boundaries = Vec::new();
+        heap::try_reserve(&mut boundaries, segments.len() * 2, &mut self.untyped)?;
+        for (range, _) in segments {
+            boundaries.push(range.start);
+            boundaries.push(range.end);
         }
+        boundaries.sort_unstable();
+        boundaries.dedup();
+
+        for window in boundaries.windows(2) {
+            let range = window[0]..window[1];
+            let mut covered = false;
+            let mut permissions = MapPermissions::empty();
+            for (segment, segment_permissions) in segments {
+                if segment.start <= range.start && range.end <= segment.end {
+                    covered = true;
+                    permissions |= *segment_permissions;
+                }
+            }
+            if !covered {
+                continue;
+            }
+
+            for page in range.clone().step_by(globals::BASE_PAGE_LENGTH) {
+                self.map_segment_page(page.into(), permissions)?;
+            }
+
+            info!(
+                target: "elf",
+                "allocate done. Start: {:#x}, End: {:#x}, Permissions: {:?}",
+                range.start,
+                range.end,
+                permissions,
+            );
+
+            match self.allocated.last_mut() {
+                Some(last) if last.end == range.start => last.end = range.end,
+                _ => {
+                    heap::try_reserve(&mut self.allocated, 1, &mut self.untyped)?;
+                    self.allocated.push(range)
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Copy `data` to `offset` bytes after the base of the binary, which must be in
+    /// the mapped segments. Returns the address it was copied to.
+    fn write_at(&mut self, offset: u64, data: &[u8]) -> Result<VAddr, ElfLoadError> {
+        let start = self
+            .vaddr_at(offset)
+            .map_err(|_| ElfLoadError::WriteOutOfBounds)?;
+        let start_usize: usize = start.into();
+        let in_segments = start_usize
+            .checked_add(data.len())
+            .map_or(false, |end| self.is_allocated(start_usize..end));
+        if !in_segments {
+            return Err(ElfLoadError::WriteOutOfBounds);
+        }
+
+        // The data is loaded into the target address space rather than the current one.
+        let root = self.top_level_table.address_space_paddr()?;
+        user_memory::load_user_bytes(root, start, data)
+            .map_err(|_| ElfLoadError::WriteOutOfBounds)?;
+        Ok(start)
     }
 
     pub fn map_empty_page(
-        pml4: &mut CapAccessorMut<'_, L4>,
+        top_level_table: &StoredCap,
         untyped: &mut CapAccessorMut<'_, UntypedMemory>,
         search_cpool: Option<&mut CapAccessorMut<'_, Cpool>>,
         store_cpool: &mut CapAccessorMut<'_, Cpool>,
@@ -77,7 +377,7 @@ impl<'a> DefaultElfLoader<'a> {
         permissions: MapPermissions,
     ) -> Result<(), CapabilityErrors> {
         let page_cap = StoredCap::base_page_retype_from::<[u8; 4096]>(untyped, store_cpool, true)?;
-        pml4.l4_map(
+        top_level_table.address_space_map(
             page_start_addr,
             &page_cap.0,
             untyped,
@@ -104,21 +404,23 @@ impl<'a> DefaultElfLoader<'a> {
 /// then `load` will be called to fill the allocated regions, and finally
 /// `relocate` is called for every entry in the RELA table.
 impl<'a> ElfLoader for DefaultElfLoader<'a> {
-    /// Allocates a virtual region of `size` bytes at address `base`.
+    /// Allocates the virtual regions of all LOAD program headers.
     fn allocate(&mut self, load_headers: LoadableHeaders) -> Result<(), &'static str> {
+        let mut segments = Vec::new();
         for header in load_headers {
             info!(
                 target:"elf",
                 "allocate base = {:#x}, end = {:#x} size = {:#x} flags = {}",
                 header.virtual_addr(),
-                header.virtual_addr() + header.mem_size(),
+                header.virtual_addr().wrapping_add(header.mem_size()),
                 header.mem_size(),
                 header.flags()
             );
 
-            let virt_addr_to_load_at = header.virtual_addr() as usize;
-            let virt_addr_to_load_at_page_aligned =
-                align::align_down(virt_addr_to_load_at, globals::BASE_PAGE_LENGTH);
+            let range = match self.segment_range(header.virtual_addr(), header.mem_size()) {
+                Ok(range) => range,
+                Err(error) => return Err(self.fail(error)),
+            };
 
             // We load only Ring 3 ELFs. So, add Ring3 permissions as well.
             let mut target_permissions = MapPermissions::empty();
@@ -130,121 +432,54 @@ impl<'a> ElfLoader for DefaultElfLoader<'a> {
                 target_permissions |= MapPermissions::EXECUTE;
             }
 
-            let end_vaddr_to_load_at_aligned = align::align_up(
-                virt_addr_to_load_at + header.mem_size() as usize,
-                globals::BASE_PAGE_LENGTH,
-            ) as usize;
-
-            // TODO: deal with overlapping regions.
-            let total_size = end_vaddr_to_load_at_aligned - virt_addr_to_load_at_page_aligned;
-            let virt_addr_to_load_at_page_aligned_vaddr: VAddr =
-                virt_addr_to_load_at_page_aligned.into();
-
-            for page_count in 0..(total_size / globals::BASE_PAGE_LENGTH) {
-                let r = Self::map_empty_page(
-                    &mut self.pml4.as_l4_mut().unwrap(),
-                    &mut self.untyped,
-                    Some(&mut self.cpool),
-                    &mut self.current_user_data_pool.as_cpool_mut().unwrap(),
-                    virt_addr_to_load_at_page_aligned_vaddr
-                        + page_count * globals::BASE_PAGE_LENGTH,
-                    target_permissions,
-                );
-                if r == Err(CapabilityErrors::CapabilitySlotsFull) {
-                    let user_data_pool =
-                        StoredCap::cpool_retype_from(&mut self.untyped, &mut self.cpool).unwrap();
-                    self.current_user_data_pool = user_data_pool.0;
-                    Self::map_empty_page(
-                        &mut self.pml4.as_l4_mut().unwrap(),
-                        &mut self.untyped,
-                        Some(&mut self.cpool),
-                        &mut self.current_user_data_pool.as_cpool_mut().unwrap(),
-                        virt_addr_to_load_at_page_aligned_vaddr
-                            + page_count * globals::BASE_PAGE_LENGTH,
-                        target_permissions,
-                    )
-                    .unwrap();
-                } else if r.is_err() {
-                    r.unwrap();
-                }
+            if let Err(error) = heap::try_reserve(&mut segments, 1, &mut self.untyped) {
+                return Err(self.fail(error.into()));
             }
-
-            info!(
-                target: "elf",
-                "allocate done. Start: {:#x}, End: {:#x}",
-                virt_addr_to_load_at_page_aligned,
-                end_vaddr_to_load_at_aligned,
-            )
+            segments.push((range, target_permissions));
         }
 
-        Ok(())
+        self.map_segments(&segments)
+            .map_err(|error| self.fail(error))
     }
 
     /// Copies `region` into memory starting at `base`.
     /// The caller makes sure that there was an `allocate` call previously
     /// to initialize the region.
     fn load(&mut self, flags: Flags, base: u64, region: &[u8]) -> Result<(), &'static str> {
-        let start = self.vbase + base;
-        let end = self.vbase + base as usize + region.len();
-
+        let start = self
+            .write_at(base, region)
+            .map_err(|error| self.fail(error))?;
         if flags.is_execute() {
-            self.exe_section_location = start.into();
+            self.exe_section_location = start;
         }
 
-        let pml4 = &self.pml4.as_l4().unwrap().page_data;
         info!(
-                target:"elf", "load region into = {:#x} -- {:#x} (Size: {:#x}), Start PAddr: {:?}",
-                start, end, end - start, start.translate(pml4));
-
-        for i in 0..end - start {
-            // Because we load everything in a target mapper rather than the current one, we use the mapper provided
-            // for getting target locations.
-            // TODO: Reduce virt_to_phys calls.
-            let result = (start + i).translate(&pml4);
-            let target_physical_addr = match result {
-                Some(a) => a,
-                None => panic!("Unable to translate virtual address {:x}", (start + i)),
-            };
-            let virt_addr_in_current = target_physical_addr.to_paddr_global();
-            let data_to_write = region[i as usize];
-            unsafe { *virt_addr_in_current.as_mut_ptr::<u8>() = data_to_write };
-        }
-
+            target:"elf", "load region into = {:#x} -- {:#x} (Size: {:#x})",
+            start, start + region.len(), region.len());
         Ok(())
     }
 
     /// Request for the client to relocate the given `entry`
     /// within the loaded ELF file.
     fn relocate(&mut self, entry: &Rela<P64>) -> Result<(), &'static str> {
-        let elf_entry_type = TypeRela64::from(entry.get_type());
-
-        let (target_vaddr, vaddr_in_current) = {
-            let pml4 = &self.pml4.as_l4().unwrap().page_data;
-            let target_vaddr = self.vbase + entry.get_offset();
-            let target_paddr = target_vaddr.translate(pml4).expect("Unable to translate");
-            let vaddr_in_current = target_paddr.to_paddr_global();
-            (target_vaddr, vaddr_in_current)
-        };
-
         // https://www.intezer.com/blog/elf/executable-and-linkable-format-101-part-3-relocations/
-        match elf_entry_type {
-            TypeRela64::R_RELATIVE => {
-                // This is a relative relocation, add the offset (where we put our
-                // binary in the vspace) to the addend and we're done.
-                debug!(target:"elf",
-                    "R_RELATIVE *{:?} = {:#x}",
-                    target_vaddr,
-                    self.vbase + entry.get_addend()
-                );
-
-                unsafe {
-                    *vaddr_in_current.as_mut_ptr::<u64>() = (self.vbase + entry.get_addend()).into()
-                };
-
-                Ok(())
-            }
-            r => panic!("Unexpected relocation encountered: {:?}", r),
-        }
+        let value = self
+            .relocation_value(
+                entry.get_type(),
+                entry.get_symbol_table_index(),
+                entry.get_addend(),
+            )
+            .map_err(|error| self.fail(error))?;
+        debug!(target:"elf",
+            "{:?} *{:#x} = {:#x}",
+            TypeRela64::from(entry.get_type()),
+            entry.get_offset(),
+            value
+        );
+
+        self.write_at(entry.get_offset(), &value.to_le_bytes())
+            .map(|_| ())
+            .map_err(|error| self.fail(error))
     }
 
     fn tls(
@@ -256,7 +491,7 @@ impl<'a> ElfLoader for DefaultElfLoader<'a> {
     ) -> Result<(), &'static str> {
         info!(target: "elf", "Found TLS data: Length: {}, Size: {}, Align: {}", tdata_length, total_size, align);
         if self.tls_info.tls_loaded {
-            panic!("Cannot handle multiple TLS calls.");
+            return Err(self.fail(ElfLoadError::MultipleTlsSegments));
         }
         self.tls_info.tls_loaded = true;
 
@@ -268,3 +503,127 @@ impl<'a> ElfLoader for DefaultElfLoader<'a> {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use relic_utils::elf::{R_X86_64_64, R_X86_64_RELATIVE, R_X86_64_TPOFF64};
+
+    use super::*;
+    use crate::{
+        arch::user_memory::{copy_from_user, copy_to_user, UserPtr},
+        capability::test_fixture::Fixture,
+    };
+
+    const VBASE: usize = 0x40_0000;
+
+    #[test]
+    fn test_segments() {
+        let fixture = Fixture::new();
+        let mut bootstrap_info = BootstrapInfo::default();
+        let mut loader = DefaultElfLoader::new(
+            VBASE.into(),
+            fixture.cpool(),
+            &mut bootstrap_info,
+            fixture.untyped(),
+        )
+        .unwrap();
+
+        assert_eq!(
+            loader.segment_range(0x1800, 0x1000),
+            Ok(VBASE + 0x1000..VBASE + 0x3000)
+        );
+        // Segments that wrap around or leave the user half.
+        let out_of_bounds = Err(ElfLoadError::SegmentOutOfBounds);
+        assert_eq!(loader.segment_range(u64::MAX - 0x1000, 0x10), out_of_bounds);
+        assert_eq!(loader.segment_range(0x1000, u64::MAX), out_of_bounds);
+        assert_eq!(
+            loader.segment_range(0x7FFF_FFFF_0000, 0x10_0000),
+            out_of_bounds
+        );
+
+        // The code ends in the page where the data starts.
+        let code = (VBASE + 0x1000..VBASE + 0x3000, MapPermissions::EXECUTE);
+        let data = (VBASE + 0x2000..VBASE + 0x4000, MapPermissions::WRITE);
+        let rodata = (VBASE + 0x5000..VBASE + 0x6000, MapPermissions::empty());
+        loader.map_segments(&[code, data, rodata]).unwrap();
+        assert_eq!(
+            loader.allocated,
+            [
+                VBASE + 0x1000..VBASE + 0x4000,
+                VBASE + 0x5000..VBASE + 0x6000
+            ]
+        );
+
+        // The shared page is mapped once with the permissions of both segments.
+        let root = loader.top_level_table.address_space_paddr().unwrap();
+        let ptr = |offset: usize| UserPtr::<u64>::new((VBASE + offset).into()).unwrap();
+        assert_eq!(copy_from_user(root, ptr(0x1000)), Ok(0));
+        assert!(copy_to_user(root, ptr(0x1000), &1).is_err());
+        assert_eq!(copy_to_user(root, ptr(0x2000), &1), Ok(()));
+        assert_eq!(copy_to_user(root, ptr(0x3000), &1), Ok(()));
+        assert!(copy_from_user(root, ptr(0x4000)).is_err());
+        assert!(copy_to_user(root, ptr(0x5000), &1).is_err());
+
+        assert!(loader.is_allocated(VBASE + 0x1000..VBASE + 0x4000));
+        assert!(loader.is_allocated(VBASE + 0x4000..VBASE + 0x4000));
+        assert!(!loader.is_allocated(VBASE + 0x3000..VBASE + 0x5001));
+        assert!(!loader.is_allocated(VBASE..VBASE + 0x1001));
+
+        // Read-only pages are loaded too, across page boundaries.
+        assert_eq!(
+            loader.write_at(0x1FF8, &[0xAB; 16]),
+            Ok((VBASE + 0x1FF8).into())
+        );
+        assert_eq!(copy_from_user(root, ptr(0x1FF8)), Ok(0xABAB_ABAB_ABAB_ABAB));
+        let out_of_bounds = Err(ElfLoadError::WriteOutOfBounds);
+        assert_eq!(loader.write_at(0x3FF8, &[0; 16]), out_of_bounds);
+        assert_eq!(loader.write_at(u64::MAX - 4, &[0; 8]), out_of_bounds);
+    }
+
+    #[test]
+    fn test_relocations() {
+        let fixture = Fixture::new();
+        let mut bootstrap_info = BootstrapInfo::default();
+        let mut loader = DefaultElfLoader::new(
+            VBASE.into(),
+            fixture.cpool(),
+            &mut bootstrap_info,
+            fixture.untyped(),
+        )
+        .unwrap();
+        loader
+            .map_segments(&[(VBASE..VBASE + 0x1000, MapPermissions::WRITE)])
+            .unwrap();
+
+        assert_eq!(
+            loader.relocation_value(R_X86_64_RELATIVE, 0, 0x20),
+            Ok(VBASE as u64 + 0x20)
+        );
+        // R_X86_64_PC32
+        assert_eq!(
+            loader.relocation_value(2, 0, 0),
+            Err(ElfLoadError::UnsupportedRelocation(2))
+        );
+        // The binary has no dynamic symbols.
+        assert_eq!(
+            loader.relocation_value(R_X86_64_64, 1, 0),
+            Err(ElfLoadError::InvalidBinary("Symbol index is out of bounds"))
+        );
+        assert_eq!(
+            loader.relocation_value(R_X86_64_TPOFF64, 0, 0),
+            Err(ElfLoadError::MissingTlsSegment)
+        );
+
+        // Relocations write whole values into the segments.
+        let value = (VBASE as u64 + 0x20).to_le_bytes();
+        assert_eq!(loader.write_at(0xFF8, &value), Ok((VBASE + 0xFF8).into()));
+        assert_eq!(
+            loader.write_at(0xFFC, &value),
+            Err(ElfLoadError::WriteOutOfBounds)
+        );
+        assert_eq!(
+            loader.write_at(0x1000, &value),
+            Err(ElfLoadError::WriteOutOfBounds)
+        );
+    }
+}
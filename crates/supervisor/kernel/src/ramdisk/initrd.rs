@@ -0,0 +1,142 @@
+//! The initial ram disk loaded by BOOTBOOT. It is mapped read-only into sigma,
+//! so that userspace can read the files next to sigma. A gzip, zstd or lz4
+//! compressed initrd is decompressed first.
+
+use relic_abi::{bootstrap::BootstrapInfo, cap::CapabilityErrors};
+use relic_utils::{
+    align,
+    decompress::{self, Format},
+    ustar::UStarArchive,
+};
+
+use super::elf_loader::ElfLoadError;
+use crate::{
+    addr::{PAddr, PAddrGlobal, VAddr},
+    arch::globals::{self, BASE_PAGE_LENGTH},
+    bootboot::bootboot,
+    capability::{CapAccessorMut, Cpool, MapPermissions, StoredCap, UntypedMemory},
+};
+
+#[derive(Debug)]
+pub struct Initrd {
+    ptr: PAddrGlobal,
+    size: usize,
+    /// Untyped memory holding the archive if it was decompressed.
+    decompressed: Option<StoredCap>,
+}
+
+impl Initrd {
+    pub fn new_from_bootboot() -> Self {
+        unsafe {
+            Self {
+                ptr: PAddr::new(bootboot.initrd_ptr).to_paddr_global(),
+                size: bootboot.initrd_size as _,
+                decompressed: None,
+            }
+        }
+    }
+
+    fn data(&self) -> &'static [u8] {
+        unsafe { core::slice::from_raw_parts(self.ptr.as_raw_ptr::<u8>(), self.size) }
+    }
+
+    /**
+    Decompress the initrd if it is compressed. The archive is written to untyped
+    memory derived from `untyped`, whose capability is stored in `cpool_to_store`,
+    and the returned initrd refers to it.
+    */
+    pub fn decompress(
+        self,
+        untyped: &mut CapAccessorMut<'_, UntypedMemory>,
+        cpool_to_store: &mut CapAccessorMut<'_, Cpool>,
+    ) -> Result<Self, ElfLoadError> {
+        let compressed = self.data();
+        let format = match Format::detect(compressed) {
+            Some(format) => format,
+            None => return Ok(self),
+        };
+        let size = decompress::decompressed_size(format, compressed)?;
+        info!(target: "initrd",
+            "Decompress {:?} initrd from {:#x} to {:#x} bytes", format, self.size, size);
+
+        let length = align::align_up(size, BASE_PAGE_LENGTH);
+        let (decompressed, _) = StoredCap::untyped_retype_from(untyped, length, cpool_to_store)?;
+        let ptr = decompressed.as_untyped_memory_mut()?.start_paddr();
+        let output = unsafe { core::slice::from_raw_parts_mut(ptr.as_raw_ptr::<u8>(), length) };
+        decompress::decompress(format, compressed, &mut output[..size])?;
+        // The whole last page is mapped into sigma, so clear what follows the archive.
+        output[size..].iter_mut().for_each(|byte| *byte = 0);
+
+        Ok(Self {
+            ptr,
+            size,
+            decompressed: Some(decompressed),
+        })
+    }
+
+    /// The archive in the initrd, read through the kernel mapping.
+    pub fn archive(&self) -> UStarArchive<'static> {
+        UStarArchive::new(self.data())
+    }
+
+    /**
+    Map the pages of the initrd read-only at [`globals::SIGMA_INITRD_START`]. The
+    pages are retyped without clearing them, from the decompressed memory or from
+    device untyped memory over the initrd, so that they cannot hold kernel objects.
+    Their capabilities are stored in new cpools.
+    */
+    pub fn bootstrap_and_map(
+        &self,
+        untyped: &mut CapAccessorMut<'_, UntypedMemory>,
+        cpool_to_store: &mut CapAccessorMut<'_, Cpool>,
+        top_level_table: &StoredCap,
+        bootstrap_info: &mut BootstrapInfo,
+    ) -> Result<(), CapabilityErrors> {
+        let length = align::align_up(self.size, BASE_PAGE_LENGTH);
+        let initrd_vaddr = VAddr::new(globals::SIGMA_INITRD_START);
+        info!(target: "initrd", "Map {:#x} bytes of initrd at {:?}", length, initrd_vaddr);
+
+        let untyped_initrd = match &self.decompressed {
+            Some(decompressed) => decompressed.clone(),
+            None => {
+                let untyped_initrd = unsafe { UntypedMemory::bootstrap(self.ptr, length, true) };
+                let cpool_location = cpool_to_store.get_free_index()?;
+                cpool_to_store.write_to_if_empty(cpool_location, untyped_initrd)?
+            }
+        };
+        let mut untyped_initrd = untyped_initrd.as_untyped_memory_mut()?;
+
+        let mut current_cpool = StoredCap::cpool_retype_from(untyped, cpool_to_store)?.0;
+        for offset in (0..length).step_by(BASE_PAGE_LENGTH) {
+            let result = StoredCap::base_page_retype_from::<[u8; 4096]>(
+                &mut untyped_initrd,
+                &mut current_cpool.as_cpool_mut()?,
+                false,
+            );
+            let raw_page = match result {
+                Err(CapabilityErrors::CapabilitySlotsFull) => {
+                    current_cpool = StoredCap::cpool_retype_from(untyped, cpool_to_store)?.0;
+                    StoredCap::base_page_retype_from::<[u8; 4096]>(
+                        &mut untyped_initrd,
+                        &mut current_cpool.as_cpool_mut()?,
+                        false,
+                    )?
+                }
+                result => result?,
+            };
+
+            top_level_table.address_space_map(
+                initrd_vaddr + offset,
+                &raw_page.0,
+                untyped,
+                cpool_to_store,
+                None,
+                MapPermissions::empty(),
+            )?;
+        }
+
+        bootstrap_info.initrd_info.initrd_vaddr = initrd_vaddr.into();
+        bootstrap_info.initrd_info.initrd_size = self.size as u64;
+        Ok(())
+    }
+}
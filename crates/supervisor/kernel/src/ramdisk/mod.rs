@@ -3,4 +3,4 @@
 //! into the kernel as part of the initial bootup.
 
 pub mod elf_loader;
-pub mod ustar;
+pub mod initrd;
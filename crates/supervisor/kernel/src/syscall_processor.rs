@@ -1,11 +1,25 @@
+use core::{
+    mem::{size_of, MaybeUninit},
+    ptr,
+};
+
 use relic_abi::{
+    caddr::CAddr,
     cap::CapabilityErrors,
-    syscall::{SystemCall, TaskBuffer},
+    syscall::{SystemCall, TaskBuffer, TASK_BUFFER_PAYLOAD_LENGTH},
+    task::TaskRegisters,
 };
 
 use crate::{
-    addr::VAddr,
-    capability::{CapAccessorMut, MapPermissions, Scheduler, StoredCap, Task, TaskStatus},
+    addr::{PAddr, VAddr},
+    arch::{
+        task::timer_ticks,
+        user_memory::{copy_from_user, copy_to_user, UserPtr},
+    },
+    capability::{
+        CapAccessorMut, CapabilityEnum, Cpool, MapPermissions, Scheduler, StoredCap, Task,
+        TaskStatus, UntypedMemory,
+    },
 };
 
 pub fn process_syscall(
@@ -27,7 +41,13 @@ pub fn process_syscall(
     let syscall = syscall.unwrap();
     match syscall {
         SystemCall::Yield => {
-            set_result_and_schedule(source_task, (CapabilityErrors::None, 0, 0), scheduler);
+            // Yielding gives up the timeslice, so the task goes through the run queue.
+            source_task.set_status(TaskStatus::SyscalledReadyToResume(
+                CapabilityErrors::None,
+                0,
+                0,
+            ));
+            scheduler.add_task_with_priority(source_task);
             return;
         }
         SystemCall::UntypedTotalFree(caddr) => {
@@ -60,24 +80,8 @@ pub fn process_syscall(
                     .lookup(untyped_memory)
                     .ok_or(CapabilityErrors::CapabilitySearchFailed)?;
                 let mut untyped = untyped_op.as_untyped_memory_mut()?;
-                let raw_page_cap = match size {
-                    1 => StoredCap::large_page_retype_from::<[u8; 0x20_0000]>(
-                        &mut untyped,
-                        &mut cpool,
-                        true,
-                    )?,
-                    2 => StoredCap::huge_page_retype_from::<[u8; 0x4000_0000]>(
-                        &mut untyped,
-                        &mut cpool,
-                        true,
-                    )?,
-                    _ => StoredCap::base_page_retype_from::<[u8; 0x1000]>(
-                        &mut untyped,
-                        &mut cpool,
-                        true,
-                    )?,
-                };
-                Ok((raw_page_cap.1 as u64, 0u64))
+                let index = retype_raw_page(&mut untyped, &mut cpool, size)?;
+                Ok((index as u64, 0u64))
             };
 
             match result() {
@@ -90,14 +94,78 @@ pub fn process_syscall(
             }
             return;
         }
+        SystemCall::CpoolRetype { untyped_memory } => {
+            let result = || -> Result<u64, CapabilityErrors> {
+                let mut cpool = cpool_cap.as_cpool_mut()?;
+                let untyped_op = cpool
+                    .lookup(untyped_memory)
+                    .ok_or(CapabilityErrors::CapabilitySearchFailed)?;
+                let mut untyped = untyped_op.as_untyped_memory_mut()?;
+                let (_, index) = StoredCap::cpool_retype_from(&mut untyped, &mut cpool)?;
+                Ok(index as u64)
+            };
+
+            match result() {
+                Ok(index) => set_result_and_schedule(
+                    source_task,
+                    (CapabilityErrors::None, index, 0),
+                    scheduler,
+                ),
+                Err(e) => set_result_and_schedule(source_task, (e, 0, 0), scheduler),
+            }
+            return;
+        }
         SystemCall::RawPageMap {
             untyped_memory,
             top_level_table,
             vaddr,
             raw_page,
         } => {
-            let func = move || -> Result<(), CapabilityErrors> {
-                let mut cpool = cpool_cap.as_cpool_mut()?;
+            let perms = MapPermissions::WRITE | MapPermissions::EXECUTE;
+            let result = map_raw_page(
+                &cpool_cap,
+                untyped_memory,
+                top_level_table,
+                vaddr,
+                raw_page,
+                perms,
+            );
+            let data = result.err().unwrap_or(CapabilityErrors::None);
+            set_result_and_schedule(source_task, (data, 0, 0), scheduler);
+            return;
+        }
+        SystemCall::RawPageMapWithPermissions {
+            untyped_memory,
+            top_level_table,
+            vaddr,
+            raw_page,
+        } => {
+            const PERMISSION_MASK: u64 = 0xFFF;
+            let perms = MapPermissions::from_bits_truncate((vaddr & PERMISSION_MASK) as u8)
+                & (MapPermissions::WRITE
+                    | MapPermissions::EXECUTE
+                    | MapPermissions::CACHE_DISABLE
+                    | MapPermissions::WRITE_THROUGH
+                    | MapPermissions::WRITE_COMBINING);
+            let result = map_raw_page(
+                &cpool_cap,
+                untyped_memory,
+                top_level_table,
+                vaddr & !PERMISSION_MASK,
+                raw_page,
+                perms,
+            );
+            let data = result.err().unwrap_or(CapabilityErrors::None);
+            set_result_and_schedule(source_task, (data, 0, 0), scheduler);
+            return;
+        }
+        SystemCall::RawPageUnmap {
+            top_level_table,
+            vaddr,
+            raw_page,
+        } => {
+            let func = || -> Result<(), CapabilityErrors> {
+                let cpool = cpool_cap.as_cpool()?;
                 let raw_page = cpool
                     .lookup(raw_page)
                     .ok_or(CapabilityErrors::CapabilitySearchFailed)?;
@@ -107,16 +175,502 @@ pub fn process_syscall(
 
                 let vaddr: VAddr = vaddr.into();
                 vaddr.validate_user_mode()?;
+
+                top_level_table.address_space_unmap(vaddr, &raw_page)
+            };
+            let data = func().err().unwrap_or(CapabilityErrors::None);
+            set_result_and_schedule(source_task, (data, 0, 0), scheduler);
+            return;
+        }
+        SystemCall::RawPageRevoke(caddr) => {
+            let func = || -> Result<(), CapabilityErrors> {
+                // The cpool is released first, as revoking fails on borrowed neighbours.
+                let raw_page = cpool_cap
+                    .as_cpool()?
+                    .lookup(caddr)
+                    .ok_or(CapabilityErrors::CapabilitySearchFailed)?;
+
+                match raw_page.base_page_revoke() {
+                    Err(CapabilityErrors::CapabilityMismatch) => {}
+                    result => return result,
+                }
+                match raw_page.large_page_revoke() {
+                    Err(CapabilityErrors::CapabilityMismatch) => {}
+                    result => return result,
+                }
+                raw_page.huge_page_revoke()
+            };
+            let data = func().err().unwrap_or(CapabilityErrors::None);
+            set_result_and_schedule(source_task, (data, 0, 0), scheduler);
+            return;
+        }
+        SystemCall::CopyCapability {
+            address,
+            cpool_to_store_in,
+        } => {
+            let result = || -> Result<u64, CapabilityErrors> {
+                let (source, target) = {
+                    let cpool = cpool_cap.as_cpool()?;
+                    let source = cpool
+                        .lookup(address)
+                        .ok_or(CapabilityErrors::CapabilitySearchFailed)?;
+                    let target = cpool
+                        .lookup(cpool_to_store_in)
+                        .ok_or(CapabilityErrors::CapabilitySearchFailed)?;
+                    (source, target)
+                };
+
+                // The source might be borrowed, like the calling task.
+                let is_type = |check: fn(&CapabilityEnum) -> bool| {
+                    source
+                        .try_borrow()
+                        .map_or(false, |cap| check(&cap.capability_data))
+                };
+
+                let index = if is_type(|c| matches!(c, CapabilityEnum::BasePage(_))) {
+                    StoredCap::base_page_copy(&source, &mut target.as_cpool_mut()?)?.1
+                } else if is_type(|c| matches!(c, CapabilityEnum::LargePage(_))) {
+                    StoredCap::large_page_copy(&source, &mut target.as_cpool_mut()?)?.1
+                } else if is_type(|c| matches!(c, CapabilityEnum::HugePage(_))) {
+                    StoredCap::huge_page_copy(&source, &mut target.as_cpool_mut()?)?.1
+                } else if is_type(|c| matches!(c, CapabilityEnum::L5(_))) {
+                    StoredCap::l5_copy(&source, &target)?.1
+                } else if is_type(|c| matches!(c, CapabilityEnum::L4(_))) {
+                    StoredCap::l4_copy(&source, &target)?.1
+                } else if is_type(|c| matches!(c, CapabilityEnum::IoPortRange(_))) {
+                    StoredCap::io_port_range_copy(&source, &mut target.as_cpool_mut()?)?.1
+                } else if is_type(|c| matches!(c, CapabilityEnum::Cpool(_))) {
+                    if source.as_ptr() == target.as_ptr() {
+                        StoredCap::cpool_copy(&source, None)?.1
+                    } else {
+                        StoredCap::cpool_copy(&source, Some(&target))?.1
+                    }
+                } else {
+                    return Err(CapabilityErrors::CapabilityMismatch);
+                };
+                Ok(index as u64)
+            };
+
+            match result() {
+                Ok(index) => set_result_and_schedule(
+                    source_task,
+                    (CapabilityErrors::None, index, 0),
+                    scheduler,
+                ),
+                Err(e) => set_result_and_schedule(source_task, (e, 0, 0), scheduler),
+            }
+            return;
+        }
+        SystemCall::TaskExit(exit_code) => {
+            source_task.task_exit(exit_code, scheduler);
+            return;
+        }
+        SystemCall::TaskJoin(caddr) => {
+            let mut result = || -> Result<Option<u64>, CapabilityErrors> {
+                let task_data = lookup_other_task(&cpool_cap, caddr, source_task)?;
+                let mut task = task_data.as_task_mut()?;
+                task.task_add_exit_waiter(source_task)
+            };
+
+            match result() {
+                Ok(Some(exit_code)) => set_result_and_schedule(
+                    source_task,
+                    (CapabilityErrors::None, exit_code, 0),
+                    scheduler,
+                ),
+                // The task is scheduled again when the target task exits.
+                Ok(None) => {}
+                Err(e) => set_result_and_schedule(source_task, (e, 0, 0), scheduler),
+            }
+            return;
+        }
+        SystemCall::TaskInfo(caddr) => {
+            let result = || -> Result<_, CapabilityErrors> {
+                let task_data = lookup_other_task(&cpool_cap, caddr, source_task)?;
+                let task = task_data.as_task()?;
+                Ok(task.task_info())
+            };
+
+            match result() {
+                Ok(info) => set_result_with_data_and_schedule(
+                    source_task,
+                    (CapabilityErrors::None, 0, 0),
+                    info,
+                    scheduler,
+                ),
+                Err(e) => set_result_and_schedule(source_task, (e, 0, 0), scheduler),
+            }
+            return;
+        }
+        SystemCall::TaskReadRegisters(caddr) => {
+            let result = || -> Result<_, CapabilityErrors> {
+                let task_data = lookup_other_task(&cpool_cap, caddr, source_task)?;
+                let task = task_data.as_task()?;
+                Ok(task.read_registers())
+            };
+
+            match result() {
+                Ok(regs) => set_result_with_data_and_schedule(
+                    source_task,
+                    (CapabilityErrors::None, 0, 0),
+                    regs,
+                    scheduler,
+                ),
+                Err(e) => set_result_and_schedule(source_task, (e, 0, 0), scheduler),
+            }
+            return;
+        }
+        SystemCall::TaskWriteRegisters(caddr) => {
+            let result = || -> Result<(), CapabilityErrors> {
+                let regs: TaskRegisters = read_task_buffer_payload(source_task)?;
+
+                let task_data = lookup_other_task(&cpool_cap, caddr, source_task)?;
+                let mut task = task_data.as_task_mut()?;
+                task.write_registers(&regs)
+            };
+
+            let data = result().err().unwrap_or(CapabilityErrors::None);
+            set_result_and_schedule(source_task, (data, 0, 0), scheduler);
+            return;
+        }
+        SystemCall::SchedContextRetype {
+            untyped_memory,
+            budget,
+            period,
+        } => {
+            let result = || -> Result<u64, CapabilityErrors> {
+                let mut cpool = cpool_cap.as_cpool_mut()?;
                 let untyped_op = cpool
                     .lookup(untyped_memory)
                     .ok_or(CapabilityErrors::CapabilitySearchFailed)?;
                 let mut untyped = untyped_op.as_untyped_memory_mut()?;
-                let perms = MapPermissions::WRITE | MapPermissions::EXECUTE;
+                let (_, index) = StoredCap::sched_context_retype_from(
+                    &mut untyped,
+                    &mut cpool,
+                    budget,
+                    period,
+                    timer_ticks(),
+                )?;
+                Ok(index as u64)
+            };
 
-                let mut top_level_table_mut = top_level_table.as_l4_mut()?;
-                top_level_table_mut.l4_map(vaddr, &raw_page, &mut untyped, &mut cpool, None, perms)
+            match result() {
+                Ok(index) => set_result_and_schedule(
+                    source_task,
+                    (CapabilityErrors::None, index, 0),
+                    scheduler,
+                ),
+                Err(e) => set_result_and_schedule(source_task, (e, 0, 0), scheduler),
+            }
+            return;
+        }
+        SystemCall::TaskSetSchedContext {
+            task,
+            sched_context,
+        } => {
+            let mut result = || -> Result<(), CapabilityErrors> {
+                let cpool = cpool_cap.as_cpool()?;
+                let sched_context_data = cpool
+                    .lookup(sched_context)
+                    .ok_or(CapabilityErrors::CapabilitySearchFailed)?;
+                let mut sched_context = sched_context_data.as_sched_context_mut()?;
+                let task_data = cpool
+                    .lookup(task)
+                    .ok_or(CapabilityErrors::CapabilitySearchFailed)?;
+
+                // The calling task is already borrowed.
+                if task_data.as_ptr() == source_task.cap().as_ptr() {
+                    source_task.task_set_sched_context(&mut sched_context)
+                } else {
+                    let mut task = task_data.as_task_mut()?;
+                    task.task_set_sched_context(&mut sched_context)
+                }
             };
-            let data = func().err().unwrap_or(CapabilityErrors::None);
+
+            let data = result().err().unwrap_or(CapabilityErrors::None);
+            set_result_and_schedule(source_task, (data, 0, 0), scheduler);
+            return;
+        }
+        SystemCall::L4Clone {
+            untyped_memory,
+            top_level_table,
+            cpool_to_store_in,
+        } => {
+            let result = || -> Result<u64, CapabilityErrors> {
+                let (untyped_op, target) =
+                    lookup_retype_caps(&cpool_cap, untyped_memory, cpool_to_store_in)?;
+                let top_level_table = cpool_cap
+                    .as_cpool()?
+                    .lookup(top_level_table)
+                    .ok_or(CapabilityErrors::CapabilitySearchFailed)?;
+                let mut untyped = untyped_op.as_untyped_memory_mut()?;
+                let (_, index) = StoredCap::address_space_clone(
+                    &top_level_table,
+                    &mut untyped,
+                    &mut target.as_cpool_mut()?,
+                )?;
+                Ok(index as u64)
+            };
+
+            match result() {
+                Ok(index) => set_result_and_schedule(
+                    source_task,
+                    (CapabilityErrors::None, index, 0),
+                    scheduler,
+                ),
+                Err(e) => set_result_and_schedule(source_task, (e, 0, 0), scheduler),
+            }
+            return;
+        }
+        SystemCall::TaskSetFaultUntyped {
+            task,
+            untyped_memory,
+        } => {
+            let mut result = || -> Result<(), CapabilityErrors> {
+                let cpool = cpool_cap.as_cpool()?;
+                let untyped_op = cpool
+                    .lookup(untyped_memory)
+                    .ok_or(CapabilityErrors::CapabilitySearchFailed)?;
+                let untyped = untyped_op.as_untyped_memory_mut()?;
+                let task_data = cpool
+                    .lookup(task)
+                    .ok_or(CapabilityErrors::CapabilitySearchFailed)?;
+
+                // The calling task is already borrowed.
+                if task_data.as_ptr() == source_task.cap().as_ptr() {
+                    source_task.task_set_fault_untyped(&untyped);
+                } else {
+                    task_data.as_task_mut()?.task_set_fault_untyped(&untyped);
+                }
+                Ok(())
+            };
+
+            let data = result().err().unwrap_or(CapabilityErrors::None);
+            set_result_and_schedule(source_task, (data, 0, 0), scheduler);
+            return;
+        }
+        SystemCall::PageGetPhysical(caddr) => {
+            let result = || -> Result<(u64, u64), CapabilityErrors> {
+                let cpool = cpool_cap.as_cpool()?;
+                let raw_page = cpool
+                    .lookup(caddr)
+                    .ok_or(CapabilityErrors::CapabilitySearchFailed)?;
+                let (paddr, length) = raw_page.raw_page_physical()?;
+                Ok((paddr.into(), length as u64))
+            };
+
+            match result() {
+                Ok(r) => set_result_and_schedule(
+                    source_task,
+                    (CapabilityErrors::None, r.0, r.1),
+                    scheduler,
+                ),
+                Err(e) => set_result_and_schedule(source_task, (e, 0, 0), scheduler),
+            }
+            return;
+        }
+        SystemCall::IoPortRangeDerive {
+            io_port_range,
+            first_port,
+            count,
+        } => {
+            let result = || -> Result<u64, CapabilityErrors> {
+                let mut cpool = cpool_cap.as_cpool_mut()?;
+                let source_data = cpool
+                    .lookup(io_port_range)
+                    .ok_or(CapabilityErrors::CapabilitySearchFailed)?;
+                let source = *source_data.as_io_port_range()?;
+                let (_, index) =
+                    StoredCap::io_port_range_derive(&source, first_port, count, &mut cpool)?;
+                Ok(index as u64)
+            };
+
+            match result() {
+                Ok(index) => set_result_and_schedule(
+                    source_task,
+                    (CapabilityErrors::None, index, 0),
+                    scheduler,
+                ),
+                Err(e) => set_result_and_schedule(source_task, (e, 0, 0), scheduler),
+            }
+            return;
+        }
+        SystemCall::TaskGrantIoPorts {
+            task,
+            io_port_range,
+        } => {
+            let mut result = || -> Result<(), CapabilityErrors> {
+                let cpool = cpool_cap.as_cpool()?;
+                let range_data = cpool
+                    .lookup(io_port_range)
+                    .ok_or(CapabilityErrors::CapabilitySearchFailed)?;
+                let range = *range_data.as_io_port_range()?;
+                let task_data = cpool
+                    .lookup(task)
+                    .ok_or(CapabilityErrors::CapabilitySearchFailed)?;
+
+                // The calling task is already borrowed.
+                if task_data.as_ptr() == source_task.cap().as_ptr() {
+                    source_task.task_grant_io_ports(&range)
+                } else {
+                    let mut task = task_data.as_task_mut()?;
+                    task.task_grant_io_ports(&range)
+                }
+            };
+
+            let data = result().err().unwrap_or(CapabilityErrors::None);
+            set_result_and_schedule(source_task, (data, 0, 0), scheduler);
+            return;
+        }
+        SystemCall::UntypedRetype {
+            untyped_memory,
+            length,
+            cpool_to_store_in,
+        } => {
+            let result = || -> Result<u64, CapabilityErrors> {
+                let (untyped_op, target) =
+                    lookup_retype_caps(&cpool_cap, untyped_memory, cpool_to_store_in)?;
+                let mut untyped = untyped_op.as_untyped_memory_mut()?;
+                let (_, index) = StoredCap::untyped_retype_from(
+                    &mut untyped,
+                    length as usize,
+                    &mut target.as_cpool_mut()?,
+                )?;
+                Ok(index as u64)
+            };
+
+            match result() {
+                Ok(index) => set_result_and_schedule(
+                    source_task,
+                    (CapabilityErrors::None, index, 0),
+                    scheduler,
+                ),
+                Err(e) => set_result_and_schedule(source_task, (e, 0, 0), scheduler),
+            }
+            return;
+        }
+        SystemCall::TopLevelTableRetype {
+            untyped_memory,
+            cpool_to_store_in,
+        } => {
+            let result = || -> Result<u64, CapabilityErrors> {
+                let (untyped_op, target) =
+                    lookup_retype_caps(&cpool_cap, untyped_memory, cpool_to_store_in)?;
+                let mut untyped = untyped_op.as_untyped_memory_mut()?;
+                let (_, index) = StoredCap::address_space_retype_from(
+                    &mut untyped,
+                    &mut target.as_cpool_mut()?,
+                )?;
+                Ok(index as u64)
+            };
+
+            match result() {
+                Ok(index) => set_result_and_schedule(
+                    source_task,
+                    (CapabilityErrors::None, index, 0),
+                    scheduler,
+                ),
+                Err(e) => set_result_and_schedule(source_task, (e, 0, 0), scheduler),
+            }
+            return;
+        }
+        SystemCall::RawPageRetypeInto {
+            untyped_memory,
+            size,
+            cpool_to_store_in,
+        } => {
+            let result = || -> Result<u64, CapabilityErrors> {
+                let (untyped_op, target) =
+                    lookup_retype_caps(&cpool_cap, untyped_memory, cpool_to_store_in)?;
+                let mut untyped = untyped_op.as_untyped_memory_mut()?;
+                let index = retype_raw_page(&mut untyped, &mut target.as_cpool_mut()?, size)?;
+                Ok(index as u64)
+            };
+
+            match result() {
+                Ok(index) => set_result_and_schedule(
+                    source_task,
+                    (CapabilityErrors::None, index, 0),
+                    scheduler,
+                ),
+                Err(e) => set_result_and_schedule(source_task, (e, 0, 0), scheduler),
+            }
+            return;
+        }
+        SystemCall::TaskRetype {
+            untyped_memory,
+            cpool,
+            top_level_table,
+            task_buffer,
+        } => {
+            let result = || -> Result<u64, CapabilityErrors> {
+                let mut root_cpool = cpool_cap.as_cpool_mut()?;
+                let lookup = |caddr| {
+                    root_cpool
+                        .lookup(caddr)
+                        .ok_or(CapabilityErrors::CapabilitySearchFailed)
+                };
+                let untyped_op = lookup(untyped_memory)?;
+                let task_cpool = lookup(cpool)?;
+                let top_level_table = lookup(top_level_table)?;
+                let task_buffer = lookup(task_buffer)?;
+
+                let mut task_cpool = task_cpool.as_cpool_mut()?;
+                let mut task_buffer = task_buffer.as_base_page_mut()?;
+                if task_cpool.linked_task.is_some() || task_buffer.linked_task.is_some() {
+                    return Err(CapabilityErrors::CapabilityAlreadyOccupied);
+                }
+
+                let mut untyped = untyped_op.as_untyped_memory_mut()?;
+                let (task, index) = StoredCap::task_retype_from(
+                    &mut untyped,
+                    &mut root_cpool,
+                    *source_task.priority(),
+                )?;
+                let mut task = task.as_task_mut()?;
+                task.task_set_cpool(&mut task_cpool)?;
+                task.task_set_top_level_table(&top_level_table)?;
+                task.task_set_task_buffer(&mut task_buffer)?;
+                Ok(index as u64)
+            };
+
+            match result() {
+                Ok(index) => set_result_and_schedule(
+                    source_task,
+                    (CapabilityErrors::None, index, 0),
+                    scheduler,
+                ),
+                Err(e) => set_result_and_schedule(source_task, (e, 0, 0), scheduler),
+            }
+            return;
+        }
+        SystemCall::TaskStart(caddr) => {
+            let result = || -> Result<(), CapabilityErrors> {
+                let task_data = lookup_other_task(&cpool_cap, caddr, source_task)?;
+                let mut task = task_data.as_task_mut()?;
+                task.task_start(scheduler)
+            };
+
+            let data = result().err().unwrap_or(CapabilityErrors::None);
+            set_result_and_schedule(source_task, (data, 0, 0), scheduler);
+            return;
+        }
+        SystemCall::TaskSetStackGuard { task, start, end } => {
+            let result = || -> Result<(), CapabilityErrors> {
+                if end <= start {
+                    return Err(CapabilityErrors::InvalidMemoryAddress);
+                }
+                VAddr::from(start).validate_user_mode()?;
+                VAddr::from(end - 1).validate_user_mode()?;
+
+                let task_data = lookup_other_task(&cpool_cap, task, source_task)?;
+                task_data
+                    .as_task_mut()?
+                    .set_stack_guard(Some(start.into()..end.into()));
+                Ok(())
+            };
+
+            let data = result().err().unwrap_or(CapabilityErrors::None);
             set_result_and_schedule(source_task, (data, 0, 0), scheduler);
             return;
         }
@@ -129,6 +683,86 @@ pub fn process_syscall(
     }
 }
 
+/// Create a raw page of the given size type in the cpool. Returns its index.
+fn retype_raw_page(
+    untyped: &mut UntypedMemory,
+    cpool: &mut Cpool,
+    size: u64,
+) -> Result<usize, CapabilityErrors> {
+    // Clearing device memory would write to the registers of the device.
+    let zero_out = !untyped.is_device_memory();
+    let raw_page_cap = match size {
+        1 => StoredCap::large_page_retype_from::<[u8; 0x20_0000]>(untyped, cpool, zero_out)?,
+        2 => StoredCap::huge_page_retype_from::<[u8; 0x4000_0000]>(untyped, cpool, zero_out)?,
+        _ => StoredCap::base_page_retype_from::<[u8; 0x1000]>(untyped, cpool, zero_out)?,
+    };
+    Ok(raw_page_cap.1)
+}
+
+/// Lookup the untyped memory and the cpool to store a retyped capability in. The
+/// cpool is usually a child of the current cpool.
+fn lookup_retype_caps(
+    cpool_cap: &StoredCap,
+    untyped_memory: CAddr,
+    cpool_to_store_in: CAddr,
+) -> Result<(StoredCap, StoredCap), CapabilityErrors> {
+    let cpool = cpool_cap.as_cpool()?;
+    let untyped = cpool
+        .lookup(untyped_memory)
+        .ok_or(CapabilityErrors::CapabilitySearchFailed)?;
+    let target = cpool
+        .lookup(cpool_to_store_in)
+        .ok_or(CapabilityErrors::CapabilitySearchFailed)?;
+    Ok((untyped, target))
+}
+
+/// Map a raw page into the top level table with the given permissions.
+fn map_raw_page(
+    cpool_cap: &StoredCap,
+    untyped_memory: CAddr,
+    top_level_table: CAddr,
+    vaddr: u64,
+    raw_page: CAddr,
+    perms: MapPermissions,
+) -> Result<(), CapabilityErrors> {
+    let mut cpool = cpool_cap.as_cpool_mut()?;
+    let raw_page = cpool
+        .lookup(raw_page)
+        .ok_or(CapabilityErrors::CapabilitySearchFailed)?;
+    let top_level_table = cpool
+        .lookup(top_level_table)
+        .ok_or(CapabilityErrors::CapabilitySearchFailed)?;
+
+    let vaddr: VAddr = vaddr.into();
+    vaddr.validate_user_mode()?;
+    let untyped_op = cpool
+        .lookup(untyped_memory)
+        .ok_or(CapabilityErrors::CapabilitySearchFailed)?;
+    let mut untyped = untyped_op.as_untyped_memory_mut()?;
+
+    top_level_table.address_space_map(vaddr, &raw_page, &mut untyped, &mut cpool, None, perms)
+}
+
+/// Lookup a task capability that is not the calling task. The calling task is
+/// already borrowed while its syscall is processed.
+fn lookup_other_task(
+    cpool_cap: &StoredCap,
+    caddr: CAddr,
+    source_task: &CapAccessorMut<Task>,
+) -> Result<StoredCap, CapabilityErrors> {
+    let cpool = cpool_cap.as_cpool()?;
+    let task_data = cpool
+        .lookup(caddr)
+        .ok_or(CapabilityErrors::CapabilitySearchFailed)?;
+    if task_data.as_ptr() == source_task.cap().as_ptr() {
+        return Err(CapabilityErrors::TaskInvalidState);
+    }
+
+    Ok(task_data)
+}
+
+/// Set the result of the syscall and resume the task directly, as the syscall
+/// did not block. See [`Scheduler::resume_directly`] for when it is queued instead.
 fn set_result_and_schedule(
     task: &mut CapAccessorMut<Task>,
     result: (CapabilityErrors, u64, u64),
@@ -137,29 +771,86 @@ fn set_result_and_schedule(
     task.set_status(TaskStatus::SyscalledReadyToResume(
         result.0, result.1, result.2,
     ));
-    scheduler.add_task_with_priority(task);
+    scheduler.resume_directly(task);
 }
 
-#[allow(dead_code)]
-fn set_result_with_data_and_schedule<T>(
+fn set_result_with_data_and_schedule<T: Copy>(
     task: &mut CapAccessorMut<Task>,
     mut result: (CapabilityErrors, u64, u64),
     data: T,
     scheduler: &Scheduler,
 ) {
-    let buffer = task.task_buffer();
-    if let Some(buf) = buffer {
-        let mut b = buf.as_base_page_mut().unwrap();
-        let buf = b.page_data_mut::<TaskBuffer>();
-        buf.write_to_task_buffer(&data)
-            .expect("Set result memory exceeded");
-    } else {
-        result = (CapabilityErrors::TaskBufferNotFound, 0, 0);
+    if let Err(e) = write_task_buffer_payload(task, &data) {
+        result = (e, 0, 0);
     }
 
     task.set_status(TaskStatus::SyscalledReadyToResume(
         result.0, result.1, result.2,
     ));
 
-    scheduler.add_task_with_priority(task);
+    scheduler.resume_directly(task);
+}
+
+/**
+Root of the address space of `task` and the user address of its task buffer. The
+address is the one the task stored in the buffer, so the buffer is accessed like
+any other user memory: through the task's page tables and with SMAP lifted only
+for the copy.
+*/
+fn user_task_buffer(task: &Task) -> Result<(PAddr, UserPtr<TaskBuffer>), CapabilityErrors> {
+    let buffer = task
+        .task_buffer()
+        .clone()
+        .ok_or(CapabilityErrors::TaskBufferNotFound)?;
+    let vaddr = buffer
+        .as_base_page()?
+        .page_data::<TaskBuffer>()
+        .self_address;
+    let root = task
+        .top_level_table()
+        .clone()
+        .ok_or(CapabilityErrors::CapabilityMismatch)?
+        .address_space_paddr()?;
+    Ok((root, UserPtr::new(vaddr.into())?))
+}
+
+/// Offsets of the payload length and the payload data in a [`TaskBuffer`].
+fn payload_offsets() -> (usize, usize) {
+    let buffer = MaybeUninit::<TaskBuffer>::uninit();
+    let base = buffer.as_ptr();
+    unsafe {
+        (
+            ptr::addr_of!((*base).payload_length) as usize - base as usize,
+            ptr::addr_of!((*base).payload_data) as usize - base as usize,
+        )
+    }
+}
+
+/// Read the payload of the task buffer of `task` as a `T`. Fails if the payload
+/// length is not the size of `T`.
+fn read_task_buffer_payload<T: Copy>(task: &Task) -> Result<T, CapabilityErrors> {
+    let (root, buffer) = user_task_buffer(task)?;
+    let (length_offset, data_offset) = payload_offsets();
+
+    let length = copy_from_user(root, UserPtr::<usize>::new(buffer.vaddr() + length_offset)?)?;
+    if length != size_of::<T>() {
+        return Err(CapabilityErrors::TaskBufferPayloadInvalid);
+    }
+    copy_from_user(root, UserPtr::new(buffer.vaddr() + data_offset)?)
+}
+
+/// Write `data` as the payload of the task buffer of `task`.
+fn write_task_buffer_payload<T: Copy>(task: &Task, data: &T) -> Result<(), CapabilityErrors> {
+    let (root, buffer) = user_task_buffer(task)?;
+    let (length_offset, data_offset) = payload_offsets();
+    if size_of::<T>() > TASK_BUFFER_PAYLOAD_LENGTH {
+        return Err(CapabilityErrors::TaskBufferPayloadInvalid);
+    }
+
+    copy_to_user(root, UserPtr::new(buffer.vaddr() + data_offset)?, data)?;
+    copy_to_user(
+        root,
+        UserPtr::<usize>::new(buffer.vaddr() + length_offset)?,
+        &size_of::<T>(),
+    )
 }
@@ -0,0 +1,102 @@
+//! Microbenchmarks for the kernel.
+
+use relic_abi::{
+    bootstrap::BootstrapInfo,
+    cap::CapabilityErrors,
+    prelude::CAddr,
+    syscall::{TaskBuffer, MAP_PERMISSION_WRITE},
+};
+use std::{
+    syscall_wrapper::{self, get_free_space},
+    untyped::{PageSize, BASE_PAGE_SIZE},
+    vspace,
+};
+
+/// Number of syscalls made for every measurement.
+const ITERATIONS: u64 = 1000;
+
+/// Pages of the stack of the second task.
+const PEER_STACK_PAGES: u64 = 4;
+
+/// Untyped memory given to the second task for its syscalls.
+const PEER_MEMORY: u64 = 0x10_0000;
+
+/// Average number of timestamp counter cycles taken by `f`.
+fn measure_cycles<F: FnMut()>(mut f: F) -> u64 {
+    let start = unsafe { core::arch::x86_64::_rdtsc() };
+    for _ in 0..ITERATIONS {
+        f();
+    }
+    let end = unsafe { core::arch::x86_64::_rdtsc() };
+    (end - start) / ITERATIONS
+}
+
+/// Entry of the second task. Makes as many syscalls as the measuring task on its
+/// own untyped memory, which is at `untyped_index` in its cpool.
+extern "C" fn peer_main(untyped_index: u64) -> ! {
+    let untyped_memory = CAddr::from(untyped_index as u8);
+    for _ in 0..ITERATIONS {
+        let _ = get_free_space(untyped_memory);
+    }
+    syscall_wrapper::task_exit(0)
+}
+
+/// Start a second task in this address space that runs [`peer_main`]. It has the
+/// priority of the caller and its own cpool, task buffer and stack.
+fn start_peer(bootstrap_info: &BootstrapInfo) -> Result<CAddr, CapabilityErrors> {
+    let untyped_memory = bootstrap_info.free_mem_regions.0;
+    let cpool = syscall_wrapper::retype_cpool(untyped_memory)?;
+    let untyped_index = syscall_wrapper::retype_untyped(untyped_memory, PEER_MEMORY, cpool)?;
+
+    let task_buffer = syscall_wrapper::retype_raw_page(untyped_memory, 0)?;
+    let buffer_vaddr = vspace::map_pages(&[task_buffer], PageSize::Base, MAP_PERMISSION_WRITE)?;
+    unsafe { (*(buffer_vaddr as *mut TaskBuffer)).self_address = buffer_vaddr };
+    let stack = vspace::map_anonymous(PEER_STACK_PAGES * BASE_PAGE_SIZE, MAP_PERMISSION_WRITE)?;
+
+    let task = syscall_wrapper::task_retype(
+        untyped_memory,
+        cpool,
+        bootstrap_info.top_level_pml4,
+        task_buffer,
+    )?;
+    let mut registers = syscall_wrapper::task_read_registers(task)?;
+    registers.rip = peer_main as u64;
+    // Aligned as if the entry was called.
+    registers.rsp = stack + PEER_STACK_PAGES * BASE_PAGE_SIZE - 8;
+    registers.rdi = untyped_index as u64;
+    registers.fs = buffer_vaddr;
+    syscall_wrapper::task_write_registers(task, &registers)?;
+    syscall_wrapper::task_start(task)?;
+    Ok(task)
+}
+
+/// Measure the round trip of a non-blocking syscall. It returns to the caller
+/// directly while no other task of its priority is ready. With a second task of the
+/// same priority making the same syscalls, every syscall goes through the run
+/// queue and the tasks take turns, so half of the time is spent in each task.
+pub fn syscall_round_trip(bootstrap_info: &BootstrapInfo) {
+    let untyped_memory = bootstrap_info.free_mem_regions.0;
+    let direct = measure_cycles(|| {
+        get_free_space(untyped_memory).unwrap();
+    });
+
+    let peer = match start_peer(bootstrap_info) {
+        Ok(peer) => peer,
+        Err(error) => {
+            warn!(
+                "Syscall round trip: cannot start a second task: {:?}",
+                error
+            );
+            return;
+        }
+    };
+    let queued = measure_cycles(|| {
+        get_free_space(untyped_memory).unwrap();
+    }) / 2;
+    let _ = syscall_wrapper::task_join(peer);
+
+    info!(
+        "Syscall round trip: {} cycles direct, {} cycles through the run queue",
+        direct, queued
+    );
+}
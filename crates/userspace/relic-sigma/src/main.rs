@@ -15,12 +15,20 @@ use relic_std::prelude::*;
 #[macro_use]
 extern crate log;
 
+#[cfg(feature = "benchmark")]
+pub mod benchmark;
 pub mod graphics;
 
 #[no_mangle]
 pub fn user_main(bootstrap_info: &BootstrapInfo) {
     load_graphics(&bootstrap_info.fb_info);
     info!("Welcome to Relic OS!");
+    #[cfg(feature = "list-initrd")]
+    if let Some(initrd) = std::initrd::archive(bootstrap_info) {
+        info!("Initrd: {}", initrd);
+    }
+    #[cfg(feature = "benchmark")]
+    benchmark::syscall_round_trip(bootstrap_info);
 }
 
 fn load_graphics(fb_info: &FrameBufferInfo) {